@@ -1,7 +1,7 @@
 use criterion::async_executor::FuturesExecutor;
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use kvs::thread_pool::RayonThreadPool;
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvsEngine, NoopEngine, SledKvsEngine};
 use rand::prelude::*;
 use tempfile::TempDir;
 
@@ -50,6 +50,18 @@ fn set_bench(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+
+    // the cost of the engine calls alone, to compare the engines against
+    group.bench_function("noop", |b| {
+        b.to_async(FuturesExecutor).iter(|| async {
+            for i in 1..(1 << 12) {
+                NoopEngine
+                    .set(format!("key{}", i), "value".to_string())
+                    .await
+                    .unwrap();
+            }
+        })
+    });
     group.finish();
 }
 
@@ -93,6 +105,15 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
+    group.bench_function("noop", |b| {
+        let rng = SmallRng::from_seed([0; 32]);
+        b.to_async(FuturesExecutor).iter(|| async {
+            NoopEngine
+                .get(format!("key{}", rng.clone().gen_range(1..1 << 8)))
+                .await
+                .unwrap();
+        })
+    });
     group.finish();
 }
 
@@ -0,0 +1,63 @@
+use serde::Deserialize;
+
+use crate::server::secure_eq;
+
+/// The users allowed on a `KvsServer` and the keys each of them may read and write.
+///
+/// Permissions are granted on key prefixes: a user may read a key if it starts with
+/// one of their `read` prefixes, and write it if it starts with one of their `write`
+/// prefixes. An empty prefix grants access to every key.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    users: Vec<AclUser>,
+}
+
+/// A user of an `Acl`, usually read from the server config file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AclUser {
+    /// The name the user authenticates with.
+    pub name: String,
+    /// The password the user authenticates with.
+    pub password: String,
+    /// Prefixes of the keys the user may read.
+    #[serde(default)]
+    pub read: Vec<String>,
+    /// Prefixes of the keys the user may set, append to or remove.
+    #[serde(default)]
+    pub write: Vec<String>,
+    /// Whether the user may run admin requests such as `Request::FlushAll` and
+    /// `Request::Backup`.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl Acl {
+    /// Creates an `Acl` allowing the given users.
+    pub fn new(users: Vec<AclUser>) -> Self {
+        Acl { users }
+    }
+
+    /// The user with the given name, if the password matches.
+    pub(crate) fn authenticate(&self, name: &str, password: &str) -> Option<&AclUser> {
+        self.users
+            .iter()
+            .find(|user| user.name == name)
+            .filter(|user| secure_eq(user.password.as_bytes(), password.as_bytes()))
+    }
+}
+
+impl AclUser {
+    /// Whether the user may read `key`.
+    pub fn can_read(&self, key: &str) -> bool {
+        self.read
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    /// Whether the user may write `key`.
+    pub fn can_write(&self, key: &str) -> bool {
+        self.write
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use serde::Serialize;
+use tracing::error;
+
+use crate::{KeyEvent, KvsEngine, Result, ScanOptions, ValueMeta};
+
+/// An append-only log of the writes clients make, kept apart from the data.
+///
+/// Every successful write is appended as one JSON object per line, carrying the time
+/// in milliseconds since the Unix epoch, the client's address, the ACL user it
+/// authenticated as, the database, the operation and the key. Values are never
+/// logged. Clones share the same file.
+#[derive(Clone)]
+pub struct AuditLog {
+    writer: Arc<Mutex<Writer>>,
+}
+
+/// Options for `AuditLog::open_with_options`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditLogOptions {
+    /// The size in bytes at which the log is rotated, or None to let it grow.
+    ///
+    /// Rotating renames `audit.log` to `audit.log.1`, `audit.log.1` to `audit.log.2`
+    /// and so on, then starts a new file.
+    pub max_size: Option<u64>,
+    /// How many rotated files to keep; older ones are deleted.
+    pub max_files: usize,
+}
+
+impl Default for AuditLogOptions {
+    fn default() -> Self {
+        AuditLogOptions {
+            max_size: None,
+            max_files: 5,
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    options: AuditLogOptions,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp_ms: u64,
+    peer: Option<SocketAddr>,
+    user: Option<&'a str>,
+    db: u32,
+    op: &'a str,
+    key: Option<&'a str>,
+}
+
+impl AuditLog {
+    /// Append to the audit log at `path`, creating it if needed, without rotation.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, AuditLogOptions::default())
+    }
+
+    /// Append to the audit log at `path` with the given options.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: AuditLogOptions) -> Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let len = file.metadata()?.len();
+        Ok(AuditLog {
+            writer: Arc::new(Mutex::new(Writer {
+                path,
+                file,
+                len,
+                options,
+            })),
+        })
+    }
+
+    fn record(&self, caller: &Caller, op: &str, key: Option<&str>) -> Result<()> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_millis() as u64);
+        let entry = Entry {
+            timestamp_ms,
+            peer: caller.peer,
+            user: caller.user.as_deref(),
+            db: caller.db,
+            op,
+            key,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(max_size) = writer.options.max_size {
+            if writer.len > 0 && writer.len + line.len() as u64 > max_size {
+                writer.rotate()?;
+            }
+        }
+        // one write per entry, so lines of concurrent connections never interleave
+        writer.file.write_all(&line)?;
+        writer.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Writer {
+    /// Shift the rotated files up by one, dropping the oldest, and start a new file.
+    fn rotate(&mut self) -> Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        let max_files = self.options.max_files;
+        if max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            match fs::remove_file(rotated(max_files)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            for n in (1..max_files).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+/// Who a connection's writes are audited as.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Caller {
+    // None for a Unix socket
+    pub(crate) peer: Option<SocketAddr>,
+    // the ACL user, if the connection authenticated as one
+    pub(crate) user: Option<String>,
+    pub(crate) db: u32,
+}
+
+/// The engine of one connection, appending its successful writes to the audit log
+/// if there is one.
+#[derive(Clone)]
+pub(crate) struct Audited<E: KvsEngine> {
+    inner: E,
+    log: Option<AuditLog>,
+    caller: Arc<Caller>,
+}
+
+impl<E: KvsEngine> Audited<E> {
+    pub(crate) fn new(inner: E, log: Option<AuditLog>, caller: Caller) -> Self {
+        Audited {
+            inner,
+            log,
+            caller: Arc::new(caller),
+        }
+    }
+
+    /// Log `op` on `key` if `res` reports success.
+    fn audit<T>(&self, op: &str, key: Option<&str>, res: &Result<T>) {
+        if let (Some(log), Ok(_)) = (&self.log, res) {
+            if let Err(e) = log.record(&self.caller, op, key) {
+                error!("Failed to write the audit log: {}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine> KvsEngine for Audited<E> {
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        let res = self.inner.clone().set_bytes(key.clone(), value).await;
+        self.audit("set", Some(&String::from_utf8_lossy(&key)), &res);
+        res
+    }
+
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        self.inner.get_bytes(key).await
+    }
+
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
+        let res = self.inner.clone().remove_bytes(key.clone()).await;
+        self.audit("remove", Some(&String::from_utf8_lossy(&key)), &res);
+        res
+    }
+
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        let res = self.inner.clone().append(key.clone(), suffix).await;
+        self.audit("append", Some(&key), &res);
+        res
+    }
+
+    async fn getdel(self, key: String) -> Result<Option<String>> {
+        let res = self.inner.clone().getdel(key.clone()).await;
+        // nothing was removed if the key didn't exist
+        if let Ok(Some(_)) = res {
+            self.audit("remove", Some(&key), &res);
+        }
+        res
+    }
+
+    async fn getset(self, key: String, value: String) -> Result<Option<String>> {
+        let res = self.inner.clone().getset(key.clone(), value).await;
+        self.audit("set", Some(&key), &res);
+        res
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        let res = self
+            .inner
+            .clone()
+            .compare_and_swap(key.clone(), expected, value)
+            .await;
+        if let Ok(true) = res {
+            self.audit("set", Some(&key), &res);
+        }
+        res
+    }
+
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        let res = self.inner.clone().rename(from.clone(), to.clone()).await;
+        self.audit("remove", Some(&from), &res);
+        self.audit("set", Some(&to), &res);
+        res
+    }
+
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        let res = self.inner.clone().copy(from, to.clone(), overwrite).await;
+        if let Ok(true) = res {
+            self.audit("set", Some(&to), &res);
+        }
+        res
+    }
+
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        self.inner.scan(options).await
+    }
+
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let res = self
+            .inner
+            .clone()
+            .set_with_ttl(key.clone(), value, ttl)
+            .await;
+        self.audit("set", Some(&key), &res);
+        res
+    }
+
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        self.inner.ttl(key).await
+    }
+
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        let res = self.inner.clone().expire(key.clone(), ttl).await;
+        if let Ok(true) = res {
+            self.audit("expire", Some(&key), &res);
+        }
+        res
+    }
+
+    async fn persist(self, key: String) -> Result<bool> {
+        let res = self.inner.clone().persist(key.clone()).await;
+        if let Ok(true) = res {
+            self.audit("persist", Some(&key), &res);
+        }
+        res
+    }
+
+    async fn meta(self, key: String) -> Result<Option<ValueMeta>> {
+        self.inner.meta(key).await
+    }
+
+    async fn exists(self, key: String) -> Result<bool> {
+        self.inner.exists(key).await
+    }
+
+    async fn len(self) -> Result<u64> {
+        self.inner.len().await
+    }
+
+    async fn count_prefix(self, prefix: String) -> Result<u64> {
+        self.inner.count_prefix(prefix).await
+    }
+
+    async fn clear(self) -> Result<()> {
+        let res = self.inner.clone().clear().await;
+        self.audit("clear", None, &res);
+        res
+    }
+
+    async fn write_batch(self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        let keys: Vec<_> = writes
+            .iter()
+            .map(|(key, value)| (key.clone(), value.is_some()))
+            .collect();
+        let res = self.inner.clone().write_batch(writes).await;
+        for (key, set) in keys {
+            let op = if set { "set" } else { "remove" };
+            self.audit(op, Some(&key), &res);
+        }
+        res
+    }
+
+    async fn flush(self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn engine_stats(self) -> Result<BTreeMap<String, u64>> {
+        self.inner.engine_stats().await
+    }
+
+    async fn backup(self, dest: PathBuf) -> Result<()> {
+        self.inner.backup(dest).await
+    }
+
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        self.inner.watch(prefix)
+    }
+}
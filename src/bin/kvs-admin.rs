@@ -0,0 +1,269 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+use kvs::{
+    thread_pool::RayonThreadPool, EngineHandle, KvStore, KvsEngine, KvsEngineExt, KvsError, Result,
+    SledKvsEngine,
+};
+use structopt::{
+    clap::{arg_enum, AppSettings},
+    StructOpt,
+};
+
+// the tools work through a single thread, no requests are served concurrently
+const THREADS: u32 = 1;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-admin",
+    about = "Maintains the data directory of a stopped kvs-server",
+    global_settings = &
+    [AppSettings::DisableHelpSubcommand, AppSettings::VersionlessSubcommands]
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+    #[structopt(
+        long,
+        global = true,
+        help = "Sets the data directory",
+        value_name = "PATH",
+        default_value = "."
+    )]
+    dir: PathBuf,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "compact", about = "Reclaim the space of stale log entries")]
+    Compact,
+    #[structopt(
+        name = "verify",
+        about = "Check every log record and the index built from them"
+    )]
+    Verify,
+    #[structopt(name = "stats", about = "Show the size of the store")]
+    Stats,
+    #[structopt(name = "backup", about = "Copy the store into a new data directory")]
+    Backup {
+        #[structopt(name = "DEST", about = "Directory to copy into")]
+        dest: PathBuf,
+    },
+    #[structopt(
+        name = "restore",
+        about = "Verify a backup and copy it into an empty data directory"
+    )]
+    Restore {
+        #[structopt(name = "SRC", about = "Directory of the backup")]
+        src: PathBuf,
+    },
+    #[structopt(name = "migrate", about = "Copy the pairs into another engine")]
+    Migrate {
+        #[structopt(
+            long,
+            help = "Sets the engine to migrate to",
+            value_name = "ENGINE-NAME",
+            possible_values = &Engine::variants()
+        )]
+        to: Engine,
+    },
+}
+
+arg_enum! {
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    #[allow(non_camel_case_types)]
+    enum Engine {
+        kvs,
+        sled,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::from_args();
+    if let Err(err) = run(opt).await {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+async fn run(opt: Opt) -> Result<()> {
+    let dir = opt.dir;
+    match opt.command {
+        Command::Compact => {
+            require_kvs(&dir, "Compaction")?;
+            let before = KvStore::<RayonThreadPool>::inspect(&dir)?;
+            let store = KvStore::<RayonThreadPool>::open(&dir, THREADS)?;
+            store.clone().compact().await?;
+            store.close().await?;
+            let after = KvStore::<RayonThreadPool>::inspect(&dir)?;
+            println!(
+                "Compacted {} log files ({} bytes) into {} ({} bytes)",
+                before.generations.len(),
+                before.log_bytes,
+                after.generations.len(),
+                after.log_bytes
+            );
+        }
+        Command::Verify => {
+            require_kvs(&dir, "Verification")?;
+            let report = KvStore::<RayonThreadPool>::verify(&dir)?;
+            println!("Records: {}, live keys: {}", report.records, report.keys);
+            for record in &report.corrupted {
+                println!(
+                    "Generation {} at byte {}: {}",
+                    record.generation, record.position, record.reason
+                );
+            }
+            if !report.is_ok() {
+                return Err(KvsError::StringError(format!(
+                    "Found {} corrupted records",
+                    report.corrupted.len()
+                )));
+            }
+            println!("No corruption found");
+        }
+        Command::Stats => match initialized_engine(&dir)? {
+            Engine::kvs => {
+                let info = KvStore::<RayonThreadPool>::inspect(&dir)?;
+                println!("engine\tkvs");
+                println!("keys\t{}", info.keys);
+                println!("log_files\t{}", info.generations.len());
+                println!("log_bytes\t{}", info.log_bytes);
+                println!("uncompacted_bytes\t{}", info.uncompacted);
+            }
+            Engine::sled => {
+                let db = sled::open(&dir)?;
+                println!("engine\tsled");
+                println!("keys\t{}", db.len());
+                println!("size_on_disk\t{}", db.size_on_disk()?);
+            }
+        },
+        Command::Backup { dest } => {
+            require_kvs(&dir, "Backups")?;
+            let store = KvStore::<RayonThreadPool>::open(&dir, THREADS)?;
+            store.clone().backup(dest.clone()).await?;
+            store.close().await?;
+            // the copy can be served as it is
+            fs::write(dest.join("engine"), format!("{}", Engine::kvs))?;
+            println!("Backed up {} to {}", dir.display(), dest.display());
+        }
+        Command::Restore { src } => {
+            match read_marker(&dir)? {
+                Some(Engine::kvs) | None => {}
+                Some(engine) => {
+                    return Err(KvsError::StringError(format!(
+                        "{} holds a {} store",
+                        dir.display(),
+                        engine
+                    )))
+                }
+            }
+            let report = KvStore::<RayonThreadPool>::restore(&src, &dir)?;
+            fs::write(dir.join("engine"), format!("{}", Engine::kvs))?;
+            println!("Restored {} keys into {}", report.keys, dir.display());
+        }
+        Command::Migrate { to } => migrate(&dir, to).await?,
+    }
+    Ok(())
+}
+
+/// Copies every pair into the `to` engine through a dump file and points the engine
+/// marker at it, like `kvs-server --migrate-to`.
+async fn migrate(dir: &Path, to: Engine) -> Result<()> {
+    let from = initialized_engine(dir)?;
+    if from == to {
+        return Err(KvsError::StringError(format!(
+            "The data directory already uses the {} engine",
+            to
+        )));
+    }
+    let source = open_engine(dir, from)?;
+    let target = open_engine(dir, to)?;
+    if target.clone().first_key().await?.is_some() {
+        return Err(KvsError::StringError(format!(
+            "The {} engine already holds data in {}",
+            to,
+            dir.display()
+        )));
+    }
+
+    let dump = dir.join("migration.dump");
+    let exported = source
+        .clone()
+        .export(BufWriter::new(File::create(&dump)?))
+        .await?;
+    let imported = target
+        .clone()
+        .import(BufReader::new(File::open(&dump)?))
+        .await?;
+    target.close().await?;
+    source.close().await?;
+    fs::remove_file(&dump)?;
+    if imported != exported {
+        return Err(KvsError::StringError(format!(
+            "Exported {} pairs but imported {}, the engine marker was not changed",
+            exported, imported
+        )));
+    }
+
+    let staged = dir.join("engine.tmp");
+    fs::write(&staged, format!("{}", to))?;
+    fs::rename(&staged, dir.join("engine"))?;
+    println!(
+        "Migrated {} pairs, the {} files can be removed once the new engine is verified",
+        imported, from
+    );
+    Ok(())
+}
+
+fn open_engine(dir: &Path, engine: Engine) -> Result<EngineHandle> {
+    let engine = match engine {
+        Engine::kvs => EngineHandle::new(KvStore::<RayonThreadPool>::open(dir, THREADS)?),
+        Engine::sled => EngineHandle::new(SledKvsEngine::<RayonThreadPool>::new(
+            sled::open(dir)?,
+            THREADS,
+        )?),
+    };
+    Ok(engine)
+}
+
+/// Fails unless the data directory holds a kvs engine store, which is all `what`
+/// supports.
+fn require_kvs(dir: &Path, what: &str) -> Result<()> {
+    match initialized_engine(dir)? {
+        Engine::kvs => Ok(()),
+        engine => Err(KvsError::StringError(format!(
+            "{} is not supported by the {} engine",
+            what, engine
+        ))),
+    }
+}
+
+/// The engine named by the engine marker, which must exist.
+fn initialized_engine(dir: &Path) -> Result<Engine> {
+    read_marker(dir)?.ok_or_else(|| {
+        KvsError::StringError(format!(
+            "No engine marker found in {}, it holds no store",
+            dir.display()
+        ))
+    })
+}
+
+fn read_marker(dir: &Path) -> Result<Option<Engine>> {
+    let marker = dir.join("engine");
+    if !marker.exists() {
+        return Ok(None);
+    }
+    let name = fs::read_to_string(marker)?;
+    name.parse().map(Some).map_err(|_| {
+        KvsError::StringError(format!(
+            "The {} engine is not supported by kvs-admin",
+            name.trim()
+        ))
+    })
+}
@@ -1,10 +1,36 @@
-use std::{net::SocketAddr, process::exit};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    mem,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use kvs::{KvsClient, Result};
-use structopt::{clap::AppSettings, StructOpt};
+use futures::{StreamExt, TryStreamExt};
+use kvs::{
+    rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName},
+    KvsClient, KvsClientBuilder, KvsError, Request, Response, Result, ServerStats,
+};
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use structopt::{
+    clap::{arg_enum, AppSettings},
+    StructOpt,
+};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
-const ADDRESS_FORMAT: &str = "IP:PORT";
+const ADDRESS_FORMAT: &str = "HOST:PORT";
+// sets sent per pipeline by `import`
+const IMPORT_PIPELINE_LEN: usize = 1000;
+// rows `top` prints between repeating its header
+const TOP_HEADER_EVERY: u64 = 20;
+// exit codes, for scripts to tell failures apart
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_UNAVAILABLE: i32 = 4;
 
 #[derive(StructOpt, Debug)]
 #[structopt(
@@ -15,6 +41,92 @@ const ADDRESS_FORMAT: &str = "IP:PORT";
 struct Opt {
     #[structopt(subcommand)]
     command: Command,
+    #[structopt(
+        long,
+        global = true,
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        env = "KVS_ADDR"
+    )]
+    addr: String,
+    #[structopt(
+        long,
+        global = true,
+        help = "Gives up on requests unanswered after this many seconds, 0 for never",
+        value_name = "SECONDS",
+        env = "KVS_TIMEOUT"
+    )]
+    timeout: Option<u64>,
+    #[structopt(
+        long,
+        global = true,
+        help = "Authenticates with the server's password",
+        value_name = "PASSWORD",
+        env = "KVS_PASSWORD",
+        hide_env_values = true
+    )]
+    password: Option<String>,
+    #[structopt(
+        short,
+        long,
+        global = true,
+        help = "Prints only results and errors, not informational messages"
+    )]
+    quiet: bool,
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        global = true,
+        help = "Connects over the server's Unix socket instead of the address",
+        value_name = "PATH"
+    )]
+    unix_socket: Option<PathBuf>,
+    #[structopt(
+        long,
+        global = true,
+        help = "Selects this numbered database on the server",
+        value_name = "N"
+    )]
+    db: Option<u32>,
+    #[structopt(
+        long,
+        global = true,
+        help = "Connects over TLS, trusting the CAs in --ca-file",
+        requires = "ca-file"
+    )]
+    tls: bool,
+    #[structopt(
+        long,
+        global = true,
+        help = "Trusts server certificates signed by a CA in this PEM bundle",
+        value_name = "PATH",
+        requires = "tls"
+    )]
+    ca_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        global = true,
+        help = "Presents this PEM certificate chain to servers requiring one",
+        value_name = "PATH",
+        requires_all = &["tls", "tls-key"]
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long,
+        global = true,
+        help = "Signs with this PEM private key for --tls-cert",
+        value_name = "PATH",
+        requires = "tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long,
+        global = true,
+        help = "Refuses requests and responses larger than this many bytes",
+        value_name = "BYTES"
+    )]
+    max_frame_length: Option<usize>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -23,72 +135,626 @@ enum Command {
     Get {
         #[structopt(name = "KEY", about = "String key")]
         key: String,
+        #[structopt(long, help = "Fails if the key doesn't exist")]
+        strict: bool,
         #[structopt(
             long,
-            help = "Sets the server address",
-            value_name = ADDRESS_FORMAT,
-            default_value = DEFAULT_LISTENING_ADDRESS,
-            parse(try_from_str)
+            help = "Writes the value to this file instead of stdout",
+            value_name = "PATH",
+            parse(from_os_str)
         )]
-        addr: SocketAddr,
+        output: Option<PathBuf>,
     },
     #[structopt(name = "set", about = "Set the value of a given key")]
     Set {
         #[structopt(name = "KEY", about = "String key")]
         key: String,
-        #[structopt(name = "VALUE", about = "String value")]
-        value: String,
+        #[structopt(name = "VALUE", about = "String value", required_unless = "value-file")]
+        value: Option<String>,
         #[structopt(
             long,
-            help = "Sets the server address",
-            value_name = ADDRESS_FORMAT,
-            default_value = DEFAULT_LISTENING_ADDRESS,
-            parse(try_from_str)
+            help = "Reads the value from this file instead of the command line",
+            value_name = "PATH",
+            parse(from_os_str),
+            conflicts_with = "VALUE"
         )]
-        addr: SocketAddr,
+        value_file: Option<PathBuf>,
+        #[structopt(
+            long,
+            help = "Removes the key after this many seconds",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
     },
     #[structopt(name = "rm", about = "Remove a given key")]
     Remove {
         #[structopt(name = "KEY", about = "String key")]
         key: String,
+    },
+    #[structopt(
+        name = "scan",
+        about = "List the keys starting with a prefix and their values"
+    )]
+    Scan {
+        #[structopt(name = "PREFIX", about = "Key prefix")]
+        prefix: Option<String>,
+        #[structopt(
+            long = "prefix",
+            help = "Lists keys starting with this prefix, like PREFIX",
+            value_name = "PREFIX",
+            conflicts_with = "PREFIX"
+        )]
+        prefix_option: Option<String>,
+        #[structopt(long, help = "Lists at most this many pairs", value_name = "N")]
+        limit: Option<usize>,
         #[structopt(
             long,
-            help = "Sets the server address",
-            value_name = ADDRESS_FORMAT,
-            default_value = DEFAULT_LISTENING_ADDRESS,
-            parse(try_from_str)
+            help = "Prints tab separated pairs or JSON lines",
+            value_name = "FORMAT",
+            possible_values = &Output::variants(),
+            case_insensitive = true,
+            default_value = "tsv"
+        )]
+        output: Output,
+    },
+    #[structopt(name = "keys", about = "List the keys starting with a prefix")]
+    Keys {
+        #[structopt(name = "PREFIX", about = "Key prefix", default_value = "")]
+        prefix: String,
+        #[structopt(long, help = "Lists at most this many keys", value_name = "N")]
+        limit: Option<usize>,
+        #[structopt(
+            long,
+            help = "Prints a key per line or JSON strings",
+            value_name = "FORMAT",
+            possible_values = &Output::variants(),
+            case_insensitive = true,
+            default_value = "tsv"
+        )]
+        output: Output,
+    },
+    #[structopt(
+        name = "import",
+        about = "Set the pairs of a file, given as `SET key value` or JSON lines"
+    )]
+    Import {
+        #[structopt(
+            name = "FILE",
+            about = "File to read, or - for stdin",
+            parse(from_os_str)
+        )]
+        file: PathBuf,
+    },
+    #[structopt(
+        name = "export",
+        about = "Write the pairs whose keys start with a prefix as JSON lines"
+    )]
+    Export {
+        #[structopt(name = "PREFIX", about = "Key prefix", default_value = "")]
+        prefix: String,
+    },
+    #[structopt(name = "slowlog", about = "Show the requests the server found slow")]
+    SlowLog {
+        #[structopt(
+            long,
+            help = "Shows at most this many entries",
+            value_name = "N",
+            default_value = "10"
+        )]
+        count: usize,
+        #[structopt(long, help = "Empties the log instead of showing it")]
+        reset: bool,
+    },
+    #[structopt(name = "stats", about = "Show the server's statistics")]
+    Stats,
+    #[structopt(
+        name = "top",
+        about = "Show the server's statistics again every interval, one line each"
+    )]
+    Top {
+        #[structopt(
+            long,
+            help = "Waits this many seconds between samples",
+            value_name = "SECONDS",
+            default_value = "1"
+        )]
+        interval: u64,
+        #[structopt(long, help = "Stops after this many samples", value_name = "N")]
+        count: Option<u64>,
+    },
+    #[structopt(
+        name = "backup",
+        about = "Have the server copy its store into a directory while it keeps running"
+    )]
+    Backup {
+        #[structopt(
+            name = "PATH",
+            about = "Directory on the server, relative to its --backup-dir",
+            parse(from_os_str)
         )]
-        addr: SocketAddr,
+        path: PathBuf,
     },
+    #[structopt(
+        name = "flushall",
+        about = "Remove every key of the selected database; atomic on a server with one database"
+    )]
+    FlushAll {
+        #[structopt(long, help = "Confirms that every key should be removed")]
+        yes: bool,
+    },
+    #[structopt(name = "ping", about = "Check that the server is up")]
+    Ping,
+    #[structopt(
+        name = "bench",
+        about = "Measure round trips to the server with echo requests"
+    )]
+    Bench {
+        #[structopt(
+            long,
+            help = "Sends this many requests",
+            value_name = "N",
+            default_value = "1000"
+        )]
+        requests: usize,
+        #[structopt(
+            long,
+            help = "Echoes payloads of this many bytes",
+            value_name = "BYTES",
+            default_value = "64"
+        )]
+        size: usize,
+    },
+}
+
+arg_enum! {
+    /// How `scan` and `keys` print what they list.
+    #[derive(Debug, Clone, Copy)]
+    #[allow(non_camel_case_types)]
+    enum Output {
+        tsv,
+        json,
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let opt = Opt::from_args();
+    let matches = Opt::clap().get_matches_safe().unwrap_or_else(|e| {
+        // help and version go to stdout and succeed
+        if !e.use_stderr() {
+            e.exit();
+        }
+        eprintln!("{}", e.message);
+        exit(EXIT_USAGE);
+    });
+    let opt = Opt::from_clap(&matches);
     if let Err(err) = run(opt).await {
         eprintln!("{}", err);
-        exit(1);
+        exit(exit_code(&err));
+    }
+}
+
+/// The exit code for failing with `err`.
+fn exit_code(err: &KvsError) -> i32 {
+    match err {
+        KvsError::KeyNotFound => EXIT_NOT_FOUND,
+        // the server's error only survives the wire as its message
+        KvsError::StringError(e) if *e == KvsError::KeyNotFound.to_string() => EXIT_NOT_FOUND,
+        KvsError::Timeout => EXIT_UNAVAILABLE,
+        KvsError::Io(e) => match e.kind() {
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof => EXIT_UNAVAILABLE,
+            _ => EXIT_FAILURE,
+        },
+        _ => EXIT_FAILURE,
     }
 }
 
 async fn run(opt: Opt) -> Result<()> {
-    match opt.command {
-        Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr).await?;
-            if let Some(value) = client.get(key).await? {
-                println!("{}", value);
+    match &opt.command {
+        Command::Get {
+            key,
+            strict,
+            output,
+        } => {
+            let mut client = connect(&opt).await?;
+            match (client.get(key.clone()).await?, output) {
+                // written as is, without the newline printing adds
+                (Some(value), Some(path)) => fs::write(path, value)?,
+                (Some(value), None) => println!("{}", value),
+                (None, _) if *strict => return Err(KvsError::KeyNotFound),
+                (None, _) => {
+                    if !opt.quiet {
+                        println!("Key not found");
+                    }
+                }
+            }
+        }
+        Command::Set {
+            key,
+            value,
+            value_file,
+            ttl,
+        } => {
+            let value = match (value, value_file) {
+                (Some(value), _) => value.clone(),
+                (None, Some(path)) => fs::read_to_string(path)?,
+                (None, None) => unreachable!("VALUE is required without --value-file"),
+            };
+            let mut client = connect(&opt).await?;
+            match ttl {
+                Some(secs) => {
+                    let ttl = Duration::from_secs(*secs);
+                    client.set_with_ttl(key.clone(), value, ttl).await?
+                }
+                None => client.set(key.clone(), value).await?,
+            }
+        }
+        Command::Remove { key } => {
+            let mut client = connect(&opt).await?;
+            client.remove(key.clone()).await?;
+        }
+        Command::Scan {
+            prefix,
+            prefix_option,
+            limit,
+            output,
+        } => {
+            let mut client = connect(&opt).await?;
+            let prefix = prefix.as_ref().or(prefix_option.as_ref());
+            let mut pairs = client
+                .scan(prefix.cloned().unwrap_or_default())
+                .take(limit.unwrap_or(usize::MAX));
+            let mut stdout = io::stdout().lock();
+            while let Some((key, value)) = pairs.try_next().await? {
+                match output {
+                    Output::tsv => writeln!(stdout, "{}\t{}", key, value)?,
+                    Output::json => {
+                        serde_json::to_writer(&mut stdout, &Pair { key, value })?;
+                        writeln!(stdout)?;
+                    }
+                }
+            }
+        }
+        Command::Keys {
+            prefix,
+            limit,
+            output,
+        } => {
+            let mut client = connect(&opt).await?;
+            let mut pairs = client
+                .scan(prefix.clone())
+                .take(limit.unwrap_or(usize::MAX));
+            let mut stdout = io::stdout().lock();
+            while let Some((key, _)) = pairs.try_next().await? {
+                match output {
+                    Output::tsv => writeln!(stdout, "{}", key)?,
+                    Output::json => {
+                        serde_json::to_writer(&mut stdout, &key)?;
+                        writeln!(stdout)?;
+                    }
+                }
+            }
+        }
+        Command::Import { file } => {
+            let input: Box<dyn BufRead> = if file == Path::new("-") {
+                Box::new(io::stdin().lock())
             } else {
-                println!("Key not found");
+                Box::new(BufReader::new(File::open(file)?))
+            };
+            let mut client = connect(&opt).await?;
+            let (mut requests, mut line_numbers) = (Vec::new(), Vec::new());
+            let mut imported = 0;
+            for (i, line) in input.lines().enumerate() {
+                let pair = parse_import_line(&line?)
+                    .map_err(|e| KvsError::StringError(format!("Line {}: {}", i + 1, e)))?;
+                if let Some(Pair { key, value }) = pair {
+                    requests.push(Request::Set {
+                        key,
+                        value,
+                        ttl_ms: None,
+                    });
+                    line_numbers.push(i + 1);
+                }
+                if requests.len() == IMPORT_PIPELINE_LEN {
+                    imported += requests.len();
+                    import(&mut client, mem::take(&mut requests), &line_numbers).await?;
+                    line_numbers.clear();
+                }
+            }
+            imported += requests.len();
+            import(&mut client, requests, &line_numbers).await?;
+            if !opt.quiet {
+                eprintln!("Imported {} pairs", imported);
             }
         }
-        Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr).await?;
-            client.set(key, value).await?
+        Command::Export { prefix } => {
+            let mut client = connect(&opt).await?;
+            let mut pairs = client.scan(prefix.clone());
+            let mut stdout = io::stdout().lock();
+            while let Some((key, value)) = pairs.try_next().await? {
+                serde_json::to_writer(&mut stdout, &Pair { key, value })?;
+                writeln!(stdout)?;
+            }
         }
-        Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr).await?;
-            client.remove(key).await?;
+        Command::SlowLog { count, reset } => {
+            let mut client = connect(&opt).await?;
+            if *reset {
+                client.slowlog_reset().await?;
+            } else {
+                for entry in client.slowlog(*count).await? {
+                    let peer = entry.peer.map_or("-".to_owned(), |peer| peer.to_string());
+                    println!(
+                        "{} {} {}us {} {} {}",
+                        entry.id,
+                        entry.timestamp,
+                        entry.duration.as_micros(),
+                        entry.op,
+                        entry.key.as_deref().unwrap_or("-"),
+                        peer
+                    );
+                }
+            }
+        }
+        Command::Stats => {
+            let mut client = connect(&opt).await?;
+            let stats = client.stats().await?;
+            println!("uptime_secs: {}", stats.uptime.as_secs());
+            println!("connections: {}", stats.connections);
+            println!("total_connections: {}", stats.total_connections);
+            if let Some(bytes) = stats.data_dir_bytes {
+                println!("data_dir_bytes: {}", bytes);
+            }
+            for (op, count) in &stats.ops {
+                println!("ops.{}: {}", op, count);
+            }
+            for (name, value) in &stats.engine {
+                println!("engine.{}: {}", name, value);
+            }
+        }
+        Command::Top { interval, count } => {
+            let mut client = connect(&opt).await?;
+            let mut previous: Option<ServerStats> = None;
+            let mut stdout = io::stdout().lock();
+            for sample in 0..count.unwrap_or(u64::MAX) {
+                if sample > 0 {
+                    tokio::time::sleep(Duration::from_secs(*interval)).await;
+                }
+                let started = Instant::now();
+                let stats = client.stats().await?;
+                let latency = started.elapsed();
+                if sample % TOP_HEADER_EVERY == 0 {
+                    writeln!(
+                        stdout,
+                        "{:<12} {:<7} {:<10} {:<10} {:<12} {:<12}",
+                        "keys", "conns", "ops/s", "latency", "uncompacted", "data"
+                    )?;
+                }
+                // rates need a previous sample
+                let ops_per_sec = match &previous {
+                    Some(previous) => {
+                        let ops = stats.ops.values().sum::<u64>();
+                        let previous_ops = previous.ops.values().sum::<u64>();
+                        let elapsed = stats.uptime.saturating_sub(previous.uptime);
+                        let rate = ops.saturating_sub(previous_ops) as f64
+                            / elapsed.as_secs_f64().max(f64::EPSILON);
+                        format!("{:.0}", rate)
+                    }
+                    None => "-".to_owned(),
+                };
+                let engine = |name: &str| stats.engine.get(name).copied();
+                writeln!(
+                    stdout,
+                    "{:<12} {:<7} {:<10} {:<10} {:<12} {:<12}",
+                    engine("keys").map_or("-".to_owned(), |keys| keys.to_string()),
+                    stats.connections,
+                    ops_per_sec,
+                    format!("{:.2}ms", latency.as_secs_f64() * 1000.0),
+                    engine("uncompacted_bytes").map_or("-".to_owned(), human_bytes),
+                    stats.data_dir_bytes.map_or("-".to_owned(), human_bytes),
+                )?;
+                stdout.flush()?;
+                previous = Some(stats);
+            }
+        }
+        Command::Backup { path } => {
+            let mut client = connect(&opt).await?;
+            client.backup(path.clone()).await?;
+        }
+        Command::FlushAll { yes } => {
+            if !yes {
+                return Err(KvsError::StringError(
+                    "Refusing to remove every key without --yes".to_owned(),
+                ));
+            }
+            let mut client = connect(&opt).await?;
+            client.flush_all().await?;
+        }
+        Command::Ping => {
+            let mut client = connect(&opt).await?;
+            client.ping().await?;
+            if !opt.quiet {
+                println!("PONG");
+            }
+        }
+        Command::Bench { requests, size } => {
+            let mut client = connect(&opt).await?;
+            let payload = vec![b'x'; *size];
+            let mut latencies = Vec::with_capacity(*requests);
+            let started = Instant::now();
+            for _ in 0..*requests {
+                let start = Instant::now();
+                let echoed = client.echo(payload.clone()).await?;
+                latencies.push(start.elapsed());
+                if echoed != payload {
+                    return Err(KvsError::StringError(
+                        "Server echoed a different payload".to_owned(),
+                    ));
+                }
+            }
+            let elapsed = started.elapsed();
+            latencies.sort();
+            if let (Some(min), Some(max)) = (latencies.first(), latencies.last()) {
+                let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+                println!("requests: {}", requests);
+                println!(
+                    "requests_per_sec: {:.0}",
+                    *requests as f64 / elapsed.as_secs_f64()
+                );
+                println!("min_us: {}", min.as_micros());
+                println!("p50_us: {}", percentile(50).as_micros());
+                println!("p99_us: {}", percentile(99).as_micros());
+                println!("max_us: {}", max.as_micros());
+            }
         }
     }
     Ok(())
 }
+
+/// `bytes` with a binary unit, e.g. `1.50M`, as `top` shows sizes.
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.2}{}", size, UNITS[unit])
+    }
+}
+
+/// A pair as `import` reads it and `export` and `scan --output json` write it, one
+/// JSON object per line.
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// The pair of one line given to `import`, or None for a blank line.
+///
+/// Lines are either `SET key value`, the value running to the end of the line, or
+/// a JSON object like `export` writes, for keys and values with whitespace.
+fn parse_import_line(line: &str) -> Result<Option<Pair>> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.starts_with('{') {
+        return Ok(Some(serde_json::from_str(line)?));
+    }
+    let pair = line
+        .split_once(char::is_whitespace)
+        .filter(|(command, _)| command.eq_ignore_ascii_case("SET"))
+        .and_then(|(_, rest)| rest.trim_start().split_once(char::is_whitespace))
+        .map(|(key, value)| Pair {
+            key: key.to_owned(),
+            value: value.trim_start().to_owned(),
+        });
+    match pair {
+        Some(pair) => Ok(Some(pair)),
+        None => Err(KvsError::StringError(
+            "Expected `SET key value` or a JSON object".to_owned(),
+        )),
+    }
+}
+
+/// Pipeline the sets of an `import`, failing with the line of the first one the
+/// server refused.
+async fn import(client: &mut KvsClient, requests: Vec<Request>, lines: &[usize]) -> Result<()> {
+    if requests.is_empty() {
+        return Ok(());
+    }
+    for (line, response) in lines.iter().zip(client.pipeline(requests).await?) {
+        match response {
+            Response::Set => {}
+            Response::Err(e) => return Err(KvsError::StringError(format!("Line {}: {}", line, e))),
+            _ => return Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+    Ok(())
+}
+
+async fn connect(opt: &Opt) -> Result<KvsClient> {
+    let addr = opt.addr.as_str();
+    #[cfg(unix)]
+    let mut client = match &opt.unix_socket {
+        Some(path) => client_builder(opt)?.connect_unix(path).await?,
+        None => client_builder(opt)?.connect(addr).await?,
+    };
+    #[cfg(not(unix))]
+    let mut client = client_builder(opt)?.connect(addr).await?;
+    if let Some(db) = opt.db {
+        client.select(db).await?;
+    }
+    Ok(client)
+}
+
+/// The client options for connecting to `--addr`, with the timeout, password and
+/// TLS if asked for.
+fn client_builder(opt: &Opt) -> Result<KvsClientBuilder> {
+    let mut builder = KvsClient::builder();
+    match opt.timeout {
+        Some(0) => builder = builder.request_timeout(None),
+        Some(secs) => builder = builder.request_timeout(Some(Duration::from_secs(secs))),
+        None => {}
+    }
+    if let Some(password) = &opt.password {
+        builder = builder.credentials(None, password.clone());
+    }
+    if let Some(size) = opt.max_frame_length {
+        builder = builder.max_frame_length(size);
+    }
+    let ca_file = match (opt.tls, &opt.ca_file) {
+        (true, Some(ca_file)) => ca_file,
+        _ => return Ok(builder),
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_file)? {
+        roots
+            .add(&cert)
+            .map_err(|e| KvsError::StringError(format!("Invalid CA certificate: {}", e)))?;
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => config
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| KvsError::StringError(format!("Invalid TLS certificate or key: {}", e)))?,
+        _ => config.with_no_client_auth(),
+    };
+
+    // the certificate is checked against the host, without the port
+    let addr = opt.addr.as_str();
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let server_name = ServerName::try_from(host)
+        .map_err(|e| KvsError::StringError(format!("Invalid server name {}: {}", host, e)))?;
+    Ok(builder.tls(Arc::new(config), server_name))
+}
+
+/// Load the certificates of a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Load the first private key of a PEM file.
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| KvsError::StringError(format!("No private key found in {}", path.display())))
+}
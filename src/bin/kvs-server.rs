@@ -1,24 +1,53 @@
-use std::{env::current_dir, fs, net::SocketAddr, process::exit};
+use std::{
+    env, fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::{self, exit},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use kvs::{thread_pool::RayonThreadPool, KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
-use log::{error, info, warn, LevelFilter};
+use futures::future;
+use kvs::{
+    rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool},
+    Acl, AclUser, AuditLog, AuditLogOptions, EngineHandle, KvStore, KvStoreOptions, KvsEngine,
+    KvsEngineExt, KvsError, KvsServer, MemKvsEngine, NoopEngine, Result, RouterEngine,
+    ServerHandle, ShardMap, SledKvsEngine,
+};
+use rustls_pemfile::Item;
+use serde::Deserialize;
 use structopt::{clap::arg_enum, StructOpt};
+use tokio::signal;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const ADDRESS_FORMAT: &str = "IP:PORT";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_POOL: Pool = Pool::Rayon;
+const DEFAULT_LOG_LEVEL: &str = "debug";
+// the defaults of KvsServer, for when only one of the slow log flags is given
+const DEFAULT_SLOWLOG_THRESHOLD: Duration = Duration::from_millis(10);
+const DEFAULT_SLOWLOG_MAX_LEN: usize = 128;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
 struct Opt {
     #[structopt(
         long,
-        help = "Sets the listening address",
+        help = "Sets the listening address, repeat to listen on several (e.g. IPv4 and IPv6)",
         value_name = ADDRESS_FORMAT,
         default_value = DEFAULT_LISTENING_ADDRESS,
+        number_of_values = 1,
         parse(try_from_str)
     )]
-    addr: SocketAddr,
+    addr: Vec<SocketAddr>,
     #[structopt(
         long,
         help = "Sets the storage engine",
@@ -26,6 +55,272 @@ struct Opt {
         possible_values = &Engine::variants()
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Keeps the data and the engine marker in this directory",
+        value_name = "PATH",
+        default_value = ".",
+        parse(from_os_str)
+    )]
+    data_dir: PathBuf,
+    #[structopt(
+        long,
+        help = "Reinitializes a data directory holding no keys with the engine of --engine",
+        requires = "engine",
+        conflicts_with_all = &["check", "migrate-to"]
+    )]
+    force_engine: bool,
+    #[structopt(
+        long,
+        help = "Validates the configuration and data directory, then exits without serving"
+    )]
+    check: bool,
+    #[structopt(
+        long,
+        help = "Copies every pair into another engine and switches the data directory to it, then exits",
+        value_name = "ENGINE_NAME",
+        possible_values = &Engine::variants(),
+        conflicts_with = "check"
+    )]
+    migrate_to: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Runs the kvs, sled or rocksdb engine on this thread pool [default: rayon]",
+        value_name = "POOL",
+        possible_values = POOLS
+    )]
+    pool: Option<Pool>,
+    #[structopt(
+        long,
+        help = "Gives the thread pool this many threads [default: one per CPU]",
+        value_name = "N"
+    )]
+    threads: Option<u32>,
+    #[structopt(
+        long,
+        help = "Limits how many clients are served at once",
+        value_name = "N"
+    )]
+    max_connections: Option<usize>,
+    #[structopt(
+        long,
+        help = "Serves over TLS with this PEM certificate chain",
+        value_name = "PATH",
+        requires = "tls-key"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Serves over TLS with this PEM private key",
+        value_name = "PATH",
+        requires = "tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Requires clients to present a certificate signed by a CA in this PEM bundle",
+        value_name = "PATH",
+        requires = "tls-cert"
+    )]
+    tls_client_ca: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Requires clients to authenticate with this password",
+        value_name = "PASSWORD",
+        env = "KVS_REQUIREPASS",
+        hide_env_values = true
+    )]
+    requirepass: Option<String>,
+    #[structopt(
+        long,
+        help = "Lets clients not authenticated as an ACL user run admin requests like flushall"
+    )]
+    enable_admin: bool,
+    #[structopt(
+        long,
+        help = "Lets admins back the store up into directories under this one",
+        value_name = "PATH"
+    )]
+    backup_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Also serves the Redis protocol on this address",
+        value_name = ADDRESS_FORMAT,
+        parse(try_from_str)
+    )]
+    resp_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        help = "Also serves clients on a Unix socket at this path",
+        value_name = "PATH"
+    )]
+    unix_socket: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Closes connections that send no request for this many seconds",
+        value_name = "SECONDS"
+    )]
+    idle_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Refuses requests larger than this many bytes",
+        value_name = "BYTES"
+    )]
+    max_request_size: Option<usize>,
+    #[structopt(
+        long,
+        help = "Refuses values larger than this many bytes",
+        value_name = "BYTES"
+    )]
+    max_value_size: Option<usize>,
+    #[structopt(
+        long,
+        help = "Does the file IO of requests on their task, not the thread pool (kvs engine only)"
+    )]
+    inline_io: bool,
+    #[structopt(
+        long,
+        help = "Splits the keys across N writers with a log file each (kvs engine only)",
+        value_name = "N"
+    )]
+    writer_shards: Option<usize>,
+    #[structopt(long, help = "Sets TCP_NODELAY on client connections")]
+    tcp_nodelay: bool,
+    #[structopt(
+        long,
+        help = "Sends TCP keepalive probes after this many idle seconds",
+        value_name = "SECONDS"
+    )]
+    tcp_keepalive: Option<u64>,
+    #[structopt(
+        long,
+        help = "Lets this many connections wait to be accepted",
+        value_name = "N"
+    )]
+    backlog: Option<u32>,
+    #[structopt(long, help = "Leaves SO_REUSEADDR off on listening sockets")]
+    no_reuse_address: bool,
+    #[structopt(
+        long,
+        help = "Appends every write clients make to this audit log",
+        value_name = "PATH"
+    )]
+    audit_log: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Rotates the audit log once it reaches this many bytes",
+        value_name = "BYTES",
+        requires = "audit-log"
+    )]
+    audit_log_max_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Keeps this many rotated audit logs [default: 5]",
+        value_name = "N",
+        requires = "audit-log-max-size"
+    )]
+    audit_log_max_files: Option<usize>,
+    #[structopt(
+        long,
+        help = "Logs requests taking at least this many microseconds",
+        value_name = "MICROS"
+    )]
+    slowlog_slower_than: Option<u64>,
+    #[structopt(
+        long,
+        help = "Keeps this many slow requests, 0 turns the slow log off",
+        value_name = "N"
+    )]
+    slowlog_max_len: Option<usize>,
+    #[structopt(
+        long = "shard",
+        help = "Adds a backend server for the router engine to forward keys to",
+        value_name = ADDRESS_FORMAT,
+        number_of_values = 1,
+        parse(try_from_str)
+    )]
+    shards: Vec<SocketAddr>,
+    #[structopt(
+        long,
+        help = "Serves this many numbered databases, selected by clients",
+        value_name = "N"
+    )]
+    databases: Option<u32>,
+    #[structopt(long, help = "Reads settings from this TOML file", value_name = "PATH")]
+    config: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Writes the server's PID to this file, refusing to start if a running server did",
+        value_name = "PATH"
+    )]
+    pid_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Logs events of this level and above, overriding RUST_LOG [default: debug]",
+        value_name = "LEVEL",
+        possible_values = &LogLevel::variants(),
+        case_insensitive = true
+    )]
+    log_level: Option<LogLevel>,
+    #[structopt(
+        long,
+        help = "Logs lines of text or JSON objects",
+        value_name = "FORMAT",
+        possible_values = &LogFormat::variants(),
+        case_insensitive = true,
+        default_value = "text",
+        env = "KVS_LOG_FORMAT"
+    )]
+    log_format: LogFormat,
+}
+
+/// Settings read from the file given with `--config`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Users allowed to authenticate by name, each limited to the key prefixes it's granted.
+    #[serde(default, rename = "user")]
+    users: Vec<AclUser>,
+    /// The thread pool of the engine, unless given with `--pool`.
+    pool: Option<Pool>,
+    /// The threads of the pool, unless given with `--threads`.
+    threads: Option<u32>,
+}
+
+/// The thread pools the kvs, sled and rocksdb engines can run their blocking work on.
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum Pool {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+const POOLS: &[&str] = &["naive", "shared-queue", "rayon"];
+
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(Pool::Naive),
+            "shared-queue" => Ok(Pool::SharedQueue),
+            "rayon" => Ok(Pool::Rayon),
+            _ => Err(format!("Unknown thread pool {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Pool::Naive => "naive",
+            Pool::SharedQueue => "shared-queue",
+            Pool::Rayon => "rayon",
+        };
+        f.write_str(name)
+    }
 }
 
 arg_enum! {
@@ -34,27 +329,88 @@ arg_enum! {
     pub enum Engine {
         kvs,
         sled,
+        memory,
+        noop,
+        rocksdb,
+        router,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    #[allow(non_camel_case_types)]
+    enum LogLevel {
+        trace,
+        debug,
+        info,
+        warn,
+        error,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    #[allow(non_camel_case_types)]
+    enum LogFormat {
+        text,
+        json,
+    }
+}
+
+impl Engine {
+    /// Whether the engine keeps its data in the data directory.
+    fn is_persistent(self) -> bool {
+        !matches!(self, Engine::memory | Engine::noop | Engine::router)
     }
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::builder()
-        .filter_level(LevelFilter::Debug)
-        .init();
-
     let mut opt = Opt::from_args();
+    init_logging(&opt);
 
     let res = async {
-        let initialized_engine = get_initialized_engine()?;
+        let initialized_engine = get_initialized_engine(&opt.data_dir)?;
 
         if opt.engine.is_none() {
             opt.engine = initialized_engine;
         }
 
-        if initialized_engine.is_some() && opt.engine != initialized_engine {
-            error!("Wrong engine selected!");
-            exit(1);
+        // the flags win over the config file
+        let config = load_config(&opt)?;
+        opt.pool = opt.pool.or(config.pool);
+        opt.threads = opt.threads.or(config.threads);
+        if opt.threads == Some(0) {
+            return Err(KvsError::StringError(
+                "The thread pool needs at least one thread".to_owned(),
+            ));
+        }
+
+        // engines that never touch the data directory may run anywhere
+        if let (Some(initialized), Some(engine)) = (initialized_engine, opt.engine) {
+            if engine != initialized && engine.is_persistent() {
+                if !opt.force_engine {
+                    return Err(KvsError::StringError(format!(
+                        "{} was initialized with the {} engine, not {}: start with \
+                         --engine {}, switch with --migrate-to {} or, if it holds no keys, \
+                         reinitialize it with --force-engine",
+                        opt.data_dir.display(),
+                        initialized,
+                        engine,
+                        initialized,
+                        engine
+                    )));
+                }
+                reinitialize(&opt, initialized).await?;
+            }
+        }
+
+        if opt.check {
+            return check(opt);
+        }
+
+        if let Some(to) = opt.migrate_to {
+            return migrate(opt, to).await;
         }
 
         run(opt).await
@@ -66,43 +422,587 @@ async fn main() {
     }
 }
 
+/// Log to stderr at `--log-level`, or as `RUST_LOG` says without it, in the format
+/// of `--log-format`.
+fn init_logging(opt: &Opt) {
+    let filter = match opt.log_level {
+        Some(level) => EnvFilter::new(level.to_string()),
+        None if env::var_os(EnvFilter::DEFAULT_ENV).is_some() => EnvFilter::from_default_env(),
+        None => EnvFilter::new(DEFAULT_LOG_LEVEL),
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match opt.log_format {
+        // one JSON object per line, with the fields of the enclosing spans
+        LogFormat::json => subscriber.json().init(),
+        LogFormat::text => subscriber.init(),
+    }
+}
+
 async fn run(opt: Opt) -> Result<()> {
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
+    if engine.is_persistent() {
+        info!(
+            "Thread pool: {} with {} threads",
+            opt.pool.unwrap_or(DEFAULT_POOL),
+            opt.threads.unwrap_or_else(|| num_cpus::get() as u32)
+        );
+    }
+
+    // removed when this returns, i.e. once the server stopped
+    let _pid_file = match &opt.pid_file {
+        Some(path) => Some(PidFile::create(path)?),
+        None => None,
+    };
 
     // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    if engine.is_persistent() {
+        fs::create_dir_all(&opt.data_dir)?;
+        fs::write(opt.data_dir.join("engine"), format!("{}", engine))?;
+    }
+
+    if opt.inline_io && engine != Engine::kvs {
+        return Err(KvsError::StringError(
+            "--inline-io is only supported by the kvs engine".to_owned(),
+        ));
+    }
+    if opt.writer_shards.is_some() && engine != Engine::kvs {
+        return Err(KvsError::StringError(
+            "--writer-shards is only supported by the kvs engine".to_owned(),
+        ));
+    }
+
+    let shard_map = match engine {
+        Engine::router => Some(ShardMap::new(opt.shards.clone())?),
+        _ if !opt.shards.is_empty() => return Err(shards_without_router()),
+        _ => None,
+    };
+
+    let persistent = engine.is_persistent();
+    let engine = open_engine(engine, &opt, shard_map.clone())?;
+    let mut server = KvsServer::new(engine);
+    if persistent {
+        server = server.data_dir(&opt.data_dir);
+    }
+    if let Some(map) = shard_map {
+        server = server.shard_map(map);
+    }
+    if let Some(count) = opt.databases {
+        server = server.databases(count);
+    }
+    if let Some(limit) = opt.max_connections {
+        server = server.max_connections(limit);
+    }
+    if let Some(password) = &opt.requirepass {
+        server = server.require_pass(password.as_str());
+    }
+    if opt.enable_admin {
+        server = server.enable_admin(true);
+    }
+    if let Some(dir) = &opt.backup_dir {
+        server = server.backup_dir(dir);
+    }
+    if let Some(size) = opt.max_request_size {
+        server = server.max_request_size(size);
+    }
+    if let Some(size) = opt.max_value_size {
+        server = server.max_value_size(size);
+    }
+    if let Some(secs) = opt.idle_timeout {
+        server = server.idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = opt.tcp_keepalive {
+        server = server.tcp_keepalive(Duration::from_secs(secs));
+    }
+    server = server.tcp_nodelay(opt.tcp_nodelay);
+    if let Some(backlog) = opt.backlog {
+        server = server.listen_backlog(backlog);
+    }
+    if opt.no_reuse_address {
+        server = server.reuse_address(false);
+    }
+    if opt.slowlog_slower_than.is_some() || opt.slowlog_max_len.is_some() {
+        let threshold = opt
+            .slowlog_slower_than
+            .map_or(DEFAULT_SLOWLOG_THRESHOLD, Duration::from_micros);
+        let max_len = opt.slowlog_max_len.unwrap_or(DEFAULT_SLOWLOG_MAX_LEN);
+        server = server.slowlog(threshold, max_len);
+    }
+    if let Some(path) = &opt.audit_log {
+        let mut options = AuditLogOptions {
+            max_size: opt.audit_log_max_size,
+            ..AuditLogOptions::default()
+        };
+        if let Some(max_files) = opt.audit_log_max_files {
+            options.max_files = max_files;
+        }
+        info!("Audit log: {}", path.display());
+        server = server.audit_log(AuditLog::open_with_options(path, options)?);
+    }
+    let config = load_config(&opt)?;
+    if !config.users.is_empty() {
+        server = server.acl(Acl::new(config.users));
+    }
+    let mut handles = Vec::new();
+    if let Some(addr) = opt.resp_addr {
+        info!("Serving the Redis protocol on {}", addr);
+        // runs in the background next to the native listener
+        handles.push(server.clone().spawn_resp(addr).await?);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &opt.unix_socket {
+        info!("Listening on Unix socket {}", path.display());
+        handles.push(server.clone().spawn_unix(path).await?);
+    }
+    let tls = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => Some(tls_config(cert, key, opt.tls_client_ca.as_deref())?),
+        _ => None,
+    };
+    for &addr in &opt.addr {
+        info!("Listening on {}", addr);
+        let handle = match &tls {
+            Some(tls) => server.clone().spawn_tls(addr, tls.clone()).await?,
+            None => server.clone().spawn(addr).await?,
+        };
+        handles.push(handle);
+    }
+
+    shutdown_signal().await?;
+    info!("Shutting down");
+    future::try_join_all(handles.into_iter().map(ServerHandle::shutdown)).await?;
+    Ok(())
+}
+
+/// Resolves once the process is asked to stop with Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            res = signal::ctrl_c() => res?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// The file written for `--pid-file`, removed again when dropped.
+struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the PID of this process to `path`, unless a running process wrote its
+    /// own there. A file left behind by a process that's gone is replaced.
+    fn create(path: &Path) -> Result<Self> {
+        if let Some(pid) = running_pid(path)? {
+            return Err(already_running(pid, path));
+        }
+        match fs::remove_file(path) {
+            Ok(()) => warn!("Replacing stale PID file {}", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        // fails if another server created the file in the meantime
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        writeln!(file, "{}", process::id())?;
+        Ok(PidFile {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove PID file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// The PID in the PID file at `path` if that process is running.
+fn running_pid(path: &Path) -> Result<Option<u32>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|&pid| is_running(pid))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn already_running(pid: u32, path: &Path) -> KvsError {
+    KvsError::StringError(format!(
+        "kvs-server is already running with PID {} (from {})",
+        pid,
+        path.display()
+    ))
+}
+
+/// Whether a process holds the lock sled takes on its database file in `dir`.
+#[cfg(unix)]
+fn sled_locked(dir: &Path) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = File::open(dir.join("db"))?;
+    // sled locks the file exclusively, so even a shared lock is refused while it's
+    // open; ours is released when the file is closed
+    let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) };
+    if res == 0 {
+        return Ok(false);
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(true)
+    } else {
+        Err(e.into())
+    }
+}
+
+/// Whether a process holds the lock on the sled database. There's no portable way
+/// to tell, so it's assumed not to.
+#[cfg(not(unix))]
+fn sled_locked(_dir: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// Whether a process with the given PID exists.
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    match libc::pid_t::try_from(pid) {
+        // 0 and negative PIDs would address process groups
+        Ok(pid) if pid > 0 => {
+            // signal 0 only checks whether the process could be signaled
+            let res = unsafe { libc::kill(pid, 0) };
+            res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+        }
+        _ => false,
+    }
+}
+
+/// Whether a process with the given PID exists. There's no portable way to tell,
+/// so it's assumed to.
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+/// Open `engine` in the data directory, on the thread pool of `--pool` and
+/// `--threads`. The router engine forwards to `shard_map`.
+fn open_engine(engine: Engine, opt: &Opt, shard_map: Option<ShardMap>) -> Result<EngineHandle> {
+    let threads = opt.threads.unwrap_or_else(|| num_cpus::get() as u32);
+    match opt.pool.unwrap_or(DEFAULT_POOL) {
+        Pool::Naive => open_engine_on::<NaiveThreadPool>(engine, opt, shard_map, threads),
+        Pool::SharedQueue => {
+            open_engine_on::<SharedQueueThreadPool>(engine, opt, shard_map, threads)
+        }
+        Pool::Rayon => open_engine_on::<RayonThreadPool>(engine, opt, shard_map, threads),
+    }
+}
+
+fn open_engine_on<P: ThreadPool>(
+    engine: Engine,
+    opt: &Opt,
+    shard_map: Option<ShardMap>,
+    max_threads: u32,
+) -> Result<EngineHandle> {
+    let engine = match engine {
+        Engine::kvs => {
+            let options = KvStoreOptions {
+                max_value_size: opt.max_value_size,
+                inline_io: opt.inline_io,
+                writer_shards: opt.writer_shards.unwrap_or(1),
+                ..KvStoreOptions::default()
+            };
+            EngineHandle::new(KvStore::<P>::open_with_options(
+                &opt.data_dir,
+                max_threads,
+                options,
+            )?)
+        }
+        Engine::sled => EngineHandle::new(SledKvsEngine::<P>::new(
+            sled::open(&opt.data_dir)?,
+            max_threads,
+        )?),
+        Engine::memory => EngineHandle::new(MemKvsEngine::new()),
+        Engine::noop => EngineHandle::new(NoopEngine::new()),
+        #[cfg(feature = "rocksdb")]
+        Engine::rocksdb => {
+            EngineHandle::new(kvs::RocksKvsEngine::<P>::open(&opt.data_dir, max_threads)?)
+        }
+        #[cfg(not(feature = "rocksdb"))]
+        Engine::rocksdb => return Err(without_rocksdb()),
+        Engine::router => {
+            let map = shard_map.expect("the shard map is built for the router");
+            info!("Routing to {} shards", map.shards().len());
+            EngineHandle::new(RouterEngine::new(map))
+        }
+    };
+    Ok(engine)
+}
+
+fn load_config(opt: &Opt) -> Result<Config> {
+    match &opt.config {
+        Some(path) => toml::from_str(&fs::read_to_string(path)?).map_err(|e| {
+            KvsError::StringError(format!("Invalid config file {}: {}", path.display(), e))
+        }),
+        None => Ok(Config::default()),
+    }
+}
+
+/// Load the TLS configuration from a PEM certificate chain and private key,
+/// requiring client certificates signed by a CA in `client_ca` if given.
+fn tls_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert)?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            KvsError::StringError(format!("No private key found in {}", key.display()))
+        })?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| KvsError::StringError(format!("Invalid CA certificate: {}", e)))?;
+            }
+            builder.with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(roots)))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| KvsError::StringError(format!("Invalid TLS certificate or key: {}", e)))?;
+    Ok(Arc::new(config))
+}
+
+/// Load the certificates of a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Reports what a real start would do without touching the data directory.
+fn check(opt: Opt) -> Result<()> {
+    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    let dir = &opt.data_dir;
+
+    info!("kvs-server {} (check)", env!("CARGO_PKG_VERSION"));
+    let config = load_config(&opt)?;
+    info!("ACL users: {}", config.users.len());
+    info!("Data directory: {}", dir.display());
+    // permission bits don't tell, e.g. for read-only mounts or another owner
+    let probe = dir.join(format!(".kvs-check-{}", process::id()));
+    if let Err(e) = OpenOptions::new().write(true).create_new(true).open(&probe) {
+        return Err(KvsError::StringError(format!(
+            "{} is not writable: {}",
+            dir.display(),
+            e
+        )));
+    }
+    fs::remove_file(&probe)?;
+
+    if let Some(path) = &opt.pid_file {
+        match running_pid(path)? {
+            Some(pid) => return Err(already_running(pid, path)),
+            None => info!("PID file: {}", path.display()),
+        }
+    }
 
-    let max_threads = num_cpus::get() as u32;
+    if get_initialized_engine(dir)?.is_some() {
+        info!("Engine marker: {}", engine);
+    } else {
+        info!("Engine marker: none, would be initialized as {}", engine);
+    }
 
     match engine {
         Engine::kvs => {
-            run_with_engine(
-                KvStore::<RayonThreadPool>::open(current_dir()?, max_threads)?,
-                opt.addr,
-            )
-            .await
+            let report = KvStore::<RayonThreadPool>::verify(dir)?;
+            info!(
+                "Log records: {}, live keys: {}",
+                report.records, report.keys
+            );
+            if let Some(record) = report.corrupted.first() {
+                return Err(KvsError::StringError(format!(
+                    "Found {} corrupted records, the first in generation {} at byte {}: {}",
+                    report.corrupted.len(),
+                    record.generation,
+                    record.position,
+                    record.reason
+                )));
+            }
         }
         Engine::sled => {
-            run_with_engine(
-                SledKvsEngine::<RayonThreadPool>::new(sled::open(current_dir()?)?, max_threads)?,
-                opt.addr,
-            )
-            .await
+            if dir.join("db").exists() {
+                // opening it would take the lock and could write to it
+                if sled_locked(dir)? {
+                    return Err(KvsError::StringError(
+                        "The sled database is in use by another process".to_owned(),
+                    ));
+                }
+                info!("Sled database: found, not in use");
+            } else {
+                info!("Sled database: none, would be created");
+            }
+        }
+        Engine::memory => info!("Memory engine: nothing is read from or written to disk"),
+        Engine::noop => info!("Noop engine: every write is discarded"),
+        #[cfg(feature = "rocksdb")]
+        Engine::rocksdb => {
+            if dir.join("CURRENT").exists() {
+                info!("RocksDB database: found");
+            } else {
+                info!("RocksDB database: none, would be created");
+            }
+        }
+        #[cfg(not(feature = "rocksdb"))]
+        Engine::rocksdb => return Err(without_rocksdb()),
+        Engine::router => {
+            let map = ShardMap::new(opt.shards.clone())?;
+            info!("Router engine: forwarding to {} shards", map.shards().len());
+        }
+    }
+    if engine != Engine::router && !opt.shards.is_empty() {
+        return Err(shards_without_router());
+    }
+
+    for addr in &opt.addr {
+        std::net::TcpListener::bind(addr)?;
+        info!("Would listen on {}", addr);
+    }
+    info!("Check passed");
+
+    Ok(())
+}
+
+/// Copies every pair of the data directory's engine into `to`, in the same directory,
+/// and points the engine marker at it.
+///
+/// The pairs go through a dump file, so neither engine has to hold them in memory.
+/// The old engine's files are left in place until the copy has been verified.
+async fn migrate(opt: Opt, to: Engine) -> Result<()> {
+    let from = match get_initialized_engine(&opt.data_dir)? {
+        Some(from) => from,
+        None => {
+            return Err(KvsError::StringError(
+                "No engine marker found, there is nothing to migrate".to_owned(),
+            ))
         }
+    };
+    if from == to {
+        return Err(KvsError::StringError(format!(
+            "The data directory already uses the {} engine",
+            to
+        )));
+    }
+    if !to.is_persistent() {
+        return Err(KvsError::StringError(format!(
+            "The {} engine does not store data, there is nothing to migrate to",
+            to
+        )));
+    }
+    // RocksDB's write-ahead logs would pass for the kvs engine's log files
+    if from == Engine::rocksdb || to == Engine::rocksdb {
+        return Err(KvsError::StringError(
+            "Only the kvs and sled engines can be migrated between".to_owned(),
+        ));
+    }
+    if opt.max_value_size.is_some() && to != Engine::kvs {
+        return Err(KvsError::StringError(
+            "--max-value-size is only supported by the kvs engine".to_owned(),
+        ));
+    }
+
+    info!("Migrating from {} to {}", from, to);
+    let dir = &opt.data_dir;
+    let source = open_engine(from, &opt, None)?;
+    let target = open_engine(to, &opt, None)?;
+    // a target left over from an earlier migration would mix in stale pairs
+    if target.clone().first_key().await?.is_some() {
+        return Err(KvsError::StringError(format!(
+            "The {} engine already holds data in {}",
+            to,
+            dir.display()
+        )));
+    }
+
+    let dump = dir.join("migration.dump");
+    let exported = source
+        .clone()
+        .export(BufWriter::new(File::create(&dump)?))
+        .await?;
+    let imported = target
+        .clone()
+        .import(BufReader::new(File::open(&dump)?))
+        .await?;
+    target.close().await?;
+    source.close().await?;
+    fs::remove_file(&dump)?;
+    if imported != exported {
+        return Err(KvsError::StringError(format!(
+            "Exported {} pairs but imported {}, the engine marker was not changed",
+            exported, imported
+        )));
+    }
+
+    // renaming replaces the marker atomically, so it never names neither engine
+    let marker = dir.join("engine");
+    let staged = dir.join("engine.tmp");
+    fs::write(&staged, format!("{}", to))?;
+    fs::rename(&staged, &marker)?;
+    info!(
+        "Migrated {} pairs, the {} files can be removed once the new engine is verified",
+        imported, from
+    );
+
+    Ok(())
+}
+
+/// Lets `--force-engine` replace the `initialized` engine of the data directory,
+/// which must not hold any keys. Its files are left in place.
+async fn reinitialize(opt: &Opt, initialized: Engine) -> Result<()> {
+    let engine = open_engine(initialized, opt, None)?;
+    let first_key = engine.clone().first_key().await?;
+    engine.close().await?;
+    if first_key.is_some() {
+        return Err(KvsError::StringError(format!(
+            "The {} engine holds keys in {}, move them with --migrate-to instead",
+            initialized,
+            opt.data_dir.display()
+        )));
     }
+    warn!(
+        "Reinitializing {} from the {} engine",
+        opt.data_dir.display(),
+        initialized
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "rocksdb"))]
+fn without_rocksdb() -> KvsError {
+    KvsError::StringError("kvs-server was built without the rocksdb feature".to_owned())
 }
 
-async fn run_with_engine<T: KvsEngine>(engine: T, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr).await
+fn shards_without_router() -> KvsError {
+    KvsError::StringError("--shard is only used by the router engine".to_owned())
 }
 
-fn get_initialized_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
+fn get_initialized_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine = dir.join("engine");
     if !engine.exists() {
         return Ok(None);
     }
@@ -0,0 +1,171 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+use kvs::{sync::KvStore, KvsError, Result, ScanOptions};
+use structopt::{clap::AppSettings, StructOpt};
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs",
+    about = "Reads and writes a local kvs engine database without a server",
+    global_settings = &
+    [AppSettings::DisableHelpSubcommand, AppSettings::VersionlessSubcommands]
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+    #[structopt(
+        long,
+        global = true,
+        help = "Sets the database directory",
+        value_name = "PATH",
+        default_value = "."
+    )]
+    db: PathBuf,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "get", about = "Get the value of a given key")]
+    Get {
+        #[structopt(name = "KEY", about = "String key")]
+        key: String,
+    },
+    #[structopt(name = "set", about = "Set the value of a given key")]
+    Set {
+        #[structopt(name = "KEY", about = "String key")]
+        key: String,
+        #[structopt(name = "VALUE", about = "String value")]
+        value: String,
+    },
+    #[structopt(name = "rm", about = "Remove a given key")]
+    Remove {
+        #[structopt(name = "KEY", about = "String key")]
+        key: String,
+    },
+    #[structopt(name = "keys", about = "List the keys starting with a prefix")]
+    Keys {
+        #[structopt(name = "PREFIX", about = "Key prefix", default_value = "")]
+        prefix: String,
+        #[structopt(long, help = "Lists at most this many keys", value_name = "N")]
+        limit: Option<usize>,
+    },
+    #[structopt(
+        name = "scan",
+        about = "List the keys starting with a prefix and their values"
+    )]
+    Scan {
+        #[structopt(name = "PREFIX", about = "Key prefix", default_value = "")]
+        prefix: String,
+        #[structopt(long, help = "Lists at most this many pairs", value_name = "N")]
+        limit: Option<usize>,
+    },
+    #[structopt(name = "compact", about = "Reclaim the space of stale log entries")]
+    Compact,
+    #[structopt(name = "stats", about = "Show the statistics of the database")]
+    Stats,
+    #[structopt(name = "dump", about = "Write every pair into a dump file")]
+    Dump {
+        #[structopt(long, help = "Sets the dump file to write", value_name = "PATH")]
+        out: PathBuf,
+    },
+    #[structopt(
+        name = "restore",
+        about = "Set the pairs of a dump file in an empty database"
+    )]
+    Restore {
+        #[structopt(long = "in", help = "Sets the dump file to read", value_name = "PATH")]
+        input: PathBuf,
+    },
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    match run(opt) {
+        Ok(()) => {}
+        Err(KvsError::KeyNotFound) => {
+            println!("Key not found");
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    check_engine(&opt.db)?;
+    let store = KvStore::open(&opt.db)?;
+    match opt.command {
+        Command::Get { key } => match store.get(key)? {
+            Some(value) => println!("{}", value),
+            None => println!("Key not found"),
+        },
+        Command::Set { key, value } => store.set(key, value)?,
+        Command::Remove { key } => store.remove(key)?,
+        Command::Keys { prefix, limit } => {
+            let mut stdout = io::stdout().lock();
+            for (key, _) in store.scan(scan_options(prefix, limit))? {
+                writeln!(stdout, "{}", key)?;
+            }
+        }
+        Command::Scan { prefix, limit } => {
+            let mut stdout = io::stdout().lock();
+            for (key, value) in store.scan(scan_options(prefix, limit))? {
+                writeln!(stdout, "{}\t{}", key, value)?;
+            }
+        }
+        Command::Compact => store.compact()?,
+        Command::Stats => {
+            for (name, value) in store.engine_stats()? {
+                println!("{}\t{}", name, value);
+            }
+        }
+        Command::Dump { out } => {
+            let count = store.export(BufWriter::new(File::create(&out)?))?;
+            println!("Dumped {} pairs to {}", count, out.display());
+        }
+        Command::Restore { input } => {
+            let reader = BufReader::new(File::open(&input)?);
+            // keys the dump doesn't have would survive it
+            if !store.scan(scan_options(String::new(), Some(1)))?.is_empty() {
+                return Err(KvsError::StringError(format!(
+                    "{} is not empty",
+                    opt.db.display()
+                )));
+            }
+            let count = store.import(reader)?;
+            println!("Restored {} pairs from {}", count, input.display());
+        }
+    }
+    store.close()
+}
+
+fn scan_options(prefix: String, limit: Option<usize>) -> ScanOptions {
+    ScanOptions {
+        prefix,
+        limit,
+        ..ScanOptions::default()
+    }
+}
+
+/// Refuses a directory a server initialized with an engine other than kvs.
+fn check_engine(db: &Path) -> Result<()> {
+    let marker = db.join("engine");
+    if !marker.exists() {
+        return Ok(());
+    }
+    match fs::read_to_string(marker)?.trim() {
+        "kvs" => Ok(()),
+        engine => Err(KvsError::StringError(format!(
+            "{} holds a {} database, only the kvs engine can be opened",
+            db.display(),
+            engine
+        ))),
+    }
+}
@@ -1,76 +1,846 @@
-use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::{
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::{
-    io::{self, ReadHalf, WriteHalf},
-    net::TcpStream,
+    io::{self, AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+    net::{lookup_host, TcpStream, ToSocketAddrs},
 };
 
-use tokio_serde::{
-    formats::{Json, SymmetricalJson},
-    SymmetricallyFramed,
+use tokio_rustls::{
+    rustls::{ClientConfig, ServerName},
+    TlsConnector,
 };
+use tokio_serde::SymmetricallyFramed;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
-use crate::{KvsError, Request, Response, Result};
-use futures::{SinkExt, StreamExt};
+use crate::{
+    codec::{CodecSwitch, Wire, DEFAULT_MAX_FRAME_LENGTH},
+    protocol::{feature, ServerInfo, PROTOCOL_VERSION},
+    read_cache::{Invalidator, ReadCache},
+    server::MemoryConnector,
+    tcp::TcpOptions,
+    Chunk, Codec, Compression, KeyChange, KvsError, Request, Response, Result, ServerHandle,
+    ServerStats, ShardMap, SlowLogEntry, ValueMeta,
+};
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, SinkExt, StreamExt,
+};
+use rand::Rng;
+
+/// A connection the client can speak the protocol over.
+trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+/// Where a client connects to, kept to reconnect after the connection broke.
+#[derive(Debug, Clone)]
+enum Endpoint {
+    // every address the host names resolved to, tried in order
+    Tcp(Vec<SocketAddr>),
+    // an address or host name with a port, resolved when first connecting
+    Unresolved(String),
+    // a server spawned with `KvsServer::spawn_in_memory`
+    Memory(MemoryConnector),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// How a `KvsClient` recovers when its connection breaks, e.g. because the server
+/// restarted.
+///
+/// A broken connection is replaced by a new one before the next request, with the
+/// same codec, authentication and database. Requests that only read, such as
+/// `Get` or `Scan`, are sent again on the new connection; writes fail with the
+/// error instead, as they may have been applied before the connection broke.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times to reconnect and retry before giving up on a request.
+    pub max_retries: u32,
+    /// The delay before the first retry, doubled for each one after it.
+    pub initial_backoff: Duration,
+    /// The longest delay between two retries.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Never retry: requests fail on the first error, though the connection is
+    /// still replaced before the next one.
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// The delay before retry number `attempt`, counting from 1, picked at random
+    /// from the upper half of its exponential backoff so that clients of a
+    /// restarted server don't all reconnect at once.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        let delay = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Receives the outcome of every request a `KvsClient` sends, set with
+/// `KvsClientBuilder::metrics`, e.g. to count errors and keep latency histograms
+/// per operation without wrapping every call.
+///
+/// Called from the task sending the request once it was answered or failed, so
+/// implementations should be quick. Streamed responses and the notifications of
+/// `subscribe` are not recorded.
+pub trait ClientMetrics: Send + Sync {
+    /// Record that the request `op`, such as `"get"` or `"set"`, took `latency`,
+    /// reconnects and retries included, and failed with `error` unless None.
+    ///
+    /// Errors the server answered with are `KvsError::StringError`s. Requests sent
+    /// together with `KvsClient::pipeline` are each recorded with the time the whole
+    /// pipeline took.
+    fn record(&self, op: &'static str, latency: Duration, error: Option<&KvsError>);
+}
+
+/// The metrics of a client, set with `KvsClientBuilder::metrics`.
+#[derive(Clone)]
+struct Metrics(Arc<dyn ClientMetrics>);
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    /// Record the outcome of `op`, failed if the server answered with an error.
+    fn record(&self, op: &'static str, latency: Duration, res: Result<&Response, &KvsError>) {
+        match res {
+            Ok(Response::Err(e)) => {
+                let error = KvsError::StringError(e.clone());
+                self.0.record(op, latency, Some(&error))
+            }
+            Ok(_) => self.0.record(op, latency, None),
+            Err(e) => self.0.record(op, latency, Some(e)),
+        }
+    }
+}
+
+/// How many pairs `KvsClient::scan` fetches per request.
+const SCAN_PAGE_LEN: usize = 1000;
+
+/// How many operations of a `BatchBuilder` go in one frame.
+const BATCH_FRAME_LEN: usize = 1000;
+
+/// How many bytes an in-memory connection buffers in each direction.
+const MEMORY_BUFFER_LEN: usize = 64 * 1024;
+
+/// How long a request may take unless set otherwise.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options for connecting a `KvsClient`, created with `KvsClient::builder`.
+#[derive(Debug, Clone)]
+pub struct KvsClientBuilder {
+    tcp: TcpOptions,
+    codec: Codec,
+    compression: Option<Compression>,
+    checksums: bool,
+    retry: RetryPolicy,
+    request_timeout: Option<Duration>,
+    // the username, if any, and token to authenticate with once connected
+    credentials: Option<(Option<String>, String)>,
+    // the capacity and max age of the read cache, if there is one
+    read_cache: Option<(usize, Duration)>,
+    invalidate_from_server: bool,
+    tls: Option<Tls>,
+    metrics: Option<Metrics>,
+    max_frame_length: usize,
+}
+
+/// The TLS settings of a client, set with `KvsClientBuilder::tls`.
+#[derive(Clone)]
+struct Tls {
+    connector: TlsConnector,
+    server_name: ServerName,
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tls")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for KvsClientBuilder {
+    fn default() -> Self {
+        KvsClientBuilder {
+            tcp: TcpOptions::default(),
+            codec: Codec::default(),
+            compression: None,
+            checksums: false,
+            retry: RetryPolicy::default(),
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            tls: None,
+            credentials: None,
+            read_cache: None,
+            invalidate_from_server: false,
+            metrics: None,
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+        }
+    }
+}
+
+impl KvsClientBuilder {
+    /// Set `TCP_NODELAY`, sending requests at once instead of coalescing small
+    /// writes. Off by default.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.tcp.nodelay = nodelay;
+        self
+    }
+
+    /// Send TCP keepalive probes when the connection is idle for `time`, so a
+    /// vanished server is noticed. Off by default.
+    pub fn tcp_keepalive(mut self, time: Duration) -> Self {
+        self.tcp.keepalive = Some(time);
+        self
+    }
+
+    /// Refuse to send requests or read responses larger than `size` bytes on the
+    /// wire. Raise it along with the server's `KvsServer::max_request_size` to move
+    /// larger values. Defaults to 8 MiB.
+    pub fn max_frame_length(mut self, size: usize) -> Self {
+        self.max_frame_length = size;
+        self
+    }
+
+    /// Switch to `codec` after connecting if the server supports it, e.g.
+    /// `Codec::MessagePack` for smaller and cheaper frames. Servers that don't keep
+    /// speaking JSON. Defaults to JSON, which skips the handshake.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Compress large frames in both directions with `compression` if the server
+    /// supports it, trading CPU time for bandwidth on slow links. Off by default.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// End every frame in both directions with a CRC-32 if the server supports it,
+    /// so frames corrupted on the way, e.g. by a faulty proxy, fail the connection
+    /// instead of being decoded. Off by default.
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Reconnect and retry requests as `policy` says when the connection breaks.
+    /// Defaults to `RetryPolicy::default()`.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Give up on a request with `KvsError::Timeout` once it has waited `timeout`
+    /// for its response, or never if None. Connecting is held to the same limit.
+    /// Defaults to 30 seconds.
+    pub fn request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Authenticate with `token` once connected, as the ACL user `username` if
+    /// given or with the server's password otherwise, and again after every
+    /// reconnect. Connecting fails if the server refuses the credentials.
+    pub fn credentials(mut self, username: Option<String>, token: String) -> Self {
+        self.credentials = Some((username, token));
+        self
+    }
+
+    /// Keep the values of up to `capacity` recently read keys for at most `max_age`,
+    /// answering `KvsClient::get` for them without asking the server. Off by default.
+    ///
+    /// Keys are dropped from the cache when the client writes them. Changes made by
+    /// other clients go unnoticed until `max_age` passes, unless
+    /// `invalidate_from_server` is set as well.
+    pub fn read_cache(mut self, capacity: usize, max_age: Duration) -> Self {
+        self.read_cache = Some((capacity, max_age));
+        self
+    }
+
+    /// Also drop keys from the read cache when other clients change them, as
+    /// reported by the server over a second connection subscribed to every key.
+    ///
+    /// Should the subscription fail, the cache is emptied and subscribing is tried
+    /// again on the next read; reads meanwhile go to the server. Off by default.
+    pub fn invalidate_from_server(mut self, invalidate: bool) -> Self {
+        self.invalidate_from_server = invalidate;
+        self
+    }
+
+    /// Connect over TLS, as `KvsServer::spawn_tls` serves, checking that the
+    /// server's certificate is valid for `server_name`.
+    ///
+    /// Build `config` with `kvs::rustls`, trusting the CA that signed the server's
+    /// certificate, and with a client certificate for servers that require one.
+    /// Unix sockets ignore it.
+    pub fn tls(mut self, config: Arc<ClientConfig>, server_name: ServerName) -> Self {
+        self.tls = Some(Tls {
+            connector: TlsConnector::from(config),
+            server_name,
+        });
+        self
+    }
+
+    /// Report the latency and outcome of every request to `metrics`, on this
+    /// connection and those replacing it. Off by default.
+    pub fn metrics(mut self, metrics: Arc<dyn ClientMetrics>) -> Self {
+        self.metrics = Some(Metrics(metrics));
+        self
+    }
+
+    /// Connect to `addr` to access `KvsServer`.
+    ///
+    /// `addr` may be a host name with a port such as `"kvs.internal:4000"`. Each
+    /// address it resolves to is tried in turn until one accepts the connection;
+    /// reconnecting tries them again without resolving the name anew.
+    pub async fn connect(self, addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        let addrs: Vec<_> = lookup_host(addr).await?.collect();
+        self.open(Endpoint::Tcp(addrs)).await
+    }
+
+    /// Connect to the first server of `addrs` that accepts, e.g. a primary followed
+    /// by its standbys.
+    ///
+    /// When the connection breaks, reconnecting fails over to the servers after the
+    /// one it was connected to, wrapping around to the first. `KvsClient::peer_addr`
+    /// tells which server the connection is to.
+    pub async fn connect_any<A: ToSocketAddrs>(
+        self,
+        addrs: impl IntoIterator<Item = A>,
+    ) -> Result<KvsClient> {
+        let mut resolved = Vec::new();
+        for addr in addrs {
+            resolved.extend(lookup_host(addr).await?);
+        }
+        self.open(Endpoint::Tcp(resolved)).await
+    }
+
+    /// Connect to a `KvsServer` listening on the Unix socket at `path`.
+    #[cfg(unix)]
+    pub async fn connect_unix(self, path: impl AsRef<Path>) -> Result<KvsClient> {
+        self.open(Endpoint::Unix(path.as_ref().to_owned())).await
+    }
+
+    /// Connect to a `KvsServer` spawned in this process with
+    /// `KvsServer::spawn_in_memory`, over in-memory streams instead of a socket.
+    pub async fn connect_in_memory(self, server: &ServerHandle) -> Result<KvsClient> {
+        let connector = server.connector().ok_or_else(|| {
+            KvsError::StringError("The server was not spawned in memory".to_owned())
+        })?;
+        self.open(Endpoint::Memory(connector)).await
+    }
+
+    /// A client for `addr` that doesn't connect until its first request, so it can
+    /// be created before the server is up.
+    ///
+    /// `addr` is resolved when connecting. The first request connects, retrying as
+    /// the retry policy says, and fails if the server still can't be reached; the
+    /// request after it tries again.
+    pub fn connect_lazy(self, addr: impl Into<String>) -> KvsClient {
+        let (unconnected, _) = io::duplex(1);
+        let credentials = self.credentials.clone();
+        let mut client = KvsClient::over(
+            Box::new(unconnected),
+            Endpoint::Unresolved(addr.into()),
+            self,
+        );
+        client.session.auth = credentials;
+        client.broken = true;
+        client
+    }
+
+    async fn open(self, endpoint: Endpoint) -> Result<KvsClient> {
+        let endpoint = match endpoint {
+            Endpoint::Unresolved(addr) => Endpoint::Tcp(lookup_host(addr).await?.collect()),
+            endpoint => endpoint,
+        };
+        let mut peer = None;
+        let stream: Box<dyn Transport> = match &endpoint {
+            Endpoint::Tcp(addrs) => {
+                let tcp = connect_tcp(addrs, self.request_timeout).await?;
+                self.tcp.apply(&tcp)?;
+                peer = Some(tcp.peer_addr()?);
+                match &self.tls {
+                    Some(tls) => {
+                        let handshake = tls.connector.connect(tls.server_name.clone(), tcp);
+                        let timeout = self.request_timeout;
+                        Box::new(with_timeout(timeout, async { Ok(handshake.await?) }).await?)
+                    }
+                    None => Box::new(tcp),
+                }
+            }
+            Endpoint::Unresolved(_) => unreachable!("resolved above"),
+            Endpoint::Memory(connector) => {
+                let (stream, server_stream) = io::duplex(MEMORY_BUFFER_LEN);
+                connector.send(server_stream).map_err(|_| {
+                    io::Error::new(io::ErrorKind::ConnectionRefused, "The server stopped")
+                })?;
+                Box::new(stream)
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        };
+        let (codec, compression, checksums) = (self.codec, self.compression, self.checksums);
+        let credentials = self.credentials.clone();
+        let mut client = KvsClient::over(stream, endpoint, self);
+        client.peer = peer;
+        if codec != Codec::Json || compression.is_some() || checksums {
+            client.negotiate(codec, compression, checksums).await?;
+        }
+        if let Some((username, token)) = credentials {
+            client.send_auth(username, token).await?;
+        }
+        Ok(client)
+    }
+}
+
+/// What a connection was set up with, restored on the connection replacing it.
+#[derive(Debug, Clone, Default)]
+struct Session {
+    // the username, if any, and token last authenticated with
+    auth: Option<(Option<String>, String)>,
+    db: u32,
+}
+
+/// The endpoint, options and session of a connection, to open others like it.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionSpec {
+    endpoint: Endpoint,
+    options: KvsClientBuilder,
+    session: Session,
+}
+
+impl ConnectionSpec {
+    /// Try the addresses after `peer` first, wrapping around to those before it.
+    fn fail_over_from(&mut self, peer: SocketAddr) {
+        if let Endpoint::Tcp(addrs) = &mut self.endpoint {
+            if let Some(i) = addrs.iter().position(|&addr| addr == peer) {
+                addrs.rotate_left(i + 1);
+            }
+        }
+    }
+
+    /// Open a connection, negotiating, authenticating and selecting the database
+    /// as the original connection did.
+    ///
+    /// Boxed, as connecting sends requests of its own.
+    pub(crate) fn connect(&self) -> BoxFuture<'static, Result<KvsClient>> {
+        let spec = self.clone();
+        async move {
+            // the session holds the latest credentials, the options maybe older ones
+            let options = KvsClientBuilder {
+                retry: RetryPolicy::never(),
+                credentials: None,
+                ..spec.options.clone()
+            };
+            let mut client = options.open(spec.endpoint).await?;
+            if let Some((username, token)) = spec.session.auth {
+                client.send_auth(username, token).await?;
+            }
+            if spec.session.db != 0 {
+                client.select(spec.session.db).await?;
+            }
+            client.options = spec.options;
+            Ok(client)
+        }
+        .boxed()
+    }
+}
 
 /// Key value store client
 pub struct KvsClient {
     read_json: SymmetricallyFramed<
-        FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>,
+        FramedRead<ReadHalf<Box<dyn Transport>>, LengthDelimitedCodec>,
         Response,
-        Json<Response, Response>,
+        Wire<Response>,
     >,
     write_json: SymmetricallyFramed<
-        FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>,
+        FramedWrite<WriteHalf<Box<dyn Transport>>, LengthDelimitedCodec>,
         Request,
-        Json<Request, Request>,
+        Wire<Request>,
     >,
+    // the codec, compression and checksums both halves use, plain JSON until
+    // negotiated otherwise
+    codec: CodecSwitch,
+    server_info: Option<ServerInfo>,
+    // a streamed response was dropped before its end, which is skipped before the
+    // next request
+    unfinished_stream: bool,
+    endpoint: Endpoint,
+    options: KvsClientBuilder,
+    session: Session,
+    // the connection failed, so a new one is needed before the next request
+    broken: bool,
+    cache: Option<Arc<ReadCache>>,
+    // drops the keys other clients change from the cache while running
+    invalidator: Option<Invalidator>,
+    // the server a TCP connection is to
+    peer: Option<SocketAddr>,
 }
 
 impl KvsClient {
-    /// Connect to `addr` to access `KvsServer`.
-    pub async fn connect(addr: SocketAddr) -> Result<Self> {
-        let tcp = TcpStream::connect(addr).await?;
+    /// Connect to `addr`, an address or a host name with a port, to access
+    /// `KvsServer`.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Self::builder().connect(addr).await
+    }
 
-        let (read_half, write_half) = io::split(tcp);
+    /// Options for connecting, such as TCP socket settings.
+    pub fn builder() -> KvsClientBuilder {
+        KvsClientBuilder::default()
+    }
+
+    /// Connect to a `KvsServer` listening on the Unix socket at `path`.
+    #[cfg(unix)]
+    pub async fn connect_unix(path: impl AsRef<Path>) -> Result<Self> {
+        Self::builder().connect_unix(path).await
+    }
+
+    /// Connect to a `KvsServer` spawned in this process with
+    /// `KvsServer::spawn_in_memory`.
+    pub async fn connect_in_memory(server: &ServerHandle) -> Result<Self> {
+        Self::builder().connect_in_memory(server).await
+    }
+
+    /// A client for `addr` that connects on its first request, as
+    /// `KvsClientBuilder::connect_lazy` describes.
+    pub fn connect_lazy(addr: impl Into<String>) -> Self {
+        Self::builder().connect_lazy(addr)
+    }
+
+    fn over(stream: Box<dyn Transport>, endpoint: Endpoint, options: KvsClientBuilder) -> Self {
+        let (read_half, write_half) = io::split(stream);
+        let max_frame_length = options.max_frame_length;
+        let codec = CodecSwitch::new(max_frame_length);
+        let length_delimited = || {
+            LengthDelimitedCodec::builder()
+                .max_frame_length(max_frame_length)
+                .new_codec()
+        };
 
         let write_json = SymmetricallyFramed::new(
-            FramedWrite::new(write_half, LengthDelimitedCodec::new()),
-            SymmetricalJson::default(),
+            FramedWrite::new(write_half, length_delimited()),
+            codec.format(),
         );
         let read_json = SymmetricallyFramed::new(
-            FramedRead::new(read_half, LengthDelimitedCodec::new()),
-            SymmetricalJson::default(),
+            FramedRead::new(read_half, length_delimited()),
+            codec.format(),
         );
 
-        Ok(KvsClient {
+        KvsClient {
             read_json,
             write_json,
-        })
+            codec,
+            server_info: None,
+            unfinished_stream: false,
+            endpoint,
+            session: Session::default(),
+            broken: false,
+            cache: options
+                .read_cache
+                .map(|(capacity, max_age)| Arc::new(ReadCache::new(capacity, max_age))),
+            invalidator: None,
+            options,
+            peer: None,
+        }
+    }
+
+    /// Offer `codec`, `compression` and `checksums` in the handshake and switch to
+    /// those the server accepts.
+    async fn negotiate(
+        &mut self,
+        codec: Codec,
+        compression: Option<Compression>,
+        checksums: bool,
+    ) -> Result<()> {
+        let codecs = match codec {
+            Codec::Json => Vec::new(),
+            codec => vec![codec.name().to_owned()],
+        };
+        let compressions = compression
+            .map(|compression| compression.name().to_owned())
+            .into_iter()
+            .collect();
+        let info = self.hello(codecs, compressions, checksums).await?;
+        if let Some(codec) = info.codec.as_deref().and_then(Codec::from_name) {
+            self.codec.set(codec);
+        }
+        let compression = info.compression.as_deref().and_then(Compression::from_name);
+        self.codec.set_compression(compression);
+        self.codec.set_checksums(info.checksums);
+        Ok(())
+    }
+
+    /// The codec the connection speaks.
+    pub fn codec(&self) -> Codec {
+        self.codec.get()
     }
 
-    /// Get the value of a given key from the server.
+    /// The compression large frames use, or None if they're sent as is.
+    pub fn compression(&self) -> Option<Compression> {
+        self.codec.compression()
+    }
+
+    /// Whether frames carry checksums.
+    pub fn checksums(&self) -> bool {
+        self.codec.checksums()
+    }
+
+    /// The address of the server the connection is to, which answered the latest
+    /// request, or None for a Unix socket.
+    ///
+    /// Changes when the client fails over to another server of
+    /// `KvsClientBuilder::connect_any`.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// Whether the client holds a connection it can send the next request over.
+    ///
+    /// False for a lazy client until its first request, and after the connection
+    /// broke until the next request replaces it.
+    pub fn is_connected(&self) -> bool {
+        !self.broken
+    }
+
+    /// Give up on later requests with `KvsError::Timeout` once they have waited
+    /// `timeout` for their response, or never if None.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.options.request_timeout = timeout;
+    }
+
+    /// Get the value of a given key from the server, or from the read cache if it
+    /// holds the key.
     pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        let cache = match self.cache.clone() {
+            Some(cache) if self.cache_is_trusted().await => cache,
+            _ => return self.fetch(key).await,
+        };
+        if let Some(value) = cache.get(&key) {
+            return Ok(value);
+        }
+        let generation = cache.generation();
+        let value = self.fetch(key.clone()).await?;
+        cache.insert(key, value.clone(), generation);
+        Ok(value)
+    }
+
+    /// Whether the read cache can be used, (re)subscribing to the server's changes
+    /// first if it should follow them.
+    async fn cache_is_trusted(&mut self) -> bool {
+        if !self.options.invalidate_from_server {
+            return true;
+        }
+        if matches!(&self.invalidator, Some(invalidator) if !invalidator.is_finished()) {
+            return true;
+        }
+        self.invalidator = None;
+        let cache = match &self.cache {
+            Some(cache) => cache.clone(),
+            None => return false,
+        };
+        cache.clear();
+        let mut spec = self.spec();
+        spec.options.read_cache = None;
+        let notifications = async move {
+            let subscriber = spec.connect().await?;
+            subscriber.subscribe(String::new()).await
+        };
+        match notifications.await {
+            Ok(notifications) => {
+                self.invalidator = Some(Invalidator::spawn(cache, notifications));
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    async fn fetch(&mut self, key: String) -> Result<Option<String>> {
         let res = self.send_request(Request::Get { key }).await?;
         match res {
             Response::Get(value) => Ok(value),
-            Response::Set | Response::Remove => {
-                Err(KvsError::StringError("Invalid response".to_string()))
-            }
             Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
         }
     }
 
     /// Set the value of a string key in the server.
     pub async fn set(&mut self, key: String, value: String) -> Result<()> {
-        let res = self.send_request(Request::Set { key, value }).await?;
+        let res = self
+            .send_request(Request::Set {
+                key,
+                value,
+                ttl_ms: None,
+            })
+            .await?;
         match res {
             Response::Set => Ok(()),
-            Response::Remove | Response::Get(_) => {
-                Err(KvsError::StringError("Invalid response".to_string()))
-            }
             Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Set the value of a string key in the server that stops existing once `ttl`
+    /// has passed.
+    ///
+    /// Fails if the server doesn't support `feature::TTL`, as it would keep the key
+    /// forever.
+    pub async fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        if !self.server_info().await?.supports(feature::TTL) {
+            return Err(KvsError::StringError(
+                "The server does not support expiring keys".to_owned(),
+            ));
+        }
+        let res = self
+            .send_request(Request::Set {
+                key,
+                value,
+                ttl_ms: Some(ttl.as_millis() as u64),
+            })
+            .await?;
+        match res {
+            Response::Set => Ok(()),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Return how long a key in the server has left to live, or None if it doesn't
+    /// exist or never expires.
+    pub async fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        let res = self.send_request(Request::Ttl { key }).await?;
+        match res {
+            Response::Ttl(ttl_ms) => Ok(ttl_ms.map(Duration::from_millis)),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Atomically rename a key in the server, replacing any value the new name had.
+    ///
+    /// Fails if the key doesn't exist.
+    pub async fn rename(&mut self, from: String, to: String) -> Result<()> {
+        let res = self.send_request(Request::Rename { from, to }).await?;
+        match res {
+            Response::Rename => Ok(()),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Atomically copy a key in the server to a new name, replacing a value the new
+    /// name already has only if `overwrite` is set.
+    ///
+    /// Returns whether the key was copied.
+    pub async fn copy(&mut self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        let res = self
+            .send_request(Request::Copy {
+                from,
+                to,
+                overwrite,
+            })
+            .await?;
+        match res {
+            Response::Copy(copied) => Ok(copied),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Return whether a key exists in the server, without fetching its value.
+    pub async fn exists(&mut self, key: String) -> Result<bool> {
+        let res = self.send_request(Request::Exists { key }).await?;
+        match res {
+            Response::Exists(exists) => Ok(exists),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Return how many keys the connection's database on the server holds.
+    pub async fn len(&mut self) -> Result<u64> {
+        let res = self.send_request(Request::Len).await?;
+        match res {
+            Response::Len(len) => Ok(len),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Return whether the connection's database on the server holds no keys.
+    pub async fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len().await? == 0)
+    }
+
+    /// Return the length, modification time and time to live of a key's value in the
+    /// server without fetching it, or None if the key doesn't exist.
+    pub async fn get_meta(&mut self, key: String) -> Result<Option<ValueMeta>> {
+        let res = self.send_request(Request::GetMeta { key }).await?;
+        match res {
+            Response::GetMeta(meta) => Ok(meta),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Make an existing key in the server expire once `ttl` has passed.
+    ///
+    /// Returns whether the key exists.
+    pub async fn expire(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        let ttl_ms = ttl.as_millis() as u64;
+        let res = self.send_request(Request::Expire { key, ttl_ms }).await?;
+        match res {
+            Response::Expire(exists) => Ok(exists),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Remove the expiration of a key in the server.
+    ///
+    /// Returns whether the key existed and had an expiration.
+    pub async fn persist(&mut self, key: String) -> Result<bool> {
+        let res = self.send_request(Request::Persist { key }).await?;
+        match res {
+            Response::Persist(persisted) => Ok(persisted),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
         }
     }
 
@@ -79,21 +849,791 @@ impl KvsClient {
         let res = self.send_request(Request::Remove { key }).await?;
         match res {
             Response::Remove => Ok(()),
-            Response::Get(_) | Response::Set => {
-                Err(KvsError::StringError("Invalid response".to_string()))
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Append `suffix` to the value of a string key in the server, creating it if absent.
+    ///
+    /// Returns the length of the new value in bytes.
+    pub async fn append(&mut self, key: String, suffix: String) -> Result<u64> {
+        let res = self.send_request(Request::Append { key, suffix }).await?;
+        match res {
+            Response::Append(length) => Ok(length),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Set a string key in the server only if it doesn't exist yet.
+    ///
+    /// Returns whether the key was set. As the check and the write are atomic, only one
+    /// of several clients racing for the same key succeeds, e.g. to take a lock.
+    pub async fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        let res = self.send_request(Request::SetNx { key, value }).await?;
+        match res {
+            Response::SetNx(applied) => Ok(applied),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Set a string key in the server only if its current value is `expected`.
+    ///
+    /// Returns whether the key was set. The check and the write are atomic.
+    pub async fn set_if(&mut self, key: String, expected: String, value: String) -> Result<bool> {
+        let res = self
+            .send_request(Request::SetIf {
+                key,
+                expected,
+                value,
+            })
+            .await?;
+        match res {
+            Response::SetIf(applied) => Ok(applied),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Set a string key in the server if its current value is `expected`, or if it
+    /// doesn't exist when `expected` is None.
+    ///
+    /// The check and the write are atomic: `CasOutcome::Conflict` means the key held
+    /// something else, e.g. because another client wrote it since it was read.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<CasOutcome> {
+        let swapped = match expected {
+            Some(expected) => self.set_if(key, expected, value).await?,
+            None => self.set_nx(key, value).await?,
+        };
+        Ok(if swapped {
+            CasOutcome::Swapped
+        } else {
+            CasOutcome::Conflict
+        })
+    }
+
+    /// Set a string key in the server only if it doesn't exist yet, failing with
+    /// `CasOutcome::Conflict` if it does.
+    pub async fn set_if_absent(&mut self, key: String, value: String) -> Result<CasOutcome> {
+        self.compare_and_swap(key, None, value).await
+    }
+
+    /// Add `delta` to the integer stored at `key`, counting a missing key as 0, and
+    /// return the new value.
+    ///
+    /// The value is read and written back with `compare_and_swap`, starting over
+    /// whenever another client changed it in between, so concurrent increments are
+    /// never lost. Fails with `KvsError::NotAnInteger` if the key holds anything but
+    /// a decimal `i64`, or if the result doesn't fit one.
+    pub async fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        loop {
+            // past the read cache, which may be behind the server
+            let current = self.fetch(key.clone()).await?;
+            let number = match &current {
+                Some(value) => value.parse::<i64>().map_err(|_| KvsError::NotAnInteger)?,
+                None => 0,
+            };
+            let next = number.checked_add(delta).ok_or(KvsError::NotAnInteger)?;
+            let outcome = self
+                .compare_and_swap(key.clone(), current, next.to_string())
+                .await?;
+            if outcome == CasOutcome::Swapped {
+                return Ok(next);
             }
+        }
+    }
+
+    /// List up to `limit` key/value pairs whose keys start with `prefix`, in ascending
+    /// key order, continuing after `cursor`.
+    ///
+    /// Returns the page and the cursor of the next one, or None once every key was listed.
+    pub async fn scan_page(
+        &mut self,
+        prefix: String,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<(Vec<(String, String)>, Option<String>)> {
+        let res = self
+            .send_request(Request::Scan {
+                cursor,
+                prefix,
+                limit,
+            })
+            .await?;
+        match res {
+            Response::Scan { pairs, next_cursor } => Ok((pairs, next_cursor)),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// List every key/value pair whose key starts with `prefix`, in ascending key
+    /// order, fetching them a page at a time as the stream is read.
+    ///
+    /// Keys set or removed during the scan may or may not be listed. The stream ends
+    /// after the first error.
+    pub fn scan(&mut self, prefix: String) -> BoxStream<'_, Result<(String, String)>> {
+        // None once the last page was fetched
+        let pages = stream::unfold(Some((self, None)), move |state| {
+            let prefix = prefix.clone();
+            async move {
+                let (client, cursor) = state?;
+                match client.scan_page(prefix, cursor, SCAN_PAGE_LEN).await {
+                    Ok((pairs, Some(next))) => Some((Ok(pairs), Some((client, Some(next))))),
+                    Ok((pairs, None)) => Some((Ok(pairs), None)),
+                    Err(e) => Some((Err(e), None)),
+                }
+            }
+        });
+        pages
+            .flat_map(|page| {
+                let pairs = match page {
+                    Ok(pairs) => pairs.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                };
+                stream::iter(pairs)
+            })
+            .boxed()
+    }
+
+    /// Authenticate the connection with the password the server was started with.
+    ///
+    /// The client authenticates again by itself after reconnecting.
+    pub async fn authenticate(&mut self, token: String) -> Result<()> {
+        self.send_auth(None, token).await
+    }
+
+    /// Authenticate the connection as a user of the server's ACL, again after
+    /// every reconnect.
+    pub async fn authenticate_as(&mut self, username: String, token: String) -> Result<()> {
+        self.send_auth(Some(username), token).await
+    }
+
+    async fn send_auth(&mut self, username: Option<String>, token: String) -> Result<()> {
+        let req = Request::Auth {
+            username: username.clone(),
+            token: token.clone(),
+        };
+        let res = self.send_request(req).await?;
+        match res {
+            Response::Auth => {
+                self.session.auth = Some((username, token));
+                Ok(())
+            }
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Check that the server is up and answering.
+    pub async fn ping(&mut self) -> Result<()> {
+        let res = self.send_request(Request::Ping).await?;
+        match res {
+            Response::Pong => Ok(()),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Have the server send `payload` back, returning what it answered.
+    pub async fn echo(&mut self, payload: Vec<u8>) -> Result<Vec<u8>> {
+        let res = self.send_request(Request::Echo(payload)).await?;
+        match res {
+            Response::Echo(payload) => Ok(payload),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Negotiate the protocol version with the server and return what it supports.
+    ///
+    /// The handshake happens on the first call only; later calls return the cached result.
+    /// Servers that predate the handshake close the connection instead of answering.
+    pub async fn server_info(&mut self) -> Result<ServerInfo> {
+        if let Some(info) = &self.server_info {
+            return Ok(info.clone());
+        }
+        self.hello(Vec::new(), Vec::new(), false).await
+    }
+
+    async fn hello(
+        &mut self,
+        codecs: Vec<String>,
+        compressions: Vec<String>,
+        checksums: bool,
+    ) -> Result<ServerInfo> {
+        let res = self
+            .send_request(Request::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                codecs,
+                compressions,
+                checksums,
+            })
+            .await?;
+        match res {
+            Response::Hello(info) => {
+                self.server_info = Some(info.clone());
+                Ok(info)
+            }
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// List the `count` newest requests the server logged as slow, newest first.
+    pub async fn slowlog(&mut self, count: usize) -> Result<Vec<SlowLogEntry>> {
+        let res = self.send_request(Request::SlowLog { count }).await?;
+        match res {
+            Response::SlowLog(entries) => Ok(entries),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Empty the server's slow request log.
+    pub async fn slowlog_reset(&mut self) -> Result<()> {
+        let res = self.send_request(Request::SlowLogReset).await?;
+        match res {
+            Response::SlowLogReset => Ok(()),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Queue gets, sets and removes to send together with `BatchBuilder::send`, for
+    /// one round trip per thousand keys instead of one per key.
+    pub fn batch(&mut self) -> BatchBuilder<'_> {
+        BatchBuilder {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Send `requests` in a single frame and return their responses in the same order.
+    ///
+    /// Only `Get`, `Set`, `Remove`, `Append` and `Scan` requests can be batched; other
+    /// requests are answered with `Response::Err`. Unlike a transaction, each request
+    /// succeeds or fails on its own.
+    pub async fn send_batch(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let res = self.send_request(Request::Batch(requests)).await?;
+        match res {
+            Response::Batch(responses) => Ok(responses),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Apply `writes` atomically in a server-side transaction: a value sets its key
+    /// and None removes it. Removing a key that does not exist is not an error.
+    ///
+    /// The whole transaction is sent at once. Either every write is applied or,
+    /// if an error is returned, none of them.
+    pub async fn transaction(&mut self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        let mut requests = vec![Request::Multi];
+        requests.extend(writes.into_iter().map(|(key, value)| match value {
+            Some(value) => Request::Set {
+                key,
+                value,
+                ttl_ms: None,
+            },
+            None => Request::Remove { key },
+        }));
+        requests.push(Request::Exec);
+        let mut responses = self.pipeline(requests).await?;
+        // an error while queueing explains why Exec failed better than Exec does
+        if let Some(Response::Err(e)) = responses
+            .iter_mut()
+            .find(|res| matches!(res, Response::Err(_)))
+        {
+            return Err(KvsError::StringError(std::mem::take(e)));
+        }
+        match responses.pop() {
+            Some(Response::Exec(_)) => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Switch the connection to the numbered database `db`. Connections start in
+    /// database 0.
+    pub async fn select(&mut self, db: u32) -> Result<()> {
+        let res = self.send_request(Request::Select { db }).await?;
+        match res {
+            Response::Select => {
+                self.session.db = db;
+                Ok(())
+            }
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Fetch the server's statistics.
+    pub async fn stats(&mut self) -> Result<ServerStats> {
+        let res = self.send_request(Request::Stats).await?;
+        match res {
+            Response::Stats(stats) => Ok(stats),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Have the server write a consistent copy of its store into the directory `path`
+    /// under its backup directory, returning once the copy is complete.
+    pub async fn backup(&mut self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        let res = self.send_request(Request::Backup { path }).await?;
+        match res {
+            Response::Backup => Ok(()),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Remove every key of the connection's database on the server.
+    pub async fn flush_all(&mut self) -> Result<()> {
+        let res = self.send_request(Request::FlushAll).await?;
+        match res {
+            Response::FlushAll => Ok(()),
             Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
         }
     }
 
+    /// Fetch the shards a routing server forwards keys to, so requests can be sent to
+    /// the shard owning each key directly.
+    pub async fn shard_map(&mut self) -> Result<ShardMap> {
+        let res = self.send_request(Request::ShardMap).await?;
+        match res {
+            Response::ShardMap(shards) => ShardMap::new(shards),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        }
+    }
+
+    /// Subscribe to changes of every key starting with `prefix`, turning the connection
+    /// into a stream of `(key, change)` notifications.
+    ///
+    /// Only changes made after the server confirmed the subscription are delivered.
+    pub async fn subscribe(
+        mut self,
+        prefix: String,
+    ) -> Result<BoxStream<'static, Result<(String, KeyChange)>>> {
+        let res = self.send_request(Request::Subscribe { prefix }).await?;
+        match res {
+            Response::Subscribed => {}
+            Response::Err(e) => return Err(KvsError::StringError(e)),
+            _ => return Err(KvsError::StringError("Invalid response".to_string())),
+        }
+
+        // the write half travels along so the connection stays open
+        let halves = (self.read_json, self.write_json);
+        let notifications = stream::unfold(halves, |(mut read_json, write_json)| async move {
+            let item = match read_json.next().await? {
+                Ok(Response::Notification { key, event }) => Ok((key, event)),
+                Ok(Response::Err(e)) => Err(KvsError::StringError(e)),
+                Ok(_) => Err(KvsError::StringError("Invalid response".to_string())),
+                Err(e) => Err(e.into()),
+            };
+            Some((item, (read_json, write_json)))
+        });
+        Ok(notifications.boxed())
+    }
+
+    /// Send all `requests` at once and return their responses in the same order.
+    ///
+    /// Servers supporting `feature::PIPELINING` run the requests concurrently, so a
+    /// batch takes about as long as its slowest request. Older servers are sent the
+    /// requests one after the other.
+    pub async fn pipeline(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        requests.iter().for_each(|req| self.before_sending(req));
+        let ops: Vec<_> = requests.iter().map(Request::op_name).collect();
+        let started = Instant::now();
+        let res = self.send_pipeline(requests).await;
+        if let Some(metrics) = &self.options.metrics {
+            let latency = started.elapsed();
+            match &res {
+                Ok(responses) => {
+                    for (op, response) in ops.into_iter().zip(responses) {
+                        metrics.record(op, latency, Ok(response));
+                    }
+                }
+                Err(e) => {
+                    for op in ops {
+                        metrics.record(op, latency, Err(e));
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    async fn send_pipeline(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        if self.broken {
+            self.reconnect().await?;
+        }
+        let timeout = self.options.request_timeout;
+        let res = with_timeout(timeout, self.send_pipelined(requests)).await;
+        self.check_connection(res)
+    }
+
+    async fn send_pipelined(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        self.finish_stream().await?;
+        if !self.server_info().await?.supports(feature::PIPELINING) {
+            let mut responses = Vec::with_capacity(requests.len());
+            for req in requests {
+                responses.push(self.send_with_retries(req).await?);
+            }
+            return Ok(responses);
+        }
+
+        let count = requests.len();
+        let write_json = &mut self.write_json;
+        let read_json = &mut self.read_json;
+        // responses are read while requests are still being written, so neither side
+        // blocks on a full socket buffer
+        let send = async move {
+            for (id, request) in requests.into_iter().enumerate() {
+                let request = Box::new(request);
+                write_json
+                    .feed(Request::Tagged {
+                        id: id as u64,
+                        request,
+                    })
+                    .await?;
+            }
+            write_json.flush().await?;
+            Ok::<_, KvsError>(())
+        };
+        let receive = async move {
+            let mut responses: Vec<Option<Response>> = (0..count).map(|_| None).collect();
+            for _ in 0..count {
+                let response = read_json.next().await.ok_or_else(no_response)??;
+                match response {
+                    Response::Tagged { id, response } => match responses.get_mut(id as usize) {
+                        Some(slot) if slot.is_none() => *slot = Some(*response),
+                        _ => return Err(KvsError::StringError("Invalid response".into())),
+                    },
+                    _ => return Err(KvsError::StringError("Invalid response".into())),
+                }
+            }
+            Ok::<Vec<_>, KvsError>(responses.into_iter().flatten().collect())
+        };
+        let ((), responses) = future::try_join(send, receive).await?;
+        Ok(responses)
+    }
+
+    /// Get the value of a given key from the server in chunks, so values too large
+    /// for a single frame can be read.
+    pub async fn get_streamed(&mut self, key: String) -> Result<Option<String>> {
+        let mut chunks = self.stream(Request::Get { key }).await?;
+        let mut value: Option<String> = None;
+        while let Some(chunk) = chunks.next().await {
+            match chunk? {
+                Chunk::Value(part) => value.get_or_insert_with(String::new).push_str(&part),
+                Chunk::Pairs(_) => return Err(KvsError::StringError("Invalid response".into())),
+            }
+        }
+        Ok(value)
+    }
+
+    /// List every key/value pair whose key starts with `prefix`, in ascending key
+    /// order, as the server streams them in chunks.
+    ///
+    /// The connection can be used again once the stream is dropped; if it wasn't
+    /// read to the end, the rest of the response is skipped first.
+    pub async fn scan_streamed(
+        &mut self,
+        prefix: String,
+    ) -> Result<BoxStream<'_, Result<(String, String)>>> {
+        let chunks = self
+            .stream(Request::Scan {
+                cursor: None,
+                prefix,
+                limit: usize::MAX,
+            })
+            .await?;
+        let pairs = chunks.flat_map(|chunk| {
+            let pairs = match chunk {
+                Ok(Chunk::Pairs(pairs)) => pairs.into_iter().map(Ok).collect(),
+                Ok(Chunk::Value(_)) => vec![Err(KvsError::StringError("Invalid response".into()))],
+                Err(e) => vec![Err(e)],
+            };
+            stream::iter(pairs)
+        });
+        Ok(pairs.boxed())
+    }
+
+    /// Send `req` as a `Request::Streamed` and return the chunks of its response.
+    async fn stream(&mut self, req: Request) -> Result<BoxStream<'_, Result<Chunk>>> {
+        if !self.server_info().await?.supports(feature::STREAMING) {
+            return Err(KvsError::StringError(
+                "The server does not support streamed responses".to_owned(),
+            ));
+        }
+        self.finish_stream().await?;
+        self.write_json
+            .send(Request::Streamed(Box::new(req)))
+            .await?;
+        self.unfinished_stream = true;
+
+        let chunks = stream::unfold(Some(self), |client| async move {
+            let client = client?;
+            let item = match client.read_json.next().await {
+                Some(Ok(Response::Chunk(chunk))) => return Some((Ok(chunk), Some(client))),
+                Some(Ok(Response::End)) => {
+                    client.unfinished_stream = false;
+                    return None;
+                }
+                Some(Ok(Response::Err(e))) => {
+                    client.unfinished_stream = false;
+                    Err(KvsError::StringError(e))
+                }
+                Some(Ok(_)) => Err(KvsError::StringError("Invalid response".to_string())),
+                Some(Err(e)) => Err(e.into()),
+                None => Err(no_response()),
+            };
+            Some((item, None))
+        });
+        Ok(chunks.boxed())
+    }
+
+    /// Skip the rest of a streamed response that was dropped before its end.
+    async fn finish_stream(&mut self) -> Result<()> {
+        while self.unfinished_stream {
+            match self.read_json.next().await {
+                Some(Ok(Response::Chunk(_))) => {}
+                Some(Ok(_)) => self.unfinished_stream = false,
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err(no_response()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Send `req` and read its response, recording the outcome in the metrics.
     async fn send_request(&mut self, req: Request) -> Result<Response> {
+        self.before_sending(&req);
+        let metrics = match self.options.metrics.clone() {
+            Some(metrics) => metrics,
+            None => return self.send_with_retries(req).await,
+        };
+        let op = req.op_name();
+        let started = Instant::now();
+        let res = self.send_with_retries(req).await;
+        metrics.record(op, started.elapsed(), res.as_ref());
+        res
+    }
+
+    /// Send `req` and read its response, reconnecting and retrying as the retry
+    /// policy says if the connection is broken.
+    async fn send_with_retries(&mut self, req: Request) -> Result<Response> {
+        let policy = self.options.retry.clone();
+        let idempotent = req.is_idempotent();
+        let mut attempt = 0;
+        loop {
+            if self.broken {
+                if let Err(e) = self.reconnect().await {
+                    if attempt == policy.max_retries {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                    tokio::time::sleep(policy.backoff(attempt)).await;
+                    continue;
+                }
+            }
+            if !idempotent || attempt == policy.max_retries {
+                let res = self.exchange(req).await;
+                return self.check_connection(res);
+            }
+            let res = self.exchange(req.clone()).await;
+            match self.check_connection(res) {
+                // a server that stalled is likely to stall again, so timeouts aren't retried
+                Err(KvsError::Io(_)) => {
+                    attempt += 1;
+                    tokio::time::sleep(policy.backoff(attempt)).await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Drop what `req` changes from the read cache.
+    fn before_sending(&mut self, req: &Request) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate_request(req);
+            // the subscription follows the database and user of the old session
+            if matches!(req, Request::Select { .. } | Request::Auth { .. }) {
+                self.invalidator = None;
+            }
+        }
+    }
+
+    /// What it takes to open more connections set up like this one.
+    pub(crate) fn spec(&self) -> ConnectionSpec {
+        ConnectionSpec {
+            endpoint: self.endpoint.clone(),
+            options: self.options.clone(),
+            session: self.session.clone(),
+        }
+    }
+
+    /// Replace the broken connection with a new one to the same endpoint, set up
+    /// the same way.
+    async fn reconnect(&mut self) -> Result<()> {
+        let mut spec = self.spec();
+        if let Some(peer) = self.peer {
+            spec.fail_over_from(peer);
+        }
+        *self = spec.connect().await?;
+        Ok(())
+    }
+
+    /// Mark the connection broken if `res` failed on it rather than on the server.
+    ///
+    /// A request that timed out may still be answered, so its connection can't be
+    /// used for the next one either.
+    fn check_connection<T>(&mut self, res: Result<T>) -> Result<T> {
+        if let Err(KvsError::Io(_) | KvsError::Timeout) = &res {
+            self.broken = true;
+        }
+        res
+    }
+
+    async fn exchange(&mut self, req: Request) -> Result<Response> {
+        let timeout = self.options.request_timeout;
+        with_timeout(timeout, self.round_trip(req)).await
+    }
+
+    async fn round_trip(&mut self, req: Request) -> Result<Response> {
+        self.finish_stream().await?;
         self.write_json.send(req).await?;
-        let response = self
-            .read_json
-            .next()
-            .await
-            .ok_or_else(|| crate::KvsError::StringError("No response received".into()))?;
+        let response = self.read_json.next().await.ok_or_else(no_response)?;
 
         Ok(response?)
     }
 }
+
+/// Whether a conditional write of `KvsClient::compare_and_swap` was applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CasOutcome {
+    /// The key held the expected value, or didn't exist if none was expected, and
+    /// was written.
+    Swapped,
+    /// The key held something else, so it was left as it was.
+    Conflict,
+}
+
+/// Gets, sets and removes queued with `KvsClient::batch`, sent together by `send`.
+pub struct BatchBuilder<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+/// The result of one operation sent with `BatchBuilder::send`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchReply {
+    /// The value of a queued get, or None if the key doesn't exist.
+    Get(Option<String>),
+    /// A queued set was applied.
+    Set,
+    /// A queued remove was applied.
+    Remove,
+}
+
+impl BatchBuilder<'_> {
+    /// Queue getting the value of `key`.
+    pub fn get(mut self, key: String) -> Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    /// Queue setting `key` to `value`.
+    pub fn set(mut self, key: String, value: String) -> Self {
+        self.requests.push(Request::Set {
+            key,
+            value,
+            ttl_ms: None,
+        });
+        self
+    }
+
+    /// Queue removing `key`, which fails on its own if the key doesn't exist.
+    pub fn remove(mut self, key: String) -> Self {
+        self.requests.push(Request::Remove { key });
+        self
+    }
+
+    /// Send the queued operations and return their results in the same order.
+    ///
+    /// Each operation succeeds or fails on its own, and they are applied in order.
+    /// Servers supporting `feature::BATCH` are sent a frame per thousand operations;
+    /// older servers are sent the operations one after the other.
+    pub async fn send(self) -> Result<Vec<Result<BatchReply>>> {
+        let BatchBuilder { client, requests } = self;
+        let count = requests.len();
+        let mut responses = Vec::with_capacity(count);
+        if client.server_info().await?.supports(feature::BATCH) {
+            let mut requests = requests.into_iter().peekable();
+            while requests.peek().is_some() {
+                let chunk = requests.by_ref().take(BATCH_FRAME_LEN).collect();
+                responses.extend(client.send_batch(chunk).await?);
+            }
+        } else {
+            for req in requests {
+                responses.push(client.send_request(req).await?);
+            }
+        }
+        if responses.len() != count {
+            return Err(KvsError::StringError("Invalid response".to_string()));
+        }
+
+        let replies = responses.into_iter().map(|res| match res {
+            Response::Get(value) => Ok(BatchReply::Get(value)),
+            Response::Set => Ok(BatchReply::Set),
+            Response::Remove => Ok(BatchReply::Remove),
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::StringError("Invalid response".to_string())),
+        });
+        Ok(replies.collect())
+    }
+}
+
+/// Connect to the first of `addrs` that accepts, giving each `timeout`.
+async fn connect_tcp(addrs: &[SocketAddr], timeout: Option<Duration>) -> Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addrs {
+        match with_timeout(timeout, async { Ok(TcpStream::connect(addr).await?) }).await {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "The address resolved to nothing").into()
+    }))
+}
+
+/// Run `fut`, failing with `KvsError::Timeout` if it takes longer than `timeout`.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .unwrap_or(Err(KvsError::Timeout)),
+        None => fut.await,
+    }
+}
+
+/// The error for a connection the server closed, an I/O error so that the
+/// connection is replaced.
+fn no_response() -> KvsError {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "No response received").into()
+}
@@ -0,0 +1,289 @@
+use std::{
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+use bytes::{Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio_serde::{Deserializer, Serializer};
+
+/// The longest frame a client sends or reads by default, as with
+/// `LengthDelimitedCodec::new`.
+pub(crate) const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Frames shorter than this are sent uncompressed, as compressing them gains little.
+const MIN_COMPRESSED_LENGTH: usize = 512;
+
+// the byte compressed connections start each frame with
+const FRAME_RAW: u8 = 0;
+const FRAME_COMPRESSED: u8 = 1;
+
+// length of the CRC-32 ending each frame once checksums are negotiated
+const CHECKSUM_LEN: usize = 4;
+
+/// An encoding of requests and responses on the wire.
+///
+/// Connections start out speaking JSON. A client may offer other codecs in
+/// `Request::Hello`: the server picks the first one it supports and names it in
+/// `ServerInfo::codec`, and both sides switch to it for everything after the
+/// `Response::Hello`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// JSON, easy to inspect but larger and slower to encode.
+    #[default]
+    Json,
+    /// MessagePack, a compact binary encoding.
+    MessagePack,
+}
+
+impl Codec {
+    /// The name the codec is negotiated by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            Codec::MessagePack => "messagepack",
+        }
+    }
+
+    /// The codec with the given name, or None if it isn't supported.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Codec::Json),
+            "messagepack" => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+
+    fn encode<T: Serialize>(self, item: &T) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Json => serde_json::to_vec(item).map_err(invalid_data),
+            Codec::MessagePack => rmp_serde::to_vec(item).map_err(invalid_data),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, src: &[u8]) -> io::Result<T> {
+        match self {
+            Codec::Json => serde_json::from_slice(src).map_err(invalid_data),
+            Codec::MessagePack => rmp_serde::from_slice(src).map_err(invalid_data),
+        }
+    }
+}
+
+/// A compression applied to each frame on top of the codec, for clients on slow
+/// links.
+///
+/// Negotiated in `Request::Hello` like the codec: the server picks the first
+/// compression the client offered that it supports and names it in
+/// `ServerInfo::compression`. Small frames are still sent uncompressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4, fast with a moderate ratio.
+    Lz4,
+    /// Zstandard, slower but compressing better.
+    Zstd,
+}
+
+impl Compression {
+    /// The name the compression is negotiated by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// The compression with the given name, or None if it isn't supported.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "lz4" => Some(Compression::Lz4),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(self, src: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(src)),
+            Compression::Zstd => zstd::bulk::compress(src, 0),
+        }
+    }
+
+    /// Decompress `src`, refusing to inflate it beyond `max_len` bytes.
+    fn decompress(self, src: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Lz4 => {
+                let len = src
+                    .get(..4)
+                    .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize);
+                match len {
+                    Some(len) if len <= max_len => {
+                        lz4_flex::decompress_size_prepended(src).map_err(invalid_data)
+                    }
+                    Some(_) => Err(too_large(max_len)),
+                    None => Err(invalid_data("Truncated compressed frame")),
+                }
+            }
+            Compression::Zstd => zstd::bulk::decompress(src, max_len),
+        }
+    }
+}
+
+/// The codec, compression and checksums one connection currently uses, shared by its
+/// reading and writing halves so that switching them affects both.
+#[derive(Clone)]
+pub(crate) struct CodecSwitch(Arc<Switch>);
+
+struct Switch {
+    codec: AtomicU8,
+    // 0 for none, otherwise one more than the Compression
+    compression: AtomicU8,
+    // whether frames end with a CRC-32 of the bytes before it
+    checksums: AtomicBool,
+    // the longest frame accepted, also once decompressed
+    max_frame_length: usize,
+}
+
+impl CodecSwitch {
+    /// JSON without compression or checksums, reading frames of up to
+    /// `max_frame_length` bytes.
+    pub(crate) fn new(max_frame_length: usize) -> Self {
+        CodecSwitch(Arc::new(Switch {
+            codec: AtomicU8::new(Codec::Json as u8),
+            compression: AtomicU8::new(0),
+            checksums: AtomicBool::new(false),
+            max_frame_length,
+        }))
+    }
+
+    pub(crate) fn get(&self) -> Codec {
+        match self.0.codec.load(Ordering::Acquire) {
+            0 => Codec::Json,
+            _ => Codec::MessagePack,
+        }
+    }
+
+    pub(crate) fn set(&self, codec: Codec) {
+        self.0.codec.store(codec as u8, Ordering::Release);
+    }
+
+    pub(crate) fn compression(&self) -> Option<Compression> {
+        match self.0.compression.load(Ordering::Acquire) {
+            0 => None,
+            1 => Some(Compression::Lz4),
+            _ => Some(Compression::Zstd),
+        }
+    }
+
+    pub(crate) fn set_compression(&self, compression: Option<Compression>) {
+        let value = compression.map_or(0, |compression| compression as u8 + 1);
+        self.0.compression.store(value, Ordering::Release);
+    }
+
+    pub(crate) fn checksums(&self) -> bool {
+        self.0.checksums.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_checksums(&self, checksums: bool) {
+        self.0.checksums.store(checksums, Ordering::Release);
+    }
+
+    fn encode<T: Serialize>(&self, item: &T) -> io::Result<Vec<u8>> {
+        let mut frame = self.compress(self.get().encode(item)?)?;
+        if self.checksums() {
+            let crc = crc32fast::hash(&frame);
+            frame.extend_from_slice(&crc.to_le_bytes());
+        }
+        Ok(frame)
+    }
+
+    fn compress(&self, frame: Vec<u8>) -> io::Result<Vec<u8>> {
+        let compression = match self.compression() {
+            Some(compression) => compression,
+            None => return Ok(frame),
+        };
+        let mut out = Vec::with_capacity(frame.len() + 1);
+        if frame.len() < MIN_COMPRESSED_LENGTH {
+            out.push(FRAME_RAW);
+            out.extend_from_slice(&frame);
+        } else {
+            out.push(FRAME_COMPRESSED);
+            out.extend(compression.compress(&frame)?);
+        }
+        Ok(out)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, mut src: &[u8]) -> io::Result<T> {
+        if self.checksums() {
+            if src.len() < CHECKSUM_LEN {
+                return Err(invalid_data("Frame too short for its checksum"));
+            }
+            let (frame, crc) = src.split_at(src.len() - CHECKSUM_LEN);
+            if crc32fast::hash(frame).to_le_bytes() != crc {
+                return Err(invalid_data("Frame checksum mismatch"));
+            }
+            src = frame;
+        }
+        let compression = match self.compression() {
+            Some(compression) => compression,
+            None => return self.get().decode(src),
+        };
+        match src.split_first() {
+            Some((&FRAME_RAW, frame)) => self.get().decode(frame),
+            Some((&FRAME_COMPRESSED, frame)) => {
+                let frame = compression.decompress(frame, self.0.max_frame_length)?;
+                self.get().decode(&frame)
+            }
+            _ => Err(invalid_data("Invalid frame header")),
+        }
+    }
+
+    /// A tokio-serde format for frames of `T`, following the switch.
+    pub(crate) fn format<T>(&self) -> Wire<T> {
+        Wire {
+            codec: self.clone(),
+            _item: PhantomData,
+        }
+    }
+}
+
+/// A tokio-serde format encoding with whichever codec and compression its
+/// `CodecSwitch` is set to when a frame is written or read.
+pub(crate) struct Wire<T> {
+    codec: CodecSwitch,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize> Serializer<T> for Wire<T> {
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &T) -> io::Result<Bytes> {
+        self.codec.encode(item).map(Bytes::from)
+    }
+}
+
+impl<T: DeserializeOwned> Deserializer<T> for Wire<T> {
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<T> {
+        self.codec.decode(src)
+    }
+}
+
+fn too_large(max_len: usize) -> io::Error {
+    invalid_data(format!(
+        "Decompressed frame exceeds the limit of {} bytes",
+        max_len
+    ))
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,72 @@
+//! The portable dump format written by `KvsEngineExt::export`.
+//!
+//! A dump starts with `MAGIC` followed by a format version byte. Each pair follows
+//! as the key and then the value, each prefixed by its length as a big-endian `u32`.
+//! The dump ends where the input ends.
+
+use std::io::{self, Read, Write};
+
+use crate::{KvsError, Result};
+
+const MAGIC: &[u8] = b"KVSDUMP";
+const VERSION: u8 = 1;
+
+pub(super) fn write_header(writer: &mut impl Write) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    Ok(())
+}
+
+pub(super) fn read_header(reader: &mut impl Read) -> Result<()> {
+    let mut header = [0; MAGIC.len() + 1];
+    reader.read_exact(&mut header)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(KvsError::StringError("Not a kvs dump".to_owned()));
+    }
+    match header[MAGIC.len()] {
+        VERSION => Ok(()),
+        version => Err(KvsError::StringError(format!(
+            "Unsupported dump version {}",
+            version
+        ))),
+    }
+}
+
+pub(super) fn write_pair(writer: &mut impl Write, key: &[u8], value: &[u8]) -> Result<()> {
+    write_field(writer, key)?;
+    write_field(writer, value)
+}
+
+/// Reads the next pair, or None at the end of the dump.
+pub(super) fn read_pair(reader: &mut impl Read) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut length = [0; 4];
+    // the dump may only end between pairs
+    match reader.read(&mut length[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut length[1..])?,
+    }
+    let key = read_bytes(reader, u32::from_be_bytes(length))?;
+    reader.read_exact(&mut length)?;
+    let value = read_bytes(reader, u32::from_be_bytes(length))?;
+    Ok(Some((key, value)))
+}
+
+fn write_field(writer: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    let length = u32::try_from(bytes.len())
+        .map_err(|_| KvsError::StringError("Field too large for a dump".to_owned()))?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes(reader: &mut impl Read, length: u32) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader
+        .by_ref()
+        .take(length as u64)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() != length as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
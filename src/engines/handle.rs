@@ -0,0 +1,321 @@
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream::BoxStream};
+
+use crate::{KeyEvent, KvsEngine, Result, ScanOptions, ValueMeta};
+
+/// Any `KvsEngine` behind a single type, so the engine can be picked at runtime
+/// instead of at compile time.
+///
+/// Every call goes through one dynamic dispatch, on top of the boxing every engine
+/// already does for its futures.
+pub struct EngineHandle(Box<dyn DynEngine>);
+
+impl EngineHandle {
+    /// Erases the type of `engine`.
+    pub fn new<E: KvsEngine>(engine: E) -> Self {
+        EngineHandle(Box::new(engine))
+    }
+}
+
+impl Clone for EngineHandle {
+    fn clone(&self) -> Self {
+        EngineHandle(self.0.clone_box())
+    }
+}
+
+/// The object-safe counterpart of `KvsEngine`, implemented for every engine.
+trait DynEngine: Send {
+    fn clone_box(&self) -> Box<dyn DynEngine>;
+    fn set_bytes(self: Box<Self>, key: Bytes, value: Bytes) -> BoxFuture<'static, Result<()>>;
+    fn get_bytes(self: Box<Self>, key: Bytes) -> BoxFuture<'static, Result<Option<Bytes>>>;
+    fn remove_bytes(self: Box<Self>, key: Bytes) -> BoxFuture<'static, Result<()>>;
+    fn append(self: Box<Self>, key: String, suffix: String) -> BoxFuture<'static, Result<u64>>;
+    fn getdel(self: Box<Self>, key: String) -> BoxFuture<'static, Result<Option<String>>>;
+    fn getset(
+        self: Box<Self>,
+        key: String,
+        value: String,
+    ) -> BoxFuture<'static, Result<Option<String>>>;
+    fn compare_and_swap(
+        self: Box<Self>,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> BoxFuture<'static, Result<bool>>;
+    fn rename(self: Box<Self>, from: String, to: String) -> BoxFuture<'static, Result<()>>;
+    fn copy(
+        self: Box<Self>,
+        from: String,
+        to: String,
+        overwrite: bool,
+    ) -> BoxFuture<'static, Result<bool>>;
+    fn scan(
+        self: Box<Self>,
+        options: ScanOptions,
+    ) -> BoxFuture<'static, Result<Vec<(String, String)>>>;
+    fn set_with_ttl(
+        self: Box<Self>,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<()>>;
+    fn ttl(self: Box<Self>, key: String) -> BoxFuture<'static, Result<Option<Duration>>>;
+    fn expire(self: Box<Self>, key: String, ttl: Duration) -> BoxFuture<'static, Result<bool>>;
+    fn persist(self: Box<Self>, key: String) -> BoxFuture<'static, Result<bool>>;
+    fn meta(self: Box<Self>, key: String) -> BoxFuture<'static, Result<Option<ValueMeta>>>;
+    fn exists(self: Box<Self>, key: String) -> BoxFuture<'static, Result<bool>>;
+    fn len(self: Box<Self>) -> BoxFuture<'static, Result<u64>>;
+    fn count_prefix(self: Box<Self>, prefix: String) -> BoxFuture<'static, Result<u64>>;
+    fn clear(self: Box<Self>) -> BoxFuture<'static, Result<()>>;
+    fn flush(self: Box<Self>) -> BoxFuture<'static, Result<()>>;
+    fn close(self: Box<Self>) -> BoxFuture<'static, Result<()>>;
+    fn watch(self: Box<Self>, prefix: String) -> BoxStream<'static, KeyEvent>;
+    fn engine_stats(self: Box<Self>) -> BoxFuture<'static, Result<BTreeMap<String, u64>>>;
+    fn backup(self: Box<Self>, dest: PathBuf) -> BoxFuture<'static, Result<()>>;
+    fn write_batch(
+        self: Box<Self>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> BoxFuture<'static, Result<()>>;
+}
+
+impl<E: KvsEngine> DynEngine for E {
+    fn clone_box(&self) -> Box<dyn DynEngine> {
+        Box::new(self.clone())
+    }
+
+    fn set_bytes(self: Box<Self>, key: Bytes, value: Bytes) -> BoxFuture<'static, Result<()>> {
+        KvsEngine::set_bytes(*self, key, value)
+    }
+
+    fn get_bytes(self: Box<Self>, key: Bytes) -> BoxFuture<'static, Result<Option<Bytes>>> {
+        KvsEngine::get_bytes(*self, key)
+    }
+
+    fn remove_bytes(self: Box<Self>, key: Bytes) -> BoxFuture<'static, Result<()>> {
+        KvsEngine::remove_bytes(*self, key)
+    }
+
+    fn append(self: Box<Self>, key: String, suffix: String) -> BoxFuture<'static, Result<u64>> {
+        KvsEngine::append(*self, key, suffix)
+    }
+
+    fn getdel(self: Box<Self>, key: String) -> BoxFuture<'static, Result<Option<String>>> {
+        KvsEngine::getdel(*self, key)
+    }
+
+    fn getset(
+        self: Box<Self>,
+        key: String,
+        value: String,
+    ) -> BoxFuture<'static, Result<Option<String>>> {
+        KvsEngine::getset(*self, key, value)
+    }
+
+    fn compare_and_swap(
+        self: Box<Self>,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> BoxFuture<'static, Result<bool>> {
+        KvsEngine::compare_and_swap(*self, key, expected, value)
+    }
+
+    fn rename(self: Box<Self>, from: String, to: String) -> BoxFuture<'static, Result<()>> {
+        KvsEngine::rename(*self, from, to)
+    }
+
+    fn copy(
+        self: Box<Self>,
+        from: String,
+        to: String,
+        overwrite: bool,
+    ) -> BoxFuture<'static, Result<bool>> {
+        KvsEngine::copy(*self, from, to, overwrite)
+    }
+
+    fn scan(
+        self: Box<Self>,
+        options: ScanOptions,
+    ) -> BoxFuture<'static, Result<Vec<(String, String)>>> {
+        KvsEngine::scan(*self, options)
+    }
+
+    fn set_with_ttl(
+        self: Box<Self>,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<()>> {
+        KvsEngine::set_with_ttl(*self, key, value, ttl)
+    }
+
+    fn ttl(self: Box<Self>, key: String) -> BoxFuture<'static, Result<Option<Duration>>> {
+        KvsEngine::ttl(*self, key)
+    }
+
+    fn expire(self: Box<Self>, key: String, ttl: Duration) -> BoxFuture<'static, Result<bool>> {
+        KvsEngine::expire(*self, key, ttl)
+    }
+
+    fn persist(self: Box<Self>, key: String) -> BoxFuture<'static, Result<bool>> {
+        KvsEngine::persist(*self, key)
+    }
+
+    fn meta(self: Box<Self>, key: String) -> BoxFuture<'static, Result<Option<ValueMeta>>> {
+        KvsEngine::meta(*self, key)
+    }
+
+    fn exists(self: Box<Self>, key: String) -> BoxFuture<'static, Result<bool>> {
+        KvsEngine::exists(*self, key)
+    }
+
+    fn len(self: Box<Self>) -> BoxFuture<'static, Result<u64>> {
+        KvsEngine::len(*self)
+    }
+
+    fn count_prefix(self: Box<Self>, prefix: String) -> BoxFuture<'static, Result<u64>> {
+        KvsEngine::count_prefix(*self, prefix)
+    }
+
+    fn clear(self: Box<Self>) -> BoxFuture<'static, Result<()>> {
+        KvsEngine::clear(*self)
+    }
+
+    fn flush(self: Box<Self>) -> BoxFuture<'static, Result<()>> {
+        KvsEngine::flush(*self)
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, Result<()>> {
+        KvsEngine::close(*self)
+    }
+
+    fn watch(self: Box<Self>, prefix: String) -> BoxStream<'static, KeyEvent> {
+        KvsEngine::watch(*self, prefix)
+    }
+
+    fn engine_stats(self: Box<Self>) -> BoxFuture<'static, Result<BTreeMap<String, u64>>> {
+        KvsEngine::engine_stats(*self)
+    }
+
+    fn backup(self: Box<Self>, dest: PathBuf) -> BoxFuture<'static, Result<()>> {
+        KvsEngine::backup(*self, dest)
+    }
+
+    fn write_batch(
+        self: Box<Self>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> BoxFuture<'static, Result<()>> {
+        KvsEngine::write_batch(*self, writes)
+    }
+}
+
+#[async_trait]
+impl KvsEngine for EngineHandle {
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        self.0.set_bytes(key, value).await
+    }
+
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        self.0.get_bytes(key).await
+    }
+
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
+        self.0.remove_bytes(key).await
+    }
+
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        self.0.append(key, suffix).await
+    }
+
+    async fn getdel(self, key: String) -> Result<Option<String>> {
+        self.0.getdel(key).await
+    }
+
+    async fn getset(self, key: String, value: String) -> Result<Option<String>> {
+        self.0.getset(key, value).await
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        self.0.compare_and_swap(key, expected, value).await
+    }
+
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        self.0.rename(from, to).await
+    }
+
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        self.0.copy(from, to, overwrite).await
+    }
+
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        self.0.scan(options).await
+    }
+
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.0.set_with_ttl(key, value, ttl).await
+    }
+
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        self.0.ttl(key).await
+    }
+
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        self.0.expire(key, ttl).await
+    }
+
+    async fn persist(self, key: String) -> Result<bool> {
+        self.0.persist(key).await
+    }
+
+    async fn meta(self, key: String) -> Result<Option<ValueMeta>> {
+        self.0.meta(key).await
+    }
+
+    async fn exists(self, key: String) -> Result<bool> {
+        self.0.exists(key).await
+    }
+
+    async fn len(self) -> Result<u64> {
+        self.0.len().await
+    }
+
+    async fn count_prefix(self, prefix: String) -> Result<u64> {
+        self.0.count_prefix(prefix).await
+    }
+
+    async fn clear(self) -> Result<()> {
+        self.0.clear().await
+    }
+
+    async fn flush(self) -> Result<()> {
+        self.0.flush().await
+    }
+
+    async fn close(self) -> Result<()> {
+        self.0.close().await
+    }
+
+    async fn engine_stats(self) -> Result<BTreeMap<String, u64>> {
+        self.0.engine_stats().await
+    }
+
+    async fn backup(self, dest: PathBuf) -> Result<()> {
+        self.0.backup(dest).await
+    }
+
+    async fn write_batch(self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        self.0.write_batch(writes).await
+    }
+
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        self.0.watch(prefix)
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+
+use crate::{KeyEvent, KvsEngine, Result, ScanOptions, ValueMeta};
+
+// bucket `i` counts latencies under 2^i microseconds, the last one everything slower
+const BUCKETS: usize = 32;
+
+/// A `KvsEngine` recording how often each operation of another engine is called,
+/// how often it fails and how long it takes.
+///
+/// Operations are named after the trait methods, with the byte variants counted
+/// as their string counterparts: `set_bytes` is recorded as `set`.
+/// Operations provided on top of others, such as `first_key`, are recorded as the
+/// operations they call.
+#[derive(Clone)]
+pub struct InstrumentedEngine<E: KvsEngine> {
+    inner: E,
+    metrics: Arc<Mutex<BTreeMap<&'static str, OpMetrics>>>,
+}
+
+/// What an `InstrumentedEngine` recorded for one operation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpMetrics {
+    /// Times the operation was called.
+    pub count: u64,
+    /// Times the operation returned an error, including `KvsError::KeyNotFound`.
+    pub errors: u64,
+    /// How long the operation took.
+    pub latency: LatencyHistogram,
+}
+
+/// Latencies counted in buckets whose bounds double, from one microsecond up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: [0; BUCKETS],
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Count a latency.
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+    }
+
+    /// Number of latencies counted.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The upper bound of each bucket with the number of latencies below it and above
+    /// the previous bound. The last bucket has no bound and counts everything slower.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, &count)| {
+            let bound = (i < BUCKETS - 1).then_some(Duration::from_micros(1 << i));
+            (bound, count)
+        })
+    }
+
+    /// An upper bound of the latency below which the fraction `q` of operations
+    /// completed, or None if nothing was counted or it falls in the last bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let target = ((total as f64 * q.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bound, count) in self.buckets() {
+            seen += count;
+            if seen >= target {
+                return bound;
+            }
+        }
+        None
+    }
+}
+
+impl<E: KvsEngine> InstrumentedEngine<E> {
+    /// Wraps `inner`, starting with no recorded operations.
+    pub fn new(inner: E) -> Self {
+        InstrumentedEngine {
+            inner,
+            metrics: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// What was recorded so far for each operation that was called at least once,
+    /// shared by all clones.
+    pub fn metrics(&self) -> BTreeMap<&'static str, OpMetrics> {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Record an operation named `op` started at `start` that returned `res`.
+    fn record<T>(&self, op: &'static str, start: Instant, res: Result<T>) -> Result<T> {
+        let latency = start.elapsed();
+        let mut metrics = self.metrics.lock().unwrap();
+        let metrics = metrics.entry(op).or_default();
+        metrics.count += 1;
+        if res.is_err() {
+            metrics.errors += 1;
+        }
+        metrics.latency.record(latency);
+        res
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine> KvsEngine for InstrumentedEngine<E> {
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        let start = Instant::now();
+        let res = self.inner.clone().set_bytes(key, value).await;
+        self.record("set", start, res)
+    }
+
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        let start = Instant::now();
+        let res = self.inner.clone().get_bytes(key).await;
+        self.record("get", start, res)
+    }
+
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
+        let start = Instant::now();
+        let res = self.inner.clone().remove_bytes(key).await;
+        self.record("remove", start, res)
+    }
+
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        let start = Instant::now();
+        let res = self.inner.clone().append(key, suffix).await;
+        self.record("append", start, res)
+    }
+
+    async fn getdel(self, key: String) -> Result<Option<String>> {
+        let start = Instant::now();
+        let res = self.inner.clone().getdel(key).await;
+        self.record("getdel", start, res)
+    }
+
+    async fn getset(self, key: String, value: String) -> Result<Option<String>> {
+        let start = Instant::now();
+        let res = self.inner.clone().getset(key, value).await;
+        self.record("getset", start, res)
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        let start = Instant::now();
+        let res = self
+            .inner
+            .clone()
+            .compare_and_swap(key, expected, value)
+            .await;
+        self.record("compare_and_swap", start, res)
+    }
+
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        let start = Instant::now();
+        let res = self.inner.clone().rename(from, to).await;
+        self.record("rename", start, res)
+    }
+
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        let start = Instant::now();
+        let res = self.inner.clone().copy(from, to, overwrite).await;
+        self.record("copy", start, res)
+    }
+
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        let start = Instant::now();
+        let res = self.inner.clone().scan(options).await;
+        self.record("scan", start, res)
+    }
+
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let start = Instant::now();
+        let res = self.inner.clone().set_with_ttl(key, value, ttl).await;
+        self.record("set_with_ttl", start, res)
+    }
+
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        let start = Instant::now();
+        let res = self.inner.clone().ttl(key).await;
+        self.record("ttl", start, res)
+    }
+
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        let start = Instant::now();
+        let res = self.inner.clone().expire(key, ttl).await;
+        self.record("expire", start, res)
+    }
+
+    async fn persist(self, key: String) -> Result<bool> {
+        let start = Instant::now();
+        let res = self.inner.clone().persist(key).await;
+        self.record("persist", start, res)
+    }
+
+    async fn meta(self, key: String) -> Result<Option<ValueMeta>> {
+        let start = Instant::now();
+        let res = self.inner.clone().meta(key).await;
+        self.record("meta", start, res)
+    }
+
+    async fn exists(self, key: String) -> Result<bool> {
+        let start = Instant::now();
+        let res = self.inner.clone().exists(key).await;
+        self.record("exists", start, res)
+    }
+
+    async fn len(self) -> Result<u64> {
+        let start = Instant::now();
+        let res = self.inner.clone().len().await;
+        self.record("len", start, res)
+    }
+
+    async fn count_prefix(self, prefix: String) -> Result<u64> {
+        let start = Instant::now();
+        let res = self.inner.clone().count_prefix(prefix).await;
+        self.record("count_prefix", start, res)
+    }
+
+    async fn clear(self) -> Result<()> {
+        let start = Instant::now();
+        let res = self.inner.clone().clear().await;
+        self.record("clear", start, res)
+    }
+
+    async fn flush(self) -> Result<()> {
+        let start = Instant::now();
+        let res = self.inner.clone().flush().await;
+        self.record("flush", start, res)
+    }
+
+    async fn close(self) -> Result<()> {
+        let start = Instant::now();
+        let res = self.inner.clone().close().await;
+        self.record("close", start, res)
+    }
+
+    async fn engine_stats(self) -> Result<BTreeMap<String, u64>> {
+        self.inner.engine_stats().await
+    }
+
+    async fn backup(self, dest: PathBuf) -> Result<()> {
+        self.inner.backup(dest).await
+    }
+
+    async fn write_batch(self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        self.inner.write_batch(writes).await
+    }
+
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        self.inner.watch(prefix)
+    }
+}
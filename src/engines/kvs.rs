@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use bytes::Bytes;
 use std::{
     cell::RefCell,
     collections::BTreeMap,
@@ -8,23 +9,55 @@ use std::{
     ops::Range,
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
     },
+    time::Duration,
 };
 
 use crossbeam::queue::ArrayQueue;
 use crossbeam_skiplist::SkipMap;
-use log::error;
+use futures::stream::BoxStream;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
-use tokio::sync::oneshot;
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    sync::broadcast,
+    task,
+};
+use tracing::error;
+
+use super::{
+    as_slice, deadline, into_string, now_millis, pool_stats, subscribe, KeyEvent, ScanOptions,
+    ValueMeta,
+};
+use crate::{
+    errors::KvsError,
+    thread_pool::{Priority, ThreadPool},
+    KvsEngine, Result,
+};
+
+mod checkpoint;
+mod history;
+mod shards;
+mod snapshot;
+mod transaction;
+mod verify;
+
+pub use snapshot::Snapshot;
+pub use transaction::Transaction;
+pub use verify::{CorruptRecord, VerifyReport};
 
-use crate::{errors::KvsError, thread_pool::ThreadPool, KvsEngine, Result};
+use history::History;
+use shards::{Generations, Shards};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// events buffered per watcher before it starts skipping
+const EVENT_CAPACITY: usize = 1024;
+// how long closing waits for in-flight reads to finish
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// The `KvStore` stores string key/value pairs.
+/// The `KvStore` stores binary key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
@@ -32,10 +65,14 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 #[derive(Clone)]
 pub struct KvStore<P: ThreadPool> {
     // map generation number to the file reader
-    index: Arc<SkipMap<String, CommandPosition>>,
-    writer: Arc<Mutex<KvStoreWriter>>,
+    index: Arc<SkipMap<Bytes, CommandPosition>>,
+    writers: Arc<Shards>,
     thread_pool: P,
-    reader_pool: Arc<ArrayQueue<KvStoreReader>>,
+    reader_pool: Arc<ReaderPool>,
+    events: broadcast::Sender<KeyEvent>,
+    closed: Arc<AtomicBool>,
+    history: Arc<History>,
+    inline_io: bool,
 }
 
 impl<P: ThreadPool> KvStore<P> {
@@ -49,11 +86,32 @@ impl<P: ThreadPool> KvStore<P> {
     /// Returns an error if the directory cannot be created or if there's an issue
     /// opening or reading the existing log files.
     pub fn open(path: impl Into<PathBuf>, max_threads: u32) -> Result<Self> {
+        Self::open_with_options(path, max_threads, KvStoreOptions::default())
+    }
+
+    /// Creates a new `KvStore` or opens an existing one at the specified path, like
+    /// `open`, with the behavior adjusted by `options`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory cannot be created or if there's an issue
+    /// opening or reading the existing log files.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        max_threads: u32,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
+        if options.writer_shards == 0 {
+            return Err(KvsError::StringError(
+                "A KvStore needs at least one writer shard".to_owned(),
+            ));
+        }
         let path = Arc::new(path.into());
         fs::create_dir_all(&*path)?;
 
         let mut readers = BTreeMap::new();
         let index = Arc::new(SkipMap::new());
+        let history = Arc::new(History::new(options.versions));
 
         let generation_number_list = sorted_generation_number_list(&path)?;
         let mut uncompacted = 0;
@@ -61,14 +119,17 @@ impl<P: ThreadPool> KvStore<P> {
         for &generation_number in &generation_number_list {
             let mut reader =
                 BufReaderWithPosition::new(File::open(log_path(&path, generation_number))?)?;
-            uncompacted += load(generation_number, &mut reader, &index)?;
+            uncompacted += load(generation_number, &mut reader, &index, &history)?;
             readers.insert(generation_number, reader);
         }
 
         // Default to 1
-        let current_generation_number = generation_number_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_generation_number)?;
+        let first_generation_number = generation_number_list.last().unwrap_or(&0) + 1;
+        let shards = options.writer_shards;
+        let generations = Arc::new(Generations::new(first_generation_number, shards));
         let safe_point = Arc::new(AtomicU64::new(0));
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let pins = Arc::new(Mutex::new(BTreeMap::new()));
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
@@ -76,33 +137,276 @@ impl<P: ThreadPool> KvStore<P> {
             readers: RefCell::new(readers),
         };
 
-        let writer = KvStoreWriter {
-            reader: reader.clone(),
-            writer,
-            current_generation_number,
-            uncompacted,
-            path: Arc::clone(&path),
-            index: Arc::clone(&index),
-        };
+        let mut writers = Vec::with_capacity(shards);
+        for shard in 0..shards {
+            let current_generation_number = generations.allocate(1);
+            writers.push(KvStoreWriter {
+                reader: reader.clone(),
+                writer: new_log_file(&path, current_generation_number)?,
+                current_generation_number,
+                // which shard the loaded bytes belong to isn't tracked, so they're
+                // spread evenly
+                uncompacted: uncompacted / shards as u64,
+                path: Arc::clone(&path),
+                index: Arc::clone(&index),
+                pins: Arc::clone(&pins),
+                events: events.clone(),
+                history: Arc::clone(&history),
+                max_value_size: options.max_value_size,
+                generations: Arc::clone(&generations),
+                shard,
+            });
+        }
 
         let thread_pool = P::new(max_threads)?;
-        let reader_pool = Arc::new(ArrayQueue::new(max_threads as usize));
+        let readers = ArrayQueue::new(max_threads as usize);
         for _ in 1..max_threads {
-            reader_pool
+            readers
                 .push(reader.clone())
                 .map_err(|_| KvsError::StringError("Failed to push to reader".to_string()))?;
         }
+        let reader_pool = Arc::new(ReaderPool {
+            readers,
+            path: Arc::clone(&reader.path),
+            safe_point: Arc::clone(&reader.safe_point),
+            in_use: Mutex::new(0),
+            returned: Condvar::new(),
+        });
         reader_pool
+            .readers
             .push(reader)
             .map_err(|_| KvsError::StringError("Failed to push to reader".to_string()))?;
 
         Ok(KvStore {
             index,
-            writer: Arc::new(Mutex::new(writer)),
+            writers: Arc::new(Shards::new(writers, generations)),
             thread_pool,
             reader_pool,
+            events,
+            closed: Arc::new(AtomicBool::new(false)),
+            history,
+            inline_io: options.inline_io,
         })
     }
+
+    fn check_open(&self) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(KvsError::Closed);
+        }
+        Ok(())
+    }
+
+    /// Run `job` on the calling task if the store was opened with
+    /// `KvStoreOptions::inline_io` and is used from a multi-threaded Tokio runtime,
+    /// or on the thread pool otherwise. Writer shards found lagging behind the
+    /// others are then compacted by a background job.
+    async fn run<R, F>(&self, job: F) -> Result<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let multi_thread = Handle::try_current().map_or(false, |handle| {
+            handle.runtime_flavor() == RuntimeFlavor::MultiThread
+        });
+        let res = if self.inline_io && multi_thread {
+            task::block_in_place(job)
+        } else {
+            self.thread_pool.spawn_with_result(job).await?
+        };
+        if self.writers.needs_catch_up() {
+            let writers = self.writers.clone();
+            self.thread_pool
+                .spawn_at(Priority::Background, move || writers.catch_up());
+        }
+        Ok(res)
+    }
+
+    /// Whether `key` is missing from the index or expired, so reading it needs no
+    /// file IO.
+    fn is_missing(&self, key: &[u8]) -> bool {
+        self.index
+            .get(key)
+            .map_or(true, |entry| entry.value().is_expired())
+    }
+
+    /// Reads the log files of the store at the given path without modifying anything.
+    ///
+    /// Unlike `open`, no directory or log file is created, so this is safe to run
+    /// against the data directory of a stopped server.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory cannot be read or if any log file,
+    /// including its tail, fails to deserialize.
+    pub fn inspect(path: impl Into<PathBuf>) -> Result<StoreInfo> {
+        let path = path.into();
+        let mut info = StoreInfo::default();
+        if !path.is_dir() {
+            return Ok(info);
+        }
+
+        let index = SkipMap::new();
+        for generation_number in sorted_generation_number_list(&path)? {
+            let file = File::open(log_path(&path, generation_number))?;
+            info.log_bytes += file.metadata()?.len();
+            let mut reader = BufReaderWithPosition::new(file)?;
+            info.uncompacted += load(generation_number, &mut reader, &index, &History::new(1))?;
+            info.generations.push(generation_number);
+        }
+        info.keys = index.len() as u64;
+
+        Ok(info)
+    }
+
+    /// Starts a multi-key transaction.
+    ///
+    /// Reads made through the transaction see the store as it is now, regardless of
+    /// writes committed afterwards. Writes are buffered and only applied by
+    /// `Transaction::commit`, atomically and as a single log record, even across
+    /// writer shards, see `KvStoreOptions::writer_shards`.
+    pub async fn transaction(self) -> Result<Transaction<P>> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        let snapshot = self
+            .thread_pool
+            .spawn_with_result(move || writers.lock_all().any().snapshot())
+            .await?;
+        Ok(Transaction::new(self, snapshot))
+    }
+
+    /// Takes a point-in-time snapshot of the store.
+    ///
+    /// The snapshot keeps serving the values it saw, even while later writes and
+    /// compactions proceed. Log files it depends on are not deleted until it's dropped.
+    pub async fn snapshot(self) -> Result<Snapshot<P>> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        let view = self
+            .thread_pool
+            .spawn_with_result(move || writers.lock_all().any().snapshot())
+            .await?;
+        Ok(Snapshot::new(view, self.thread_pool))
+    }
+
+    /// Returns the retained values of a key, newest first, starting with the current
+    /// value if the key exists.
+    ///
+    /// Only the latest version is kept unless the store was opened with
+    /// `KvStoreOptions::versions` above 1. Versions from before a removal stay
+    /// available until later writes push them out.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an issue reading a version or if a value is not
+    /// valid UTF-8.
+    pub async fn get_versions(self, key: String) -> Result<Vec<String>> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.thread_pool
+            .spawn_with_result(move || {
+                // the writer lock keeps the current version and the history in step
+                writers.lock(key.as_bytes()).versions(key.as_bytes())
+            })
+            .await?
+    }
+
+    /// Writes a consistent copy of the store into the directory `dest`, which is
+    /// created if needed and must not already contain a store.
+    ///
+    /// Writes may continue while the copy is made; they're not part of it. The
+    /// copy can be opened with `KvStore::open` like any other store. The copy is
+    /// made by a background job, behind the requests queued on the thread pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `dest` already contains log files or if there's an issue
+    /// linking or copying the log files.
+    pub async fn checkpoint(self, dest: impl Into<PathBuf>) -> Result<()> {
+        self.check_open()?;
+        let dest = dest.into();
+        let writers = self.writers.clone();
+        self.thread_pool
+            .spawn_with_result_at(Priority::Background, move || {
+                // the locks are only held to record the state, not while copying
+                let checkpoint = writers.lock_all().checkpoint();
+                checkpoint.and_then(|checkpoint| checkpoint.write_to(&dest))
+            })
+            .await?
+    }
+
+    /// Rewrites the live entries into a new log file per writer shard and deletes the
+    /// stale ones now, rather than once enough bytes became reclaimable.
+    ///
+    /// Log files still needed by a snapshot are kept until a later compaction. It
+    /// runs as a background job, behind the requests queued on the thread pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an issue with creating new log files, copying
+    /// entries or removing stale log files.
+    pub async fn compact(self) -> Result<()> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.thread_pool
+            .spawn_with_result_at(Priority::Background, move || writers.lock_all().compact())
+            .await?
+    }
+}
+
+/// Options for `KvStore::open_with_options`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvStoreOptions {
+    /// How many versions of each key to keep, counting the current one.
+    ///
+    /// Versions beyond the latest survive compaction and are returned by
+    /// `KvStore::get_versions`. The default of 1 keeps only the current value.
+    pub versions: usize,
+    /// The largest value accepted in bytes, or None for no limit.
+    ///
+    /// Writes of a larger value, including appends growing a value past the limit,
+    /// fail with `KvsError::ValueTooLarge` and leave the store unchanged.
+    pub max_value_size: Option<usize>,
+    /// Whether to do the file IO of requests on the calling task instead of on the
+    /// thread pool, when called from a multi-threaded Tokio runtime.
+    ///
+    /// The IO blocks the runtime's worker thread, which hands its other tasks to
+    /// another worker first, but skips the round trip through the pool that costs
+    /// a few tens of microseconds per request. Compaction and checkpoints still
+    /// run on the pool. Off by default.
+    pub inline_io: bool,
+    /// How many writers the keyspace is split across, each with its own active log
+    /// file and compaction.
+    ///
+    /// Writes of keys in different shards don't wait for each other. Writes of
+    /// several keys, like batches, transactions and renames, lock every shard and
+    /// are written as a single log record, so they stay atomic across shards; the
+    /// shards they span start new log files. The store can be reopened with another
+    /// number of shards. The default of 1 keeps a single writer.
+    pub writer_shards: usize,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            versions: 1,
+            max_value_size: None,
+            inline_io: false,
+            writer_shards: 1,
+        }
+    }
+}
+
+/// Summary of the log files in a `KvStore` data directory.
+#[derive(Debug, Clone, Default)]
+pub struct StoreInfo {
+    /// Generation numbers of the log files, in ascending order.
+    pub generations: Vec<u64>,
+    /// Total size of the log files in bytes.
+    pub log_bytes: u64,
+    /// Number of live keys.
+    pub keys: u64,
+    /// Bytes a compaction would reclaim.
+    pub uncompacted: u64,
 }
 
 #[async_trait]
@@ -113,17 +417,10 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     ///
     /// Returns an error if there is an issue with serialization, writing to the log file,
     /// or if the compaction threshold is reached and compaction fails.
-    async fn set(self, key: String, value: String) -> Result<()> {
-        let writer = self.writer.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().set(key, value);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        rx.await
-            .map_err(|e| KvsError::StringError(format!("{}", e)))?
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || writers.lock(&key).set(key, value)).await?
     }
 
     /// Gets the value of a key from the key-value store.
@@ -132,40 +429,32 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     ///
     /// Returns an error if there is an issue with deserialization, seeking in the log file,
     /// or if the command type is unexpected.
-    async fn get(self, key: String) -> Result<Option<String>> {
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        self.check_open()?;
+        // a miss is answered without leaving the task
+        if self.is_missing(&key) {
+            return Ok(None);
+        }
         let reader_pool = self.reader_pool.clone();
         let index = self.index.clone();
-        let (tx, rx) = oneshot::channel();
-
-        self.thread_pool.spawn(move || {
-            let res = (|| {
-                if let Some(cmd_pos) = index.get(&key) {
-                    let reader = reader_pool
-                        .pop()
-                        .ok_or_else(|| KvsError::StringError("No more readers".to_string()))?;
-
-                    let res = if let Command::Set { value, .. } =
-                        reader.read_command(*cmd_pos.value())?
-                    {
-                        Ok(Some(value))
-                    } else {
-                        Err(KvsError::UnexpectedCommandType)
-                    };
-
-                    reader_pool.push(reader).map_err(|_| {
-                        KvsError::StringError("Failed to push to array".to_string())
-                    })?;
-                    res
-                } else {
-                    Ok(None)
-                }
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+
+        self.run(move || {
+            if let Some(cmd_pos) = index.get(&key).filter(|e| !e.value().is_expired()) {
+                let reader = reader_pool.take();
+
+                let res = match reader.read_command(*cmd_pos.value()) {
+                    Ok(Command::Set { value, .. }) => Ok(Some(value)),
+                    Ok(_) => Err(KvsError::UnexpectedCommandType),
+                    Err(e) => Err(e),
+                };
+
+                reader_pool.give_back(reader);
+                res
+            } else {
+                Ok(None)
             }
-        });
-        rx.await
-            .map_err(|e| KvsError::StringError(format!("{}", e)))?
+        })
+        .await?
     }
 
     /// Removes a key from the key-value store.
@@ -174,17 +463,401 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     ///
     /// Returns an error if the key is not found, or if there is an issue with serialization,
     /// writing to the log file, or if the compaction threshold is reached and compaction fails.
-    async fn remove(self, key: String) -> Result<()> {
-        let writer = self.writer.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().remove(key);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || writers.lock(&key).remove(key)).await?
+    }
+
+    /// Appends to the value of a key in the key-value store, creating it if absent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the current value cannot be read, or if there is an issue with
+    /// serialization, writing to the log file, or compaction.
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || {
+            writers
+                .lock(key.as_bytes())
+                .append(key.into(), suffix.into())
+        })
+        .await?
+    }
+
+    /// Removes a key from the key-value store and returns its previous value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the previous value cannot be read, or if there is an issue with
+    /// serialization, writing to the log file, or compaction.
+    async fn getdel(self, key: String) -> Result<Option<String>> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || {
+            writers
+                .lock(key.as_bytes())
+                .getdel(key.into())
+                .and_then(|value| value.map(into_string).transpose())
+        })
+        .await?
+    }
+
+    /// Sets the value of a key in the key-value store and returns its previous value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the previous value cannot be read, or if there is an issue with
+    /// serialization, writing to the log file, or compaction.
+    async fn getset(self, key: String, value: String) -> Result<Option<String>> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || {
+            writers
+                .lock(key.as_bytes())
+                .getset(key.into(), value.into())
+                .and_then(|value| value.map(into_string).transpose())
+        })
+        .await?
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || {
+            writers.lock(key.as_bytes()).compare_and_swap(
+                key.into(),
+                expected.map(Bytes::from),
+                value.into(),
+            )
+        })
+        .await?
+    }
+
+    /// Renames a key by writing the new key and the removal of the old one as one
+    /// batch, so a crash never leaves just one of them, even with the keys in
+    /// different writer shards.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if `from` doesn't exist, or an error if the
+    /// key can't be read or the batch can't be written.
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || writers.lock_all().rename(from.into(), to.into()))
+            .await?
+    }
+
+    /// Copies a key under the lock of every writer shard.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key can't be read or the copy can't be written.
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || writers.lock_all().copy(from.into(), to.into(), overwrite))
+            .await?
+    }
+
+    /// Returns the pairs selected by `options` as of when each key is visited.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an issue with deserialization, seeking in the log file,
+    /// or if a key or value is not valid UTF-8.
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        self.check_open()?;
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        self.run(move || {
+            let (lower, upper) = options.bounds();
+            let range = index.range::<[u8], _>((as_slice(&lower), as_slice(&upper)));
+            let entries: Box<dyn Iterator<Item = _>> = if options.reverse {
+                Box::new(range.rev())
+            } else {
+                Box::new(range)
+            };
+
+            let reader = reader_pool.take();
+            let res = entries
+                .filter(|entry| !entry.value().is_expired())
+                .take(options.limit.unwrap_or(usize::MAX))
+                .map(|entry| match reader.read_command(*entry.value())? {
+                    Command::Set { key, value, .. } => Ok((into_string(key)?, into_string(value)?)),
+                    _ => Err(KvsError::UnexpectedCommandType),
+                })
+                .collect();
+            reader_pool.give_back(reader);
+            res
+        })
+        .await?
+    }
+
+    /// Returns the smallest key straight from the in-memory index.
+    async fn first_key(self) -> Result<Option<String>> {
+        self.check_open()?;
+        self.index
+            .iter()
+            .find(|entry| !entry.value().is_expired())
+            .map(|entry| into_string(entry.key().clone()))
+            .transpose()
+    }
+
+    /// Returns the largest key straight from the in-memory index.
+    async fn last_key(self) -> Result<Option<String>> {
+        self.check_open()?;
+        self.index
+            .iter()
+            .rev()
+            .find(|entry| !entry.value().is_expired())
+            .map(|entry| into_string(entry.key().clone()))
+            .transpose()
+    }
+
+    /// Checks the key straight in the in-memory index.
+    async fn exists(self, key: String) -> Result<bool> {
+        self.check_open()?;
+        Ok(self
+            .index
+            .get(key.as_bytes())
+            .map_or(false, |entry| !entry.value().is_expired()))
+    }
+
+    /// Counts the keys of the in-memory index that haven't expired.
+    async fn len(self) -> Result<u64> {
+        self.check_open()?;
+        Ok(self
+            .index
+            .iter()
+            .filter(|entry| !entry.value().is_expired())
+            .count() as u64)
+    }
+
+    /// Counts the keys with the prefix in the in-memory index, without reading values.
+    async fn count_prefix(self, prefix: String) -> Result<u64> {
+        self.check_open()?;
+        let (lower, upper) = ScanOptions {
+            prefix,
+            ..ScanOptions::default()
+        }
+        .bounds();
+        Ok(self
+            .index
+            .range::<[u8], _>((as_slice(&lower), as_slice(&upper)))
+            .filter(|entry| !entry.value().is_expired())
+            .count() as u64)
+    }
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an issue with serialization, writing to the log file,
+    /// or if the compaction threshold is reached and compaction fails.
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || {
+            writers
+                .lock(key.as_bytes())
+                .set_expiring(key.into(), value.into(), Some(deadline(ttl)))
+        })
+        .await?
+    }
+
+    /// Returns the remaining time to live of a key straight from the in-memory index.
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        self.check_open()?;
+        let now = now_millis();
+        Ok(self
+            .index
+            .get(key.as_bytes())
+            .and_then(|entry| entry.value().expires_at)
+            .filter(|&expires_at| expires_at > now)
+            .map(|expires_at| Duration::from_millis(expires_at - now)))
+    }
+
+    /// Returns the metadata of a key from its log entry, with the modification time
+    /// recorded when it was written. Entries written by older versions have none.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry can't be read from the log.
+    async fn meta(self, key: String) -> Result<Option<ValueMeta>> {
+        self.check_open()?;
+        if self.is_missing(key.as_bytes()) {
+            return Ok(None);
+        }
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+
+        self.run(move || {
+            let cmd_pos = match index
+                .get(key.as_bytes())
+                .map(|entry| *entry.value())
+                .filter(|cmd_pos| !cmd_pos.is_expired())
+            {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(None),
+            };
+            let reader = reader_pool.take();
+            let res = match reader.read_command(cmd_pos) {
+                Ok(Command::Set {
+                    value, modified_at, ..
+                }) => Ok(Some(ValueMeta {
+                    len: value.len() as u64,
+                    modified_ms: modified_at,
+                    ttl_ms: cmd_pos
+                        .expires_at
+                        .map(|expires_at| expires_at.saturating_sub(now_millis())),
+                })),
+                Ok(_) => Err(KvsError::UnexpectedCommandType),
+                Err(e) => Err(e),
+            };
+            reader_pool.give_back(reader);
+            res
+        })
+        .await?
+    }
+
+    /// Makes an existing key expire after `ttl` by rewriting it with the new deadline.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an issue with reading or rewriting the key.
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || {
+            writers
+                .lock(key.as_bytes())
+                .set_deadline(key.into(), Some(deadline(ttl)))
+        })
+        .await?
+    }
+
+    /// Removes the expiration of a key by rewriting it without a deadline.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an issue with reading or rewriting the key.
+    async fn persist(self, key: String) -> Result<bool> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || writers.lock(key.as_bytes()).set_deadline(key.into(), None))
+            .await?
+    }
+
+    /// Removes every key from the key-value store and deletes the old log files.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an issue with creating the new log file or
+    /// writing to it.
+    async fn clear(self) -> Result<()> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || writers.lock_all().clear()).await?
+    }
+
+    /// Writes the batch as a single log record, like a committed `Transaction`.
+    async fn write_batch(self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || {
+            let cmds = writes
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Some(value) => Command::set(key.into(), value.into()),
+                    None => Command::remove(key.into()),
+                })
+                .collect();
+            writers.lock_all().write_batch(cmds)
+        })
+        .await?
+    }
+
+    /// Flushes the write buffers and syncs the active log files to disk.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if flushing or syncing the log file fails.
+    async fn flush(self) -> Result<()> {
+        self.check_open()?;
+        let writers = self.writers.clone();
+        self.run(move || writers.lock_all().sync()).await?
+    }
+
+    /// Waits for in-flight operations to finish, then flushes and syncs the active log files.
+    ///
+    /// Every clone of the store shares the closed state. Closing twice is not an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if flushing or syncing the log file fails, or if reads are
+    /// still in flight after 10 seconds; the log files are synced either way.
+    async fn close(self) -> Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let writers = self.writers.clone();
+        let reader_pool = self.reader_pool.clone();
+        self.run(move || {
+            // holding the writer locks means no write is in flight
+            let mut writers = writers.lock_all();
+            // every read hands its reader back when it's done
+            let idle = reader_pool.wait_idle(CLOSE_TIMEOUT);
+            writers.sync()?;
+            if !idle {
+                return Err(KvsError::StringError(
+                    "Timed out waiting for reads to finish".to_owned(),
+                ));
             }
-        });
-        rx.await
-            .map_err(|e| KvsError::StringError(format!("{}", e)))?
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Reports the number of keys, the bytes compaction would reclaim, the
+    /// generation of the newest active log file, the number of writer shards and
+    /// the load of the thread pool.
+    async fn engine_stats(self) -> Result<BTreeMap<String, u64>> {
+        self.check_open()?;
+        // taken first so the job below doesn't count
+        let pool = pool_stats(&self.thread_pool);
+        let writers = self.writers.clone();
+        let mut stats = self
+            .thread_pool
+            .spawn_with_result(move || {
+                let writers = writers.lock_all();
+                BTreeMap::from([
+                    ("keys".to_owned(), writers.any().index.len() as u64),
+                    ("uncompacted_bytes".to_owned(), writers.uncompacted()),
+                    ("generation".to_owned(), writers.generation()),
+                    ("writer_shards".to_owned(), writers.shards() as u64),
+                ])
+            })
+            .await?;
+        stats.extend(pool);
+        Ok(stats)
+    }
+
+    /// Takes a checkpoint, see `KvStore::checkpoint`.
+    async fn backup(self, dest: PathBuf) -> Result<()> {
+        self.checkpoint(dest).await
+    }
+
+    /// Subscribes to changes of keys starting with `prefix`.
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        subscribe(&self.events, prefix)
     }
 }
 
@@ -260,118 +933,332 @@ impl Clone for KvStoreReader {
     }
 }
 
+/// The readers of the log files, one per thread of the pool.
+struct ReaderPool {
+    readers: ArrayQueue<KvStoreReader>,
+    // to open more readers for the requests read on their own task
+    path: Arc<PathBuf>,
+    safe_point: Arc<AtomicU64>,
+    // readers taken and not given back yet
+    in_use: Mutex<usize>,
+    returned: Condvar,
+}
+
+impl ReaderPool {
+    /// Take a reader from the pool, or open a new one if every reader is in use.
+    fn take(&self) -> KvStoreReader {
+        *self.in_use.lock().unwrap() += 1;
+        self.readers.pop().unwrap_or_else(|| KvStoreReader {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::clone(&self.safe_point),
+            readers: RefCell::new(BTreeMap::new()),
+        })
+    }
+
+    /// Return a reader taken from the pool. Readers opened beyond its capacity are
+    /// dropped.
+    fn give_back(&self, reader: KvStoreReader) {
+        let _ = self.readers.push(reader);
+        let mut in_use = self.in_use.lock().unwrap();
+        *in_use -= 1;
+        if *in_use == 0 {
+            self.returned.notify_all();
+        }
+    }
+
+    /// Wait up to `timeout` for every reader taken to be given back. Returns whether
+    /// they were.
+    fn wait_idle(&self, timeout: Duration) -> bool {
+        let in_use = self.in_use.lock().unwrap();
+        let (_, res) = self
+            .returned
+            .wait_timeout_while(in_use, timeout, |in_use| *in_use > 0)
+            .unwrap();
+        !res.timed_out()
+    }
+}
+
 struct KvStoreWriter {
     reader: KvStoreReader,
     writer: BufWriterWithPosition<File>,
     current_generation_number: u64,
     uncompacted: u64,
     path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPosition>>,
+    index: Arc<SkipMap<Bytes, CommandPosition>>,
+    // generations pinned by live snapshots, with their reference counts
+    pins: Arc<Mutex<BTreeMap<u64, usize>>>,
+    events: broadcast::Sender<KeyEvent>,
+    history: Arc<History>,
+    max_value_size: Option<usize>,
+    generations: Arc<Generations>,
+    // the keys written by this writer are the ones `owns` returns true for
+    shard: usize,
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
+    fn set(&mut self, key: Bytes, value: Bytes) -> Result<()> {
+        self.write(Command::set(key, value))
+    }
+
+    /// Writes a batch of `Set` and `Remove` commands as a single log record.
+    ///
+    /// Either all of the commands survive a crash or none of them do. Removing a
+    /// key that doesn't exist is not an error inside a batch.
+    fn write_batch(&mut self, cmds: Vec<Command>) -> Result<()> {
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        self.write(Command::Batch(cmds))
+    }
+
+    fn write(&mut self, cmd: Command) -> Result<()> {
+        if let Some(max) = self.max_value_size {
+            cmd.check_value_size(max)?;
+        }
         let position = self.writer.position;
         serde_json::to_writer(&mut self.writer, &cmd)?;
         self.writer.flush()?;
 
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().length;
-            }
-            self.index.insert(
-                key,
-                (
-                    self.current_generation_number,
-                    position..self.writer.position,
-                )
-                    .into(),
-            );
+        // collected before `apply` consumes the command and updates the index
+        let mut events = Vec::new();
+        if self.events.receiver_count() > 0 {
+            key_events(&cmd, &self.index, &mut events);
         }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
+        self.uncompacted += apply(
+            &self.index,
+            &self.history,
+            self.current_generation_number,
+            cmd,
+            position..self.writer.position,
+        )?;
+
+        for event in events {
+            // only fails if every watcher went away in the meantime
+            let _ = self.events.send(event);
+        }
+
+        if self.needs_compaction() {
             self.compact()?;
         }
         Ok(())
     }
 
-    /// Compacts the log files by removing stale entries and creating a new log file.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Reads the current value of `key` through the writer's own reader.
+    ///
+    /// Must be called with the writer lock held so the value can't change before
+    /// a read-modify-write completes.
+    fn current_value(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        match self.live_entry(key) {
+            Some(cmd_pos) => match self.reader.read_command(cmd_pos)? {
+                Command::Set { value, .. } => Ok(Some(value)),
+                _ => Err(KvsError::UnexpectedCommandType),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn versions(&self, key: &[u8]) -> Result<Vec<String>> {
+        self.live_entry(key)
+            .into_iter()
+            .chain(self.history.get(key))
+            .map(|cmd_pos| match self.reader.read_command(cmd_pos)? {
+                Command::Set { value, .. } => into_string(value),
+                _ => Err(KvsError::UnexpectedCommandType),
+            })
+            .collect()
+    }
+
+    /// The index entry of `key`, unless it doesn't exist or has expired.
+    fn live_entry(&self, key: &[u8]) -> Option<CommandPosition> {
+        self.index
+            .get(key)
+            .map(|entry| *entry.value())
+            .filter(|cmd_pos| !cmd_pos.is_expired())
+    }
+
+    fn set_expiring(&mut self, key: Bytes, value: Bytes, expires_at: Option<u64>) -> Result<()> {
+        self.write(Command::set_expiring(key, value, expires_at))
+    }
+
+    /// Rewrites an existing key with a new expiration deadline.
+    ///
+    /// Returns whether anything changed: the key must exist, and when removing the
+    /// deadline it must have one.
+    fn set_deadline(&mut self, key: Bytes, expires_at: Option<u64>) -> Result<bool> {
+        let cmd_pos = match self.live_entry(&key) {
+            Some(cmd_pos) if expires_at.is_some() || cmd_pos.expires_at.is_some() => cmd_pos,
+            _ => return Ok(false),
+        };
+        // only the deadline changes, so the value keeps its modification time
+        let (value, modified_at) = match self.reader.read_command(cmd_pos)? {
+            Command::Set {
+                value, modified_at, ..
+            } => (value, modified_at),
+            _ => return Err(KvsError::UnexpectedCommandType),
+        };
+        self.write(Command::set_modified(key, value, expires_at, modified_at))?;
+        Ok(true)
+    }
+
+    fn append(&mut self, key: Bytes, suffix: Bytes) -> Result<u64> {
+        // like Redis, appending keeps the expiration of the key
+        let expires_at = self.live_entry(&key).and_then(|cmd_pos| cmd_pos.expires_at);
+        let mut value = self
+            .current_value(&key)?
+            .map(|value| value.to_vec())
+            .unwrap_or_default();
+        value.extend_from_slice(&suffix);
+        let length = value.len() as u64;
+        self.set_expiring(key, value.into(), expires_at)?;
+        Ok(length)
+    }
+
+    fn getdel(&mut self, key: Bytes) -> Result<Option<Bytes>> {
+        let old_value = self.current_value(&key)?;
+        if old_value.is_some() {
+            self.remove(key)?;
+        }
+        Ok(old_value)
+    }
+
+    fn getset(&mut self, key: Bytes, value: Bytes) -> Result<Option<Bytes>> {
+        let old_value = self.current_value(&key)?;
+        self.set(key, value)?;
+        Ok(old_value)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: Bytes,
+        expected: Option<Bytes>,
+        value: Bytes,
+    ) -> Result<bool> {
+        if self.current_value(&key)? != expected {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Compacts the keys of this shard by copying their live entries into a new log
+    /// file, and starts a new active log file.
+    ///
+    /// Log files are only deleted once every shard compacted after they were
+    /// written.
     ///
     /// # Errors
     ///
     /// Returns an error if there is an issue with creating new log files,
     /// copying entries during compaction, or removing stale log files.
     pub fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file
-        let compaction_generation_number = self.current_generation_number + 1;
-        self.current_generation_number += 2;
+        // two new generations, the first one is for the compaction file
+        let compaction_generation_number = self.generations.allocate(2);
+        self.current_generation_number = compaction_generation_number + 1;
         self.writer = new_log_file(&self.path, self.current_generation_number)?;
 
         let mut compaction_writer = new_log_file(&self.path, compaction_generation_number)?;
 
         let mut new_position = 0; //position in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
+        let reader = &self.reader;
+        let mut copy = |cmd_pos: CommandPosition| -> Result<CommandPosition> {
+            let len = reader.read_and(cmd_pos, |mut entry_reader| {
                 Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
             })?;
-            self.index.insert(
-                entry.key().clone(),
-                (
-                    compaction_generation_number,
-                    new_position..new_position + len,
-                )
-                    .into(),
-            );
+            let range = new_position..new_position + len;
             new_position += len;
+            Ok(CommandPosition {
+                expires_at: cmd_pos.expires_at,
+                ..(compaction_generation_number, range).into()
+            })
+        };
+
+        // older versions are copied ahead of the current one so they replay in order
+        let mut versions = self.history.take(|key| self.owns(key));
+        for entry in self.index.iter() {
+            // the other shards compact their own keys
+            if !self.owns(entry.key()) {
+                continue;
+            }
+            // expired keys are dropped instead of copied
+            if entry.value().is_expired() {
+                entry.remove();
+                continue;
+            }
+            if let Some(list) = versions.get_mut(entry.key()) {
+                for cmd_pos in list.iter_mut().rev() {
+                    *cmd_pos = copy(*cmd_pos)?;
+                }
+            }
+            self.index
+                .insert(entry.key().clone(), copy(*entry.value())?);
         }
+        // keys that only have older versions left were removed after them
+        let mut removed = Vec::new();
+        for (key, list) in versions.iter_mut() {
+            if self.index.contains_key(key) {
+                continue;
+            }
+            for cmd_pos in list.iter_mut().rev() {
+                *cmd_pos = copy(*cmd_pos)?;
+            }
+            removed.push(key.clone());
+        }
+        for key in removed {
+            serde_json::to_writer(&mut compaction_writer, &Command::remove(key))?;
+        }
+        self.history.restore(versions);
         compaction_writer.flush()?;
 
-        self.reader
-            .safe_point
-            .store(compaction_generation_number, Ordering::SeqCst);
-        self.reader.close_stale_handlers();
+        self.compacted(compaction_generation_number);
+        self.remove_stale_files()?;
+        self.uncompacted = 0;
+
+        Ok(())
+    }
 
-        // remove stale log files
+    /// Deletes the log files no shard needs anymore, the ones before the safe point.
+    fn remove_stale_files(&self) -> Result<()> {
         // Note that actually these files are not deleted immediately because `KvStoreReader`s
         // still keep open file handles. When `KvStoreReader` is used next time, it will clear
         // its stale file handles. On Unix, the files will be deleted after all the handles
         // are closed. On Windows, the deletions below will fail and stale files are expected
         // to be deleted in the next compaction.
 
+        let generation = self.reader.safe_point.load(Ordering::SeqCst);
+        // files still pinned by a snapshot are kept until a later compaction
+        let first_pinned = self
+            .pins
+            .lock()
+            .unwrap()
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(u64::MAX);
         let stale_generation_numbers = sorted_generation_number_list(&self.path)?
             .into_iter()
-            .filter(|&gen| gen < compaction_generation_number);
+            .filter(|&gen| gen < generation && gen < first_pinned);
         for stale_generation_number in stale_generation_numbers {
             let file_path = log_path(&self.path, stale_generation_number);
-            if let Err(err) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, err);
+            match fs::remove_file(&file_path) {
+                // another shard compacting meanwhile deleted it first
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => error!("{:?} cannot be deleted: {}", file_path, err),
+                Ok(()) => {}
             }
         }
-
-        self.uncompacted = 0;
-
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            let position = self.writer.position;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("Key not found");
-                self.uncompacted += old_cmd.value().length;
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                self.uncompacted += self.writer.position - position;
-            }
-
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
-            Ok(())
+    fn remove(&mut self, key: Bytes) -> Result<()> {
+        if self.live_entry(&key).is_some() {
+            self.write(Command::remove(key))
         } else {
             Err(KvsError::KeyNotFound)
         }
@@ -384,7 +1271,8 @@ impl KvStoreWriter {
 fn load(
     generation_num: u64,
     reader: &mut BufReaderWithPosition<File>,
-    index: &SkipMap<String, CommandPosition>,
+    index: &SkipMap<Bytes, CommandPosition>,
+    history: &History,
 ) -> Result<u64> {
     // Start reading from the beginning of the file
     let mut position = reader.seek(SeekFrom::Start(0))?;
@@ -392,27 +1280,116 @@ fn load(
     let mut uncompacted = 0;
     while let Some(cmd) = stream.next() {
         let new_position = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().length;
-                }
-                index.insert(key, (generation_num, position..new_position).into());
+        uncompacted += apply(index, history, generation_num, cmd?, position..new_position)?;
+        position = new_position;
+    }
+    Ok(uncompacted)
+}
+
+/// Apply a command stored at `range` of the given generation to the index,
+/// retiring the versions it replaces to `history`.
+///
+/// Returns how many bytes can be saved after a compaction.
+fn apply(
+    index: &SkipMap<Bytes, CommandPosition>,
+    history: &History,
+    generation_num: u64,
+    cmd: Command,
+    range: Range<u64>,
+) -> Result<u64> {
+    let mut uncompacted = 0;
+    match cmd {
+        Command::Set {
+            key, expires_at, ..
+        } => {
+            if let Some(old_cmd) = index.get(&key) {
+                uncompacted += history.retire(&key, *old_cmd.value());
+            }
+            index.insert(
+                key,
+                CommandPosition {
+                    expires_at,
+                    ..(generation_num, range).into()
+                },
+            );
+        }
+        Command::Remove { key } => {
+            if let Some(old_cmd) = index.remove(&key) {
+                uncompacted += history.retire(&key, *old_cmd.value());
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().length;
+            // the "remove" command itself can be deleted in the next compaction
+            // so we add its length to `uncompacted`
+            uncompacted += range.end - range.start;
+        }
+        Command::Batch(cmds) => {
+            let ranges = batch_positions(range.start, &cmds)?;
+            // the framing around the batched commands is never indexed
+            uncompacted +=
+                range.end - range.start - ranges.iter().map(|r| r.end - r.start).sum::<u64>();
+            for (cmd, range) in cmds.into_iter().zip(ranges) {
+                if let Command::Batch(_) | Command::Clear = cmd {
+                    return Err(KvsError::UnexpectedCommandType);
                 }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_position - position;
+                uncompacted += apply(index, history, generation_num, cmd, range)?;
             }
         }
-        position = new_position;
+        Command::Clear => {
+            // the cleared commands live in older generations, which are deleted with the clear
+            index.clear();
+            history.clear();
+            uncompacted += range.end - range.start;
+        }
     }
     Ok(uncompacted)
 }
 
+/// Collect the changes a command makes, given the index from before it's applied.
+fn key_events(cmd: &Command, index: &SkipMap<Bytes, CommandPosition>, events: &mut Vec<KeyEvent>) {
+    match cmd {
+        Command::Set { key, value, .. } => events.push(KeyEvent::Set {
+            key: String::from_utf8_lossy(key).into_owned(),
+            value: String::from_utf8_lossy(value).into_owned(),
+        }),
+        Command::Remove { key } => {
+            if index.contains_key(key) {
+                events.push(KeyEvent::Remove {
+                    key: String::from_utf8_lossy(key).into_owned(),
+                });
+            }
+        }
+        Command::Batch(cmds) => {
+            for cmd in cmds {
+                key_events(cmd, index, events);
+            }
+        }
+        Command::Clear => {
+            for entry in index.iter() {
+                events.push(KeyEvent::Remove {
+                    key: String::from_utf8_lossy(entry.key()).into_owned(),
+                });
+            }
+        }
+    }
+}
+
+/// Returns the byte ranges of the commands inside a `Command::Batch` record written at `position`.
+///
+/// serde_json serializes every command of a batch exactly as it would serialize it on its
+/// own, so the index can point straight into the batch and neither readers nor compaction
+/// need to know batches exist.
+fn batch_positions(position: u64, cmds: &[Command]) -> Result<Vec<Range<u64>>> {
+    // `{"Batch":[` precedes the first command and `]}` follows the last one
+    let mut start = position + serde_json::to_vec(&Command::Batch(Vec::new()))?.len() as u64 - 2;
+    let mut ranges = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        let end = start + serde_json::to_vec(cmd)?.len() as u64;
+        ranges.push(start..end);
+        // skip the separating comma
+        start = end + 1;
+    }
+    Ok(ranges)
+}
+
 struct BufReaderWithPosition<T: Read + Seek> {
     reader: BufReader<T>,
     position: u64,
@@ -476,6 +1453,15 @@ struct CommandPosition {
     generation_num: u64,
     position: u64,
     length: u64,
+    // milliseconds since the Unix epoch after which the key no longer exists
+    expires_at: Option<u64>,
+}
+
+impl CommandPosition {
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now_millis())
+    }
 }
 
 impl From<(u64, Range<u64>)> for CommandPosition {
@@ -484,24 +1470,165 @@ impl From<(u64, Range<u64>)> for CommandPosition {
             generation_num,
             position: range.start,
             length: range.end - range.start,
+            expires_at: None,
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        #[serde(with = "text_or_bytes")]
+        key: Bytes,
+        #[serde(with = "text_or_bytes")]
+        value: Bytes,
+        // CRC-32 of the key and value, missing in logs written by older versions
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        crc: Option<u32>,
+        // milliseconds since the Unix epoch after which the key no longer exists
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        // milliseconds since the Unix epoch when the value was written, missing in logs
+        // written by older versions
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified_at: Option<u64>,
+    },
+    Remove {
+        #[serde(with = "text_or_bytes")]
+        key: Bytes,
+    },
+    Batch(Vec<Command>),
+    Clear,
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(key: Bytes, value: Bytes) -> Command {
+        Command::set_expiring(key, value, None)
+    }
+
+    fn set_expiring(key: Bytes, value: Bytes, expires_at: Option<u64>) -> Command {
+        Command::set_modified(key, value, expires_at, Some(now_millis()))
     }
 
-    fn remove(key: String) -> Command {
+    fn set_modified(
+        key: Bytes,
+        value: Bytes,
+        expires_at: Option<u64>,
+        modified_at: Option<u64>,
+    ) -> Command {
+        let crc = Some(checksum(&key, &value));
+        Command::Set {
+            key,
+            value,
+            crc,
+            expires_at,
+            modified_at,
+        }
+    }
+
+    fn remove(key: Bytes) -> Command {
         Command::Remove { key }
     }
+
+    /// The key a `Set` or `Remove` writes.
+    fn key(&self) -> Option<&Bytes> {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key } => Some(key),
+            Command::Batch(_) | Command::Clear => None,
+        }
+    }
+
+    /// Fail with `ValueTooLarge` if the value of any `Set` is longer than `max` bytes.
+    fn check_value_size(&self, max: usize) -> Result<()> {
+        match self {
+            Command::Set { value, .. } if value.len() > max => Err(KvsError::ValueTooLarge {
+                size: value.len(),
+                max,
+            }),
+            Command::Batch(cmds) => cmds.iter().try_for_each(|cmd| cmd.check_value_size(max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the key and value of every `Set` match their recorded checksum.
+    fn checksum_matches(&self) -> bool {
+        match self {
+            Command::Set {
+                key,
+                value,
+                crc: Some(crc),
+                ..
+            } => checksum(key, value) == *crc,
+            Command::Batch(cmds) => cmds.iter().all(Command::checksum_matches),
+            _ => true,
+        }
+    }
+}
+
+fn checksum(key: &[u8], value: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    // the key length keeps ("ab", "c") and ("a", "bc") apart
+    hasher.update(&(key.len() as u64).to_le_bytes());
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize()
+}
+
+/// Serializes keys and values as JSON strings when they are valid UTF-8 and as
+/// arrays of bytes otherwise, so logs of text-only stores keep their original format.
+mod text_or_bytes {
+    use std::fmt;
+
+    use bytes::Bytes;
+    use serde::{
+        de::{self, SeqAccess, Visitor},
+        Deserializer, Serializer,
+    };
+
+    pub fn serialize<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => serializer.serialize_bytes(bytes),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Bytes;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a string or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Bytes, E> {
+            Ok(Bytes::copy_from_slice(v.as_bytes()))
+        }
+
+        fn visit_string<E: de::Error>(self, v: String) -> Result<Bytes, E> {
+            Ok(Bytes::from(v))
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+            Ok(Bytes::copy_from_slice(v))
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+            Ok(Bytes::from(v))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element::<u8>()? {
+                bytes.push(byte);
+            }
+            Ok(Bytes::from(bytes))
+        }
+    }
 }
 
 /// Returns sorted generation numbers in the given directory.
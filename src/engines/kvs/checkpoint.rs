@@ -0,0 +1,79 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tracing::debug;
+
+use super::{
+    log_path, shards::LockedShards, snapshot::GenerationPin, sorted_generation_number_list,
+};
+use crate::{KvsError, Result};
+
+/// The log files making up a consistent state of the store, kept alive while they're copied.
+pub(super) struct Checkpoint {
+    path: Arc<PathBuf>,
+    pin: GenerationPin,
+    // the active log file of every shard, with the bytes written to it when the
+    // checkpoint was taken
+    active: Vec<(u64, u64)>,
+    // the files created afterwards are from this generation on
+    next_generation: u64,
+}
+
+impl Checkpoint {
+    /// Copies the log files into `dest`, which must not contain a store yet.
+    ///
+    /// Sealed log files are never modified again, so they're hard linked where the
+    /// file system allows it. The active ones are still being appended to, so only the
+    /// part written before the checkpoint was taken is copied.
+    pub(super) fn write_to(self, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)?;
+        if !sorted_generation_number_list(dest)?.is_empty() {
+            return Err(KvsError::StringError(format!(
+                "{:?} already contains a store",
+                dest
+            )));
+        }
+
+        // older files hold nothing the index points to
+        let generations = sorted_generation_number_list(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen >= self.pin.generation && gen < self.next_generation)
+            .filter(|&gen| self.active.iter().all(|&(active, _)| active != gen));
+        for generation in generations {
+            let (src, dst) = (log_path(&self.path, generation), log_path(dest, generation));
+            if let Err(err) = fs::hard_link(&src, &dst) {
+                debug!("Cannot link {:?}, copying instead: {}", src, err);
+                fs::copy(&src, &dst)?;
+            }
+        }
+
+        for &(generation, length) in &self.active {
+            let mut active = File::open(log_path(&self.path, generation))?.take(length);
+            let mut copy = File::create(log_path(dest, generation))?;
+            io::copy(&mut active, &mut copy)?;
+            copy.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl LockedShards<'_> {
+    /// Flushes the active log files and pins the files a checkpoint needs.
+    ///
+    /// The shards are locked so the recorded lengths end on a record boundary.
+    pub(super) fn checkpoint(&mut self) -> Result<Checkpoint> {
+        let active = self.active_files()?;
+        let writer = self.any();
+        Ok(Checkpoint {
+            path: Arc::clone(&writer.path),
+            pin: writer.pin(),
+            active,
+            next_generation: writer.generations.next(),
+        })
+    }
+}
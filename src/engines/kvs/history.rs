@@ -0,0 +1,77 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Mutex,
+};
+
+use bytes::Bytes;
+
+use super::CommandPosition;
+
+/// Positions of the older versions of each key, kept when `KvStoreOptions::versions`
+/// asks for more than the latest one.
+pub(super) struct History {
+    // older versions kept per key, besides the current one
+    limit: usize,
+    // newest first
+    versions: Mutex<BTreeMap<Bytes, VecDeque<CommandPosition>>>,
+}
+
+impl History {
+    /// Keeps the latest `versions` versions of each key, counting the current one.
+    pub(super) fn new(versions: usize) -> Self {
+        History {
+            limit: versions.saturating_sub(1),
+            versions: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records the version of `key` that a write replaced or removed.
+    ///
+    /// Returns how many bytes are no longer needed by any version.
+    pub(super) fn retire(&self, key: &Bytes, old: CommandPosition) -> u64 {
+        if self.limit == 0 {
+            return old.length;
+        }
+        let mut versions = self.versions.lock().unwrap();
+        let list = versions.entry(key.clone()).or_default();
+        list.push_front(old);
+        let mut freed = 0;
+        while list.len() > self.limit {
+            freed += list.pop_back().map_or(0, |old| old.length);
+        }
+        freed
+    }
+
+    /// The older versions of `key`, newest first.
+    pub(super) fn get(&self, key: &[u8]) -> Vec<CommandPosition> {
+        self.versions
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|list| list.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub(super) fn clear(&self) {
+        self.versions.lock().unwrap().clear();
+    }
+
+    /// Takes the recorded versions of the keys `pred` returns true for out, e.g. to
+    /// move them during compaction.
+    pub(super) fn take<F>(&self, mut pred: F) -> BTreeMap<Bytes, VecDeque<CommandPosition>>
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        let mut versions = self.versions.lock().unwrap();
+        let (taken, kept) = std::mem::take(&mut *versions)
+            .into_iter()
+            .partition(|(key, _)| pred(&key[..]));
+        *versions = kept;
+        taken
+    }
+
+    /// Puts back versions taken out by `take`.
+    pub(super) fn restore(&self, versions: BTreeMap<Bytes, VecDeque<CommandPosition>>) {
+        self.versions.lock().unwrap().extend(versions);
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use bytes::Bytes;
+use tracing::error;
+
+use super::{new_log_file, Command, KvStoreWriter, COMPACTION_THRESHOLD};
+use crate::{KvsError, Result};
+
+/// The writers of a `KvStore`, each owning the keys that hash to it.
+///
+/// A write of a single key only locks the shard of the key, so writes of keys in
+/// different shards proceed in parallel. Every shard appends to its own active log
+/// file and compacts its own keys. Writes of several keys lock every shard and are
+/// written as a single record.
+pub(super) struct Shards {
+    writers: Vec<Mutex<KvStoreWriter>>,
+    generations: Arc<Generations>,
+    // a catch-up of the lagging shards is queued or running
+    catching_up: AtomicBool,
+}
+
+impl Shards {
+    pub(super) fn new(writers: Vec<KvStoreWriter>, generations: Arc<Generations>) -> Self {
+        Shards {
+            writers: writers.into_iter().map(Mutex::new).collect(),
+            generations,
+            catching_up: AtomicBool::new(false),
+        }
+    }
+
+    /// Locks the shard owning `key`.
+    pub(super) fn lock(&self, key: &[u8]) -> MutexGuard<'_, KvStoreWriter> {
+        self.writers[shard_of(key, self.writers.len())]
+            .lock()
+            .unwrap()
+    }
+
+    /// Whether a shard didn't compact for a while and no `catch_up` is queued yet.
+    /// If so, the caller must run `catch_up`.
+    pub(super) fn needs_catch_up(&self) -> bool {
+        (0..self.writers.len()).any(|shard| self.generations.is_lagging(shard))
+            && !self.catching_up.swap(true, Ordering::SeqCst)
+    }
+
+    /// Compacts the shards which didn't compact for a while, unless they're busy, so
+    /// a shard without writes doesn't keep the log files of the others from being
+    /// deleted.
+    pub(super) fn catch_up(&self) {
+        for (shard, writer) in self.writers.iter().enumerate() {
+            if !self.generations.is_lagging(shard) {
+                continue;
+            }
+            if let Ok(mut writer) = writer.try_lock() {
+                // checked again, it may have compacted before the lock was taken
+                if self.generations.is_lagging(shard) {
+                    if let Err(e) = writer.compact() {
+                        error!("Failed to compact writer shard {}: {}", shard, e);
+                    }
+                }
+            }
+        }
+        self.catching_up.store(false, Ordering::SeqCst);
+    }
+
+    /// Locks every shard, always in the same order so two callers can't deadlock.
+    pub(super) fn lock_all(&self) -> LockedShards<'_> {
+        LockedShards {
+            writers: self
+                .writers
+                .iter()
+                .map(|writer| writer.lock().unwrap())
+                .collect(),
+        }
+    }
+}
+
+/// Every shard of a `KvStore`, locked, so nothing else writes meanwhile.
+pub(super) struct LockedShards<'a> {
+    writers: Vec<MutexGuard<'a, KvStoreWriter>>,
+}
+
+impl LockedShards<'_> {
+    /// Any of the writers, for reads which need no writer in particular.
+    pub(super) fn any(&self) -> &KvStoreWriter {
+        &self.writers[0]
+    }
+
+    /// Writes a batch of `Set` and `Remove` commands as a single log record, so it
+    /// survives a crash entirely or not at all.
+    ///
+    /// A batch spanning shards goes to the shard with the newest active log file, so
+    /// it's replayed after everything the other shards wrote before it. The other
+    /// shards it touches then start new generations, so what they write next is
+    /// replayed after it. Their keys move into their own log files when they compact.
+    pub(super) fn write_batch(&mut self, cmds: Vec<Command>) -> Result<()> {
+        let shards = self.writers.len();
+        let mut touched: Vec<usize> = cmds
+            .iter()
+            .filter_map(|cmd| cmd.key())
+            .map(|key| shard_of(key, shards))
+            .collect();
+        touched.sort_unstable();
+        touched.dedup();
+        if let [shard] = touched[..] {
+            return self.writers[shard].write_batch(cmds);
+        }
+
+        let newest = (0..shards)
+            .max_by_key(|&shard| self.writers[shard].current_generation_number)
+            .unwrap_or(0);
+        self.writers[newest].write_batch(cmds)?;
+        for shard in touched {
+            if shard != newest {
+                self.writers[shard].roll()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the value of `from` to `to`, keeping its expiration.
+    pub(super) fn rename(&mut self, from: Bytes, to: Bytes) -> Result<()> {
+        let cmd_pos = self.any().live_entry(&from).ok_or(KvsError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        let value = self
+            .any()
+            .current_value(&from)?
+            .ok_or(KvsError::UnexpectedCommandType)?;
+        self.write_batch(vec![
+            Command::set_expiring(to, value, cmd_pos.expires_at),
+            Command::remove(from),
+        ])
+    }
+
+    /// Copies the value of `from` to `to`, keeping its expiration.
+    ///
+    /// Returns false if `from` doesn't exist, or `to` does and `overwrite` is false.
+    pub(super) fn copy(&mut self, from: Bytes, to: Bytes, overwrite: bool) -> Result<bool> {
+        let cmd_pos = match self.any().live_entry(&from) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(false),
+        };
+        if !overwrite && self.any().live_entry(&to).is_some() {
+            return Ok(false);
+        }
+        let value = self
+            .any()
+            .current_value(&from)?
+            .ok_or(KvsError::UnexpectedCommandType)?;
+        let shard = shard_of(&to, self.writers.len());
+        self.writers[shard].set_expiring(to, value, cmd_pos.expires_at)?;
+        Ok(true)
+    }
+
+    /// Empties the store by writing a `Clear` record at the start of a new
+    /// generation of the first shard.
+    ///
+    /// The other shards start new generations after it, so the record is replayed
+    /// after everything it cleared, and every older log file is deleted.
+    pub(super) fn clear(&mut self) -> Result<()> {
+        let generation = self.writers[0].roll()?;
+        for writer in &mut self.writers[1..] {
+            writer.roll()?;
+        }
+        for writer in &mut self.writers {
+            writer.uncompacted = 0;
+        }
+        self.writers[0].write(Command::Clear)?;
+
+        for writer in &self.writers {
+            writer.compacted(generation);
+        }
+        self.writers[0].remove_stale_files()
+    }
+
+    /// Compacts every shard.
+    pub(super) fn compact(&mut self) -> Result<()> {
+        for writer in &mut self.writers {
+            writer.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes and syncs the active log file of every shard.
+    pub(super) fn sync(&mut self) -> Result<()> {
+        for writer in &mut self.writers {
+            writer.sync()?;
+        }
+        Ok(())
+    }
+
+    /// The bytes a compaction of every shard would reclaim.
+    pub(super) fn uncompacted(&self) -> u64 {
+        self.writers.iter().map(|writer| writer.uncompacted).sum()
+    }
+
+    /// The generation of the newest active log file.
+    pub(super) fn generation(&self) -> u64 {
+        self.writers
+            .iter()
+            .map(|writer| writer.current_generation_number)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The active log file and the bytes written to it, of every shard.
+    pub(super) fn active_files(&mut self) -> Result<Vec<(u64, u64)>> {
+        self.writers
+            .iter_mut()
+            .map(|writer| -> Result<(u64, u64)> {
+                writer.writer.flush()?;
+                Ok((writer.current_generation_number, writer.writer.position))
+            })
+            .collect()
+    }
+
+    /// The number of shards.
+    pub(super) fn shards(&self) -> usize {
+        self.writers.len()
+    }
+}
+
+/// The generation numbers shared by the shards of a `KvStore`.
+pub(super) struct Generations {
+    // the next generation number to hand out
+    next: AtomicU64,
+    // the generation of the latest compaction of each shard
+    compacted: Vec<AtomicU64>,
+    // the compactions of any shard so far
+    compactions: AtomicU64,
+    // the value of `compactions` after the latest compaction of each shard
+    compacted_at: Vec<AtomicU64>,
+}
+
+impl Generations {
+    /// Generations for `shards` shards, handing out `first` first.
+    pub(super) fn new(first: u64, shards: usize) -> Self {
+        Generations {
+            next: AtomicU64::new(first),
+            compacted: (0..shards).map(|_| AtomicU64::new(0)).collect(),
+            compactions: AtomicU64::new(0),
+            compacted_at: (0..shards).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Hands out `count` consecutive generation numbers, returning the first.
+    pub(super) fn allocate(&self, count: u64) -> u64 {
+        self.next.fetch_add(count, Ordering::SeqCst)
+    }
+
+    /// The generation numbers handed out so far are below this one.
+    pub(super) fn next(&self) -> u64 {
+        self.next.load(Ordering::SeqCst)
+    }
+
+    /// Records that every key of `shard` lives in `generation` or later.
+    ///
+    /// Returns the generation before which no log file holds anything of any shard.
+    pub(super) fn compacted(&self, shard: usize, generation: u64) -> u64 {
+        self.compacted[shard].store(generation, Ordering::SeqCst);
+        let compactions = self.compactions.fetch_add(1, Ordering::SeqCst) + 1;
+        self.compacted_at[shard].store(compactions, Ordering::SeqCst);
+        self.compacted
+            .iter()
+            .map(|compacted| compacted.load(Ordering::SeqCst))
+            .min()
+            .unwrap_or(generation)
+    }
+
+    /// Whether the other shards compacted twice as often as there are shards since
+    /// `shard` last did.
+    fn is_lagging(&self, shard: usize) -> bool {
+        // it may have compacted since `compactions` was read
+        let behind = self
+            .compactions
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.compacted_at[shard].load(Ordering::SeqCst));
+        behind >= 2 * self.compacted.len() as u64
+    }
+}
+
+impl KvStoreWriter {
+    /// Starts a new active log file, returning its generation.
+    fn roll(&mut self) -> Result<u64> {
+        self.current_generation_number = self.generations.allocate(1);
+        self.writer = new_log_file(&self.path, self.current_generation_number)?;
+        Ok(self.current_generation_number)
+    }
+
+    /// Records that every key of this shard lives in `generation` or later, and
+    /// lets the readers close the files no shard needs anymore.
+    pub(super) fn compacted(&self, generation: u64) {
+        let safe_point = self.generations.compacted(self.shard, generation);
+        self.reader
+            .safe_point
+            .fetch_max(safe_point, Ordering::SeqCst);
+        self.reader.close_stale_handlers();
+    }
+
+    /// Whether `key` belongs to this shard.
+    pub(super) fn owns(&self, key: &[u8]) -> bool {
+        shard_of(key, self.generations.compacted.len()) == self.shard
+    }
+
+    /// Whether enough bytes became reclaimable for this shard to compact.
+    pub(super) fn needs_compaction(&self) -> bool {
+        // the threshold is shared, so the total across shards stays the same
+        self.uncompacted > COMPACTION_THRESHOLD / self.generations.compacted.len() as u64
+    }
+}
+
+/// The shard owning `key` among `shards`.
+fn shard_of(key: &[u8], shards: usize) -> usize {
+    if shards == 1 {
+        return 0;
+    }
+    crc32fast::hash(key) as usize % shards
+}
@@ -0,0 +1,148 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{atomic::AtomicU64, atomic::Ordering, Arc, Mutex},
+};
+
+use bytes::Bytes;
+
+use super::{Command, CommandPosition, KvStoreReader, KvStoreWriter};
+use crate::{engines::into_string, thread_pool::ThreadPool, KvsError, Result};
+
+/// A read-only, point-in-time view of a `KvStore`, created by `KvStore::snapshot`.
+///
+/// Useful for backups and long-running reads that must not observe concurrent
+/// writes. Cloning a snapshot is cheap and all clones share the same view.
+#[derive(Clone)]
+pub struct Snapshot<P: ThreadPool> {
+    view: Arc<SnapshotView>,
+    thread_pool: P,
+}
+
+impl<P: ThreadPool> Snapshot<P> {
+    pub(super) fn new(view: SnapshotView, thread_pool: P) -> Self {
+        Snapshot {
+            view: Arc::new(view),
+            thread_pool,
+        }
+    }
+
+    /// Gets the value a key had when the snapshot was taken.
+    pub async fn get(self, key: String) -> Result<Option<String>> {
+        let view = self.view.clone();
+        self.thread_pool
+            .spawn_with_result(move || {
+                view.get(key.as_bytes())
+                    .and_then(|value| value.map(into_string).transpose())
+            })
+            .await?
+    }
+
+    /// Returns all pairs whose key starts with `prefix`, in ascending key order,
+    /// as they were when the snapshot was taken.
+    pub async fn scan(self, prefix: String) -> Result<Vec<(String, String)>> {
+        let view = self.view.clone();
+        self.thread_pool
+            .spawn_with_result(move || {
+                view.scan(prefix.as_bytes()).and_then(|pairs| {
+                    pairs
+                        .into_iter()
+                        .map(|(key, value)| Ok((into_string(key)?, into_string(value)?)))
+                        .collect()
+                })
+            })
+            .await?
+    }
+}
+
+/// A frozen copy of the index together with a reader for the files it points to.
+pub(super) struct SnapshotView {
+    index: BTreeMap<Bytes, CommandPosition>,
+    reader: Mutex<KvStoreReader>,
+    _pin: GenerationPin,
+}
+
+impl SnapshotView {
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        // keys expire on time even inside a snapshot
+        match self.index.get(key).filter(|cmd_pos| !cmd_pos.is_expired()) {
+            Some(cmd_pos) => self.read_value(*cmd_pos).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<(Bytes, Bytes)>> {
+        self.index
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired())
+            .map(|(key, cmd_pos)| Ok((key.clone(), self.read_value(*cmd_pos)?)))
+            .collect()
+    }
+
+    fn read_value(&self, cmd_pos: CommandPosition) -> Result<Bytes> {
+        match self.reader.lock().unwrap().read_command(cmd_pos)? {
+            Command::Set { value, .. } => Ok(value),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+}
+
+/// Keeps log files from `generation` onwards from being deleted by compaction.
+pub(super) struct GenerationPin {
+    pub(super) generation: u64,
+    pins: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+impl Drop for GenerationPin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        if let Some(count) = pins.get_mut(&self.generation) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.generation);
+            }
+        }
+    }
+}
+
+impl KvStoreWriter {
+    /// Keeps every log file the index may point to from being deleted until the pin is dropped.
+    pub(super) fn pin(&self) -> GenerationPin {
+        // everything the index points to lives in the latest compaction generation or later
+        let generation = self.reader.safe_point.load(Ordering::SeqCst);
+        *self.pins.lock().unwrap().entry(generation).or_insert(0) += 1;
+        GenerationPin {
+            generation,
+            pins: Arc::clone(&self.pins),
+        }
+    }
+
+    /// Takes a consistent view of the index and pins the log files it points to.
+    ///
+    /// Must be called with every writer shard locked so no write lands halfway through
+    /// copying the index.
+    pub(super) fn snapshot(&self) -> SnapshotView {
+        let pin = self.pin();
+
+        let index = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+
+        // the snapshot reader never closes its handles: it must keep reading pinned files
+        let reader = KvStoreReader {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(BTreeMap::new()),
+        };
+
+        SnapshotView {
+            index,
+            reader: Mutex::new(reader),
+            _pin: pin,
+        }
+    }
+}
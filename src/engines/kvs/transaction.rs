@@ -0,0 +1,124 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use super::{snapshot::SnapshotView, Command, KvStore};
+use crate::{engines::into_string, thread_pool::ThreadPool, KvsError, Result};
+
+/// A multi-key transaction over a `KvStore`, created by `KvStore::transaction`.
+///
+/// Reads see a consistent snapshot of the store taken when the transaction began.
+/// Writes are buffered in memory and applied by `commit` as one atomic batch.
+/// Dropping the transaction without committing discards the buffered writes.
+///
+/// Commit fails with `KvsError::TransactionConflict` if any key the transaction
+/// read or wrote was changed by someone else after the snapshot was taken.
+pub struct Transaction<P: ThreadPool> {
+    store: KvStore<P>,
+    snapshot: Arc<SnapshotView>,
+    // values observed by `get`, checked again on commit
+    reads: BTreeMap<String, Option<String>>,
+    // `None` marks a removed key
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl<P: ThreadPool> Transaction<P> {
+    pub(super) fn new(store: KvStore<P>, snapshot: SnapshotView) -> Self {
+        Transaction {
+            store,
+            snapshot: Arc::new(snapshot),
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of a key as seen by this transaction.
+    ///
+    /// Buffered writes of the transaction itself are visible; writes committed by
+    /// others after the transaction began are not.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+
+        let snapshot = Arc::clone(&self.snapshot);
+        let snapshot_key = key.clone();
+        let value = self
+            .store
+            .thread_pool
+            .spawn_with_result(move || {
+                snapshot
+                    .get(snapshot_key.as_bytes())
+                    .and_then(|value| value.map(into_string).transpose())
+            })
+            .await??;
+
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Buffers setting the value of a key until the transaction commits.
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Buffers removing a key until the transaction commits.
+    ///
+    /// Removing a key that doesn't exist at commit time is not an error.
+    pub fn remove(&mut self, key: String) {
+        self.writes.insert(key, None);
+    }
+
+    /// Atomically applies the buffered writes.
+    ///
+    /// With several `KvStoreOptions::writer_shards`, other clients never see part of
+    /// the writes, but a crash may keep the writes to some shards only.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::TransactionConflict` if a key read or written by the transaction
+    /// was modified since the transaction began, or an error if writing the batch fails.
+    pub async fn commit(self) -> Result<()> {
+        let Transaction {
+            store,
+            snapshot,
+            reads,
+            writes,
+        } = self;
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let writers = store.writers.clone();
+        store
+            .thread_pool
+            .spawn_with_result(move || {
+                let mut writers = writers.lock_all();
+                for (key, value) in &reads {
+                    if writers.any().current_value(key.as_bytes())?.as_deref()
+                        != value.as_deref().map(str::as_bytes)
+                    {
+                        return Err(KvsError::TransactionConflict);
+                    }
+                }
+                for key in writes.keys().filter(|key| !reads.contains_key(*key)) {
+                    if writers.any().current_value(key.as_bytes())?
+                        != snapshot.get(key.as_bytes())?
+                    {
+                        return Err(KvsError::TransactionConflict);
+                    }
+                }
+
+                let cmds = writes
+                    .into_iter()
+                    .map(|(key, value)| match value {
+                        Some(value) => Command::set(key.into(), value.into()),
+                        None => Command::remove(key.into()),
+                    })
+                    .collect();
+                writers.write_batch(cmds)
+            })
+            .await?
+    }
+}
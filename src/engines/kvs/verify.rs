@@ -0,0 +1,152 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicU64, Arc},
+};
+
+use crossbeam_skiplist::SkipMap;
+use serde_json::Deserializer;
+
+use super::{
+    apply, history::History, log_path, sorted_generation_number_list, BufReaderWithPosition,
+    Command, KvStore, KvStoreReader,
+};
+use crate::{thread_pool::ThreadPool, KvsError, Result};
+
+/// The outcome of `KvStore::verify`.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Number of log records that were read.
+    pub records: u64,
+    /// Number of live keys.
+    pub keys: u64,
+    /// Records that failed verification, in log order.
+    pub corrupted: Vec<CorruptRecord>,
+}
+
+impl VerifyReport {
+    /// Whether no corruption was found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupted.is_empty()
+    }
+}
+
+/// A log record that failed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptRecord {
+    /// Generation number of the log file holding the record.
+    pub generation: u64,
+    /// Byte offset of the record in the log file.
+    pub position: u64,
+    /// What is wrong with the record.
+    pub reason: String,
+}
+
+impl<P: ThreadPool> KvStore<P> {
+    /// Checks every record of the store at the given path without modifying anything.
+    ///
+    /// Each record must deserialize and match its checksum, and every key of the
+    /// rebuilt index must point at a record setting that key. A record that fails to
+    /// deserialize makes the rest of its log file unreadable, so it is the last one
+    /// reported for that file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory or a log file cannot be read. Corrupted
+    /// records are not errors; they're listed in the report.
+    pub fn verify(path: impl Into<PathBuf>) -> Result<VerifyReport> {
+        let path = path.into();
+        let mut report = VerifyReport::default();
+        let index = SkipMap::new();
+        let history = History::new(1);
+
+        for generation in sorted_generation_number_list(&path)? {
+            let mut reader = BufReaderWithPosition::new(File::open(log_path(&path, generation))?)?;
+            let mut position = 0;
+            let mut stream = Deserializer::from_reader(&mut reader).into_iter::<Command>();
+            while let Some(cmd) = stream.next() {
+                let new_position = stream.byte_offset() as u64;
+                let mut corrupt = |reason: String| {
+                    report.corrupted.push(CorruptRecord {
+                        generation,
+                        position,
+                        reason,
+                    })
+                };
+                match cmd {
+                    Ok(cmd) if !cmd.checksum_matches() => corrupt("Checksum mismatch".to_owned()),
+                    Ok(cmd) => {
+                        if let Err(err) =
+                            apply(&index, &history, generation, cmd, position..new_position)
+                        {
+                            corrupt(err.to_string());
+                        }
+                    }
+                    Err(err) => {
+                        corrupt(err.to_string());
+                        break;
+                    }
+                }
+                report.records += 1;
+                position = new_position;
+            }
+        }
+
+        let reader = KvStoreReader {
+            path: Arc::new(path),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(BTreeMap::new()),
+        };
+        for entry in index.iter() {
+            let cmd_pos = *entry.value();
+            let reason = match reader.read_command(cmd_pos) {
+                Ok(Command::Set { key, .. }) if key == *entry.key() => continue,
+                Ok(_) => "Index entry does not point at its key".to_owned(),
+                Err(err) => err.to_string(),
+            };
+            report.corrupted.push(CorruptRecord {
+                generation: cmd_pos.generation_num,
+                position: cmd_pos.position,
+                reason,
+            });
+        }
+        report.keys = index.len() as u64;
+
+        Ok(report)
+    }
+
+    /// Verifies the store at `src`, typically a checkpoint, and copies it into `dest`,
+    /// which is created if needed and must not already contain a store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `src` fails verification, if `dest` already contains log
+    /// files, or if there's an issue copying the log files.
+    pub fn restore(src: impl Into<PathBuf>, dest: impl AsRef<Path>) -> Result<VerifyReport> {
+        let src = src.into();
+        let dest = dest.as_ref();
+        let report = Self::verify(&src)?;
+        if !report.is_ok() {
+            return Err(KvsError::StringError(format!(
+                "{:?} has {} corrupted records",
+                src,
+                report.corrupted.len()
+            )));
+        }
+
+        fs::create_dir_all(dest)?;
+        if !sorted_generation_number_list(dest)?.is_empty() {
+            return Err(KvsError::StringError(format!(
+                "{:?} already contains a store",
+                dest
+            )));
+        }
+        for generation in sorted_generation_number_list(&src)? {
+            fs::copy(log_path(&src, generation), log_path(dest, generation))?;
+        }
+
+        Ok(report)
+    }
+}
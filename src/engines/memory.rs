@@ -0,0 +1,332 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use crossbeam_skiplist::SkipMap;
+use futures::stream::BoxStream;
+use tokio::sync::broadcast;
+
+use super::{as_slice, deadline, into_string, now_millis, subscribe};
+use crate::{KeyEvent, KvsEngine, KvsError, Result, ScanOptions, ValueMeta};
+
+// events buffered per watcher before it starts skipping
+const EVENT_CAPACITY: usize = 1024;
+
+/// A `KvsEngine` keeping every pair in memory, without touching the disk.
+///
+/// Useful for tests, for measuring protocol overhead and as a pure cache.
+/// Everything is lost when the last clone is dropped.
+#[derive(Clone)]
+pub struct MemKvsEngine {
+    map: Arc<SkipMap<Bytes, MemValue>>,
+    // serializes writes so read-modify-write operations are atomic
+    write_lock: Arc<Mutex<()>>,
+    events: broadcast::Sender<KeyEvent>,
+}
+
+#[derive(Clone)]
+struct MemValue {
+    value: Bytes,
+    // milliseconds since the Unix epoch after which the key no longer exists
+    expires_at: Option<u64>,
+    // milliseconds since the Unix epoch when the value was written
+    modified_ms: u64,
+}
+
+impl MemValue {
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now_millis())
+    }
+}
+
+impl Default for MemKvsEngine {
+    fn default() -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        MemKvsEngine {
+            map: Arc::new(SkipMap::new()),
+            write_lock: Arc::new(Mutex::new(())),
+            events,
+        }
+    }
+}
+
+impl MemKvsEngine {
+    /// Creates an empty `MemKvsEngine`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value of `key`, unless it doesn't exist or has expired.
+    fn live(&self, key: &[u8]) -> Option<MemValue> {
+        self.map
+            .get(key)
+            .map(|entry| entry.value().clone())
+            .filter(|value| !value.is_expired())
+    }
+
+    fn insert(&self, key: Bytes, value: Bytes, expires_at: Option<u64>) {
+        if self.events.receiver_count() > 0 {
+            // only fails if every watcher went away in the meantime
+            let _ = self.events.send(KeyEvent::Set {
+                key: String::from_utf8_lossy(&key).into_owned(),
+                value: String::from_utf8_lossy(&value).into_owned(),
+            });
+        }
+        self.map.insert(
+            key,
+            MemValue {
+                value,
+                expires_at,
+                modified_ms: now_millis(),
+            },
+        );
+    }
+
+    /// Removes `key` and returns its value, unless it doesn't exist or has expired.
+    fn take(&self, key: &[u8]) -> Option<MemValue> {
+        let old = self
+            .map
+            .remove(key)
+            .map(|entry| entry.value().clone())
+            .filter(|value| !value.is_expired())?;
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(KeyEvent::Remove {
+                key: String::from_utf8_lossy(key).into_owned(),
+            });
+        }
+        Some(old)
+    }
+}
+
+#[async_trait]
+impl KvsEngine for MemKvsEngine {
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.insert(key, value, None);
+        Ok(())
+    }
+
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        Ok(self.live(&key).map(|value| value.value))
+    }
+
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.take(&key).map(|_| ()).ok_or(KvsError::KeyNotFound)
+    }
+
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        let _guard = self.write_lock.lock().unwrap();
+        let old = self.live(key.as_bytes());
+        let expires_at = old.as_ref().and_then(|old| old.expires_at);
+        let mut value = old.map(|old| old.value.to_vec()).unwrap_or_default();
+        value.extend_from_slice(suffix.as_bytes());
+        let length = value.len() as u64;
+        self.insert(key.into(), value.into(), expires_at);
+        Ok(length)
+    }
+
+    async fn getdel(self, key: String) -> Result<Option<String>> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.take(key.as_bytes())
+            .map(|old| into_string(old.value))
+            .transpose()
+    }
+
+    async fn getset(self, key: String, value: String) -> Result<Option<String>> {
+        let _guard = self.write_lock.lock().unwrap();
+        let old = self.live(key.as_bytes());
+        self.insert(key.into(), value.into(), None);
+        old.map(|old| into_string(old.value)).transpose()
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        let _guard = self.write_lock.lock().unwrap();
+        let current = self.live(key.as_bytes()).map(|entry| entry.value);
+        if current.as_deref() != expected.as_ref().map(String::as_bytes) {
+            return Ok(false);
+        }
+        self.insert(key.into(), value.into(), None);
+        Ok(true)
+    }
+
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let old = self.live(from.as_bytes()).ok_or(KvsError::KeyNotFound)?;
+        if from != to {
+            self.take(from.as_bytes());
+            self.insert(to.into(), old.value, old.expires_at);
+        }
+        Ok(())
+    }
+
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        let _guard = self.write_lock.lock().unwrap();
+        let old = match self.live(from.as_bytes()) {
+            Some(old) => old,
+            None => return Ok(false),
+        };
+        if !overwrite && self.live(to.as_bytes()).is_some() {
+            return Ok(false);
+        }
+        self.insert(to.into(), old.value, old.expires_at);
+        Ok(true)
+    }
+
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        let (lower, upper) = options.bounds();
+        let range = self
+            .map
+            .range::<[u8], _>((as_slice(&lower), as_slice(&upper)));
+        let entries: Box<dyn Iterator<Item = _>> = if options.reverse {
+            Box::new(range.rev())
+        } else {
+            Box::new(range)
+        };
+        entries
+            .filter(|entry| !entry.value().is_expired())
+            .take(options.limit.unwrap_or(usize::MAX))
+            .map(|entry| {
+                Ok((
+                    into_string(entry.key().clone())?,
+                    into_string(entry.value().value.clone())?,
+                ))
+            })
+            .collect()
+    }
+
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.insert(key.into(), value.into(), Some(deadline(ttl)));
+        Ok(())
+    }
+
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        let now = now_millis();
+        Ok(self
+            .live(key.as_bytes())
+            .and_then(|value| value.expires_at)
+            .filter(|&expires_at| expires_at > now)
+            .map(|expires_at| Duration::from_millis(expires_at - now)))
+    }
+
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        let _guard = self.write_lock.lock().unwrap();
+        match self.live(key.as_bytes()) {
+            Some(old) => {
+                self.map.insert(
+                    key.into(),
+                    MemValue {
+                        expires_at: Some(deadline(ttl)),
+                        ..old
+                    },
+                );
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn persist(self, key: String) -> Result<bool> {
+        let _guard = self.write_lock.lock().unwrap();
+        match self.live(key.as_bytes()) {
+            Some(old) if old.expires_at.is_some() => {
+                self.map.insert(
+                    key.into(),
+                    MemValue {
+                        expires_at: None,
+                        ..old
+                    },
+                );
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn meta(self, key: String) -> Result<Option<ValueMeta>> {
+        let now = now_millis();
+        Ok(self.live(key.as_bytes()).map(|value| ValueMeta {
+            len: value.value.len() as u64,
+            modified_ms: Some(value.modified_ms),
+            ttl_ms: value
+                .expires_at
+                .map(|expires_at| expires_at.saturating_sub(now)),
+        }))
+    }
+
+    async fn clear(self) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        for entry in self.map.iter() {
+            self.take(entry.key());
+        }
+        Ok(())
+    }
+
+    /// Applied under the write lock, so no other write interleaves with the batch.
+    async fn write_batch(self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        for (key, value) in writes {
+            match value {
+                Some(value) => self.insert(key.into(), value.into(), None),
+                None => {
+                    self.take(key.as_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Nothing to flush: the engine has no stable storage.
+    async fn flush(self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn close(self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn exists(self, key: String) -> Result<bool> {
+        Ok(self.live(key.as_bytes()).is_some())
+    }
+
+    async fn len(self) -> Result<u64> {
+        Ok(self
+            .map
+            .iter()
+            .filter(|entry| !entry.value().is_expired())
+            .count() as u64)
+    }
+
+    async fn count_prefix(self, prefix: String) -> Result<u64> {
+        let (lower, upper) = ScanOptions {
+            prefix,
+            ..ScanOptions::default()
+        }
+        .bounds();
+        Ok(self
+            .map
+            .range::<[u8], _>((as_slice(&lower), as_slice(&upper)))
+            .filter(|entry| !entry.value().is_expired())
+            .count() as u64)
+    }
+
+    async fn engine_stats(self) -> Result<BTreeMap<String, u64>> {
+        let keys = self.map.len() as u64;
+        Ok(BTreeMap::from([("keys".to_owned(), keys)]))
+    }
+
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        subscribe(&self.events, prefix)
+    }
+}
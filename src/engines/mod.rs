@@ -1,24 +1,506 @@
-use crate::Result;
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    ops::Bound,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{thread_pool::ThreadPool, KvsError, Result};
 use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+// pairs read per scan while exporting
+const EXPORT_PAGE_SIZE: usize = 1024;
+
+// pairs read per scan while counting keys
+const COUNT_PAGE_SIZE: usize = 1024;
+
+/// A change made to a key, as delivered by `KvsEngine::watch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyEvent {
+    /// The key was set to a new value.
+    Set {
+        /// The key that changed.
+        key: String,
+        /// The new value.
+        value: String,
+    },
+    /// The key was removed.
+    Remove {
+        /// The key that was removed.
+        key: String,
+    },
+}
+
+impl KeyEvent {
+    /// The key the event is about.
+    pub fn key(&self) -> &str {
+        match self {
+            KeyEvent::Set { key, .. } | KeyEvent::Remove { key } => key,
+        }
+    }
+}
+
+/// What `KvsEngine::meta` knows about a value without reading it out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueMeta {
+    /// The length of the value in bytes.
+    pub len: u64,
+    /// When the value was last written, in milliseconds since the Unix epoch, or None
+    /// if the engine doesn't track it.
+    pub modified_ms: Option<u64>,
+    /// How many milliseconds the key has left to live, or None if it never expires.
+    pub ttl_ms: Option<u64>,
+}
+
+/// Which pairs `KvsEngine::scan` returns and in what order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Only return keys starting with this prefix. Empty matches every key.
+    pub prefix: String,
+    /// Return at most this many pairs.
+    pub limit: Option<usize>,
+    /// Return pairs in descending instead of ascending key order.
+    pub reverse: bool,
+    /// Only return keys ordered after this one, or before it when `reverse` is set.
+    /// Passing the last key of a page continues the scan where it stopped.
+    pub after: Option<String>,
+}
+
+impl ScanOptions {
+    /// The range of keys selected by the prefix and `after`.
+    fn bounds(&self) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        let prefix = self.prefix.as_bytes();
+        let mut lower = Bound::Included(prefix.to_vec());
+        let mut upper = prefix_end(prefix).map_or(Bound::Unbounded, Bound::Excluded);
+        if let Some(after) = &self.after {
+            let after = after.as_bytes().to_vec();
+            if self.reverse {
+                if matches!(&upper, Bound::Excluded(end) if after >= *end) {
+                    return (lower, upper);
+                }
+                upper = Bound::Excluded(after);
+            } else if after.as_slice() >= prefix {
+                lower = Bound::Excluded(after);
+            }
+        }
+        (lower, upper)
+    }
+}
 
 /// Trait for a key value storage engine.
 #[async_trait]
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Set the value of a binary key to arbitrary bytes.
+    /// Return an error if the value is not written successfully.
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()>;
+
+    /// Get the binary value of a binary key. If the key does not exist, return None.
+    /// Return an error if the value is not read successfully.
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>>;
+
+    /// Remove a given binary key.
+    /// Return an error if the key does not exit or value is not read successfully.
+    async fn remove_bytes(self, key: Bytes) -> Result<()>;
+
     /// Set the value of a string key to a string.
     /// Return an error if the value is not written successfully.
-    async fn set(self, key: String, value: String) -> Result<()>;
+    async fn set(self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into(), value.into()).await
+    }
 
     /// Get the string value of a string key. If the key does not exist, return None.
-    /// Return an error if the value is not read successfully.
-    async fn get(self, key: String) -> Result<Option<String>>;
+    /// Return an error if the value is not read successfully or is not valid UTF-8.
+    async fn get(self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into())
+            .await?
+            .map(into_string)
+            .transpose()
+    }
 
     /// Remove a given string key.
     /// Return an error if the key does not exit or value is not read successfully.
-    async fn remove(self, key: String) -> Result<()>;
+    async fn remove(self, key: String) -> Result<()> {
+        self.remove_bytes(key.into()).await
+    }
+
+    /// Append `suffix` to the value of a string key, creating it if absent.
+    /// Return the length of the new value in bytes.
+    ///
+    /// The read and the write happen atomically, so concurrent appends are never lost.
+    async fn append(self, key: String, suffix: String) -> Result<u64>;
+
+    /// Remove a given string key and return its previous value, if any.
+    ///
+    /// Removing a key that does not exist is not an error and returns None.
+    async fn getdel(self, key: String) -> Result<Option<String>>;
+
+    /// Set the value of a string key and return its previous value, if any.
+    async fn getset(self, key: String, value: String) -> Result<Option<String>>;
+
+    /// Set the value of a string key only if its current value is `expected`, or if
+    /// it is absent when `expected` is None. Return whether the value was set.
+    ///
+    /// The comparison and the write happen atomically, which makes it a building
+    /// block for locks and leader election.
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool>;
+
+    /// Atomically move the value of `from`, with its expiration, to `to`, replacing
+    /// any value `to` had.
+    ///
+    /// Return `KvsError::KeyNotFound` if `from` doesn't exist, or an error if the
+    /// engine can't rename keys, which is the default.
+    async fn rename(self, _from: String, _to: String) -> Result<()> {
+        Err(KvsError::StringError(
+            "Renaming keys is not supported by this engine".to_owned(),
+        ))
+    }
+
+    /// Atomically copy the value of `from`, with its expiration, to `to`, replacing a
+    /// value `to` already has only if `overwrite` is set.
+    ///
+    /// Return whether the key was copied: it isn't if `from` doesn't exist, or if `to`
+    /// does and `overwrite` isn't set. Return an error if the engine can't copy keys,
+    /// which is the default.
+    async fn copy(self, _from: String, _to: String, _overwrite: bool) -> Result<bool> {
+        Err(KvsError::StringError(
+            "Copying keys is not supported by this engine".to_owned(),
+        ))
+    }
+
+    /// Return the key/value pairs selected by `options`, ordered by key.
+    /// Return an error if a pair is not read successfully or is not valid UTF-8.
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>>;
+
+    /// Return the smallest key in the store, or None if it is empty.
+    async fn first_key(self) -> Result<Option<String>> {
+        let options = ScanOptions {
+            limit: Some(1),
+            ..ScanOptions::default()
+        };
+        Ok(self
+            .scan(options)
+            .await?
+            .into_iter()
+            .next()
+            .map(|(key, _)| key))
+    }
+
+    /// Return the largest key in the store, or None if it is empty.
+    async fn last_key(self) -> Result<Option<String>> {
+        let options = ScanOptions {
+            limit: Some(1),
+            reverse: true,
+            ..ScanOptions::default()
+        };
+        Ok(self
+            .scan(options)
+            .await?
+            .into_iter()
+            .next()
+            .map(|(key, _)| key))
+    }
+
+    /// Return whether a key exists.
+    ///
+    /// The default reads the value; engines that can answer from an index override it.
+    async fn exists(self, key: String) -> Result<bool> {
+        Ok(self.get_bytes(key.into()).await?.is_some())
+    }
+
+    /// Return how many keys the store holds.
+    ///
+    /// The default counts the keys with any prefix.
+    async fn len(self) -> Result<u64> {
+        self.count_prefix(String::new()).await
+    }
+
+    /// Return how many keys start with `prefix`.
+    ///
+    /// The default counts them with a scan, a page at a time; engines that can answer
+    /// from an index without reading values override it.
+    async fn count_prefix(self, prefix: String) -> Result<u64> {
+        let mut options = ScanOptions {
+            prefix,
+            limit: Some(COUNT_PAGE_SIZE),
+            ..ScanOptions::default()
+        };
+        let mut count = 0;
+        loop {
+            let page = self.clone().scan(options.clone()).await?;
+            count += page.len() as u64;
+            match page.last() {
+                Some((key, _)) if page.len() == COUNT_PAGE_SIZE => {
+                    options.after = Some(key.clone())
+                }
+                _ => return Ok(count),
+            }
+        }
+    }
+
+    /// Return whether the store holds no keys.
+    async fn is_empty(self) -> Result<bool> {
+        Ok(self.first_key().await?.is_none())
+    }
+
+    /// Return statistics specific to the engine by name, such as the number of keys.
+    /// Engines without statistics of their own return an empty map.
+    async fn engine_stats(self) -> Result<BTreeMap<String, u64>> {
+        Ok(BTreeMap::new())
+    }
+
+    /// Write a consistent copy of the store into the directory `dest` while it keeps
+    /// serving requests. The copy can be opened like the engine's own data directory.
+    ///
+    /// Return an error if the engine can't take backups, which is the default.
+    async fn backup(self, _dest: PathBuf) -> Result<()> {
+        Err(KvsError::StringError(
+            "Backups are not supported by this engine".to_owned(),
+        ))
+    }
+
+    /// Set the value of a string key that stops existing once `ttl` has passed.
+    /// Setting the key again without a time to live makes it permanent.
+    /// Return an error if the value is not written successfully.
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()>;
+
+    /// Return how long a key has left to live, or None if it doesn't exist or never expires.
+    async fn ttl(self, key: String) -> Result<Option<Duration>>;
+
+    /// Make an existing key expire once `ttl` has passed, replacing any earlier expiration.
+    /// Return whether the key exists.
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool>;
+
+    /// Make a key permanent by removing its expiration.
+    /// Return whether the key existed and had an expiration.
+    async fn persist(self, key: String) -> Result<bool>;
+
+    /// Return the length, modification time and time to live of a key's value, or
+    /// None if it doesn't exist.
+    ///
+    /// The default reads the value to measure it and reports no modification time.
+    async fn meta(self, key: String) -> Result<Option<ValueMeta>> {
+        let value = self.clone().get_bytes(key.clone().into()).await?;
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let ttl = self.ttl(key).await?;
+        Ok(Some(ValueMeta {
+            len: value.len() as u64,
+            modified_ms: None,
+            ttl_ms: ttl.map(|ttl| ttl.as_millis() as u64),
+        }))
+    }
+
+    /// Atomically remove every key.
+    ///
+    /// Watchers see a removal for each key that existed.
+    async fn clear(self) -> Result<()>;
+
+    /// Atomically apply a batch of writes, in order: a value sets its key and None
+    /// removes it. Removing a key that does not exist is not an error.
+    ///
+    /// Return an error if the engine can't write atomic batches, which is the default.
+    async fn write_batch(self, _writes: Vec<(String, Option<String>)>) -> Result<()> {
+        Err(KvsError::StringError(
+            "Atomic batches are not supported by this engine".to_owned(),
+        ))
+    }
+
+    /// Force every completed write to stable storage.
+    ///
+    /// Once this returns, the data written before the call survives a crash or power loss.
+    async fn flush(self) -> Result<()>;
+
+    /// Wait for in-flight operations, flush every write to stable storage and
+    /// release the engine's resources, making shutdown deterministic.
+    ///
+    /// Operations on the engine after it's closed may fail with `KvsError::Closed`.
+    async fn close(self) -> Result<()>;
+
+    /// Subscribe to changes of every key starting with `prefix`.
+    ///
+    /// Only changes made after the call are delivered. A watcher that falls too far
+    /// behind skips the oldest events it missed.
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent>;
+}
+
+/// Convenience methods for any `KvsEngine`, built on top of its own methods.
+#[async_trait]
+pub trait KvsEngineExt: KvsEngine {
+    /// Get the value of a key, decoded from JSON. If the key does not exist, return None.
+    /// Return an error if the value is not read successfully or cannot be decoded as `T`.
+    async fn get_as<T: DeserializeOwned>(self, key: String) -> Result<Option<T>> {
+        match self.get_bytes(key.into()).await? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the value of a key to `value` encoded as JSON, so it can be read back with
+    /// `get_as` or as a plain string.
+    /// Return an error if the value cannot be encoded or is not written successfully.
+    async fn set_as<T: Serialize + Sync + ?Sized>(self, key: String, value: &T) -> Result<()> {
+        let value = serde_json::to_vec(value)?;
+        self.set_bytes(key.into(), value.into()).await
+    }
+
+    /// Write every pair to `writer` in the portable dump format and return how many
+    /// were written.
+    ///
+    /// Pairs are read a page at a time, so the store doesn't have to fit in memory.
+    /// Writes made while the export runs may or may not be part of it.
+    async fn export<W: Write + Send>(self, mut writer: W) -> Result<u64> {
+        dump::write_header(&mut writer)?;
+        let mut options = ScanOptions {
+            limit: Some(EXPORT_PAGE_SIZE),
+            ..ScanOptions::default()
+        };
+        let mut count = 0;
+        loop {
+            let page = self.clone().scan(options.clone()).await?;
+            for (key, value) in &page {
+                dump::write_pair(&mut writer, key.as_bytes(), value.as_bytes())?;
+            }
+            count += page.len() as u64;
+            match page.last() {
+                Some((key, _)) if page.len() == EXPORT_PAGE_SIZE => {
+                    options.after = Some(key.clone())
+                }
+                _ => break,
+            }
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Set every pair of a dump written by `export`, possibly by another engine,
+    /// and return how many were set. Existing keys not in the dump are kept.
+    ///
+    /// The reader is read in small pieces, so wrapping files in a `BufReader` is recommended.
+    async fn import<R: Read + Send>(self, mut reader: R) -> Result<u64> {
+        dump::read_header(&mut reader)?;
+        let mut count = 0;
+        while let Some((key, value)) = dump::read_pair(&mut reader)? {
+            self.clone().set_bytes(key.into(), value.into()).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl<E: KvsEngine> KvsEngineExt for E {}
+
+/// The smallest key greater than every key starting with `prefix`,
+/// or None if there is no such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Borrow a bound produced by `ScanOptions::bounds` for a range lookup.
+fn as_slice(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Milliseconds since the Unix epoch, the unit expiration deadlines are stored in.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// The expiration deadline of a key set now with the given time to live.
+fn deadline(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// The statistics of an engine's thread pool, named for `KvsEngine::engine_stats`.
+fn pool_stats<P: ThreadPool>(pool: &P) -> [(String, u64); 5] {
+    let stats = pool.stats();
+    [
+        ("pool_threads".to_owned(), u64::from(stats.threads)),
+        ("pool_queued_jobs".to_owned(), stats.queued),
+        ("pool_busy_jobs".to_owned(), stats.busy),
+        ("pool_completed_jobs".to_owned(), stats.completed),
+        (
+            "pool_average_wait_micros".to_owned(),
+            stats.average_wait.as_micros() as u64,
+        ),
+    ]
+}
+
+/// Decode a stored value as UTF-8 text.
+fn into_string(value: Bytes) -> Result<String> {
+    Ok(String::from_utf8(value.to_vec())?)
+}
+
+/// Turn a subscription to an engine's event bus into a stream of the events under `prefix`.
+fn subscribe(events: &broadcast::Sender<KeyEvent>, prefix: String) -> BoxStream<'static, KeyEvent> {
+    stream::unfold(events.subscribe(), move |mut rx| {
+        let prefix = prefix.clone();
+        async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) if event.key().starts_with(&prefix) => return Some((event, rx)),
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Watcher lagged behind, {} events skipped", skipped)
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+    .boxed()
 }
 
+mod dump;
+mod handle;
+mod instrumented;
 mod kvs;
+mod memory;
+mod namespace;
+mod noop;
+mod remote;
+#[cfg(feature = "rocksdb")]
+mod rocks;
+mod router;
 mod sled;
+mod tiered;
 
-pub use kvs::KvStore;
+pub use handle::EngineHandle;
+pub use instrumented::{InstrumentedEngine, LatencyHistogram, OpMetrics};
+pub use kvs::{
+    CorruptRecord, KvStore, KvStoreOptions, Snapshot, StoreInfo, Transaction, VerifyReport,
+};
+pub use memory::MemKvsEngine;
+pub use namespace::NamespacedEngine;
+pub use noop::NoopEngine;
+pub use remote::RemoteEngine;
+#[cfg(feature = "rocksdb")]
+pub use rocks::RocksKvsEngine;
+pub use router::RouterEngine;
 pub use sled::SledKvsEngine;
+pub use tiered::{TieredEngine, TieredStats};
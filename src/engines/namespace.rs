@@ -0,0 +1,308 @@
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{
+    future,
+    stream::{self, BoxStream, StreamExt},
+};
+
+use crate::{KeyEvent, KvsEngine, KvsError, Result, ScanOptions, ValueMeta};
+
+// keys removed per scan while clearing a database
+const CLEAR_PAGE_SIZE: usize = 1000;
+
+/// A numbered database within another engine, for serving several separate
+/// keyspaces from one store.
+///
+/// Database 0 is the inner engine's own keyspace, so a store keeps its keys when
+/// databases are enabled later. The keys of any other database `n` are stored under
+/// the prefix `"\0n\0"`, which is why database 0 refuses keys starting with a NUL
+/// character and leaves them out of scans.
+///
+/// Statistics, flushing, closing and backups apply to the whole inner engine.
+#[derive(Clone)]
+pub struct NamespacedEngine<E: KvsEngine> {
+    inner: E,
+    db: u32,
+    // empty for database 0
+    prefix: Arc<str>,
+    // whether the inner engine holds no other database
+    sole: bool,
+}
+
+impl<E: KvsEngine> NamespacedEngine<E> {
+    /// Serves database `db` of `inner`.
+    pub fn new(inner: E, db: u32) -> Self {
+        let prefix = match db {
+            0 => String::new(),
+            db => format!("\0{}\0", db),
+        };
+        NamespacedEngine {
+            inner,
+            db,
+            prefix: prefix.into(),
+            sole: false,
+        }
+    }
+
+    /// Serves database 0 of `inner` as its only database, so counting and clearing
+    /// the keys are left to the inner engine.
+    pub fn sole(inner: E) -> Self {
+        NamespacedEngine {
+            sole: true,
+            ..NamespacedEngine::new(inner, 0)
+        }
+    }
+
+    /// The number of the database.
+    pub fn db(&self) -> u32 {
+        self.db
+    }
+
+    /// The key the inner engine stores `key` under.
+    fn key(&self, key: &str) -> Result<String> {
+        if key.starts_with('\0') {
+            return Err(reserved());
+        }
+        Ok(format!("{}{}", self.prefix, key))
+    }
+
+    fn key_bytes(&self, key: Bytes) -> Result<Bytes> {
+        if key.first() == Some(&0) {
+            return Err(reserved());
+        }
+        Ok([self.prefix.as_bytes(), &key].concat().into())
+    }
+
+    /// The key of this database an inner key stands for, or None if it belongs
+    /// to another one.
+    fn strip(&self, key: &str) -> Option<String> {
+        let key = key.strip_prefix(&*self.prefix)?;
+        if key.starts_with('\0') {
+            return None;
+        }
+        Some(key.to_owned())
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine> KvsEngine for NamespacedEngine<E> {
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        let key = self.key_bytes(key)?;
+        self.inner.set_bytes(key, value).await
+    }
+
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        let key = self.key_bytes(key)?;
+        self.inner.get_bytes(key).await
+    }
+
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
+        let key = self.key_bytes(key)?;
+        self.inner.remove_bytes(key).await
+    }
+
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        let key = self.key(&key)?;
+        self.inner.append(key, suffix).await
+    }
+
+    async fn getdel(self, key: String) -> Result<Option<String>> {
+        let key = self.key(&key)?;
+        self.inner.getdel(key).await
+    }
+
+    async fn getset(self, key: String, value: String) -> Result<Option<String>> {
+        let key = self.key(&key)?;
+        self.inner.getset(key, value).await
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        let key = self.key(&key)?;
+        self.inner.compare_and_swap(key, expected, value).await
+    }
+
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        let (from, to) = (self.key(&from)?, self.key(&to)?);
+        self.inner.rename(from, to).await
+    }
+
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        let (from, to) = (self.key(&from)?, self.key(&to)?);
+        self.inner.copy(from, to, overwrite).await
+    }
+
+    /// Scans the inner engine under the database's prefix. Database 0 skips the
+    /// keys of the other databases, reading further pages to fill the limit.
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        if options.prefix.starts_with('\0') {
+            return Ok(Vec::new());
+        }
+        let limit = options.limit;
+        let mut inner_options = ScanOptions {
+            prefix: format!("{}{}", self.prefix, options.prefix),
+            limit,
+            reverse: options.reverse,
+            after: options
+                .after
+                .map(|after| format!("{}{}", self.prefix, after)),
+        };
+        let mut pairs = Vec::new();
+        loop {
+            let page = self.inner.clone().scan(inner_options.clone()).await?;
+            let full = inner_options.limit == Some(page.len());
+            inner_options.after = page.last().map(|(key, _)| key.clone());
+            pairs.extend(
+                page.into_iter()
+                    .filter_map(|(key, value)| Some((self.strip(&key)?, value))),
+            );
+            if !full {
+                break;
+            }
+            match limit {
+                Some(limit) if pairs.len() < limit => {
+                    inner_options.limit = Some(limit - pairs.len())
+                }
+                _ => break,
+            }
+        }
+        Ok(pairs)
+    }
+
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let key = self.key(&key)?;
+        self.inner.set_with_ttl(key, value, ttl).await
+    }
+
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        let key = self.key(&key)?;
+        self.inner.ttl(key).await
+    }
+
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        let key = self.key(&key)?;
+        self.inner.expire(key, ttl).await
+    }
+
+    async fn persist(self, key: String) -> Result<bool> {
+        let key = self.key(&key)?;
+        self.inner.persist(key).await
+    }
+
+    async fn meta(self, key: String) -> Result<Option<ValueMeta>> {
+        let key = self.key(&key)?;
+        self.inner.meta(key).await
+    }
+
+    async fn exists(self, key: String) -> Result<bool> {
+        let key = self.key(&key)?;
+        self.inner.exists(key).await
+    }
+
+    async fn len(self) -> Result<u64> {
+        if self.sole {
+            return self.inner.len().await;
+        }
+        self.count_prefix(String::new()).await
+    }
+
+    /// Counts the keys under the database's prefix. Database 0 leaves out the keys of
+    /// the other databases, which all start with a NUL character.
+    async fn count_prefix(self, prefix: String) -> Result<u64> {
+        if prefix.starts_with('\0') {
+            return Ok(0);
+        }
+        let all = prefix.is_empty() && self.db == 0 && !self.sole;
+        let count = self
+            .inner
+            .clone()
+            .count_prefix(format!("{}{}", self.prefix, prefix))
+            .await?;
+        if !all {
+            return Ok(count);
+        }
+        let others = self.inner.count_prefix("\0".to_owned()).await?;
+        Ok(count.saturating_sub(others))
+    }
+
+    /// Clears the inner engine if it holds no other database. Otherwise removes the
+    /// keys of the database a page at a time, which unlike clearing the inner engine
+    /// isn't atomic.
+    async fn clear(self) -> Result<()> {
+        if self.sole {
+            return self.inner.clear().await;
+        }
+        let options = ScanOptions {
+            limit: Some(CLEAR_PAGE_SIZE),
+            ..ScanOptions::default()
+        };
+        loop {
+            let page = self.clone().scan(options.clone()).await?;
+            if page.is_empty() {
+                return Ok(());
+            }
+            for (key, _) in page {
+                match self.clone().remove(key).await {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    async fn write_batch(self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        let writes = writes
+            .into_iter()
+            .map(|(key, value)| Ok((self.key(&key)?, value)))
+            .collect::<Result<_>>()?;
+        self.inner.write_batch(writes).await
+    }
+
+    async fn flush(self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn engine_stats(self) -> Result<BTreeMap<String, u64>> {
+        self.inner.engine_stats().await
+    }
+
+    async fn backup(self, dest: PathBuf) -> Result<()> {
+        self.inner.backup(dest).await
+    }
+
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        if prefix.starts_with('\0') {
+            return stream::empty().boxed();
+        }
+        let inner_prefix = format!("{}{}", self.prefix, prefix);
+        let this = self.clone();
+        self.inner
+            .watch(inner_prefix)
+            .filter_map(move |event| {
+                let event = match event {
+                    KeyEvent::Set { key, value } => {
+                        this.strip(&key).map(|key| KeyEvent::Set { key, value })
+                    }
+                    KeyEvent::Remove { key } => {
+                        this.strip(&key).map(|key| KeyEvent::Remove { key })
+                    }
+                };
+                future::ready(event)
+            })
+            .boxed()
+    }
+}
+
+fn reserved() -> KvsError {
+    KvsError::StringError("Keys starting with a NUL character are reserved".to_owned())
+}
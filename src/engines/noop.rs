@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+
+use crate::{KeyEvent, KvsEngine, KvsError, Result, ScanOptions};
+
+/// A `KvsEngine` that accepts every write and forgets it at once, so no key ever exists.
+///
+/// Serving it measures the cost of the network and the protocol alone, and
+/// benchmarking it measures the cost of the `KvsEngine` calls themselves.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopEngine;
+
+impl NoopEngine {
+    /// Creates a `NoopEngine`.
+    pub fn new() -> Self {
+        NoopEngine
+    }
+}
+
+#[async_trait]
+impl KvsEngine for NoopEngine {
+    async fn set_bytes(self, _key: Bytes, _value: Bytes) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get_bytes(self, _key: Bytes) -> Result<Option<Bytes>> {
+        Ok(None)
+    }
+
+    async fn remove_bytes(self, _key: Bytes) -> Result<()> {
+        Err(KvsError::KeyNotFound)
+    }
+
+    async fn append(self, _key: String, suffix: String) -> Result<u64> {
+        Ok(suffix.len() as u64)
+    }
+
+    async fn getdel(self, _key: String) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn getset(self, _key: String, _value: String) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn compare_and_swap(
+        self,
+        _key: String,
+        _expected: Option<String>,
+        _value: String,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+
+    async fn scan(self, _options: ScanOptions) -> Result<Vec<(String, String)>> {
+        Ok(Vec::new())
+    }
+
+    async fn set_with_ttl(self, _key: String, _value: String, _ttl: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    async fn ttl(self, _key: String) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    async fn expire(self, _key: String, _ttl: Duration) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn persist(self, _key: String) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn clear(self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn write_batch(self, _writes: Vec<(String, Option<String>)>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn flush(self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn close(self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Nothing ever changes, so the stream never yields.
+    fn watch(self, _prefix: String) -> BoxStream<'static, KeyEvent> {
+        stream::pending().boxed()
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use tracing::warn;
+
+use super::into_string;
+use crate::{
+    client::ConnectionSpec, KeyChange, KeyEvent, KvsClient, KvsEngine, KvsError, Result,
+    ScanOptions, ValueMeta,
+};
+
+// pairs fetched per request while scanning
+const SCAN_PAGE_SIZE: usize = 1000;
+
+/// A `KvsEngine` stored on a `kvs-server`, reached through a `KvsClient`.
+///
+/// Code written against `KvsEngine` can use a remote store in place of an embedded
+/// one, e.g. behind a `TieredEngine`. Requests that overlap in time use further
+/// connections, opened like the one the engine was created from: with the same
+/// options, authentication and database. Only operations the wire protocol carries
+/// can be forwarded: `getdel` and `getset` fail, `scan` only supports ascending
+/// order, and `backup` writes to the server's file system.
+#[derive(Clone)]
+pub struct RemoteEngine {
+    spec: Arc<ConnectionSpec>,
+    // idle connections, reused by later requests
+    idle: Arc<Mutex<Vec<KvsClient>>>,
+}
+
+impl RemoteEngine {
+    /// Creates a `RemoteEngine` serving requests over `client`.
+    pub fn new(client: KvsClient) -> Self {
+        RemoteEngine {
+            spec: Arc::new(client.spec()),
+            idle: Arc::new(Mutex::new(vec![client])),
+        }
+    }
+
+    /// An idle connection, or a new one.
+    async fn client(&self) -> Result<KvsClient> {
+        let idle = self.idle.lock().unwrap().pop();
+        match idle {
+            Some(client) => Ok(client),
+            None => self.spec.connect().await,
+        }
+    }
+
+    /// Keep the connection for reuse. A connection the request broke reconnects
+    /// by itself before its next request.
+    fn release(&self, client: KvsClient) {
+        self.idle.lock().unwrap().push(client);
+    }
+}
+
+#[async_trait]
+impl KvsEngine for RemoteEngine {
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        let (key, value) = (into_string(key)?, into_string(value)?);
+        let mut client = self.client().await?;
+        let res = client.set(key, value).await;
+        self.release(client);
+        res
+    }
+
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        let key = into_string(key)?;
+        let mut client = self.client().await?;
+        let res = client.get(key).await;
+        self.release(client);
+        Ok(res?.map(Bytes::from))
+    }
+
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
+        let key = into_string(key)?;
+        let mut client = self.client().await?;
+        let res = client.remove(key).await;
+        self.release(client);
+        match res {
+            // the server's error only survives the wire as its message
+            Err(KvsError::StringError(e)) if e == KvsError::KeyNotFound.to_string() => {
+                Err(KvsError::KeyNotFound)
+            }
+            res => res,
+        }
+    }
+
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        let mut client = self.client().await?;
+        let res = client.append(key, suffix).await;
+        self.release(client);
+        res
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        let mut client = self.client().await?;
+        let res = match expected {
+            Some(expected) => client.set_if(key, expected, value).await,
+            None => client.set_nx(key, value).await,
+        };
+        self.release(client);
+        res
+    }
+
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        let mut client = self.client().await?;
+        let res = client.rename(from, to).await;
+        self.release(client);
+        match res {
+            Err(KvsError::StringError(e)) if e == KvsError::KeyNotFound.to_string() => {
+                Err(KvsError::KeyNotFound)
+            }
+            res => res,
+        }
+    }
+
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        let mut client = self.client().await?;
+        let res = client.copy(from, to, overwrite).await;
+        self.release(client);
+        res
+    }
+
+    async fn getdel(self, _key: String) -> Result<Option<String>> {
+        Err(unsupported("getdel"))
+    }
+
+    async fn getset(self, _key: String, _value: String) -> Result<Option<String>> {
+        Err(unsupported("getset"))
+    }
+
+    /// Follows the server's cursors until `limit` pairs were read.
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        if options.reverse {
+            return Err(unsupported("reverse scan"));
+        }
+        let mut client = self.client().await?;
+        let mut pairs = Vec::new();
+        let mut cursor = options.after.clone();
+        let res = loop {
+            let wanted = options.limit.map_or(SCAN_PAGE_SIZE, |limit| {
+                (limit - pairs.len()).min(SCAN_PAGE_SIZE)
+            });
+            if wanted == 0 {
+                break Ok(());
+            }
+            match client
+                .scan_page(options.prefix.clone(), cursor, wanted)
+                .await
+            {
+                Ok((page, next_cursor)) => {
+                    pairs.extend(page);
+                    cursor = match next_cursor {
+                        Some(cursor) => Some(cursor),
+                        None => break Ok(()),
+                    };
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.release(client);
+        res.map(|()| pairs)
+    }
+
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let mut client = self.client().await?;
+        let res = client.set_with_ttl(key, value, ttl).await;
+        self.release(client);
+        res
+    }
+
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        let mut client = self.client().await?;
+        let res = client.ttl(key).await;
+        self.release(client);
+        res
+    }
+
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        let mut client = self.client().await?;
+        let res = client.expire(key, ttl).await;
+        self.release(client);
+        res
+    }
+
+    async fn persist(self, key: String) -> Result<bool> {
+        let mut client = self.client().await?;
+        let res = client.persist(key).await;
+        self.release(client);
+        res
+    }
+
+    async fn meta(self, key: String) -> Result<Option<ValueMeta>> {
+        let mut client = self.client().await?;
+        let res = client.get_meta(key).await;
+        self.release(client);
+        res
+    }
+
+    async fn exists(self, key: String) -> Result<bool> {
+        let mut client = self.client().await?;
+        let res = client.exists(key).await;
+        self.release(client);
+        res
+    }
+
+    async fn len(self) -> Result<u64> {
+        let mut client = self.client().await?;
+        let res = client.len().await;
+        self.release(client);
+        res
+    }
+
+    async fn clear(self) -> Result<()> {
+        let mut client = self.client().await?;
+        let res = client.flush_all().await;
+        self.release(client);
+        res
+    }
+
+    /// Applies the writes in a server-side transaction.
+    async fn write_batch(self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        let mut client = self.client().await?;
+        let res = client.transaction(writes).await;
+        self.release(client);
+        res
+    }
+
+    /// The server flushes its own storage, so there is nothing to flush here.
+    async fn flush(self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Drops the idle connections.
+    async fn close(self) -> Result<()> {
+        self.idle.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Has the server write the backup into `dest` under its backup directory.
+    async fn backup(self, dest: PathBuf) -> Result<()> {
+        let mut client = self.client().await?;
+        let res = client.backup(dest).await;
+        self.release(client);
+        res
+    }
+
+    /// Subscribes to the server over a connection of its own.
+    ///
+    /// The subscription is made in the background, so changes right after the call
+    /// may be missed. If subscribing fails, the error is logged and the stream ends.
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        stream::once(async move {
+            let client = self.spec.connect().await?;
+            client.subscribe(prefix).await
+        })
+        .filter_map(|res| async move {
+            res.map_err(|e| warn!("Failed to subscribe to the server: {}", e))
+                .ok()
+        })
+        .flatten()
+        .filter_map(|notification| async move {
+            let event = match notification.ok()? {
+                (key, KeyChange::Set(value)) => KeyEvent::Set { key, value },
+                (key, KeyChange::Remove) => KeyEvent::Remove { key },
+            };
+            Some(event)
+        })
+        .boxed()
+    }
+}
+
+fn unsupported(op: &str) -> KvsError {
+    KvsError::StringError(format!("{} is not supported by a remote engine", op))
+}
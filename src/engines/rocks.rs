@@ -0,0 +1,457 @@
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use rocksdb::{ColumnFamily, Direction, IteratorMode, Options, WriteBatch, DB};
+use tokio::sync::broadcast;
+
+use super::{deadline, now_millis, pool_stats, subscribe};
+use crate::{thread_pool::ThreadPool, KeyEvent, KvsEngine, KvsError, Result, ScanOptions};
+
+// column family mapping keys to their expiration deadline as big-endian milliseconds since the epoch
+const TTL_FAMILY: &str = "ttl";
+// events buffered per watcher before it starts skipping
+const EVENT_CAPACITY: usize = 1024;
+
+/// A `KvsEngine` storing its data in a RocksDB database.
+///
+/// Only available with the `rocksdb` feature.
+#[derive(Clone)]
+pub struct RocksKvsEngine<P: ThreadPool> {
+    pool: P,
+    rocks: Arc<Rocks>,
+}
+
+struct Rocks {
+    db: DB,
+    // serializes writes so read-modify-write operations are atomic
+    write_lock: Mutex<()>,
+    events: broadcast::Sender<KeyEvent>,
+}
+
+impl<P: ThreadPool> RocksKvsEngine<P> {
+    /// Opens the RocksDB database in `path`, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>, max_threads: u32) -> Result<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let db = DB::open_cf(&options, path, [TTL_FAMILY])?;
+
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Ok(RocksKvsEngine {
+            pool: P::new(max_threads)?,
+            rocks: Arc::new(Rocks {
+                db,
+                write_lock: Mutex::new(()),
+                events,
+            }),
+        })
+    }
+
+    /// Run `job` on the thread pool and wait for its result.
+    async fn spawn<T, F>(self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Rocks) -> Result<T> + Send + 'static,
+    {
+        let rocks = self.rocks.clone();
+        self.pool.spawn_with_result(move || job(&rocks)).await?
+    }
+}
+
+impl Rocks {
+    fn ttl_family(&self) -> &ColumnFamily {
+        self.db
+            .cf_handle(TTL_FAMILY)
+            .expect("the ttl column family is created on open")
+    }
+
+    /// The expiration deadline of a key, if it has one.
+    fn expires_at(&self, key: &[u8]) -> Result<Option<u64>> {
+        Ok(self.db.get_cf(self.ttl_family(), key)?.map(|deadline| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&deadline);
+            u64::from_be_bytes(bytes)
+        }))
+    }
+
+    fn is_expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(self
+            .expires_at(key)?
+            .map_or(false, |deadline| deadline <= now_millis()))
+    }
+
+    /// The value of a key, unless it doesn't exist or has expired.
+    fn live_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.is_expired(key)? {
+            return Ok(None);
+        }
+        Ok(self.db.get(key)?)
+    }
+
+    /// Set a key, expiring at `expires_at` if given, and notify watchers.
+    fn put(&self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.put(key, value);
+        match expires_at {
+            Some(expires_at) => batch.put_cf(self.ttl_family(), key, expires_at.to_be_bytes()),
+            None => batch.delete_cf(self.ttl_family(), key),
+        }
+        self.db.write(batch)?;
+        self.notify(KeyEvent::Set {
+            key: String::from_utf8_lossy(key).into_owned(),
+            value: String::from_utf8_lossy(value).into_owned(),
+        });
+        Ok(())
+    }
+
+    /// Copy the value and expiration of `from` to `to` in one batch, removing `from`
+    /// if `remove_source` is set. Must be called with the write lock held.
+    ///
+    /// Returns whether anything was copied: `from` must exist, and `to` must not unless
+    /// `overwrite` is set.
+    fn transfer(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        overwrite: bool,
+        remove_source: bool,
+    ) -> Result<bool> {
+        let value = match self.live_value(from)? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if !overwrite && self.live_value(to)?.is_some() {
+            return Ok(false);
+        }
+        if from == to {
+            return Ok(true);
+        }
+        let mut batch = WriteBatch::default();
+        batch.put(to, &value);
+        match self.expires_at(from)? {
+            Some(expires_at) => batch.put_cf(self.ttl_family(), to, expires_at.to_be_bytes()),
+            None => batch.delete_cf(self.ttl_family(), to),
+        }
+        if remove_source {
+            batch.delete(from);
+            batch.delete_cf(self.ttl_family(), from);
+        }
+        self.db.write(batch)?;
+        if remove_source {
+            self.notify(KeyEvent::Remove {
+                key: String::from_utf8_lossy(from).into_owned(),
+            });
+        }
+        self.notify(KeyEvent::Set {
+            key: String::from_utf8_lossy(to).into_owned(),
+            value: String::from_utf8_lossy(&value).into_owned(),
+        });
+        Ok(true)
+    }
+
+    /// Remove a key and return its value, unless it doesn't exist or has expired.
+    fn take(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let old = self.live_value(key)?;
+        let mut batch = WriteBatch::default();
+        batch.delete(key);
+        batch.delete_cf(self.ttl_family(), key);
+        self.db.write(batch)?;
+        if old.is_some() {
+            self.notify(KeyEvent::Remove {
+                key: String::from_utf8_lossy(key).into_owned(),
+            });
+        }
+        Ok(old)
+    }
+
+    fn notify(&self, event: KeyEvent) {
+        if self.events.receiver_count() > 0 {
+            // only fails if every watcher went away in the meantime
+            let _ = self.events.send(event);
+        }
+    }
+}
+
+fn to_string(value: Option<Vec<u8>>) -> Result<Option<String>> {
+    Ok(value.map(String::from_utf8).transpose()?)
+}
+
+#[async_trait]
+impl<P: ThreadPool> KvsEngine for RocksKvsEngine<P> {
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            rocks.put(&key, &value, None)
+        })
+        .await
+    }
+
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        self.spawn(move |rocks| Ok(rocks.live_value(&key)?.map(Bytes::from)))
+            .await
+    }
+
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            rocks.take(&key)?.map(|_| ()).ok_or(KvsError::KeyNotFound)
+        })
+        .await
+    }
+
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            let old = rocks.live_value(key.as_bytes())?;
+            // an expired key is appended to as if it didn't exist
+            let expires_at = match old {
+                Some(_) => rocks.expires_at(key.as_bytes())?,
+                None => None,
+            };
+            let mut value = old.unwrap_or_default();
+            value.extend_from_slice(suffix.as_bytes());
+            rocks.put(key.as_bytes(), &value, expires_at)?;
+            Ok(value.len() as u64)
+        })
+        .await
+    }
+
+    async fn getdel(self, key: String) -> Result<Option<String>> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            to_string(rocks.take(key.as_bytes())?)
+        })
+        .await
+    }
+
+    async fn getset(self, key: String, value: String) -> Result<Option<String>> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            let old = rocks.live_value(key.as_bytes())?;
+            rocks.put(key.as_bytes(), value.as_bytes(), None)?;
+            to_string(old)
+        })
+        .await
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            let current = rocks.live_value(key.as_bytes())?;
+            if current.as_deref() != expected.as_ref().map(String::as_bytes) {
+                return Ok(false);
+            }
+            rocks.put(key.as_bytes(), value.as_bytes(), None)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            if rocks.transfer(from.as_bytes(), to.as_bytes(), true, true)? {
+                Ok(())
+            } else {
+                Err(KvsError::KeyNotFound)
+            }
+        })
+        .await
+    }
+
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            rocks.transfer(from.as_bytes(), to.as_bytes(), overwrite, false)
+        })
+        .await
+    }
+
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        self.spawn(move |rocks| {
+            let (lower, upper) = options.bounds();
+            // start at the bound the scan moves away from, stop once past the other one
+            let (mode, start, end) = if options.reverse {
+                let mode = match &upper {
+                    Bound::Included(key) | Bound::Excluded(key) => {
+                        IteratorMode::From(key, Direction::Reverse)
+                    }
+                    Bound::Unbounded => IteratorMode::End,
+                };
+                (mode, &upper, &lower)
+            } else {
+                let mode = match &lower {
+                    Bound::Included(key) | Bound::Excluded(key) => {
+                        IteratorMode::From(key, Direction::Forward)
+                    }
+                    Bound::Unbounded => IteratorMode::Start,
+                };
+                (mode, &lower, &upper)
+            };
+            let within_end = |key: &[u8]| match end {
+                Bound::Included(end) if options.reverse => key >= end.as_slice(),
+                Bound::Included(end) => key <= end.as_slice(),
+                Bound::Excluded(end) if options.reverse => key > end.as_slice(),
+                Bound::Excluded(end) => key < end.as_slice(),
+                Bound::Unbounded => true,
+            };
+
+            let mut pairs = Vec::new();
+            for pair in rocks.db.iterator(mode) {
+                let (key, value) = pair?;
+                if matches!(start, Bound::Excluded(start) if *key == **start) {
+                    continue;
+                }
+                if !within_end(&key) || pairs.len() >= options.limit.unwrap_or(usize::MAX) {
+                    break;
+                }
+                if rocks.is_expired(&key)? {
+                    continue;
+                }
+                pairs.push((
+                    String::from_utf8(key.into_vec())?,
+                    String::from_utf8(value.into_vec())?,
+                ));
+            }
+            Ok(pairs)
+        })
+        .await
+    }
+
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            rocks.put(key.as_bytes(), value.as_bytes(), Some(deadline(ttl)))
+        })
+        .await
+    }
+
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        self.spawn(move |rocks| {
+            if rocks.db.get_pinned(&key)?.is_none() {
+                return Ok(None);
+            }
+            let now = now_millis();
+            Ok(rocks
+                .expires_at(key.as_bytes())?
+                .filter(|&deadline| deadline > now)
+                .map(|deadline| Duration::from_millis(deadline - now)))
+        })
+        .await
+    }
+
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            if rocks.live_value(key.as_bytes())?.is_none() {
+                return Ok(false);
+            }
+            rocks
+                .db
+                .put_cf(rocks.ttl_family(), &key, deadline(ttl).to_be_bytes())?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn persist(self, key: String) -> Result<bool> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            if rocks.live_value(key.as_bytes())?.is_none()
+                || rocks.expires_at(key.as_bytes())?.is_none()
+            {
+                return Ok(false);
+            }
+            rocks.db.delete_cf(rocks.ttl_family(), &key)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn clear(self) -> Result<()> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            let mut batch = WriteBatch::default();
+            let mut removed = Vec::new();
+            for pair in rocks.db.iterator(IteratorMode::Start) {
+                let (key, _) = pair?;
+                batch.delete(&key);
+                batch.delete_cf(rocks.ttl_family(), &key);
+                if !rocks.is_expired(&key)? {
+                    removed.push(key);
+                }
+            }
+            rocks.db.write(batch)?;
+            for key in removed {
+                rocks.notify(KeyEvent::Remove {
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                });
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn write_batch(self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        self.spawn(move |rocks| {
+            let _guard = rocks.write_lock.lock().unwrap();
+            let mut batch = WriteBatch::default();
+            let mut events = Vec::new();
+            for (key, value) in writes {
+                batch.delete_cf(rocks.ttl_family(), &key);
+                match value {
+                    Some(value) => {
+                        batch.put(&key, &value);
+                        events.push(KeyEvent::Set { key, value });
+                    }
+                    None => {
+                        batch.delete(&key);
+                        if rocks.live_value(key.as_bytes())?.is_some() {
+                            events.push(KeyEvent::Remove { key });
+                        }
+                    }
+                }
+            }
+            rocks.db.write(batch)?;
+            for event in events {
+                rocks.notify(event);
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn flush(self) -> Result<()> {
+        self.spawn(|rocks| Ok(rocks.db.flush_wal(true)?)).await
+    }
+
+    /// Reports the load of the thread pool.
+    async fn engine_stats(self) -> Result<BTreeMap<String, u64>> {
+        Ok(BTreeMap::from(pool_stats(&self.pool)))
+    }
+
+    async fn close(self) -> Result<()> {
+        self.spawn(|rocks| {
+            rocks.db.flush_wal(true)?;
+            Ok(rocks.db.flush()?)
+        })
+        .await
+    }
+
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        subscribe(&self.rocks.events, prefix)
+    }
+}
@@ -0,0 +1,329 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use tracing::warn;
+
+use super::into_string;
+use crate::{
+    KeyChange, KeyEvent, KvsClient, KvsEngine, KvsError, Result, ScanOptions, ShardMap, ValueMeta,
+};
+
+// pairs fetched per request while scanning a shard
+const SCAN_PAGE_SIZE: usize = 1000;
+
+/// A `KvsEngine` that stores nothing itself and forwards every request to the
+/// `kvs-server` owning the key in a `ShardMap`.
+///
+/// Served by a `KvsServer`, it turns that server into a router over the shards.
+/// Only operations the wire protocol carries can be forwarded: `getdel`, `getset`
+/// and `clear` fail, `rename` and `copy` only work between keys of one shard, and
+/// `scan` only supports ascending order.
+#[derive(Clone)]
+pub struct RouterEngine {
+    map: Arc<ShardMap>,
+    // idle connections to each shard, reused by later requests
+    pools: Arc<HashMap<SocketAddr, Mutex<Vec<KvsClient>>>>,
+}
+
+impl RouterEngine {
+    /// Creates a `RouterEngine` forwarding to the shards of `map`.
+    pub fn new(map: ShardMap) -> Self {
+        let pools = map
+            .shards()
+            .iter()
+            .map(|&addr| (addr, Mutex::new(Vec::new())))
+            .collect();
+        RouterEngine {
+            map: Arc::new(map),
+            pools: Arc::new(pools),
+        }
+    }
+
+    /// The shards requests are forwarded to.
+    pub fn shard_map(&self) -> &ShardMap {
+        &self.map
+    }
+
+    /// An idle connection to the shard at `addr`, or a new one.
+    async fn client(&self, addr: SocketAddr) -> Result<KvsClient> {
+        let idle = self.pools[&addr].lock().unwrap().pop();
+        match idle {
+            Some(client) => Ok(client),
+            None => KvsClient::connect(addr).await,
+        }
+    }
+
+    /// Keep the connection for reuse unless the request broke it.
+    fn release<T>(&self, addr: SocketAddr, client: KvsClient, res: &Result<T>) {
+        if !matches!(res, Err(KvsError::Io(_))) {
+            self.pools[&addr].lock().unwrap().push(client);
+        }
+    }
+
+    /// Scan a single shard, following its cursors until `limit` pairs were read.
+    async fn scan_shard(
+        &self,
+        addr: SocketAddr,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, String)>> {
+        let mut client = self.client(addr).await?;
+        let mut pairs = Vec::new();
+        let mut cursor = options.after.clone();
+        let res = loop {
+            let wanted = options.limit.map_or(SCAN_PAGE_SIZE, |limit| {
+                (limit - pairs.len()).min(SCAN_PAGE_SIZE)
+            });
+            if wanted == 0 {
+                break Ok(());
+            }
+            match client
+                .scan_page(options.prefix.clone(), cursor, wanted)
+                .await
+            {
+                Ok((page, next_cursor)) => {
+                    pairs.extend(page);
+                    cursor = match next_cursor {
+                        Some(cursor) => Some(cursor),
+                        None => break Ok(()),
+                    };
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.release(addr, client, &res);
+        res.map(|()| pairs)
+    }
+}
+
+#[async_trait]
+impl KvsEngine for RouterEngine {
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        let (key, value) = (into_string(key)?, into_string(value)?);
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = client.set(key, value).await;
+        self.release(addr, client, &res);
+        res
+    }
+
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        let key = into_string(key)?;
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = client.get(key).await;
+        self.release(addr, client, &res);
+        Ok(res?.map(Bytes::from))
+    }
+
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
+        let key = into_string(key)?;
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = client.remove(key).await;
+        self.release(addr, client, &res);
+        match res {
+            // the shard's error only survives the wire as its message
+            Err(KvsError::StringError(e)) if e == KvsError::KeyNotFound.to_string() => {
+                Err(KvsError::KeyNotFound)
+            }
+            res => res,
+        }
+    }
+
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = client.append(key, suffix).await;
+        self.release(addr, client, &res);
+        res
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = match expected {
+            Some(expected) => client.set_if(key, expected, value).await,
+            None => client.set_nx(key, value).await,
+        };
+        self.release(addr, client, &res);
+        res
+    }
+
+    /// Forwarded only if both keys live on the same shard.
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        let addr = self.map.shard_for(&from);
+        if self.map.shard_for(&to) != addr {
+            return Err(unsupported("rename across shards"));
+        }
+        let mut client = self.client(addr).await?;
+        let res = client.rename(from, to).await;
+        self.release(addr, client, &res);
+        res
+    }
+
+    /// Forwarded only if both keys live on the same shard.
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        let addr = self.map.shard_for(&from);
+        if self.map.shard_for(&to) != addr {
+            return Err(unsupported("copy across shards"));
+        }
+        let mut client = self.client(addr).await?;
+        let res = client.copy(from, to, overwrite).await;
+        self.release(addr, client, &res);
+        res
+    }
+
+    async fn getdel(self, _key: String) -> Result<Option<String>> {
+        Err(unsupported("getdel"))
+    }
+
+    async fn getset(self, _key: String, _value: String) -> Result<Option<String>> {
+        Err(unsupported("getset"))
+    }
+
+    /// Scans every shard and merges their pairs by key.
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        if options.reverse {
+            return Err(unsupported("reverse scan"));
+        }
+        let scans = self
+            .map
+            .shards()
+            .iter()
+            .map(|&addr| self.scan_shard(addr, &options));
+        let mut pairs: Vec<_> = futures::future::try_join_all(scans)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        if let Some(limit) = options.limit {
+            pairs.truncate(limit);
+        }
+        Ok(pairs)
+    }
+
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = client.set_with_ttl(key, value, ttl).await;
+        self.release(addr, client, &res);
+        res
+    }
+
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = client.ttl(key).await;
+        self.release(addr, client, &res);
+        res
+    }
+
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = client.expire(key, ttl).await;
+        self.release(addr, client, &res);
+        res
+    }
+
+    async fn persist(self, key: String) -> Result<bool> {
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = client.persist(key).await;
+        self.release(addr, client, &res);
+        res
+    }
+
+    async fn meta(self, key: String) -> Result<Option<ValueMeta>> {
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = client.get_meta(key).await;
+        self.release(addr, client, &res);
+        res
+    }
+
+    async fn exists(self, key: String) -> Result<bool> {
+        let addr = self.map.shard_for(&key);
+        let mut client = self.client(addr).await?;
+        let res = client.exists(key).await;
+        self.release(addr, client, &res);
+        res
+    }
+
+    /// Adds up the key counts of every shard.
+    async fn len(self) -> Result<u64> {
+        let router = &self;
+        let counts = self.map.shards().iter().map(|&addr| async move {
+            let mut client = router.client(addr).await?;
+            let res = client.len().await;
+            router.release(addr, client, &res);
+            res
+        });
+        Ok(futures::future::try_join_all(counts)
+            .await?
+            .into_iter()
+            .sum())
+    }
+
+    async fn clear(self) -> Result<()> {
+        Err(unsupported("clear"))
+    }
+
+    /// The shards own their storage, so there is nothing to flush here.
+    async fn flush(self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Drops the idle connections to the shards.
+    async fn close(self) -> Result<()> {
+        for pool in self.pools.values() {
+            pool.lock().unwrap().clear();
+        }
+        Ok(())
+    }
+
+    /// Subscribes to every shard and merges their notifications.
+    ///
+    /// The subscriptions are made in the background, so changes right after the
+    /// call may be missed. Shards that can't be subscribed to are logged and skipped.
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        let streams = self.map.shards().iter().map(|&addr| {
+            let prefix = prefix.clone();
+            stream::once(async move {
+                let client = KvsClient::connect(addr).await?;
+                client.subscribe(prefix).await
+            })
+            .filter_map(move |res| async move {
+                res.map_err(|e| warn!("Failed to subscribe to shard {}: {}", addr, e))
+                    .ok()
+            })
+            .flatten()
+            .filter_map(|notification| async move {
+                let event = match notification.ok()? {
+                    (key, KeyChange::Set(value)) => KeyEvent::Set { key, value },
+                    (key, KeyChange::Remove) => KeyEvent::Remove { key },
+                };
+                Some(event)
+            })
+            .boxed()
+        });
+        stream::select_all(streams).boxed()
+    }
+}
+
+fn unsupported(op: &str) -> KvsError {
+    KvsError::StringError(format!("{} is not supported by the router", op))
+}
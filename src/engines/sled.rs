@@ -1,15 +1,25 @@
+use std::{collections::BTreeMap, time::Duration};
+
 use async_trait::async_trait;
-use log::error;
-use sled::Db;
-use tokio::sync::oneshot;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use sled::{
+    transaction::{ConflictableTransactionResult, TransactionError, TransactionalTree},
+    Db, Event, IVec, Transactional, Tree,
+};
+
+use super::{deadline, now_millis, pool_stats};
+use crate::{thread_pool::ThreadPool, KeyEvent, KvsEngine, KvsError, Result, ScanOptions};
 
-use crate::{thread_pool::ThreadPool, KvsEngine, KvsError, Result};
+// tree mapping keys to their expiration deadline as big-endian milliseconds since the epoch
+const TTL_TREE: &str = "ttl";
 
 /// Wrapper of `sled::Db
 #[derive(Clone)]
 pub struct SledKvsEngine<P: ThreadPool> {
     pool: P,
     db: Db,
+    ttl: Tree,
 }
 
 /// Implementation of SledKvsEngine
@@ -17,63 +27,411 @@ impl<P: ThreadPool> SledKvsEngine<P> {
     /// Creates a `SledKvsEngine` from `sled::Db`.
     pub fn new(db: Db, max_threads: u32) -> Result<Self> {
         let pool = P::new(max_threads)?;
-        Ok(SledKvsEngine { pool, db })
+        let ttl = db.open_tree(TTL_TREE)?;
+        Ok(SledKvsEngine { pool, db, ttl })
+    }
+}
+
+/// The expiration deadline of a key, if it has one.
+fn expires_at(ttl: &Tree, key: &[u8]) -> Result<Option<u64>> {
+    Ok(ttl.get(key)?.as_deref().map(decode_deadline))
+}
+
+fn decode_deadline(deadline: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(deadline);
+    u64::from_be_bytes(bytes)
+}
+
+/// The value and raw deadline of a key within a transaction, unless it doesn't exist
+/// or has expired.
+fn live_entry(
+    db: &TransactionalTree,
+    ttl: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<Option<(IVec, Option<IVec>)>> {
+    let deadline = ttl.get(key)?;
+    let expired = deadline
+        .as_deref()
+        .map_or(false, |deadline| decode_deadline(deadline) <= now_millis());
+    Ok(db
+        .get(key)?
+        .filter(|_| !expired)
+        .map(|value| (value, deadline)))
+}
+
+/// Copy the value and expiration of `from` to `to` in one transaction, removing
+/// `from` if `remove_source` is set.
+///
+/// Returns whether anything was copied: `from` must exist, and `to` must not unless
+/// `overwrite` is set.
+fn transfer(
+    db: &Db,
+    ttl: &Tree,
+    from: &[u8],
+    to: &[u8],
+    overwrite: bool,
+    remove_source: bool,
+) -> Result<bool> {
+    let copied = (&**db, ttl)
+        .transaction(|(db, ttl)| -> ConflictableTransactionResult<bool> {
+            let (value, deadline) = match live_entry(db, ttl, from)? {
+                Some(entry) => entry,
+                None => return Ok(false),
+            };
+            if !overwrite && live_entry(db, ttl, to)?.is_some() {
+                return Ok(false);
+            }
+            if from == to {
+                return Ok(true);
+            }
+            db.insert(to, value)?;
+            match deadline {
+                Some(deadline) => ttl.insert(to, deadline)?,
+                None => ttl.remove(to)?,
+            };
+            if remove_source {
+                db.remove(from)?;
+                ttl.remove(from)?;
+            }
+            Ok(true)
+        })
+        .map_err(|e| match e {
+            TransactionError::Abort(()) => KvsError::StringError("Transfer aborted".to_owned()),
+            TransactionError::Storage(e) => KvsError::from(e),
+        })?;
+    db.flush()?;
+    Ok(copied)
+}
+
+fn is_expired(ttl: &Tree, key: &[u8]) -> Result<bool> {
+    Ok(expires_at(ttl, key)?.map_or(false, |deadline| deadline <= now_millis()))
+}
+
+/// The value of a key, unless it doesn't exist or has expired.
+fn live_value(db: &Db, ttl: &Tree, key: &[u8]) -> Result<Option<IVec>> {
+    if is_expired(ttl, key)? {
+        return Ok(None);
     }
+    Ok(db.get(key)?)
+}
+
+fn to_string(value: Option<IVec>) -> Result<Option<String>> {
+    Ok(value
+        .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
+        .map(String::from_utf8)
+        .transpose()?)
 }
 
 /// Implementation of KvsEngine for SledKvsEngine trait
 #[async_trait]
 impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
-    async fn set(self, key: String, value: String) -> Result<()> {
-        let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = db
-                .insert(key, value.into_bytes())
-                .and_then(|_| db.flush())
-                .map(|_| ())
-                .map_err(KvsError::from);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        rx.await
-            .map_err(|e| KvsError::StringError(format!("{}", e)))?
-    }
-
-    async fn get(self, key: String) -> Result<Option<String>> {
-        let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (move || {
-                Ok(db
-                    .get(key)?
-                    .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-                    .map(String::from_utf8)
-                    .transpose()?)
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        rx.await
-            .map_err(|e| KvsError::StringError(format!("{}", e)))?
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                db.insert(&key[..], &value[..])?;
+                ttl.remove(&key[..])?;
+                db.flush()?;
+                Ok(())
+            })
+            .await?
+    }
+
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                live_value(&db, &ttl, &key)
+                    .map(|value| value.map(|i_vec| Bytes::copy_from_slice(&i_vec)))
+            })
+            .await?
     }
 
-    async fn remove(self, key: String) -> Result<()> {
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
         let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (|| {
-                db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                let expired = is_expired(&ttl, &key)?;
+                let old_value = db.remove(&key[..])?;
+                ttl.remove(&key[..])?;
+                old_value
+                    .filter(|_| !expired)
+                    .ok_or(KvsError::KeyNotFound)?;
                 db.flush()?;
                 Ok(())
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        rx.await
-            .map_err(|e| KvsError::StringError(format!("{}", e)))?
+            })
+            .await?
+    }
+
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                // an expired key is appended to as if it didn't exist
+                if is_expired(&ttl, key.as_bytes())? {
+                    db.remove(&key)?;
+                    ttl.remove(&key)?;
+                }
+                let value = db.update_and_fetch(key, |old| {
+                    let mut value = old.map(<[u8]>::to_vec).unwrap_or_default();
+                    value.extend_from_slice(suffix.as_bytes());
+                    Some(value)
+                })?;
+                db.flush()?;
+                Ok(value.map_or(0, |value| value.len() as u64))
+            })
+            .await?
+    }
+
+    async fn getdel(self, key: String) -> Result<Option<String>> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                let expired = is_expired(&ttl, key.as_bytes())?;
+                let old_value = db.remove(&key)?;
+                ttl.remove(&key)?;
+                db.flush()?;
+                to_string(old_value.filter(|_| !expired))
+            })
+            .await?
+    }
+
+    async fn getset(self, key: String, value: String) -> Result<Option<String>> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                let expired = is_expired(&ttl, key.as_bytes())?;
+                let old_value = db.insert(&key, value.into_bytes())?;
+                ttl.remove(&key)?;
+                db.flush()?;
+                to_string(old_value.filter(|_| !expired))
+            })
+            .await?
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                let expired = is_expired(&ttl, key.as_bytes())?;
+                let stored = db.get(&key)?;
+                let current = stored.as_deref().filter(|_| !expired);
+                if current != expected.as_ref().map(String::as_bytes) {
+                    return Ok(false);
+                }
+                // swap against what was read, so a concurrent write makes it fail
+                if db
+                    .compare_and_swap(&key, stored, Some(value.into_bytes()))?
+                    .is_err()
+                {
+                    return Ok(false);
+                }
+                ttl.remove(&key)?;
+                db.flush()?;
+                Ok(true)
+            })
+            .await?
+    }
+
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                match transfer(&db, &ttl, from.as_bytes(), to.as_bytes(), true, true) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err(KvsError::KeyNotFound),
+                    Err(e) => Err(e),
+                }
+            })
+            .await?
+    }
+
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                transfer(&db, &ttl, from.as_bytes(), to.as_bytes(), overwrite, false)
+            })
+            .await?
+    }
+
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                let iter = db.range(options.bounds());
+                let pairs: Box<dyn Iterator<Item = _>> = if options.reverse {
+                    Box::new(iter.rev())
+                } else {
+                    Box::new(iter)
+                };
+                pairs
+                    .map(|pair| {
+                        let (key, value) = pair?;
+                        Ok((!is_expired(&ttl, &key)?).then_some((key, value)))
+                    })
+                    .filter_map(Result::transpose)
+                    .take(options.limit.unwrap_or(usize::MAX))
+                    .map(|pair: Result<_>| {
+                        let (key, value) = pair?;
+                        Ok((
+                            String::from_utf8(key.to_vec())?,
+                            String::from_utf8(value.to_vec())?,
+                        ))
+                    })
+                    .collect()
+            })
+            .await?
+    }
+
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let db = self.db.clone();
+        let deadlines = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                db.insert(&key, value.into_bytes())?;
+                deadlines.insert(&key, &deadline(ttl).to_be_bytes()[..])?;
+                db.flush()?;
+                Ok(())
+            })
+            .await?
+    }
+
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                if !db.contains_key(&key)? {
+                    return Ok(None);
+                }
+                let now = now_millis();
+                Ok(expires_at(&ttl, key.as_bytes())?
+                    .filter(|&deadline| deadline > now)
+                    .map(|deadline| Duration::from_millis(deadline - now)))
+            })
+            .await?
+    }
+
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        let db = self.db.clone();
+        let deadlines = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                if live_value(&db, &deadlines, key.as_bytes())?.is_none() {
+                    return Ok(false);
+                }
+                deadlines.insert(&key, &deadline(ttl).to_be_bytes()[..])?;
+                deadlines.flush()?;
+                Ok(true)
+            })
+            .await?
+    }
+
+    async fn persist(self, key: String) -> Result<bool> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                if live_value(&db, &ttl, key.as_bytes())?.is_none() {
+                    return Ok(false);
+                }
+                let persisted = ttl.remove(&key)?.is_some();
+                ttl.flush()?;
+                Ok(persisted)
+            })
+            .await?
+    }
+
+    async fn clear(self) -> Result<()> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                db.clear()?;
+                ttl.clear()?;
+                db.flush()?;
+                Ok(())
+            })
+            .await?
+    }
+
+    /// Applied in a sled transaction over the data and expiration trees.
+    async fn write_batch(self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        let db = self.db.clone();
+        let ttl = self.ttl.clone();
+        self.pool
+            .spawn_with_result(move || {
+                (&*db, &ttl)
+                    .transaction(|(db, ttl)| -> ConflictableTransactionResult<()> {
+                        for (key, value) in &writes {
+                            match value {
+                                Some(value) => db.insert(key.as_bytes(), value.as_bytes())?,
+                                None => db.remove(key.as_bytes())?,
+                            };
+                            ttl.remove(key.as_bytes())?;
+                        }
+                        Ok(())
+                    })
+                    .map_err(|e| match e {
+                        TransactionError::Abort(()) => {
+                            KvsError::StringError("Batch aborted".to_owned())
+                        }
+                        TransactionError::Storage(e) => e.into(),
+                    })?;
+                db.flush()?;
+                Ok(())
+            })
+            .await?
+    }
+
+    async fn flush(self) -> Result<()> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn engine_stats(self) -> Result<BTreeMap<String, u64>> {
+        let mut stats = BTreeMap::new();
+        stats.insert("keys".to_owned(), self.db.len() as u64);
+        stats.insert("size_on_disk".to_owned(), self.db.size_on_disk()?);
+        stats.extend(pool_stats(&self.pool));
+        Ok(stats)
+    }
+
+    async fn close(self) -> Result<()> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        stream::unfold(self.db.watch_prefix(prefix), |mut subscriber| async move {
+            let event = match (&mut subscriber).await? {
+                Event::Insert { key, value } => KeyEvent::Set {
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                    value: String::from_utf8_lossy(&value).into_owned(),
+                },
+                Event::Remove { key } => KeyEvent::Remove {
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                },
+            };
+            Some((event, subscriber))
+        })
+        .boxed()
     }
 }
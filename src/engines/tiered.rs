@@ -0,0 +1,284 @@
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{future, stream::BoxStream};
+use lru::LruCache;
+
+use crate::{KeyEvent, KvsEngine, Result, ScanOptions, ValueMeta};
+
+/// A `KvsEngine` serving hot reads from a bounded in-memory tier in front of
+/// another engine, such as `KvStore` or `SledKvsEngine`.
+///
+/// Writes go through to the inner engine and drop the key from the memory tier,
+/// so the next read fetches the new value. The tier assumes all writes go through
+/// it: a change made to the inner engine directly may be hidden by a cached value.
+#[derive(Clone)]
+pub struct TieredEngine<E: KvsEngine> {
+    inner: E,
+    cache: Arc<Mutex<Cache>>,
+    stats: Arc<Stats>,
+}
+
+/// Hit and miss counts of a `TieredEngine`'s memory tier.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TieredStats {
+    /// Reads served by the memory tier.
+    pub hits: u64,
+    /// Reads passed on to the inner engine.
+    pub misses: u64,
+}
+
+struct Cache {
+    entries: LruCache<Bytes, CachedValue>,
+    // bumped by every write, so a read racing with it doesn't cache what it replaced
+    writes: u64,
+}
+
+struct CachedValue {
+    value: Bytes,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<E: KvsEngine> TieredEngine<E> {
+    /// Wraps `inner` with a memory tier holding up to `capacity` keys.
+    pub fn new(inner: E, capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        TieredEngine {
+            inner,
+            cache: Arc::new(Mutex::new(Cache {
+                entries: LruCache::new(capacity),
+                writes: 0,
+            })),
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    /// Hit and miss counts since the engine was created, shared by all clones.
+    pub fn stats(&self) -> TieredStats {
+        TieredStats {
+            hits: self.stats.hits.load(Ordering::Relaxed),
+            misses: self.stats.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Drops `key` from the memory tier after it was written.
+    fn invalidate(&self, key: &[u8]) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.writes += 1;
+        cache.entries.pop(key);
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine> KvsEngine for TieredEngine<E> {
+    async fn set_bytes(self, key: Bytes, value: Bytes) -> Result<()> {
+        self.inner.clone().set_bytes(key.clone(), value).await?;
+        self.invalidate(&key);
+        Ok(())
+    }
+
+    async fn get_bytes(self, key: Bytes) -> Result<Option<Bytes>> {
+        let writes = {
+            let mut cache = self.cache.lock().unwrap();
+            let cached = cache
+                .entries
+                .get(&key)
+                .map(|cached| (cached.value.clone(), cached.expires_at));
+            match cached {
+                Some((value, expires_at)) if expires_at.map_or(true, |at| at > Instant::now()) => {
+                    self.stats.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(Some(value));
+                }
+                Some(_) => {
+                    cache.entries.pop(&key);
+                }
+                None => {}
+            }
+            cache.writes
+        };
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+
+        // the time to live decides how long the value may stay cached, but it can
+        // only be asked for string keys, so binary keys are never cached
+        let name = match String::from_utf8(key.to_vec()) {
+            Ok(name) => name,
+            Err(_) => return self.inner.get_bytes(key).await,
+        };
+        let started = Instant::now();
+        let (value, ttl) = future::try_join(
+            self.inner.clone().get_bytes(key.clone()),
+            self.inner.clone().ttl(name),
+        )
+        .await?;
+        if let Some(value) = &value {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.writes == writes {
+                let cached = CachedValue {
+                    value: value.clone(),
+                    expires_at: ttl.map(|ttl| started + ttl),
+                };
+                cache.entries.put(key, cached);
+            }
+        }
+        Ok(value)
+    }
+
+    async fn remove_bytes(self, key: Bytes) -> Result<()> {
+        let res = self.inner.clone().remove_bytes(key.clone()).await;
+        self.invalidate(&key);
+        res
+    }
+
+    async fn append(self, key: String, suffix: String) -> Result<u64> {
+        let res = self.inner.clone().append(key.clone(), suffix).await;
+        self.invalidate(key.as_bytes());
+        res
+    }
+
+    async fn getdel(self, key: String) -> Result<Option<String>> {
+        let res = self.inner.clone().getdel(key.clone()).await;
+        self.invalidate(key.as_bytes());
+        res
+    }
+
+    async fn getset(self, key: String, value: String) -> Result<Option<String>> {
+        let res = self.inner.clone().getset(key.clone(), value).await;
+        self.invalidate(key.as_bytes());
+        res
+    }
+
+    async fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        let res = self
+            .inner
+            .clone()
+            .compare_and_swap(key.clone(), expected, value)
+            .await;
+        self.invalidate(key.as_bytes());
+        res
+    }
+
+    async fn rename(self, from: String, to: String) -> Result<()> {
+        let res = self.inner.clone().rename(from.clone(), to.clone()).await;
+        self.invalidate(from.as_bytes());
+        self.invalidate(to.as_bytes());
+        res
+    }
+
+    async fn copy(self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        let res = self.inner.clone().copy(from, to.clone(), overwrite).await;
+        self.invalidate(to.as_bytes());
+        res
+    }
+
+    /// Scans are always served by the inner engine.
+    async fn scan(self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        self.inner.scan(options).await
+    }
+
+    async fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let res = self
+            .inner
+            .clone()
+            .set_with_ttl(key.clone(), value, ttl)
+            .await;
+        self.invalidate(key.as_bytes());
+        res
+    }
+
+    async fn ttl(self, key: String) -> Result<Option<Duration>> {
+        self.inner.ttl(key).await
+    }
+
+    async fn meta(self, key: String) -> Result<Option<ValueMeta>> {
+        self.inner.meta(key).await
+    }
+
+    async fn exists(self, key: String) -> Result<bool> {
+        self.inner.exists(key).await
+    }
+
+    async fn len(self) -> Result<u64> {
+        self.inner.len().await
+    }
+
+    async fn count_prefix(self, prefix: String) -> Result<u64> {
+        self.inner.count_prefix(prefix).await
+    }
+
+    async fn expire(self, key: String, ttl: Duration) -> Result<bool> {
+        let res = self.inner.clone().expire(key.clone(), ttl).await;
+        self.invalidate(key.as_bytes());
+        res
+    }
+
+    async fn persist(self, key: String) -> Result<bool> {
+        let res = self.inner.clone().persist(key.clone()).await;
+        self.invalidate(key.as_bytes());
+        res
+    }
+
+    async fn clear(self) -> Result<()> {
+        let res = self.inner.clone().clear().await;
+        let mut cache = self.cache.lock().unwrap();
+        cache.writes += 1;
+        cache.entries.clear();
+        res
+    }
+
+    async fn flush(self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(self) -> Result<()> {
+        self.cache.lock().unwrap().entries.clear();
+        self.inner.close().await
+    }
+
+    /// The inner engine's statistics, plus the hits and misses of the memory tier.
+    async fn engine_stats(self) -> Result<BTreeMap<String, u64>> {
+        let mut stats = self.inner.clone().engine_stats().await?;
+        let TieredStats { hits, misses } = self.stats();
+        stats.insert("cache_hits".to_owned(), hits);
+        stats.insert("cache_misses".to_owned(), misses);
+        Ok(stats)
+    }
+
+    async fn write_batch(self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        let keys: Vec<_> = writes.iter().map(|(key, _)| key.clone()).collect();
+        let res = self.inner.clone().write_batch(writes).await;
+        for key in keys {
+            self.invalidate(key.as_bytes());
+        }
+        res
+    }
+
+    /// Backs up the inner engine, which holds every write.
+    async fn backup(self, dest: PathBuf) -> Result<()> {
+        self.inner.backup(dest).await
+    }
+
+    fn watch(self, prefix: String) -> BoxStream<'static, KeyEvent> {
+        self.inner.watch(prefix)
+    }
+}
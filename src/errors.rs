@@ -29,9 +29,49 @@ pub enum KvsError {
     #[error("Sled error")]
     SledError(#[from] sled::Error),
 
+    /// RocksDB error
+    #[cfg(feature = "rocksdb")]
+    #[error("RocksDB error: {}", _0)]
+    RocksDbError(#[from] rocksdb::Error),
+
     /// Key or value is invalid UTF-8 sequence
     #[error("UTF-8 error")]
     Utf8Error(#[from] FromUtf8Error),
+
+    /// A key touched by a transaction was modified after the transaction began.
+    #[error("Transaction conflict")]
+    TransactionConflict,
+
+    /// A value is larger than the engine or server accepts.
+    #[error("Value of {size} bytes exceeds the limit of {max} bytes")]
+    ValueTooLarge {
+        /// The size of the rejected value in bytes.
+        size: usize,
+        /// The largest size accepted in bytes.
+        max: usize,
+    },
+
+    /// The engine was closed with `KvsEngine::close`.
+    #[error("Engine is closed")]
+    Closed,
+
+    /// The server didn't answer a request within the client's request timeout.
+    #[error("Request timed out")]
+    Timeout,
+
+    /// A value `KvsClient::incr` was asked to increment isn't a decimal `i64`, or
+    /// the result would overflow one.
+    #[error("Value is not an integer or out of range")]
+    NotAnInteger,
+
+    /// A job spawned with `ThreadPool::spawn_with_result` panicked, with the panic
+    /// message if it had one.
+    #[error("Job panicked: {}", _0)]
+    JobPanicked(String),
+
+    /// A job spawned with a `CancellationToken` was cancelled before it started.
+    #[error("Job cancelled")]
+    Cancelled,
 }
 
 /// Result type for kvs.
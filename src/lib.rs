@@ -1,16 +1,45 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
+mod acl;
+mod audit;
 mod client;
+mod codec;
 mod engines;
 mod errors;
 mod protocol;
+mod read_cache;
+mod resp;
 mod server;
+mod shard;
+mod slowlog;
+mod stats;
+pub mod sync;
+mod tcp;
 /// The thread pool implementation
 pub mod thread_pool;
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use acl::{Acl, AclUser};
+pub use audit::{AuditLog, AuditLogOptions};
+pub use client::{
+    BatchBuilder, BatchReply, CasOutcome, ClientMetrics, KvsClient, KvsClientBuilder, RetryPolicy,
+};
+pub use codec::{Codec, Compression};
+#[cfg(feature = "rocksdb")]
+pub use engines::RocksKvsEngine;
+pub use engines::{
+    CorruptRecord, EngineHandle, InstrumentedEngine, KeyEvent, KvStore, KvStoreOptions, KvsEngine,
+    KvsEngineExt, LatencyHistogram, MemKvsEngine, NamespacedEngine, NoopEngine, OpMetrics,
+    RemoteEngine, RouterEngine, ScanOptions, SledKvsEngine, Snapshot, StoreInfo, TieredEngine,
+    TieredStats, Transaction, ValueMeta, VerifyReport,
+};
 pub use errors::{KvsError, Result};
-pub use protocol::{Request, Response};
-pub use server::KvsServer;
+pub use protocol::{
+    feature, Chunk, KeyChange, Request, Response, ServerInfo, ServerStats, SlowLogEntry,
+    PROTOCOL_VERSION,
+};
+pub use server::{KvsServer, ServerHandle};
+pub use shard::ShardMap;
+/// The TLS library behind `KvsServer::run_tls` and `KvsClientBuilder::tls`, to build
+/// their configurations with.
+pub use tokio_rustls::rustls;
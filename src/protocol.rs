@@ -1,9 +1,70 @@
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
+use crate::{KeyEvent, ValueMeta};
+
+/// Version of the wire protocol spoken by this crate.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Names of the optional protocol features a server may advertise in `ServerInfo`.
+///
+/// Features are plain strings so that clients can parse the feature list of newer
+/// servers and ignore what they don't know.
+pub mod feature {
+    /// `Request::Append` is supported.
+    pub const APPEND: &str = "append";
+    /// `Request::Auth` is supported.
+    pub const AUTH: &str = "auth";
+    /// `Request::Ping` is supported.
+    pub const PING: &str = "ping";
+    /// `Request::Tagged` is supported, so requests can be pipelined on one connection.
+    pub const PIPELINING: &str = "pipelining";
+    /// `Request::Subscribe` is supported.
+    pub const SUBSCRIBE: &str = "subscribe";
+    /// `Request::Scan` is supported.
+    pub const SCAN: &str = "scan";
+    /// `Request::ShardMap` is supported.
+    pub const SHARD_MAP: &str = "shard-map";
+    /// `Request::SlowLog` and `Request::SlowLogReset` are supported.
+    pub const SLOWLOG: &str = "slowlog";
+    /// `Request::Stats` is supported.
+    pub const STATS: &str = "stats";
+    /// `Request::Backup` is supported, though the engine may still refuse it.
+    pub const BACKUP: &str = "backup";
+    /// `Request::Select` is supported, though the server may only have database 0.
+    pub const SELECT: &str = "select";
+    /// `Request::Multi`, `Request::Exec` and `Request::Discard` are supported, though
+    /// the engine may still refuse to execute transactions.
+    pub const TRANSACTIONS: &str = "transactions";
+    /// `Request::Batch` is supported.
+    pub const BATCH: &str = "batch";
+    /// `Request::SetNx` and `Request::SetIf` are supported.
+    pub const CONDITIONAL_SET: &str = "conditional-set";
+    /// `Request::Set` honors `ttl_ms`, and `Request::Ttl`, `Request::Expire` and
+    /// `Request::Persist` are supported.
+    pub const TTL: &str = "ttl";
+    /// `Request::Streamed` is supported.
+    pub const STREAMING: &str = "streaming";
+    /// `Request::GetMeta` is supported.
+    pub const GET_META: &str = "get-meta";
+    /// `Request::Echo` is supported.
+    pub const ECHO: &str = "echo";
+    /// `Request::Hello` honors `checksums`.
+    pub const CHECKSUMS: &str = "checksums";
+    /// `Request::FlushAll` is supported.
+    pub const FLUSH_ALL: &str = "flush-all";
+    /// `Request::Len` and `Request::Exists` are supported.
+    pub const KEY_COUNT: &str = "key-count";
+    /// `Request::Rename` and `Request::Copy` are supported, though the engine may
+    /// still refuse them.
+    pub const RENAME: &str = "rename";
+}
+
 /// Represents the various types of requests that can be sent from a client to a key-value store server.
 ///
 /// Requests include operations like getting a value for a given key, setting a key-value pair, or removing a key.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
     /// Request to get the value associated with a specific key.
     Get {
@@ -16,12 +77,281 @@ pub enum Request {
         key: String,
         /// The value to associate with the key.
         value: String,
+        /// How many milliseconds the key lives, or None to keep it until removed.
+        /// Servers without `feature::TTL` ignore it.
+        #[serde(default)]
+        ttl_ms: Option<u64>,
     },
     /// Request to remove a key and its associated value from the store.
     Remove {
         /// The key to be removed.
         key: String,
     },
+    /// Request to append a suffix to the value of a key, creating it if absent.
+    Append {
+        /// The key whose value is extended.
+        key: String,
+        /// The string appended to the current value.
+        suffix: String,
+    },
+    /// Request for how long a key has left to live.
+    Ttl {
+        /// The key to look up.
+        key: String,
+    },
+    /// Request to atomically move the value of a key, with its expiration, to another
+    /// key, replacing any value that key had.
+    Rename {
+        /// The key to rename, which must exist.
+        from: String,
+        /// The new name of the key.
+        to: String,
+    },
+    /// Request to atomically copy the value of a key, with its expiration, to another
+    /// key, answered with whether it was copied.
+    Copy {
+        /// The key to copy.
+        from: String,
+        /// The key to copy to.
+        to: String,
+        /// Replace the value `to` already has instead of leaving it alone.
+        #[serde(default)]
+        overwrite: bool,
+    },
+    /// Request for whether a key exists, without its value.
+    Exists {
+        /// The key to look up.
+        key: String,
+    },
+    /// Request for the number of keys in the connection's database.
+    Len,
+    /// Request for the length, modification time and time to live of a key's value,
+    /// without the value itself.
+    GetMeta {
+        /// The key to look up.
+        key: String,
+    },
+    /// Request to make an existing key expire, replacing any earlier expiration.
+    Expire {
+        /// The key to expire.
+        key: String,
+        /// How many milliseconds the key has left to live.
+        ttl_ms: u64,
+    },
+    /// Request to remove the expiration of a key, keeping it until removed.
+    Persist {
+        /// The key to make permanent.
+        key: String,
+    },
+    /// Request to set a key only if it doesn't exist, answered with whether it was set.
+    SetNx {
+        /// The key to set.
+        key: String,
+        /// The value to associate with the key.
+        value: String,
+    },
+    /// Request to set a key only if its current value is `expected`, answered with
+    /// whether it was set.
+    SetIf {
+        /// The key to set.
+        key: String,
+        /// The value the key must have for the write to happen.
+        expected: String,
+        /// The value to associate with the key.
+        value: String,
+    },
+    /// Request to negotiate the protocol version and learn about the server.
+    Hello {
+        /// The highest protocol version the client speaks.
+        protocol_version: u32,
+        /// Names of the codecs the client would rather speak than JSON, most preferred
+        /// first. Only honored when the request isn't tagged.
+        #[serde(default)]
+        codecs: Vec<String>,
+        /// Names of the frame compressions the client can use, most preferred first.
+        /// Only honored when the request isn't tagged.
+        #[serde(default)]
+        compressions: Vec<String>,
+        /// Whether the client wants every frame to end with a CRC-32 of the rest of
+        /// it, so corruption on the way is detected. Only honored when the request
+        /// isn't tagged.
+        #[serde(default)]
+        checksums: bool,
+    },
+    /// Request to authenticate the connection with the server's password.
+    ///
+    /// Servers started with a password answer every other request except `Hello`
+    /// with an error until the connection is authenticated.
+    Auth {
+        /// The user to authenticate as, or None for the server's shared password.
+        #[serde(default)]
+        username: Option<String>,
+        /// The password of the user, or the shared password the server was configured with.
+        token: String,
+    },
+    /// Request to check that the server is up, answered without touching the engine.
+    ///
+    /// Allowed before authenticating, so health checks need no credentials.
+    Ping,
+    /// Request to send a payload back unchanged, for measuring round trips and
+    /// debugging codecs. Unlike `Ping` it goes through authentication and is counted
+    /// and traced like any other request.
+    Echo(Vec<u8>),
+    /// Request to list a page of the key/value pairs whose keys start with a prefix,
+    /// in ascending key order.
+    Scan {
+        /// Where to continue: the `next_cursor` of the previous page, or None for the first.
+        cursor: Option<String>,
+        /// The prefix of the keys to list. Empty lists every key.
+        prefix: String,
+        /// The most pairs to return. The server may return fewer.
+        limit: usize,
+    },
+    /// Request to list the shards a router forwards keys to, so clients can build the
+    /// same `ShardMap` and talk to the shards directly.
+    ShardMap,
+    /// Request to list the newest requests the server found slow, newest first.
+    ///
+    /// Only allowed for connections not limited by the ACL, as entries name any key.
+    SlowLog {
+        /// The most entries to return.
+        count: usize,
+    },
+    /// Request to empty the slow request log.
+    SlowLogReset,
+    /// Request for the server's statistics.
+    ///
+    /// Only allowed for connections not limited by the ACL.
+    Stats,
+    /// Request to write a consistent copy of the store into a directory on the
+    /// server, without stopping it. Answered once the copy is complete.
+    ///
+    /// Only allowed for connections not limited by the ACL.
+    Backup {
+        /// The directory to write to, relative to the server's working directory.
+        /// It's created if needed and must not already contain a store.
+        path: PathBuf,
+    },
+    /// Request to remove every key of the connection's database.
+    ///
+    /// Only allowed for connections not limited by the ACL.
+    FlushAll,
+    /// Many `Get`, `Set`, `Remove`, `Append` and `Scan` requests sent in one frame,
+    /// answered with a `Response::Batch` in the same order.
+    ///
+    /// Consecutive reads run concurrently and consecutive sets are written as one
+    /// engine batch, so later requests see the effects of earlier ones. The batch is
+    /// not atomic: each request succeeds or fails on its own.
+    Batch(Vec<Request>),
+    /// Request to start a transaction. Until `Exec` or `Discard`, `Set` and `Remove`
+    /// requests are answered with `Response::Queued` instead of running, and any other
+    /// request is refused.
+    Multi,
+    /// Request to apply the writes queued since `Multi` as one atomic batch.
+    ///
+    /// Fails without writing anything if a request was refused while queueing.
+    Exec,
+    /// Request to drop the writes queued since `Multi`, ending the transaction.
+    Discard,
+    /// Request to switch the connection to another numbered database. Every
+    /// connection starts in database 0.
+    Select {
+        /// The number of the database, below the number the server was configured with.
+        db: u32,
+    },
+    /// Request to answer a `Get` or `Scan` with a series of `Response::Chunk`s ending
+    /// in `Response::End`, so values and scans of any size fit in bounded frames.
+    ///
+    /// A streamed `Get` sends no chunk if the key doesn't exist. A streamed `Scan`
+    /// ignores the page size of the server, returning up to `limit` pairs from
+    /// `cursor` on; pairs are never split, so a pair too large for a frame must be
+    /// read with a streamed `Get`. An error ends the stream with `Response::Err`
+    /// instead of `End`.
+    /// Streamed requests cannot be tagged.
+    Streamed(Box<Request>),
+    /// Request to be notified of changes to every key starting with a prefix.
+    ///
+    /// The server confirms with `Response::Subscribed` and then pushes a
+    /// `Response::Notification` for each change until the connection closes. Only
+    /// `Ping` is answered on a subscribed connection, other requests fail.
+    Subscribe {
+        /// The prefix of the keys to watch. Empty watches every key.
+        prefix: String,
+    },
+    /// A request carrying an ID, answered with a `Response::Tagged` with the same ID.
+    ///
+    /// Tagged requests that reach the engine run concurrently, so their responses may
+    /// arrive in a different order than the requests were sent. Untagged requests are
+    /// still answered one at a time, in order.
+    Tagged {
+        /// The ID chosen by the client, echoed in the response.
+        id: u64,
+        /// The request to run, which may not be tagged itself.
+        request: Box<Request>,
+    },
+}
+
+impl Request {
+    /// Whether sending the request again after its connection broke can't change
+    /// the outcome, because it only reads or sets up the connection.
+    pub(crate) fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Request::Get { .. }
+                | Request::GetMeta { .. }
+                | Request::Ttl { .. }
+                | Request::Exists { .. }
+                | Request::Len
+                | Request::Scan { .. }
+                | Request::Hello { .. }
+                | Request::Auth { .. }
+                | Request::Ping
+                | Request::Echo(_)
+                | Request::ShardMap
+                | Request::SlowLog { .. }
+                | Request::Stats
+                | Request::Select { .. }
+        )
+    }
+
+    /// The name the request is reported under, such as `"get"`, after the
+    /// request it wraps for `Tagged` and `Streamed`.
+    pub(crate) fn op_name(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Remove { .. } => "remove",
+            Request::Append { .. } => "append",
+            Request::Ttl { .. } => "ttl",
+            Request::Rename { .. } => "rename",
+            Request::Copy { .. } => "copy",
+            Request::Exists { .. } => "exists",
+            Request::Len => "len",
+            Request::GetMeta { .. } => "getmeta",
+            Request::Expire { .. } => "expire",
+            Request::Persist { .. } => "persist",
+            Request::SetNx { .. } => "setnx",
+            Request::SetIf { .. } => "setif",
+            Request::Hello { .. } => "hello",
+            Request::Auth { .. } => "auth",
+            Request::Ping => "ping",
+            Request::Echo(_) => "echo",
+            Request::Scan { .. } => "scan",
+            Request::ShardMap => "shardmap",
+            Request::SlowLog { .. } => "slowlog",
+            Request::SlowLogReset => "slowlogreset",
+            Request::Stats => "stats",
+            Request::Backup { .. } => "backup",
+            Request::FlushAll => "flushall",
+            Request::Batch(_) => "batch",
+            Request::Multi => "multi",
+            Request::Exec => "exec",
+            Request::Discard => "discard",
+            Request::Select { .. } => "select",
+            Request::Subscribe { .. } => "subscribe",
+            Request::Streamed(request) | Request::Tagged { request, .. } => request.op_name(),
+        }
+    }
 }
 
 /// Represents the various types of responses that can be sent from a server to a key-value store client.
@@ -41,6 +371,212 @@ pub enum Response {
     ///
     /// The response can either be successful or an error message.
     Remove,
+    /// Represents the response to an 'Append' request from the key-value store server.
+    ///
+    /// Carries the length of the new value in bytes.
+    Append(u64),
+    /// Represents the response to a 'Ttl' request from the key-value store server.
+    ///
+    /// Carries the milliseconds the key has left to live, or None if it doesn't exist
+    /// or never expires.
+    Ttl(Option<u64>),
+    /// Represents the response to a successful 'Rename' request from the key-value
+    /// store server.
+    Rename,
+    /// Represents the response to a 'Copy' request from the key-value store server.
+    ///
+    /// Carries whether the key was copied, which it isn't if the source doesn't exist
+    /// or the destination does and may not be overwritten.
+    Copy(bool),
+    /// Represents the response to an 'Exists' request from the key-value store server.
+    Exists(bool),
+    /// Represents the response to a 'Len' request from the key-value store server.
+    ///
+    /// Carries the number of keys.
+    Len(u64),
+    /// Represents the response to a 'GetMeta' request from the key-value store server.
+    ///
+    /// Carries the metadata of the value, or None if the key doesn't exist.
+    GetMeta(Option<ValueMeta>),
+    /// Represents the response to an 'Expire' request from the key-value store server.
+    ///
+    /// Carries whether the key exists.
+    Expire(bool),
+    /// Represents the response to a 'Persist' request from the key-value store server.
+    ///
+    /// Carries whether the key existed and had an expiration.
+    Persist(bool),
+    /// Represents the response to a 'SetNx' request from the key-value store server.
+    ///
+    /// Carries whether the key was set, which it isn't if it already existed.
+    SetNx(bool),
+    /// Represents the response to a 'SetIf' request from the key-value store server.
+    ///
+    /// Carries whether the key was set, which it isn't if its value didn't match.
+    SetIf(bool),
+    /// Represents the response to a 'Hello' request from the key-value store server.
+    Hello(ServerInfo),
+    /// Represents the response to a successful 'Auth' request from the key-value store server.
+    Auth,
+    /// Represents the response to a 'Ping' request from the key-value store server.
+    Pong,
+    /// Represents the response to an 'Echo' request from the key-value store server.
+    ///
+    /// Carries the payload of the request.
+    Echo(Vec<u8>),
+    /// Represents the response to a 'Scan' request from the key-value store server.
+    Scan {
+        /// The pairs of the page, ordered by key.
+        pairs: Vec<(String, String)>,
+        /// The cursor of the next page, or None if this was the last one.
+        next_cursor: Option<String>,
+    },
+    /// Represents the response to a 'ShardMap' request from the key-value store server.
+    ///
+    /// Carries the shard addresses, to pass to `ShardMap::new`.
+    ShardMap(Vec<SocketAddr>),
+    /// Represents the response to a 'SlowLog' request from the key-value store server.
+    SlowLog(Vec<SlowLogEntry>),
+    /// Represents the response to a 'SlowLogReset' request from the key-value store server.
+    SlowLogReset,
+    /// Represents the response to a 'Stats' request from the key-value store server.
+    Stats(ServerStats),
+    /// Represents the response to a 'Backup' request from the key-value store server.
+    Backup,
+    /// Represents the response to a 'FlushAll' request from the key-value store server.
+    FlushAll,
+    /// Represents the response to a 'Batch' request from the key-value store server.
+    ///
+    /// Carries the response to each request of the batch, in order.
+    Batch(Vec<Response>),
+    /// Represents the response to a 'Multi' request from the key-value store server.
+    Multi,
+    /// A request queued by a transaction, to run on `Exec`.
+    Queued,
+    /// Represents the response to an 'Exec' request from the key-value store server.
+    ///
+    /// Carries the response to each queued request, in order.
+    Exec(Vec<Response>),
+    /// Represents the response to a 'Discard' request from the key-value store server.
+    Discard,
+    /// Represents the response to a 'Select' request from the key-value store server.
+    Select,
+    /// A piece of the response to a 'Streamed' request.
+    Chunk(Chunk),
+    /// The end of the response to a 'Streamed' request.
+    End,
+    /// Represents the response to a 'Subscribe' request from the key-value store server.
+    Subscribed,
+    /// A change to a watched key, pushed to subscribed connections.
+    Notification {
+        /// The key that changed.
+        key: String,
+        /// What happened to the key.
+        event: KeyChange,
+    },
+    /// Represents the response to a 'Tagged' request from the key-value store server.
+    Tagged {
+        /// The ID of the request this answers.
+        id: u64,
+        /// The response to the request.
+        response: Box<Response>,
+    },
     /// Error response with a message indicating the reason for the failure.
     Err(String),
 }
+
+/// A piece of a streamed response, as sent in a `Response::Chunk`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Chunk {
+    /// The next part of the value of a streamed `Get`, to append to the earlier ones.
+    Value(String),
+    /// The next pairs of a streamed `Scan`, ordered by key.
+    Pairs(Vec<(String, String)>),
+}
+
+/// What happened to a key, as pushed in a `Response::Notification`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyChange {
+    /// The key was set to this value.
+    Set(String),
+    /// The key was removed.
+    Remove,
+}
+
+impl From<KeyEvent> for Response {
+    fn from(event: KeyEvent) -> Self {
+        match event {
+            KeyEvent::Set { key, value } => Response::Notification {
+                key,
+                event: KeyChange::Set(value),
+            },
+            KeyEvent::Remove { key } => Response::Notification {
+                key,
+                event: KeyChange::Remove,
+            },
+        }
+    }
+}
+
+/// A request that took longer than the server's slow log threshold.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlowLogEntry {
+    /// Increases by one with every logged request.
+    pub id: u64,
+    /// When the request completed, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// How long the engine took to answer.
+    pub duration: Duration,
+    /// The kind of request, such as "get" or "scan".
+    pub op: String,
+    /// The key or prefix the request was about, if any.
+    pub key: Option<String>,
+    /// The client that sent the request, or None for a Unix socket.
+    pub peer: Option<SocketAddr>,
+}
+
+/// Statistics of a running server, returned for `Request::Stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// How long the server has been running.
+    pub uptime: Duration,
+    /// Connections currently open.
+    pub connections: u64,
+    /// Connections accepted since the server started.
+    pub total_connections: u64,
+    /// Requests answered by the engine since the server started, by operation.
+    pub ops: BTreeMap<String, u64>,
+    /// Statistics reported by the engine, see `KvsEngine::engine_stats`.
+    pub engine: BTreeMap<String, u64>,
+    /// Total size of the files in the data directory, if the server has one.
+    pub data_dir_bytes: Option<u64>,
+}
+
+/// Information about a server, returned from the protocol handshake.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// The protocol version both sides agreed on: the lower of the two versions spoken.
+    pub protocol_version: u32,
+    /// The version of the server software.
+    pub server_version: String,
+    /// The optional features the server supports, see the `feature` module.
+    pub features: Vec<String>,
+    /// The codec the connection switched to after the handshake, or None if it keeps
+    /// speaking JSON.
+    #[serde(default)]
+    pub codec: Option<String>,
+    /// The compression frames use after the handshake, or None if they stay
+    /// uncompressed.
+    #[serde(default)]
+    pub compression: Option<String>,
+    /// Whether frames carry checksums after the handshake.
+    #[serde(default)]
+    pub checksums: bool,
+}
+
+impl ServerInfo {
+    /// Returns whether the server advertised the given feature.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::stream::{BoxStream, StreamExt};
+use lru::LruCache;
+use tokio::task::JoinHandle;
+
+use crate::{KeyChange, Request, Result};
+
+/// The values a client read recently, so repeated reads of hot keys skip the
+/// network.
+pub(crate) struct ReadCache {
+    entries: Mutex<Entries>,
+    max_age: Duration,
+}
+
+struct Entries {
+    values: LruCache<String, Cached>,
+    // bumped by every invalidation, so a read racing with one doesn't cache what
+    // it replaced
+    generation: u64,
+}
+
+struct Cached {
+    // None for a key that didn't exist
+    value: Option<String>,
+    fetched_at: Instant,
+}
+
+impl ReadCache {
+    /// Hold up to `capacity` keys, each for at most `max_age`.
+    pub(crate) fn new(capacity: usize, max_age: Duration) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        ReadCache {
+            entries: Mutex::new(Entries {
+                values: LruCache::new(capacity),
+                generation: 0,
+            }),
+            max_age,
+        }
+    }
+
+    /// The cached value of `key`, or None if it has to be read from the server.
+    pub(crate) fn get(&self, key: &str) -> Option<Option<String>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.values.get(key) {
+            Some(cached) if cached.fetched_at.elapsed() < self.max_age => {
+                Some(cached.value.clone())
+            }
+            Some(_) => {
+                entries.values.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// The generation to pass to `insert` for a read starting now.
+    pub(crate) fn generation(&self) -> u64 {
+        self.entries.lock().unwrap().generation
+    }
+
+    /// Cache `value` for `key`, unless an invalidation happened since `generation`.
+    pub(crate) fn insert(&self, key: String, value: Option<String>, generation: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation == generation {
+            let fetched_at = Instant::now();
+            entries.values.put(key, Cached { value, fetched_at });
+        }
+    }
+
+    /// Drop `key` after it was written.
+    pub(crate) fn invalidate(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.values.pop(key);
+    }
+
+    /// Drop every key.
+    pub(crate) fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.values.clear();
+    }
+
+    /// Drop what `req` is about to change.
+    pub(crate) fn invalidate_request(&self, req: &Request) {
+        match req {
+            Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::Append { key, .. }
+            | Request::Expire { key, .. }
+            | Request::SetNx { key, .. }
+            | Request::SetIf { key, .. }
+            | Request::Copy { to: key, .. } => self.invalidate(key),
+            Request::Rename { from, to } => {
+                self.invalidate(from);
+                self.invalidate(to);
+            }
+            Request::Batch(reqs) => reqs.iter().for_each(|req| self.invalidate_request(req)),
+            Request::Tagged { request, .. } | Request::Streamed(request) => {
+                self.invalidate_request(request)
+            }
+            // the keys visible to the connection change
+            Request::FlushAll | Request::Select { .. } | Request::Auth { .. } => self.clear(),
+            _ => {}
+        }
+    }
+}
+
+/// A task dropping the keys the server reports changed from a `ReadCache`, stopped
+/// when dropped.
+pub(crate) struct Invalidator {
+    task: JoinHandle<()>,
+}
+
+impl Invalidator {
+    /// Invalidate the keys of `notifications` until they end.
+    pub(crate) fn spawn(
+        cache: Arc<ReadCache>,
+        mut notifications: BoxStream<'static, Result<(String, KeyChange)>>,
+    ) -> Self {
+        let task = tokio::spawn(async move {
+            while let Some(Ok((key, _))) = notifications.next().await {
+                cache.invalidate(&key);
+            }
+            // changes are missed from now on, so nothing cached can be trusted
+            cache.clear();
+        });
+        Invalidator { task }
+    }
+
+    /// Whether the notifications ended, e.g. because the connection broke.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl Drop for Invalidator {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
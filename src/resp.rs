@@ -0,0 +1,380 @@
+//! A frontend speaking a subset of the Redis serialization protocol (RESP), so
+//! `redis-cli` and Redis client libraries can talk to a `KvsServer`.
+//!
+//! Supported commands: `PING`, `GET`, `SET` (with `EX`/`PX`), `DEL`, `EXISTS`,
+//! `AUTH`, `QUIT` and an empty `COMMAND` reply for client introspection.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use crate::{
+    audit::{Audited, Caller},
+    server::{idle, Connection},
+    AclUser, KvsEngine, KvsError, Result,
+};
+
+// longest bulk string or command accepted, as in Redis
+const MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+// longest inline command or array and bulk string header, as in Redis
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// A reply to a RESP command.
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Bytes>),
+    EmptyArray,
+}
+
+/// Serve RESP commands from `stream` until the client quits or the server shuts down,
+/// auditing writes as the peer of `conn` and the user it authenticates as. Commands
+/// larger than the request size limit are refused before they're read, closing the
+/// connection.
+pub(crate) async fn serve<E, S>(
+    engine: E,
+    stream: S,
+    conn: Connection,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    E: KvsEngine,
+    S: AsyncRead + AsyncWrite,
+{
+    let Connection {
+        auth,
+        idle_timeout,
+        max_request_size,
+        max_value_size,
+        peer,
+        audit,
+        ..
+    } = conn;
+    let (read_half, mut write_half) = io::split(stream);
+    let mut reader = BufReader::new(read_half);
+
+    let mut authenticated = auth.password.is_none() && auth.acl.is_none();
+    let mut user: Option<AclUser> = None;
+    let audited = |user: Option<&AclUser>| {
+        let caller = Caller {
+            peer,
+            user: user.map(|user| user.name.clone()),
+            db: 0,
+        };
+        Audited::new(engine.clone(), audit.clone(), caller)
+    };
+    let mut engine = audited(None);
+    loop {
+        let args = tokio::select! {
+            args = read_command(&mut reader, max_request_size) => match args {
+                Ok(Some(args)) => args,
+                Ok(None) => break,
+                // the rest of the command can't be skipped, so the connection ends here
+                Err(e @ KvsError::StringError(_)) => {
+                    write_reply(&mut write_half, Reply::Error(format!("ERR {}", e))).await?;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            },
+            _ = idle(idle_timeout) => {
+                debug!("Closing idle connection");
+                break;
+            }
+            _ = shutdown.cancelled() => {
+                debug!("Server shutting down, closing connection");
+                break;
+            }
+        };
+        let name = match args.first() {
+            Some(name) => String::from_utf8_lossy(name).to_ascii_uppercase(),
+            None => continue,
+        };
+
+        let reply = match name.as_str() {
+            "QUIT" => {
+                write_reply(&mut write_half, Reply::Simple("OK")).await?;
+                break;
+            }
+            "PING" => match &args[1..] {
+                [] => Reply::Simple("PONG"),
+                [message] => Reply::Bulk(Some(message.clone())),
+                _ => wrong_arguments(&name),
+            },
+            "AUTH" => {
+                let (username, token) = match &args[1..] {
+                    [token] => (None, token),
+                    [username, token] => (Some(String::from_utf8_lossy(username)), token),
+                    _ => {
+                        write_reply(&mut write_half, wrong_arguments(&name)).await?;
+                        continue;
+                    }
+                };
+                match auth.login(username.as_deref(), &String::from_utf8_lossy(token)) {
+                    Ok(login) => {
+                        authenticated = true;
+                        user = login;
+                        engine = audited(user.as_ref());
+                        Reply::Simple("OK")
+                    }
+                    Err(e) => Reply::Error(format!("WRONGPASS {}", e)),
+                }
+            }
+            "COMMAND" => Reply::EmptyArray,
+            _ if !authenticated => Reply::Error("NOAUTH Authentication required".to_owned()),
+            _ => match execute(
+                engine.clone(),
+                user.as_ref(),
+                max_value_size,
+                &name,
+                &args[1..],
+            )
+            .await
+            {
+                Ok(reply) => reply,
+                Err(e) => Reply::Error(format!("ERR {}", e)),
+            },
+        };
+        write_reply(&mut write_half, reply).await?;
+    }
+
+    Ok(())
+}
+
+/// Run a data command, checking `user` may access every key it touches and that a
+/// value it sets is at most `max_value_size` bytes.
+async fn execute<E: KvsEngine>(
+    engine: E,
+    user: Option<&AclUser>,
+    max_value_size: Option<usize>,
+    name: &str,
+    args: &[Bytes],
+) -> Result<Reply> {
+    let allowed = |key: &Bytes, write: bool| {
+        let key = String::from_utf8_lossy(key);
+        user.map_or(true, |user| {
+            if write {
+                user.can_write(&key)
+            } else {
+                user.can_read(&key)
+            }
+        })
+    };
+    let denied = || Ok(Reply::Error("NOPERM Permission denied".to_owned()));
+
+    match (name, args) {
+        ("GET", [key]) => {
+            if !allowed(key, false) {
+                return denied();
+            }
+            Ok(Reply::Bulk(engine.get_bytes(key.clone()).await?))
+        }
+        ("SET", [key, value, options @ ..]) => {
+            if !allowed(key, true) {
+                return denied();
+            }
+            if let Some(max) = max_value_size.filter(|&max| value.len() > max) {
+                return Err(KvsError::ValueTooLarge {
+                    size: value.len(),
+                    max,
+                });
+            }
+            match options {
+                [] => engine.set_bytes(key.clone(), value.clone()).await?,
+                [unit, amount] => {
+                    let ttl = match parse_ttl(unit, amount) {
+                        Some(ttl) => ttl,
+                        None => return Ok(Reply::Error("ERR syntax error".to_owned())),
+                    };
+                    // engines only take text keys and values along with a TTL
+                    let (key, value) = match (
+                        String::from_utf8(key.to_vec()),
+                        String::from_utf8(value.to_vec()),
+                    ) {
+                        (Ok(key), Ok(value)) => (key, value),
+                        _ => {
+                            return Ok(Reply::Error(
+                                "ERR EX and PX need a key and value that are valid UTF-8"
+                                    .to_owned(),
+                            ))
+                        }
+                    };
+                    engine.set_with_ttl(key, value, ttl).await?;
+                }
+                _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+            }
+            Ok(Reply::Simple("OK"))
+        }
+        ("DEL", keys) if !keys.is_empty() => {
+            if !keys.iter().all(|key| allowed(key, true)) {
+                return denied();
+            }
+            let mut removed = 0;
+            for key in keys {
+                match engine.clone().remove_bytes(key.clone()).await {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Reply::Integer(removed))
+        }
+        ("EXISTS", keys) if !keys.is_empty() => {
+            if !keys.iter().all(|key| allowed(key, false)) {
+                return denied();
+            }
+            let mut found = 0;
+            for key in keys {
+                let exists = match std::str::from_utf8(key) {
+                    Ok(key) => engine.clone().exists(key.to_owned()).await?,
+                    // `exists` only takes text keys, a binary one is looked up instead
+                    Err(_) => engine.clone().get_bytes(key.clone()).await?.is_some(),
+                };
+                if exists {
+                    found += 1;
+                }
+            }
+            Ok(Reply::Integer(found))
+        }
+        ("GET" | "SET" | "DEL" | "EXISTS", _) => Ok(wrong_arguments(name)),
+        _ => Ok(Reply::Error(format!("ERR unknown command '{}'", name))),
+    }
+}
+
+/// The time to live given by the `EX seconds` or `PX milliseconds` option of `SET`.
+fn parse_ttl(unit: &[u8], amount: &[u8]) -> Option<Duration> {
+    let amount: u64 = std::str::from_utf8(amount).ok()?.parse().ok()?;
+    match unit.to_ascii_uppercase().as_slice() {
+        b"EX" if amount > 0 => Some(Duration::from_secs(amount)),
+        b"PX" if amount > 0 => Some(Duration::from_millis(amount)),
+        _ => None,
+    }
+}
+
+fn wrong_arguments(name: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+/// Read the next command, either an array of bulk strings or an inline command,
+/// refusing it once it adds up to more than `max_size` bytes.
+/// Returns None once the client closed the connection.
+async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<Option<Vec<Bytes>>> {
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match line.strip_prefix('*') {
+        Some(count) => parse_length(count, MAX_ARGUMENTS)?,
+        None => {
+            let args = line
+                .split_whitespace()
+                .map(|arg| Bytes::copy_from_slice(arg.as_bytes()))
+                .collect();
+            return Ok(Some(args));
+        }
+    };
+
+    let mut size = line.len() + 2;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(reader)
+            .await?
+            .ok_or_else(|| protocol_error("unexpected end of stream"))?;
+        let length = match line.strip_prefix('$') {
+            Some(length) => parse_length(length, MAX_BULK_LENGTH)?,
+            None => return Err(protocol_error("expected a bulk string")),
+        };
+        size += line.len() + length + 4;
+        if size > max_size {
+            return Err(protocol_error(&format!(
+                "command exceeds the limit of {} bytes",
+                max_size
+            )));
+        }
+        // grows as the bytes arrive, so a length the client doesn't send costs nothing
+        let mut arg = Vec::new();
+        (&mut *reader)
+            .take(length as u64 + 2)
+            .read_to_end(&mut arg)
+            .await?;
+        if arg.len() < length + 2 {
+            return Err(protocol_error("unexpected end of stream"));
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(length);
+        args.push(arg.into());
+    }
+    Ok(Some(args))
+}
+
+/// Read a CRLF terminated line of at most `MAX_LINE_LENGTH` bytes, without the
+/// terminator.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_LINE_LENGTH as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() > MAX_LINE_LENGTH {
+            return Err(protocol_error("line too long"));
+        }
+        return Err(protocol_error("unexpected end of stream"));
+    }
+    let mut line = String::from_utf8(line).map_err(|_| protocol_error("invalid UTF-8"))?;
+    let trimmed = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(trimmed);
+    Ok(Some(line))
+}
+
+fn parse_length(length: &str, max: usize) -> Result<usize> {
+    match length.parse() {
+        Ok(length) if length <= max => Ok(length),
+        _ => Err(protocol_error("invalid length")),
+    }
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::StringError(format!("Protocol error: {}", message))
+}
+
+async fn write_reply<W: AsyncWrite + Unpin>(writer: &mut W, reply: Reply) -> Result<()> {
+    let mut out = Vec::new();
+    match reply {
+        Reply::Simple(message) => out.extend_from_slice(format!("+{}\r\n", message).as_bytes()),
+        Reply::Error(message) => {
+            // a line break would end the error early and desync the client
+            let message = message.replace(['\r', '\n'], " ");
+            out.extend_from_slice(format!("-{}\r\n", message).as_bytes())
+        }
+        Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+        Reply::Bulk(Some(value)) => {
+            out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+            out.extend_from_slice(&value);
+            out.extend_from_slice(b"\r\n");
+        }
+        Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+        Reply::EmptyArray => out.extend_from_slice(b"*0\r\n"),
+    }
+    writer.write_all(&out).await?;
+    writer.flush().await?;
+    Ok(())
+}
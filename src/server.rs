@@ -1,71 +1,1598 @@
-use std::net::SocketAddr;
+#[cfg(unix)]
+use std::{fs, os::unix::fs::FileTypeExt};
+use std::{
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use futures::{SinkExt, StreamExt, TryFutureExt};
-use log::error;
+use futures::{
+    future,
+    stream::{BoxStream, FuturesUnordered, Peekable},
+    Sink, SinkExt, Stream, StreamExt,
+};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::{
-    io,
+    io::{self, AsyncRead, AsyncWrite, DuplexStream},
     net::{TcpListener, TcpStream},
+    sync::{mpsc, Semaphore},
+    task::{self, JoinHandle},
+    time,
+};
+use tokio_rustls::{rustls::ServerConfig, server::TlsStream, TlsAcceptor};
+use tokio_serde::SymmetricallyFramed;
+use tokio_util::{
+    codec::{FramedRead, FramedWrite, LengthDelimitedCodec, LengthDelimitedCodecError},
+    sync::CancellationToken,
+};
+use tracing::{debug, debug_span, error, field, info_span, trace, warn, Instrument};
+
+use crate::{
+    audit::{Audited, Caller},
+    codec::{Codec, CodecSwitch, Compression},
+    protocol::{feature, Chunk, ServerInfo, ServerStats, PROTOCOL_VERSION},
+    resp,
+    slowlog::SlowLog,
+    stats::{dir_size, Counters},
+    tcp::{ListenOptions, TcpOptions},
+    Acl, AclUser, AuditLog, KeyEvent, KvsEngine, KvsError, NamespacedEngine, Request, Response,
+    Result, ScanOptions, ShardMap,
 };
-use tokio_serde::{formats::SymmetricalJson, SymmetricallyFramed};
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
-use crate::{KvsEngine, Request, Response, Result};
+/// The optional protocol features this server implements.
+const FEATURES: &[&str] = &[
+    feature::APPEND,
+    feature::AUTH,
+    feature::PING,
+    feature::PIPELINING,
+    feature::SUBSCRIBE,
+    feature::SCAN,
+    feature::SHARD_MAP,
+    feature::SLOWLOG,
+    feature::STATS,
+    feature::BACKUP,
+    feature::SELECT,
+    feature::TRANSACTIONS,
+    feature::BATCH,
+    feature::CONDITIONAL_SET,
+    feature::TTL,
+    feature::STREAMING,
+    feature::GET_META,
+    feature::ECHO,
+    feature::CHECKSUMS,
+    feature::FLUSH_ALL,
+    feature::KEY_COUNT,
+    feature::RENAME,
+];
+
+/// The most tagged requests one connection may have running on the engine at once.
+/// Further requests are left unread until one of them completes.
+const MAX_IN_FLIGHT: usize = 128;
+
+/// The most pairs returned for one `Request::Scan`.
+const MAX_SCAN_PAGE: usize = 1000;
+
+/// The size in bytes a `Response::Chunk` is filled up to.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Requests taking at least this long are logged by default, as in Redis.
+const DEFAULT_SLOWLOG_THRESHOLD: Duration = Duration::from_millis(10);
+/// How many slow requests are kept by default.
+const DEFAULT_SLOWLOG_MAX_LEN: usize = 128;
+
+/// The largest request frame accepted by default, in bytes.
+const DEFAULT_MAX_REQUEST_SIZE: usize = 8 * 1024 * 1024;
+/// How long the rest of an oversized request is read and discarded before closing.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a client may take to finish the TLS handshake, unless the idle timeout
+/// is shorter.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long accepting pauses after the first failure, doubling on each further one.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+/// The longest pause between failing accepts.
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
 
 /// The server of the key value store.
+#[derive(Clone)]
 pub struct KvsServer<T: KvsEngine> {
     engine: T,
+    max_connections: usize,
+    auth: Auth,
+    shards: Option<Arc<ShardMap>>,
+    slowlog: SlowLog,
+    tcp: TcpOptions,
+    listen_options: ListenOptions,
+    idle_timeout: Option<Duration>,
+    max_request_size: usize,
+    max_value_size: Option<usize>,
+    counters: Arc<Counters>,
+    data_dir: Option<Arc<PathBuf>>,
+    backup_dir: Option<Arc<PathBuf>>,
+    databases: u32,
+    audit: Option<AuditLog>,
+}
+
+/// What a connection needs besides the engine.
+pub(crate) struct Connection {
+    pub(crate) auth: Auth,
+    pub(crate) shards: Option<Arc<ShardMap>>,
+    pub(crate) slowlog: SlowLog,
+    // the client's address, or None for a Unix socket
+    pub(crate) peer: Option<SocketAddr>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) max_request_size: usize,
+    pub(crate) max_value_size: Option<usize>,
+    pub(crate) counters: Arc<Counters>,
+    pub(crate) data_dir: Option<Arc<PathBuf>>,
+    pub(crate) backup_dir: Option<Arc<PathBuf>>,
+    pub(crate) databases: u32,
+    pub(crate) audit: Option<AuditLog>,
+}
+
+/// The writes a connection queued since `Request::Multi`, applied by `Request::Exec`.
+#[derive(Default)]
+struct Queued {
+    writes: Vec<(String, Option<String>)>,
+    // a request was refused while queueing, so the transaction can only be discarded
+    refused: bool,
+}
+
+/// How clients prove who they are, shared by every connection.
+#[derive(Clone, Default)]
+pub(crate) struct Auth {
+    pub(crate) password: Option<Arc<str>>,
+    pub(crate) acl: Option<Arc<Acl>>,
+    // clients not authenticated as an ACL user may run admin requests
+    pub(crate) admin: bool,
+}
+
+/// The protocol spoken by a listener.
+#[derive(Clone, Copy)]
+enum Frontend {
+    Native,
+    Resp,
 }
 
 impl<T: KvsEngine> KvsServer<T> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: T) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            max_connections: Semaphore::MAX_PERMITS,
+            auth: Auth::default(),
+            shards: None,
+            slowlog: SlowLog::new(DEFAULT_SLOWLOG_THRESHOLD, DEFAULT_SLOWLOG_MAX_LEN),
+            tcp: TcpOptions::default(),
+            listen_options: ListenOptions::default(),
+            idle_timeout: None,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            max_value_size: None,
+            counters: Arc::new(Counters::new()),
+            data_dir: None,
+            backup_dir: None,
+            databases: 1,
+            audit: None,
+        }
+    }
+
+    /// Require clients to authenticate with `password` before any other request.
+    pub fn require_pass(mut self, password: impl Into<String>) -> Self {
+        self.auth.password = Some(password.into().into());
+        self
+    }
+
+    /// Let the users of `acl` authenticate by name, and limit each of them to the keys
+    /// it grants. Clients authenticated with the shared password may access every key.
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.auth.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Let clients not authenticated as an ACL user run admin requests such as
+    /// `Request::FlushAll` and `Request::Backup`. ACL users need `admin` set instead.
+    /// Off by default.
+    pub fn enable_admin(mut self, enable: bool) -> Self {
+        self.auth.admin = enable;
+        self
+    }
+
+    /// Advertise `map` in answer to `Request::ShardMap`, for a server routing to shards
+    /// with a `RouterEngine`. Smart clients use it to talk to the shards directly.
+    pub fn shard_map(mut self, map: ShardMap) -> Self {
+        self.shards = Some(Arc::new(map));
+        self
+    }
+
+    /// Log requests whose engine call takes at least `threshold`, keeping the `max_len`
+    /// newest for `Request::SlowLog`. Defaults to 10 milliseconds and 128 requests;
+    /// a `max_len` of 0 turns the log off.
+    pub fn slowlog(mut self, threshold: Duration, max_len: usize) -> Self {
+        self.slowlog = SlowLog::new(threshold, max_len);
+        self
+    }
+
+    /// Close connections that send no request for `timeout`. Connections waiting on
+    /// the engine or subscribed to changes are never idle. Disabled by default.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Serve `count` numbered databases, which clients switch between with
+    /// `Request::Select`. Each is a `NamespacedEngine` over the engine, so keys
+    /// starting with a NUL character are refused. Defaults to 1.
+    pub fn databases(mut self, count: u32) -> Self {
+        self.databases = count.max(1);
+        self
+    }
+
+    /// Report the size of `dir`, where the engine keeps its data, in `Request::Stats`.
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(Arc::new(dir.into()));
+        self
+    }
+
+    /// Let admins back the store up with `Request::Backup` into directories under
+    /// `dir`, named by paths relative to it. Backups are refused by default.
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(Arc::new(dir.into()));
+        self
+    }
+
+    /// Append every write clients make, over either protocol, to `log`. Off by default.
+    pub fn audit_log(mut self, log: AuditLog) -> Self {
+        self.audit = Some(log);
+        self
+    }
+
+    /// Refuse requests larger than `size` bytes on the wire, answering with an error
+    /// and closing the connection. Defaults to 8 MiB.
+    pub fn max_request_size(mut self, size: usize) -> Self {
+        self.max_request_size = size;
+        self
+    }
+
+    /// Refuse to store values larger than `size` bytes, over either protocol and with
+    /// any engine, answering with `KvsError::ValueTooLarge`. An `Append` is checked by
+    /// the length of its suffix. Off by default.
+    pub fn max_value_size(mut self, size: usize) -> Self {
+        self.max_value_size = Some(size);
+        self
+    }
+
+    /// Set `TCP_NODELAY` on client connections, sending responses at once instead of
+    /// coalescing small writes. Off by default.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.tcp.nodelay = nodelay;
+        self
+    }
+
+    /// Send TCP keepalive probes on client connections idle for `time`, so connections
+    /// to vanished clients are eventually closed. Off by default.
+    pub fn tcp_keepalive(mut self, time: Duration) -> Self {
+        self.tcp.keepalive = Some(time);
+        self
+    }
+
+    /// Let up to `backlog` connections wait to be accepted. Defaults to 1024; the
+    /// operating system may cap it further.
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.listen_options.backlog = backlog;
+        self
+    }
+
+    /// Set `SO_REUSEADDR` on listening sockets, so a restarted server can bind its
+    /// address while connections of the old one linger. On by default, except on
+    /// Windows where it would let other processes bind the same port.
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.listen_options.reuse_address = reuse;
+        self
+    }
+
+    /// Serve at most `limit` clients at once. Further clients wait in the listen
+    /// backlog until a connection closes. Unlimited by default.
+    pub fn max_connections(mut self, limit: usize) -> Self {
+        self.max_connections = limit.min(Semaphore::MAX_PERMITS);
+        self
     }
 
     /// Run the server listening on the given address
     pub async fn run(self, addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(addr).await?;
-        while let Ok((tcp, _)) = listener.accept().await {
-            let engine = self.engine.clone();
-            tokio::spawn(serve(engine, tcp).map_err(|e| error!("Error on serving client: {}", e)));
+        self.spawn(addr).await?.wait().await
+    }
+
+    /// Run the server listening on the given address, encrypting every connection with TLS.
+    pub async fn run_tls(self, addr: SocketAddr, tls_config: Arc<ServerConfig>) -> Result<()> {
+        self.spawn_tls(addr, tls_config).await?.wait().await
+    }
+
+    /// Start listening on the given address and serve clients in the background.
+    ///
+    /// Binding port 0 picks a free port, reported by `ServerHandle::local_addr`.
+    /// IPv6 addresses only accept IPv6 clients: to serve both, spawn clones of the
+    /// server on an IPv4 and an IPv6 address.
+    pub async fn spawn(self, addr: SocketAddr) -> Result<ServerHandle> {
+        let listener = self.listen_options.bind(addr)?;
+        self.listen(Listener::Tcp(listener, None), Frontend::Native)
+    }
+
+    /// Like `spawn`, but speaking the Redis protocol (RESP) instead of the native one,
+    /// so `redis-cli` and Redis client libraries can use the server. Supported commands
+    /// are `PING`, `GET`, `SET` with `EX`/`PX`, `DEL`, `EXISTS`, `AUTH` and `QUIT`.
+    ///
+    /// Both protocols can be served at once from clones of the same server.
+    pub async fn spawn_resp(self, addr: SocketAddr) -> Result<ServerHandle> {
+        let listener = self.listen_options.bind(addr)?;
+        self.listen(Listener::Tcp(listener, None), Frontend::Resp)
+    }
+
+    /// Like `spawn`, but encrypting every connection with TLS.
+    pub async fn spawn_tls(
+        self,
+        addr: SocketAddr,
+        tls_config: Arc<ServerConfig>,
+    ) -> Result<ServerHandle> {
+        let listener = self.listen_options.bind(addr)?;
+        let tls = TlsAcceptor::from(tls_config);
+        self.listen(Listener::Tcp(listener, Some(tls)), Frontend::Native)
+    }
+
+    /// Like `spawn`, but listening on a Unix socket created at `path`, which is
+    /// removed again once the server stops.
+    ///
+    /// A stale socket left at `path` by a server that didn't stop cleanly is replaced.
+    #[cfg(unix)]
+    pub async fn spawn_unix(self, path: impl Into<PathBuf>) -> Result<ServerHandle> {
+        let path = path.into();
+        if fs::symlink_metadata(&path).map_or(false, |meta| meta.file_type().is_socket()) {
+            fs::remove_file(&path)?;
         }
+        let listener = UnixListener::bind(&path)?;
+        self.listen(Listener::Unix(listener, path), Frontend::Native)
+    }
 
-        Ok(())
+    /// Like `spawn`, but serving clients in the same process over in-memory
+    /// streams instead of sockets, e.g. for tests that shouldn't take a port.
+    ///
+    /// Clients connect with `KvsClientBuilder::connect_in_memory`.
+    pub async fn spawn_in_memory(self) -> Result<ServerHandle> {
+        let (connector, connections) = mpsc::unbounded_channel();
+        let mut handle = self.listen(Listener::Memory(connections), Frontend::Native)?;
+        handle.connector = Some(connector);
+        Ok(handle)
+    }
+
+    fn listen(self, mut listener: Listener, frontend: Frontend) -> Result<ServerHandle> {
+        let local_addr = match &listener {
+            Listener::Tcp(listener, _) => Some(listener.local_addr()?),
+            Listener::Memory(_) => None,
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        };
+        let shutdown = CancellationToken::new();
+
+        let token = shutdown.clone();
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        let task = tokio::spawn(async move {
+            let mut backoff = ACCEPT_BACKOFF_MIN;
+            loop {
+                // only accept once a connection slot is free
+                let permit = tokio::select! {
+                    permit = connections.clone().acquire_owned() => {
+                        permit.expect("the connection semaphore is never closed")
+                    }
+                    _ = token.cancelled() => break,
+                };
+                let incoming = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(incoming) => {
+                            backoff = ACCEPT_BACKOFF_MIN;
+                            incoming
+                        }
+                        // errors such as running out of file descriptors pass once
+                        // connections close, so they only pause accepting
+                        Err(e) => {
+                            warn!("Failed to accept a connection, retrying in {:?}: {}", backoff, e);
+                            tokio::select! {
+                                _ = time::sleep(backoff) => {}
+                                _ = token.cancelled() => break,
+                            }
+                            backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                            continue;
+                        }
+                    },
+                    _ = token.cancelled() => break,
+                };
+                let engine = self.engine.clone();
+                let conn = Connection {
+                    auth: self.auth.clone(),
+                    shards: self.shards.clone(),
+                    slowlog: self.slowlog.clone(),
+                    peer: incoming.peer_addr(),
+                    idle_timeout: self.idle_timeout,
+                    max_request_size: self.max_request_size,
+                    max_value_size: self.max_value_size,
+                    counters: self.counters.clone(),
+                    data_dir: self.data_dir.clone(),
+                    backup_dir: self.backup_dir.clone(),
+                    databases: self.databases,
+                    audit: self.audit.clone(),
+                };
+                let counters = self.counters.clone();
+                let tcp_options = self.tcp;
+                let token = token.clone();
+                let span = info_span!("connection", peer = field::Empty);
+                if let Some(peer) = conn.peer {
+                    span.record("peer", field::display(peer));
+                }
+                let serve = async move {
+                    let _open = counters.connection();
+                    // the handshake runs here so a slow client doesn't hold up accepting
+                    let res = match incoming {
+                        Incoming::Tcp(tcp, tls) => {
+                            if let Err(e) = tcp_options.apply(&tcp) {
+                                warn!("Failed to set socket options: {}", e);
+                            }
+                            match tls {
+                                Some(tls) => match tls_accept(&tls, tcp, conn.idle_timeout).await {
+                                    Ok(stream) => frontend.serve(engine, stream, conn, token).await,
+                                    Err(e) => Err(e),
+                                },
+                                None => frontend.serve(engine, tcp, conn, token).await,
+                            }
+                        }
+                        Incoming::Memory(stream) => {
+                            frontend.serve(engine, stream, conn, token).await
+                        }
+                        #[cfg(unix)]
+                        Incoming::Unix(stream) => frontend.serve(engine, stream, conn, token).await,
+                    };
+                    if let Err(e) = res {
+                        error!("Error on serving client: {}", e);
+                    }
+                    drop(permit);
+                };
+                tokio::spawn(serve.instrument(span));
+            }
+            listener.close();
+        });
+
+        Ok(ServerHandle {
+            local_addr,
+            connector: None,
+            shutdown,
+            task,
+        })
     }
 }
 
-async fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
-    let (read_half, write_half) = io::split(tcp);
+/// The sending end of the connections of a server spawned with
+/// `KvsServer::spawn_in_memory`, which clients hand their server halves to.
+pub(crate) type MemoryConnector = mpsc::UnboundedSender<DuplexStream>;
+
+/// A socket the server accepts connections on.
+enum Listener {
+    Tcp(TcpListener, Option<TlsAcceptor>),
+    Memory(mpsc::UnboundedReceiver<DuplexStream>),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// A connection accepted by a `Listener`, before any TLS handshake.
+enum Incoming {
+    Tcp(TcpStream, Option<TlsAcceptor>),
+    Memory(DuplexStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Incoming {
+    /// The address of the client, unknown for Unix sockets and in-memory streams.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Incoming::Tcp(tcp, _) => tcp.peer_addr().ok(),
+            Incoming::Memory(_) => None,
+            #[cfg(unix)]
+            Incoming::Unix(_) => None,
+        }
+    }
+}
+
+impl Listener {
+    async fn accept(&mut self) -> io::Result<Incoming> {
+        match self {
+            Listener::Tcp(listener, tls) => {
+                let (tcp, _) = listener.accept().await?;
+                Ok(Incoming::Tcp(tcp, tls.clone()))
+            }
+            // the handle keeps a sender, so the channel only closes once nobody can
+            // connect anymore; the server then waits for its shutdown like any other
+            Listener::Memory(connections) => match connections.recv().await {
+                Some(stream) => Ok(Incoming::Memory(stream)),
+                None => future::pending().await,
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Incoming::Unix(stream))
+            }
+        }
+    }
+
+    /// Stop listening, removing the socket file of a Unix socket.
+    fn close(self) {
+        #[cfg(unix)]
+        if let Listener::Unix(listener, path) = self {
+            drop(listener);
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl Frontend {
+    async fn serve<E, S>(
+        self,
+        engine: E,
+        stream: S,
+        conn: Connection,
+        shutdown: CancellationToken,
+    ) -> Result<()>
+    where
+        E: KvsEngine,
+        S: AsyncRead + AsyncWrite,
+    {
+        match self {
+            Frontend::Native => serve(engine, stream, conn, shutdown).await,
+            Frontend::Resp => resp::serve(engine, stream, conn, shutdown).await,
+        }
+    }
+}
+
+/// A running `KvsServer`, returned by `KvsServer::spawn`.
+pub struct ServerHandle {
+    local_addr: Option<SocketAddr>,
+    // set for a server spawned with `KvsServer::spawn_in_memory`
+    connector: Option<MemoryConnector>,
+    shutdown: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on, or None for a Unix socket or an
+    /// in-memory server.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Where clients of an in-memory server connect, or None for other servers.
+    pub(crate) fn connector(&self) -> Option<MemoryConnector> {
+        self.connector.clone()
+    }
+
+    /// Stop accepting connections and wait until the listener is closed.
+    ///
+    /// Open connections are closed once the request they are serving completes.
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.cancel();
+        self.wait().await
+    }
+
+    /// Wait until the server stops after `shutdown`.
+    pub async fn wait(self) -> Result<()> {
+        self.task
+            .await
+            .map_err(|e| KvsError::StringError(format!("{}", e)))
+    }
+}
+
+async fn serve<E, S>(
+    engine: E,
+    stream: S,
+    conn: Connection,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    E: KvsEngine,
+    S: AsyncRead + AsyncWrite,
+{
+    let Connection {
+        auth,
+        shards,
+        slowlog,
+        peer,
+        idle_timeout,
+        max_request_size,
+        max_value_size,
+        counters,
+        data_dir,
+        backup_dir,
+        databases,
+        audit,
+    } = conn;
+
+    let (read_half, write_half) = io::split(stream);
+    // uncompressed JSON without checksums until the client picks otherwise with
+    // Request::Hello
+    let codec = CodecSwitch::new(max_request_size);
+
+    let length_delimited = |max_frame_length| {
+        LengthDelimitedCodec::builder()
+            .max_frame_length(max_frame_length)
+            .new_codec()
+    };
 
     let mut read_json = SymmetricallyFramed::new(
-        FramedRead::new(read_half, LengthDelimitedCodec::new()),
-        SymmetricalJson::default(),
-    );
+        FramedRead::new(read_half, length_delimited(max_request_size)),
+        codec.format(),
+    )
+    .peekable();
 
+    // a value as large as the largest request must fit into a response, and a lower
+    // limit mustn't cut off responses that fit before, such as pages of a scan
     let mut write_json = SymmetricallyFramed::new(
-        FramedWrite::new(write_half, LengthDelimitedCodec::new()),
-        SymmetricalJson::default(),
+        FramedWrite::new(
+            write_half,
+            length_delimited(max_request_size.max(DEFAULT_MAX_REQUEST_SIZE)),
+        ),
+        codec.format(),
     );
+    // the database requests run on, switched by Request::Select and audited as the
+    // user the connection authenticated as
+    let select = |db: u32, user: Option<&AclUser>| {
+        let caller = Caller {
+            peer,
+            user: user.map(|user| user.name.clone()),
+            db,
+        };
+        let engine = if databases == 1 {
+            NamespacedEngine::sole(engine.clone())
+        } else {
+            NamespacedEngine::new(engine.clone(), db)
+        };
+        Audited::new(engine, audit.clone(), caller)
+    };
+    let mut db = 0;
+    let mut selected = select(db, None);
+    // the open transaction, if any
+    let mut queued: Option<Queued> = None;
+    let mut authenticated = auth.password.is_none() && auth.acl.is_none();
+    // the ACL user the connection authenticated as, limited to the keys it grants
+    let mut user = None;
+    // tagged requests running on the engine, answered as they complete
+    let mut in_flight = FuturesUnordered::new();
+    loop {
+        let req = tokio::select! {
+            req = read_json.next(), if in_flight.len() < MAX_IN_FLIGHT => match req {
+                Some(req) => req,
+                None => break,
+            },
+            Some((id, resp)) = in_flight.next(), if !in_flight.is_empty() => {
+                write_json.send(tagged(Some(id), resp?)).await?;
+                continue;
+            }
+            _ = idle(idle_timeout), if in_flight.is_empty() => {
+                debug!("Closing idle connection");
+                break;
+            }
+            _ = shutdown.cancelled() => {
+                debug!("Server shutting down, closing connection");
+                break;
+            }
+        };
 
-    while let Some(req) = read_json.next().await {
-        let engine = engine.clone();
-        let resp = match req? {
-            Request::Get { key } => Response::Get(engine.get(key).await?),
-            Request::Set { key, value } => {
-                engine.set(key, value).await?;
-                Response::Set
-            }
-            Request::Remove { key } => {
-                let res = engine.remove(key).await;
-                match res {
-                    Ok(_) => Response::Remove,
-                    Err(e) => Response::Err(e.to_string()),
+        let req = match req {
+            Ok(req) => req,
+            // the rest of the frame can't be skipped, so the connection ends here
+            Err(e) if is_frame_too_large(&e) => {
+                let resp = Response::Err(format!(
+                    "Request exceeds the limit of {} bytes",
+                    max_request_size
+                ));
+                write_json.send(resp).await?;
+                write_json.close().await?;
+                // closing with unread data would reset the connection, and with it
+                // the error the client is about to read
+                let unread = read_json.get_mut().get_mut().get_mut();
+                let _ = time::timeout(DRAIN_TIMEOUT, io::copy(unread, &mut io::sink())).await;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let (id, req) = match req {
+            Request::Tagged { id, request } => (Some(id), *request),
+            req => (None, req),
+        };
+        let req = match req {
+            Request::Tagged { .. } => {
+                let resp = Response::Err("Tagged requests cannot be nested".to_owned());
+                write_json.send(tagged(id, resp)).await?;
+                continue;
+            }
+            Request::Auth { username, token } => {
+                let resp = match auth.login(username.as_deref(), &token) {
+                    Ok(login) => {
+                        authenticated = true;
+                        user = login;
+                        selected = select(db, user.as_ref());
+                        Response::Auth
+                    }
+                    Err(e) => Response::Err(e.to_owned()),
+                };
+                write_json.send(tagged(id, resp)).await?;
+                continue;
+            }
+            Request::Ping => {
+                write_json.send(tagged(id, Response::Pong)).await?;
+                continue;
+            }
+            req @ Request::Hello { .. } => req,
+            _ if !authenticated => {
+                let resp = Response::Err("Authentication required".to_owned());
+                write_json.send(tagged(id, resp)).await?;
+                continue;
+            }
+            req if !permitted(user.as_ref(), auth.admin(user.as_ref()), &req) => {
+                let resp = Response::Err("Permission denied".to_owned());
+                write_json.send(tagged(id, resp)).await?;
+                continue;
+            }
+            Request::Backup { path } => {
+                match backup_path(backup_dir.as_ref().map(|dir| dir.as_path()), &path) {
+                    Ok(path) => Request::Backup { path },
+                    Err(e) => {
+                        write_json
+                            .send(tagged(id, Response::Err(e.to_owned())))
+                            .await?;
+                        continue;
+                    }
+                }
+            }
+            req => match check_value_size(&req, max_value_size) {
+                Ok(()) => req,
+                Err(e) => {
+                    write_json
+                        .send(tagged(id, Response::Err(e.to_string())))
+                        .await?;
+                    continue;
+                }
+            },
+        };
+
+        if let Some(transaction) = queued.as_mut() {
+            let resp = match req {
+                Request::Set {
+                    key,
+                    value,
+                    ttl_ms: None,
+                } => {
+                    transaction.writes.push((key, Some(value)));
+                    Response::Queued
+                }
+                Request::Set { .. } => {
+                    transaction.refused = true;
+                    Response::Err("A Set with a TTL cannot be queued in a transaction".to_owned())
+                }
+                Request::Remove { key } => {
+                    transaction.writes.push((key, None));
+                    Response::Queued
                 }
+                Request::Exec if transaction.refused => {
+                    queued = None;
+                    Response::Err("Transaction discarded because of an earlier error".to_owned())
+                }
+                Request::Exec => {
+                    let writes = std::mem::take(&mut transaction.writes);
+                    queued = None;
+                    exec(selected.clone(), writes).await
+                }
+                Request::Discard => {
+                    queued = None;
+                    Response::Discard
+                }
+                Request::Multi => {
+                    transaction.refused = true;
+                    Response::Err("Transactions cannot be nested".to_owned())
+                }
+                _ => {
+                    transaction.refused = true;
+                    Response::Err("Only Set and Remove can be queued in a transaction".to_owned())
+                }
+            };
+            write_json.send(tagged(id, resp)).await?;
+            continue;
+        }
+
+        match req {
+            Request::Multi => {
+                queued = Some(Queued::default());
+                write_json.send(tagged(id, Response::Multi)).await?;
+                continue;
+            }
+            Request::Exec | Request::Discard => {
+                let resp = Response::Err("No transaction is open".to_owned());
+                write_json.send(tagged(id, resp)).await?;
+                continue;
+            }
+            Request::SlowLog { count } => {
+                write_json
+                    .send(tagged(id, Response::SlowLog(slowlog.get(count))))
+                    .await?;
+                continue;
+            }
+            Request::SlowLogReset => {
+                slowlog.reset();
+                write_json.send(tagged(id, Response::SlowLogReset)).await?;
+                continue;
+            }
+            Request::Select { db: requested } => {
+                let resp = if requested < databases {
+                    db = requested;
+                    selected = select(db, user.as_ref());
+                    Response::Select
+                } else {
+                    Response::Err(format!(
+                        "Database {} does not exist, the server has {}",
+                        requested, databases
+                    ))
+                };
+                write_json.send(tagged(id, resp)).await?;
+                continue;
+            }
+            Request::Stats => {
+                let resp = match stats(engine.clone(), &counters, data_dir.clone()).await {
+                    Ok(stats) => Response::Stats(stats),
+                    Err(e) => Response::Err(e.to_string()),
+                };
+                write_json.send(tagged(id, resp)).await?;
+                continue;
+            }
+            _ => {}
+        }
+
+        if let Request::Batch(reqs) = req {
+            let resps = run_batch(
+                selected.clone(),
+                reqs,
+                user.as_ref(),
+                &slowlog,
+                &counters,
+                peer,
+            )
+            .await?;
+            write_json.send(tagged(id, Response::Batch(resps))).await?;
+            continue;
+        }
+
+        if let Request::ShardMap = req {
+            let resp = match &shards {
+                Some(map) => Response::ShardMap(map.shards().to_vec()),
+                None => Response::Err("Sharding is not enabled".to_owned()),
+            };
+            write_json.send(tagged(id, resp)).await?;
+            continue;
+        }
+
+        if let Request::Streamed(req) = req {
+            if id.is_some() {
+                let resp = Response::Err("Streamed requests cannot be tagged".to_owned());
+                write_json.send(tagged(id, resp)).await?;
+                continue;
+            }
+            // chunks can't be told apart from earlier responses, so those go first
+            while let Some((id, resp)) = in_flight.next().await {
+                write_json.send(tagged(Some(id), resp?)).await?;
+            }
+            stream_response(selected.clone(), *req, &counters, &mut write_json).await?;
+            continue;
+        }
+
+        if let Request::Subscribe { prefix } = req {
+            if id.is_some() {
+                let resp = Response::Err("Subscribe cannot be tagged".to_owned());
+                write_json.send(tagged(id, resp)).await?;
+                continue;
+            }
+            // answer what's still running before the connection switches to streaming
+            while let Some((id, resp)) = in_flight.next().await {
+                write_json.send(tagged(Some(id), resp?)).await?;
+            }
+            let events = selected.clone().watch(prefix);
+            return stream_events(events, &mut read_json, &mut write_json, shutdown).await;
+        }
+
+        if let Some(id) = id {
+            // responses to tagged requests are written out of order, so only an
+            // untagged Hello can switch codecs, compression or checksums
+            let req = match req {
+                Request::Hello {
+                    protocol_version, ..
+                } => Request::Hello {
+                    protocol_version,
+                    codecs: Vec::new(),
+                    compressions: Vec::new(),
+                    checksums: false,
+                },
+                req => req,
+            };
+            let engine = selected.clone();
+            let (slowlog, counters) = (slowlog.clone(), counters.clone());
+            in_flight.push(async move {
+                let resp = handle_logged(engine, req, Some(id), &slowlog, &counters, peer).await;
+                (id, resp)
+            });
+            continue;
+        }
+
+        // Dropping the engine future when the client goes away closes its oneshot
+        // channel, so the queued job is skipped instead of running for nobody.
+        let resp = tokio::select! {
+            resp = handle_logged(selected.clone(), req, None, &slowlog, &counters, peer) => resp?,
+            _ = disconnected(&mut read_json) => {
+                debug!("Client disconnected, cancelling in-flight request");
+                return Ok(());
             }
         };
 
         write_json.send(resp).await?;
+        // the client switches once it read the response, and sends nothing meanwhile
+        if let Response::Hello(info) = &resp {
+            if let Some(name) = &info.codec {
+                codec.set(Codec::from_name(name).unwrap_or_default());
+            }
+            if let Some(name) = &info.compression {
+                codec.set_compression(Compression::from_name(name));
+            }
+            if info.checksums {
+                codec.set_checksums(true);
+            }
+        }
     }
 
     Ok(())
 }
+
+/// Confirm a subscription and push `events` until the client disconnects or the server
+/// shuts down, refusing every request but `Ping` meanwhile.
+async fn stream_events<R, W>(
+    mut events: BoxStream<'static, KeyEvent>,
+    requests: &mut R,
+    responses: &mut W,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    R: Stream<Item = io::Result<Request>> + Unpin,
+    W: Sink<Response, Error = io::Error> + Unpin,
+{
+    // the watch is registered before confirming, so no later change is missed
+    responses.send(Response::Subscribed).await?;
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => responses.send(event.into()).await?,
+                None => break,
+            },
+            req = requests.next() => match req {
+                Some(Ok(Request::Ping)) => responses.send(Response::Pong).await?,
+                Some(Ok(_)) => {
+                    let resp = Response::Err("Only Ping is allowed while subscribed".to_owned());
+                    responses.send(resp).await?;
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            },
+            _ = shutdown.cancelled() => {
+                debug!("Server shutting down, closing connection");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Answer the `Get` or `Scan` of a `Request::Streamed` with chunks of at most about
+/// `CHUNK_SIZE` bytes, then `Response::End`.
+async fn stream_response<E, W>(
+    engine: E,
+    req: Request,
+    counters: &Counters,
+    responses: &mut W,
+) -> Result<()>
+where
+    E: KvsEngine,
+    W: Sink<Response, Error = io::Error> + Unpin,
+{
+    match req {
+        Request::Get { key } => {
+            counters.op("get");
+            let value = match engine.get(key).await {
+                Ok(value) => value,
+                Err(e) => {
+                    responses.send(Response::Err(e.to_string())).await?;
+                    return Ok(());
+                }
+            };
+            if let Some(value) = value {
+                // an empty value still takes a chunk, to tell it from a missing key
+                let mut rest = value.as_str();
+                loop {
+                    let mut end = rest.len().min(CHUNK_SIZE);
+                    while !rest.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    let (chunk, tail) = rest.split_at(end);
+                    responses
+                        .feed(Response::Chunk(Chunk::Value(chunk.to_owned())))
+                        .await?;
+                    rest = tail;
+                    if rest.is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+        Request::Scan {
+            cursor,
+            prefix,
+            limit,
+        } => {
+            counters.op("scan");
+            let mut after = cursor;
+            let mut remaining = limit;
+            let mut pairs = Vec::new();
+            let mut size = 0;
+            while remaining > 0 {
+                let options = ScanOptions {
+                    prefix: prefix.clone(),
+                    limit: Some(remaining.min(MAX_SCAN_PAGE)),
+                    reverse: false,
+                    after: after.take(),
+                };
+                let page = match engine.clone().scan(options).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        responses.send(Response::Err(e.to_string())).await?;
+                        return Ok(());
+                    }
+                };
+                let last_page = page.len() < remaining.min(MAX_SCAN_PAGE);
+                remaining -= page.len();
+                after = page.last().map(|(key, _)| key.clone());
+                for (key, value) in page {
+                    let pair_size = key.len() + value.len();
+                    if size + pair_size > CHUNK_SIZE && !pairs.is_empty() {
+                        let chunk = Chunk::Pairs(std::mem::take(&mut pairs));
+                        responses.feed(Response::Chunk(chunk)).await?;
+                        size = 0;
+                    }
+                    size += pair_size;
+                    pairs.push((key, value));
+                }
+                if last_page {
+                    break;
+                }
+            }
+            if !pairs.is_empty() {
+                responses.feed(Response::Chunk(Chunk::Pairs(pairs))).await?;
+            }
+        }
+        _ => {
+            let resp = Response::Err("Only Get and Scan can be streamed".to_owned());
+            responses.send(resp).await?;
+            return Ok(());
+        }
+    }
+    responses.send(Response::End).await?;
+    Ok(())
+}
+
+/// Run the requests of a `Request::Batch` in order. Consecutive reads run
+/// concurrently and consecutive sets go to the engine as one batch.
+async fn run_batch<E: KvsEngine>(
+    engine: E,
+    reqs: Vec<Request>,
+    user: Option<&AclUser>,
+    slowlog: &SlowLog,
+    counters: &Counters,
+    peer: Option<SocketAddr>,
+) -> Result<Vec<Response>> {
+    // why a request can't run as part of the batch
+    let refusal = |req: &Request| match req {
+        Request::Get { .. }
+        | Request::Set { .. }
+        | Request::Remove { .. }
+        | Request::Append { .. }
+        | Request::Scan { .. } => (!permitted(user, false, req)).then_some("Permission denied"),
+        _ => Some("Only Get, Set, Remove, Append and Scan can be batched"),
+    };
+    let is_read = |req: &Request| matches!(req, Request::Get { .. } | Request::Scan { .. });
+
+    let mut resps = Vec::with_capacity(reqs.len());
+    let mut reqs = reqs.into_iter().peekable();
+    while let Some(req) = reqs.next() {
+        if let Some(refusal) = refusal(&req) {
+            resps.push(Response::Err(refusal.to_owned()));
+            continue;
+        }
+        match req {
+            req if is_read(&req) => {
+                let mut reads = vec![req];
+                while let Some(req) = reqs.next_if(|req| is_read(req) && refusal(req).is_none()) {
+                    reads.push(req);
+                }
+                let reads = reads
+                    .into_iter()
+                    .map(|req| handle_logged(engine.clone(), req, None, slowlog, counters, peer));
+                resps.extend(future::try_join_all(reads).await?);
+            }
+            Request::Set {
+                key,
+                value,
+                ttl_ms: None,
+            } => {
+                let mut pairs = vec![(key, value)];
+                while let Some(Request::Set { key, value, .. }) = reqs.next_if(|req| {
+                    matches!(req, Request::Set { ttl_ms: None, .. }) && refusal(req).is_none()
+                }) {
+                    pairs.push((key, value));
+                }
+                resps.extend(set_all(engine.clone(), pairs, slowlog, counters, peer).await?);
+            }
+            req => {
+                resps.push(handle_logged(engine.clone(), req, None, slowlog, counters, peer).await?)
+            }
+        }
+    }
+    Ok(resps)
+}
+
+/// Set `pairs` in one engine batch, or one by one if the engine can't write batches.
+async fn set_all<E: KvsEngine>(
+    engine: E,
+    pairs: Vec<(String, String)>,
+    slowlog: &SlowLog,
+    counters: &Counters,
+    peer: Option<SocketAddr>,
+) -> Result<Vec<Response>> {
+    if pairs.len() > 1 {
+        let writes = pairs
+            .iter()
+            .map(|(key, value)| (key.clone(), Some(value.clone())))
+            .collect();
+        let start = Instant::now();
+        if engine.clone().write_batch(writes).await.is_ok() {
+            for _ in &pairs {
+                counters.op("set");
+            }
+            slowlog.record("set", None, start.elapsed(), peer);
+            return Ok(pairs.iter().map(|_| Response::Set).collect());
+        }
+    }
+    let mut resps = Vec::with_capacity(pairs.len());
+    for (key, value) in pairs {
+        let req = Request::Set {
+            key,
+            value,
+            ttl_ms: None,
+        };
+        resps.push(handle_logged(engine.clone(), req, None, slowlog, counters, peer).await?);
+    }
+    Ok(resps)
+}
+
+/// Apply the writes of a transaction as one batch, answering for each of them.
+async fn exec<E: KvsEngine>(engine: E, writes: Vec<(String, Option<String>)>) -> Response {
+    let responses = writes
+        .iter()
+        .map(|(_, value)| match value {
+            Some(_) => Response::Set,
+            None => Response::Remove,
+        })
+        .collect();
+    match engine.write_batch(writes).await {
+        Ok(()) => Response::Exec(responses),
+        Err(e) => Response::Err(e.to_string()),
+    }
+}
+
+/// The server's statistics, including the engine's and the size of the data directory.
+async fn stats<E: KvsEngine>(
+    engine: E,
+    counters: &Counters,
+    data_dir: Option<Arc<PathBuf>>,
+) -> Result<ServerStats> {
+    let mut stats = counters.snapshot();
+    stats.engine = engine.engine_stats().await?;
+    if let Some(dir) = data_dir {
+        let size = task::spawn_blocking(move || dir_size(&dir))
+            .await
+            .map_err(|e| KvsError::StringError(format!("{}", e)))??;
+        stats.data_dir_bytes = Some(size);
+    }
+    Ok(stats)
+}
+
+/// Resolves once a connection was idle for `timeout`, or never without a timeout.
+pub(crate) async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => time::sleep(timeout).await,
+        None => future::pending().await,
+    }
+}
+
+/// Wrap the response to a request in a `Response::Tagged` if the request was tagged.
+fn tagged(id: Option<u64>, response: Response) -> Response {
+    match id {
+        Some(id) => Response::Tagged {
+            id,
+            response: Box::new(response),
+        },
+        None => response,
+    }
+}
+
+/// Run `req` on the engine in a span carrying its tag, op and key, counting it and
+/// adding it to `slowlog` if it took too long.
+async fn handle_logged<E: KvsEngine>(
+    engine: E,
+    req: Request,
+    id: Option<u64>,
+    slowlog: &SlowLog,
+    counters: &Counters,
+    peer: Option<SocketAddr>,
+) -> Result<Response> {
+    let (op, key) = match &req {
+        Request::Get { key } => ("get", Some(key.clone())),
+        Request::Set { key, .. } => ("set", Some(key.clone())),
+        Request::Ttl { key } => ("ttl", Some(key.clone())),
+        Request::GetMeta { key } => ("getmeta", Some(key.clone())),
+        Request::Exists { key } => ("exists", Some(key.clone())),
+        Request::Rename { from, .. } => ("rename", Some(from.clone())),
+        Request::Copy { from, .. } => ("copy", Some(from.clone())),
+        Request::Len => ("len", None),
+        Request::Expire { key, .. } => ("expire", Some(key.clone())),
+        Request::Persist { key } => ("persist", Some(key.clone())),
+        Request::Remove { key } => ("remove", Some(key.clone())),
+        Request::Append { key, .. } => ("append", Some(key.clone())),
+        Request::SetNx { key, .. } => ("setnx", Some(key.clone())),
+        Request::SetIf { key, .. } => ("setif", Some(key.clone())),
+        Request::Scan { prefix, .. } => ("scan", Some(prefix.clone())),
+        Request::Hello { .. } => ("hello", None),
+        Request::Backup { .. } => ("backup", None),
+        Request::FlushAll => ("flushall", None),
+        Request::Echo(_) => ("echo", None),
+        _ => ("other", None),
+    };
+    let span = debug_span!("request", id, op, key = key.as_deref());
+    let start = Instant::now();
+    let resp = handle(engine, req).instrument(span.clone()).await;
+    let elapsed = start.elapsed();
+    span.in_scope(|| trace!(?elapsed, "Request served"));
+    counters.op(op);
+    slowlog.record(op, key.as_deref(), elapsed, peer);
+    resp
+}
+
+async fn handle<E: KvsEngine>(engine: E, req: Request) -> Result<Response> {
+    let resp = match req {
+        Request::Get { key } => match engine.get(key).await {
+            Ok(value) => Response::Get(value),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Set { key, value, ttl_ms } => {
+            let res = match ttl_ms {
+                Some(ttl_ms) => {
+                    let ttl = Duration::from_millis(ttl_ms);
+                    engine.set_with_ttl(key, value, ttl).await
+                }
+                None => engine.set(key, value).await,
+            };
+            match res {
+                Ok(()) => Response::Set,
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::Ttl { key } => match engine.ttl(key).await {
+            Ok(ttl) => Response::Ttl(ttl.map(|ttl| ttl.as_millis() as u64)),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Echo(payload) => Response::Echo(payload),
+        Request::Rename { from, to } => match engine.rename(from, to).await {
+            Ok(()) => Response::Rename,
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Copy {
+            from,
+            to,
+            overwrite,
+        } => match engine.copy(from, to, overwrite).await {
+            Ok(copied) => Response::Copy(copied),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Exists { key } => match engine.exists(key).await {
+            Ok(exists) => Response::Exists(exists),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Len => match engine.len().await {
+            Ok(len) => Response::Len(len),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::GetMeta { key } => match engine.meta(key).await {
+            Ok(meta) => Response::GetMeta(meta),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Expire { key, ttl_ms } => {
+            match engine.expire(key, Duration::from_millis(ttl_ms)).await {
+                Ok(exists) => Response::Expire(exists),
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::Persist { key } => match engine.persist(key).await {
+            Ok(persisted) => Response::Persist(persisted),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Remove { key } => {
+            let res = engine.remove(key).await;
+            match res {
+                Ok(_) => Response::Remove,
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::Append { key, suffix } => match engine.append(key, suffix).await {
+            Ok(length) => Response::Append(length),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::SetNx { key, value } => match engine.compare_and_swap(key, None, value).await {
+            Ok(applied) => Response::SetNx(applied),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::SetIf {
+            key,
+            expected,
+            value,
+        } => match engine.compare_and_swap(key, Some(expected), value).await {
+            Ok(applied) => Response::SetIf(applied),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Scan {
+            cursor,
+            prefix,
+            limit,
+        } => {
+            let limit = limit.clamp(1, MAX_SCAN_PAGE);
+            let options = ScanOptions {
+                prefix,
+                // one more pair than requested tells whether another page follows
+                limit: Some(limit + 1),
+                reverse: false,
+                after: cursor,
+            };
+            match engine.scan(options).await {
+                Ok(mut pairs) => {
+                    let next_cursor = if pairs.len() > limit {
+                        pairs.truncate(limit);
+                        pairs.last().map(|(key, _)| key.clone())
+                    } else {
+                        None
+                    };
+                    Response::Scan { pairs, next_cursor }
+                }
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::FlushAll => match engine.clear().await {
+            Ok(()) => Response::FlushAll,
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Backup { path } => match engine.backup(path).await {
+            Ok(()) => Response::Backup,
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Hello {
+            protocol_version,
+            codecs,
+            compressions,
+            checksums,
+        } => Response::Hello(ServerInfo {
+            protocol_version: protocol_version.min(PROTOCOL_VERSION),
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            // the first codec offered that the server speaks
+            codec: codecs
+                .into_iter()
+                .find(|name| Codec::from_name(name).is_some()),
+            compression: compressions
+                .into_iter()
+                .find(|name| Compression::from_name(name).is_some()),
+            checksums,
+        }),
+        Request::Auth { .. }
+        | Request::Ping
+        | Request::ShardMap
+        | Request::SlowLog { .. }
+        | Request::SlowLogReset
+        | Request::Stats
+        | Request::Select { .. }
+        | Request::Batch(_)
+        | Request::Multi
+        | Request::Exec
+        | Request::Discard
+        | Request::Streamed(_)
+        | Request::Subscribe { .. }
+        | Request::Tagged { .. } => {
+            unreachable!("answered by serve")
+        }
+    };
+    Ok(resp)
+}
+
+impl Auth {
+    /// Whether a connection authenticated as `user` may run admin requests.
+    fn admin(&self, user: Option<&AclUser>) -> bool {
+        user.map_or(self.admin, |user| user.admin)
+    }
+
+    /// Check the credentials of a `Request::Auth` and return the ACL user they belong to,
+    /// or None for the shared password.
+    pub(crate) fn login(
+        &self,
+        username: Option<&str>,
+        token: &str,
+    ) -> std::result::Result<Option<AclUser>, &'static str> {
+        match (username, &self.password, &self.acl) {
+            (Some(username), _, Some(acl)) => acl
+                .authenticate(username, token)
+                .map(|user| Some(user.clone()))
+                .ok_or("Invalid username or password"),
+            (Some(_), _, None) => Err("User authentication is not enabled"),
+            (None, Some(password), _) if secure_eq(token.as_bytes(), password.as_bytes()) => {
+                Ok(None)
+            }
+            (None, Some(_), _) => Err("Invalid password"),
+            (None, None, _) => Err("Authentication is not enabled"),
+        }
+    }
+}
+
+/// Whether a request only touches keys `user` has access to, and is no admin request
+/// unless `admin` is set. Connections not authenticated as an ACL user may access
+/// every key.
+fn permitted(user: Option<&AclUser>, admin: bool, req: &Request) -> bool {
+    if let Request::Backup { .. } | Request::FlushAll = req {
+        return admin;
+    }
+    let user = match user {
+        Some(user) => user,
+        None => return true,
+    };
+    match req {
+        Request::Get { key }
+        | Request::Ttl { key }
+        | Request::GetMeta { key }
+        | Request::Exists { key }
+        | Request::Scan { prefix: key, .. }
+        | Request::Subscribe { prefix: key } => user.can_read(key),
+        Request::Set { key, .. }
+        | Request::Remove { key }
+        | Request::Append { key, .. }
+        | Request::Expire { key, .. }
+        | Request::Persist { key }
+        | Request::SetNx { key, .. }
+        | Request::SetIf { key, .. } => user.can_write(key),
+        Request::Rename { from, to } => user.can_write(from) && user.can_write(to),
+        Request::Copy { from, to, .. } => user.can_read(from) && user.can_write(to),
+        // entries name keys of every user
+        Request::SlowLog { .. } | Request::SlowLogReset => false,
+        // counts the keys of every user
+        Request::Len => false,
+        // admin requests, which may touch every key
+        Request::Stats | Request::Backup { .. } | Request::FlushAll => false,
+        Request::Hello { .. }
+        | Request::Auth { .. }
+        | Request::Ping
+        | Request::Echo(_)
+        | Request::ShardMap
+        | Request::Select { .. }
+        | Request::Multi
+        | Request::Exec
+        | Request::Discard
+        | Request::Tagged { .. } => true,
+        // checked for each request of the batch
+        Request::Batch(_) => true,
+        Request::Streamed(req) => permitted(Some(user), admin, req),
+    }
+}
+
+/// Fail with `KvsError::ValueTooLarge` if a request stores a value longer than `max`
+/// bytes, or appends a longer suffix.
+fn check_value_size(req: &Request, max: Option<usize>) -> Result<()> {
+    let max = match max {
+        Some(max) => max,
+        None => return Ok(()),
+    };
+    let size = match req {
+        Request::Set { value, .. }
+        | Request::SetNx { value, .. }
+        | Request::SetIf { value, .. } => value.len(),
+        Request::Append { suffix, .. } => suffix.len(),
+        Request::Batch(reqs) => {
+            return reqs
+                .iter()
+                .try_for_each(|req| check_value_size(req, Some(max)))
+        }
+        _ => return Ok(()),
+    };
+    if size > max {
+        return Err(KvsError::ValueTooLarge { size, max });
+    }
+    Ok(())
+}
+
+/// Where under `root` a `Request::Backup` of `path` writes, refusing paths which
+/// could leave it.
+fn backup_path(root: Option<&Path>, path: &Path) -> std::result::Result<PathBuf, &'static str> {
+    let root = root.ok_or("Backups are not enabled on this server")?;
+    let mut components = path.components().peekable();
+    if components.peek().is_none() || !components.all(|c| matches!(c, Component::Normal(_))) {
+        return Err("Backup path must be relative to the backup directory, without '..'");
+    }
+    Ok(root.join(path))
+}
+
+/// Finish the TLS handshake of `tcp`, giving up after the idle timeout or
+/// `TLS_HANDSHAKE_TIMEOUT`, whichever is shorter, as the connection holds a permit.
+async fn tls_accept(
+    tls: &TlsAcceptor,
+    tcp: TcpStream,
+    idle_timeout: Option<Duration>,
+) -> Result<TlsStream<TcpStream>> {
+    let timeout = idle_timeout.map_or(TLS_HANDSHAKE_TIMEOUT, |idle| {
+        idle.min(TLS_HANDSHAKE_TIMEOUT)
+    });
+    match time::timeout(timeout, tls.accept(tcp)).await {
+        Ok(stream) => Ok(stream?),
+        Err(_) => Err(KvsError::StringError("TLS handshake timed out".to_owned())),
+    }
+}
+
+/// Whether a read failed because the frame is longer than the codec accepts.
+fn is_frame_too_large(e: &io::Error) -> bool {
+    e.get_ref()
+        .map_or(false, |inner| inner.is::<LengthDelimitedCodecError>())
+}
+
+/// Compare two secrets in time independent of where they differ.
+pub(crate) fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Resolves once the client has closed the connection or reset it, so the in-flight
+/// request has nobody left to answer.
+///
+/// A pipelined request, or a frame the next read fails on, stays buffered for the
+/// next read, which only comes after the in-flight request was answered.
+async fn disconnected<S>(requests: &mut Peekable<S>)
+where
+    S: Stream<Item = io::Result<Request>> + Unpin,
+{
+    match Pin::new(requests).peek().await {
+        None => {}
+        Some(Err(e)) if is_reset(e) => {}
+        Some(_) => future::pending::<()>().await,
+    }
+}
+
+/// Whether a read failed because the connection is gone, rather than on what it read.
+fn is_reset(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
@@ -0,0 +1,66 @@
+use std::net::SocketAddr;
+
+use crate::{KvsError, Result};
+
+/// Points each shard gets on the ring. More points spread the keys more evenly.
+const VIRTUAL_NODES: u32 = 160;
+
+/// Assigns keys to the servers of a sharded keyspace by consistent hashing.
+///
+/// Every shard owns many points on a hash ring, and a key belongs to the shard owning
+/// the first point at or after the key's hash. Adding or removing a shard only moves
+/// the keys next to its points. The assignment depends on the shard addresses alone,
+/// so a client rebuilding the map from the list a router advertises routes every key
+/// to the same shard as the router.
+#[derive(Debug, Clone)]
+pub struct ShardMap {
+    shards: Vec<SocketAddr>,
+    // (point, index into shards), sorted by point
+    ring: Vec<(u64, usize)>,
+}
+
+impl ShardMap {
+    /// Creates a `ShardMap` over the given shards.
+    ///
+    /// Return an error if `shards` is empty.
+    pub fn new(shards: Vec<SocketAddr>) -> Result<Self> {
+        if shards.is_empty() {
+            return Err(KvsError::StringError(
+                "A shard map needs at least one shard".to_owned(),
+            ));
+        }
+        let mut ring: Vec<(u64, usize)> = shards
+            .iter()
+            .enumerate()
+            .flat_map(|(index, addr)| {
+                (0..VIRTUAL_NODES).map(move |node| (hash(format!("{}#{}", addr, node)), index))
+            })
+            .collect();
+        ring.sort_unstable();
+        Ok(ShardMap { shards, ring })
+    }
+
+    /// The addresses of the shards, in the order the map was created with.
+    pub fn shards(&self) -> &[SocketAddr] {
+        &self.shards
+    }
+
+    /// The address of the shard `key` belongs to.
+    pub fn shard_for(&self, key: &str) -> SocketAddr {
+        let point = hash(key);
+        let next = self.ring.partition_point(|&(p, _)| p < point);
+        // past the last point the ring wraps around to the first one
+        let (_, index) = self.ring[next % self.ring.len()];
+        self.shards[index]
+    }
+}
+
+/// 64-bit FNV-1a. Unlike the standard library's hasher it's stable across builds,
+/// which routers and clients rely on to agree where keys live.
+fn hash(data: impl AsRef<[u8]>) -> u64 {
+    data.as_ref()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
@@ -0,0 +1,73 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::SlowLogEntry;
+
+/// The requests a server found slow, newest first, shared by every connection.
+#[derive(Clone)]
+pub(crate) struct SlowLog {
+    threshold: Duration,
+    max_len: usize,
+    log: Arc<Mutex<Log>>,
+}
+
+#[derive(Default)]
+struct Log {
+    entries: VecDeque<SlowLogEntry>,
+    // ID of the next entry, kept across resets so clients can tell entries apart
+    next_id: u64,
+}
+
+impl SlowLog {
+    /// Log requests taking at least `threshold`, keeping the `max_len` newest ones.
+    pub(crate) fn new(threshold: Duration, max_len: usize) -> Self {
+        SlowLog {
+            threshold,
+            max_len,
+            log: Arc::default(),
+        }
+    }
+
+    /// Log a request if it took at least the threshold.
+    pub(crate) fn record(
+        &self,
+        op: &str,
+        key: Option<&str>,
+        duration: Duration,
+        peer: Option<SocketAddr>,
+    ) {
+        if duration < self.threshold || self.max_len == 0 {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        let mut log = self.log.lock().unwrap();
+        let entry = SlowLogEntry {
+            id: log.next_id,
+            timestamp,
+            duration,
+            op: op.to_owned(),
+            key: key.map(str::to_owned),
+            peer,
+        };
+        log.next_id += 1;
+        log.entries.push_front(entry);
+        log.entries.truncate(self.max_len);
+    }
+
+    /// The `count` newest entries, newest first.
+    pub(crate) fn get(&self, count: usize) -> Vec<SlowLogEntry> {
+        let log = self.log.lock().unwrap();
+        log.entries.iter().take(count).cloned().collect()
+    }
+
+    /// Forget every entry.
+    pub(crate) fn reset(&self) {
+        self.log.lock().unwrap().entries.clear();
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use crate::ServerStats;
+
+/// Counters of a server, shared by every listener and connection.
+pub(crate) struct Counters {
+    started: Instant,
+    connections: AtomicU64,
+    total_connections: AtomicU64,
+    ops: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Counters {
+    pub(crate) fn new() -> Self {
+        Counters {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            ops: Mutex::default(),
+        }
+    }
+
+    /// Count a connection as open until the returned guard is dropped.
+    pub(crate) fn connection(&self) -> ConnectionGuard<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self)
+    }
+
+    /// Count a request answered by the engine.
+    pub(crate) fn op(&self, op: &'static str) {
+        *self.ops.lock().unwrap().entry(op).or_default() += 1;
+    }
+
+    /// The counters as reported by `Request::Stats`, without the engine's statistics.
+    pub(crate) fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            uptime: self.started.elapsed(),
+            connections: self.connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            ops: self
+                .ops
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(&op, &count)| (op.to_owned(), count))
+                .collect(),
+            engine: BTreeMap::new(),
+            data_dir_bytes: None,
+        }
+    }
+}
+
+/// Marks a connection as open while it's alive.
+pub(crate) struct ConnectionGuard<'a>(&'a Counters);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The total size of the files under `dir`, following no symlinks.
+pub(crate) fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
@@ -0,0 +1,92 @@
+//! A blocking API over the storage engines, for callers that don't run an async runtime.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use futures::executor::block_on;
+
+use crate::{thread_pool::RayonThreadPool, KvsEngine, KvsEngineExt, Result, ScanOptions};
+
+/// A blocking handle to a `kvs::KvStore`, sharing its log format and behavior.
+///
+/// Every call waits for the engine's thread pool to finish the operation, so no
+/// async runtime is needed. Clones share the same store.
+#[derive(Clone)]
+pub struct KvStore {
+    engine: crate::KvStore<RayonThreadPool>,
+}
+
+impl KvStore {
+    /// Open the store in the given directory, creating it if it doesn't exist,
+    /// with one worker thread per CPU.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let engine = crate::KvStore::open(path, num_cpus::get() as u32)?;
+        Ok(KvStore { engine })
+    }
+
+    /// The async engine behind this handle.
+    pub fn engine(&self) -> crate::KvStore<RayonThreadPool> {
+        self.engine.clone()
+    }
+
+    /// Set the value of a string key to a string.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        block_on(self.engine.clone().set(key, value))
+    }
+
+    /// Get the string value of a string key. If the key does not exist, return None.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        block_on(self.engine.clone().get(key))
+    }
+
+    /// Remove a given string key.
+    /// Return `KvsError::KeyNotFound` if the key does not exist.
+    pub fn remove(&self, key: String) -> Result<()> {
+        block_on(self.engine.clone().remove(key))
+    }
+
+    /// Set the value of a string key that stops existing once `ttl` has passed.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        block_on(self.engine.clone().set_with_ttl(key, value, ttl))
+    }
+
+    /// Return the key/value pairs selected by `options`, ordered by key.
+    pub fn scan(&self, options: ScanOptions) -> Result<Vec<(String, String)>> {
+        block_on(self.engine.clone().scan(options))
+    }
+
+    /// Rewrite the live entries into a new log file and delete the stale ones now.
+    pub fn compact(&self) -> Result<()> {
+        block_on(self.engine.clone().compact())
+    }
+
+    /// Return the engine's statistics by name, such as the number of keys.
+    pub fn engine_stats(&self) -> Result<BTreeMap<String, u64>> {
+        block_on(self.engine.clone().engine_stats())
+    }
+
+    /// Write every pair to `writer` in the portable dump format of
+    /// `KvsEngineExt::export` and return how many were written.
+    pub fn export<W: Write + Send>(&self, writer: W) -> Result<u64> {
+        block_on(self.engine.clone().export(writer))
+    }
+
+    /// Set every pair of a dump written by `export` and return how many were set.
+    pub fn import<R: Read + Send>(&self, reader: R) -> Result<u64> {
+        block_on(self.engine.clone().import(reader))
+    }
+
+    /// Force every completed write to stable storage.
+    pub fn flush(&self) -> Result<()> {
+        block_on(self.engine.clone().flush())
+    }
+
+    /// Wait for in-flight operations and flush every write to stable storage.
+    pub fn close(self) -> Result<()> {
+        block_on(self.engine.close())
+    }
+}
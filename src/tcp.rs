@@ -0,0 +1,68 @@
+use std::{net::SocketAddr, time::Duration};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    io,
+    net::{TcpListener, TcpSocket, TcpStream},
+};
+
+/// Connections waiting to be accepted by default, as with `TcpListener::bind`.
+const DEFAULT_BACKLOG: u32 = 1024;
+
+/// Socket options applied to every TCP connection of a server or client.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TcpOptions {
+    /// Send small writes at once instead of coalescing them (`TCP_NODELAY`).
+    pub(crate) nodelay: bool,
+    /// Probe an idle connection after this long to detect a vanished peer.
+    pub(crate) keepalive: Option<Duration>,
+}
+
+impl TcpOptions {
+    pub(crate) fn apply(&self, tcp: &TcpStream) -> io::Result<()> {
+        tcp.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            SockRef::from(tcp).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        Ok(())
+    }
+}
+
+/// Options of the sockets a server listens on.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ListenOptions {
+    /// How many connections may wait to be accepted.
+    pub(crate) backlog: u32,
+    /// Allow binding an address still held by connections of an earlier server
+    /// (`SO_REUSEADDR`).
+    pub(crate) reuse_address: bool,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        ListenOptions {
+            backlog: DEFAULT_BACKLOG,
+            // what TcpListener::bind does, except on Windows where it lets another
+            // process steal the port
+            reuse_address: cfg!(unix),
+        }
+    }
+}
+
+impl ListenOptions {
+    /// Listen on `addr`. IPv6 addresses only accept IPv6 clients, so the IPv4
+    /// wildcard address can be bound next to the IPv6 one on the same port.
+    pub(crate) fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => {
+                let socket = TcpSocket::new_v6()?;
+                SockRef::from(&socket).set_only_v6(true)?;
+                socket
+            }
+        };
+        socket.set_reuseaddr(self.reuse_address)?;
+        socket.bind(addr)?;
+        socket.listen(self.backlog)
+    }
+}
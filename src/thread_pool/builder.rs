@@ -0,0 +1,122 @@
+use std::{fmt, io, sync::Arc, thread};
+
+use super::ThreadPool;
+use crate::Result;
+
+// the names of the threads unless set with `ThreadPoolBuilder::name_prefix`
+const DEFAULT_NAME_PREFIX: &str = "kvs-worker";
+
+type Callback = Arc<dyn Fn() + Send + Sync>;
+
+/// Options for the threads of a pool, created with `ThreadPoolBuilder::new`.
+#[derive(Clone)]
+pub struct ThreadPoolBuilder {
+    threads: u32,
+    name_prefix: String,
+    stack_size: Option<usize>,
+    on_start: Option<Callback>,
+    on_stop: Option<Callback>,
+}
+
+impl fmt::Debug for ThreadPoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadPoolBuilder")
+            .field("threads", &self.threads)
+            .field("name_prefix", &self.name_prefix)
+            .field("stack_size", &self.stack_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// Options for a pool of `threads` threads, the same as `ThreadPool::new`.
+    pub fn new(threads: u32) -> Self {
+        ThreadPoolBuilder {
+            threads,
+            name_prefix: DEFAULT_NAME_PREFIX.to_owned(),
+            stack_size: None,
+            on_start: None,
+            on_stop: None,
+        }
+    }
+
+    /// Name the threads `<prefix>-<n>`, numbered from 0 in the order they're
+    /// started. Defaults to `kvs-worker`.
+    pub fn name_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.name_prefix = prefix.into();
+        self
+    }
+
+    /// Give every thread a stack of `size` bytes. Defaults to the stack size of
+    /// `std::thread`.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Call `callback` on every thread once it started, before it runs a job.
+    pub fn on_thread_start<F>(mut self, callback: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.on_start = Some(Arc::new(callback));
+        self
+    }
+
+    /// Call `callback` on every thread before it exits, unless it's unwinding
+    /// from a panic.
+    pub fn on_thread_stop<F>(mut self, callback: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.on_stop = Some(Arc::new(callback));
+        self
+    }
+
+    /// Creates the pool.
+    pub fn build<P: ThreadPool>(self) -> Result<P> {
+        P::from_builder(self)
+    }
+
+    /// The number of threads of the pool.
+    pub fn threads(&self) -> u32 {
+        self.threads
+    }
+
+    /// The name of the thread started `index`th.
+    pub(super) fn thread_name(&self, index: usize) -> String {
+        format!("{}-{}", self.name_prefix, index)
+    }
+
+    pub(super) fn thread_stack_size(&self) -> Option<usize> {
+        self.stack_size
+    }
+
+    /// Call the start callback, if any.
+    pub(super) fn started(&self) {
+        if let Some(callback) = &self.on_start {
+            callback();
+        }
+    }
+
+    /// Call the stop callback, if any.
+    pub(super) fn stopped(&self) {
+        if let Some(callback) = &self.on_stop {
+            callback();
+        }
+    }
+
+    /// Spawn the thread started `index`th, running `f`. The callbacks are left to
+    /// `f`, so they run while the thread still counts as running.
+    pub(super) fn spawn<F>(&self, index: usize, f: F) -> io::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut builder = thread::Builder::new().name(self.thread_name(index));
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+        builder.spawn(f)?;
+        Ok(())
+    }
+}
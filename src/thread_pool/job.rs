@@ -0,0 +1,68 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    thread,
+    time::Instant,
+};
+
+use tokio::sync::oneshot;
+
+use super::{panic_message, CancellationToken};
+use crate::{KvsError, Result};
+
+/// A future resolving to the result of a job spawned with
+/// `ThreadPool::spawn_with_result`.
+///
+/// Dropping the handle before the job started makes the pool skip it.
+#[must_use = "the job is skipped if its handle is dropped before it starts"]
+pub struct JobHandle<R> {
+    rx: oneshot::Receiver<thread::Result<R>>,
+    // tell why the job was skipped
+    cancel: Option<CancellationToken>,
+    deadline: Option<Instant>,
+}
+
+impl<R> JobHandle<R> {
+    pub(super) fn new(
+        rx: oneshot::Receiver<thread::Result<R>>,
+        cancel: Option<CancellationToken>,
+        deadline: Option<Instant>,
+    ) -> Self {
+        JobHandle {
+            rx,
+            cancel,
+            deadline,
+        }
+    }
+
+    fn skipped(&self) -> KvsError {
+        if self
+            .cancel
+            .as_ref()
+            .map_or(false, |cancel| cancel.is_cancelled())
+        {
+            KvsError::Cancelled
+        } else if self
+            .deadline
+            .map_or(false, |deadline| Instant::now() >= deadline)
+        {
+            KvsError::Timeout
+        } else {
+            // the pool was shut down before the job ran
+            KvsError::StringError("The job was dropped without running".to_owned())
+        }
+    }
+}
+
+impl<R> Future for JobHandle<R> {
+    type Output = Result<R>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx).map(|res| match res {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(payload)) => Err(KvsError::JobPanicked(panic_message(&*payload))),
+            Err(_) => Err(self.skipped()),
+        })
+    }
+}
@@ -1,12 +1,53 @@
-use crate::Result;
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
+use tokio::sync::oneshot;
+use tracing::{debug, error, warn};
+
+use crate::{KvsError, Result};
+
+mod builder;
+mod job;
 mod naive;
 mod rayon;
 mod shared_queue;
 
+pub use builder::ThreadPoolBuilder;
+pub use job::JobHandle;
 pub use naive::NaiveThreadPool;
 pub use rayon::RayonThreadPool;
-pub use shared_queue::SharedQueueThreadPool;
+pub use shared_queue::{SharedQueueThreadPool, WorkerStats};
+pub use tokio_util::sync::CancellationToken;
+
+/// The priority of a job, given to `ThreadPool::spawn_at`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Work a request waits for.
+    #[default]
+    Foreground,
+    /// Maintenance like compaction, run once no foreground job is queued.
+    Background,
+}
+
+/// How a job spawned with `ThreadPool::spawn_with_options` runs.
+#[derive(Debug, Default, Clone)]
+pub struct JobOptions {
+    /// The priority of the job. Pools without priorities, like the naive and rayon
+    /// ones, run every job alike.
+    pub priority: Priority,
+    /// Skip the job if the token is cancelled before it starts.
+    pub cancel: Option<CancellationToken>,
+    /// Skip the job if it hasn't started by then. A job that started in time but
+    /// finishes late is counted in `PoolStats::overran`.
+    pub deadline: Option<Instant>,
+}
 
 /// A trait for defining a simple thread pool.
 pub trait ThreadPool: Clone + Send + 'static {
@@ -20,6 +61,14 @@ pub trait ThreadPool: Clone + Send + 'static {
     ///
     /// Returns a `Result` containing the newly created thread pool if successful.
     fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::from_builder(ThreadPoolBuilder::new(threads))
+    }
+
+    /// Creates a new thread pool with the threads configured by `builder`.
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self>
     where
         Self: Sized;
 
@@ -33,6 +82,285 @@ pub trait ThreadPool: Clone + Send + 'static {
     ///
     /// The closure should take no arguments (`FnOnce()`) and be both `Send` and `'static`.
     fn spawn<T>(&self, job: T)
+    where
+        T: FnOnce() + Send + 'static,
+    {
+        self.spawn_with_options(JobOptions::default(), job)
+    }
+
+    /// Spawns a job in the thread pool with the given priority.
+    ///
+    /// Pools without priorities, like the naive and rayon ones, run it like any
+    /// other job.
+    fn spawn_at<T>(&self, priority: Priority, job: T)
+    where
+        T: FnOnce() + Send + 'static,
+    {
+        let options = JobOptions {
+            priority,
+            ..JobOptions::default()
+        };
+        self.spawn_with_options(options, job)
+    }
+
+    /// Spawns a job in the thread pool, skipped if it's cancelled or its deadline
+    /// passed before it starts.
+    fn spawn_with_options<T>(&self, options: JobOptions, job: T)
     where
         T: FnOnce() + Send + 'static;
+
+    /// Spawns a job in the thread pool and returns a handle resolving to its result.
+    ///
+    /// The handle fails with `KvsError::JobPanicked` if the job panics, the panic
+    /// doesn't reach the pool. A job whose handle was dropped before it started is
+    /// skipped.
+    fn spawn_with_result<R, T>(&self, job: T) -> JobHandle<R>
+    where
+        T: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.spawn_with_result_and_options(JobOptions::default(), job)
+    }
+
+    /// Like `spawn_with_result`, with the given priority.
+    fn spawn_with_result_at<R, T>(&self, priority: Priority, job: T) -> JobHandle<R>
+    where
+        T: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let options = JobOptions {
+            priority,
+            ..JobOptions::default()
+        };
+        self.spawn_with_result_and_options(options, job)
+    }
+
+    /// Like `spawn_with_result`, with the given options.
+    ///
+    /// The handle fails with `KvsError::Cancelled` or `KvsError::Timeout` if the job
+    /// is skipped because it was cancelled or its deadline passed.
+    fn spawn_with_result_and_options<R, T>(&self, options: JobOptions, job: T) -> JobHandle<R>
+    where
+        T: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let handle = JobHandle::new(rx, options.cancel.clone(), options.deadline);
+        self.spawn_with_options(options, move || {
+            if tx.is_closed() {
+                debug!("Request cancelled, skipping job");
+                return;
+            }
+            let res = panic::catch_unwind(AssertUnwindSafe(job));
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        handle
+    }
+
+    /// Shuts the pool down, waiting at most `timeout` for it to finish.
+    ///
+    /// Jobs spawned afterwards, by this handle or any clone, are dropped without
+    /// running. Jobs already spawned still run, and the call returns once they
+    /// finished and every worker thread exited. Calling it again waits again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if jobs are still running once `timeout` has passed, e.g.
+    /// because the pool is shut down from one of its own jobs. They keep running
+    /// in the background.
+    fn shutdown(&self, timeout: Duration) -> Result<()>;
+
+    /// Reports the threads of the pool and the jobs queued, running and finished,
+    /// to size it by.
+    fn stats(&self) -> PoolStats;
+}
+
+/// The statistics of a pool, from `ThreadPool::stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// The threads running.
+    pub threads: u32,
+    /// The jobs spawned and not started yet.
+    pub queued: u64,
+    /// The jobs running.
+    pub busy: u64,
+    /// The jobs that finished or panicked since the pool was created.
+    pub completed: u64,
+    /// How long the started jobs were queued on average.
+    pub average_wait: Duration,
+    /// The jobs skipped because they were cancelled before they started.
+    pub cancelled: u64,
+    /// The jobs skipped because their deadline passed before they started.
+    pub expired: u64,
+    /// The jobs that finished after their deadline.
+    pub overran: u64,
+}
+
+/// Counts the jobs of a pool for `ThreadPool::stats`.
+#[derive(Default)]
+struct JobStats {
+    queued: AtomicU64,
+    busy: AtomicU64,
+    completed: AtomicU64,
+    started: AtomicU64,
+    wait_nanos: AtomicU64,
+    cancelled: AtomicU64,
+    expired: AtomicU64,
+    overran: AtomicU64,
+}
+
+impl JobStats {
+    /// Count `job` as queued until it starts, or is dropped without running, then
+    /// as busy until it returns or panics. It's skipped if `options` say so once
+    /// it's its turn.
+    fn track<T>(self: &Arc<Self>, options: JobOptions, job: T) -> impl FnOnce() + Send + 'static
+    where
+        T: FnOnce() + Send + 'static,
+    {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let queued = QueuedJob {
+            stats: Arc::clone(self),
+            since: Instant::now(),
+        };
+        move || {
+            if options.cancel.map_or(false, |cancel| cancel.is_cancelled()) {
+                debug!("Job cancelled, skipping it");
+                queued.stats.cancelled.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            if options
+                .deadline
+                .map_or(false, |deadline| Instant::now() >= deadline)
+            {
+                debug!("Deadline of the job passed, skipping it");
+                queued.stats.expired.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            let running = queued.start();
+            job();
+            if let Some(deadline) = options.deadline {
+                let now = Instant::now();
+                if now > deadline {
+                    warn!("Job finished {:?} after its deadline", now - deadline);
+                    running.0.overran.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn snapshot(&self, threads: u32) -> PoolStats {
+        let started = self.started.load(Ordering::Relaxed);
+        let wait_nanos = self.wait_nanos.load(Ordering::Relaxed);
+        PoolStats {
+            threads,
+            queued: self.queued.load(Ordering::Relaxed),
+            busy: self.busy.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            average_wait: Duration::from_nanos(wait_nanos.checked_div(started).unwrap_or(0)),
+            cancelled: self.cancelled.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            overran: self.overran.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct QueuedJob {
+    stats: Arc<JobStats>,
+    since: Instant,
+}
+
+impl QueuedJob {
+    fn start(self) -> RunningJob {
+        let stats = &self.stats;
+        stats.started.fetch_add(1, Ordering::Relaxed);
+        let wait = self.since.elapsed().as_nanos() as u64;
+        stats.wait_nanos.fetch_add(wait, Ordering::Relaxed);
+        stats.busy.fetch_add(1, Ordering::Relaxed);
+        RunningJob(Arc::clone(stats))
+    }
+}
+
+impl Drop for QueuedJob {
+    fn drop(&mut self) {
+        self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct RunningJob(Arc<JobStats>);
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        self.0.busy.fetch_sub(1, Ordering::Relaxed);
+        self.0.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts the threads of a pool that haven't exited, so shutting down can wait
+/// for them.
+#[derive(Default)]
+struct Workers {
+    running: Mutex<usize>,
+    exited: Condvar,
+}
+
+impl Workers {
+    /// Count `threads` more threads as running.
+    fn add(&self, threads: usize) {
+        *self.running.lock().unwrap() += threads;
+    }
+
+    /// Count a thread as exited.
+    fn exit(&self) {
+        *self.running.lock().unwrap() -= 1;
+        self.exited.notify_all();
+    }
+
+    /// Count a thread as running until the returned guard is dropped, even while
+    /// unwinding from a panic. The guard is moved into the thread once spawned.
+    fn start(self: &Arc<Self>) -> WorkerGuard {
+        self.add(1);
+        WorkerGuard(Arc::clone(self))
+    }
+
+    /// The threads that haven't exited.
+    fn running(&self) -> u32 {
+        *self.running.lock().unwrap() as u32
+    }
+
+    /// Wait for every thread to exit, for at most `timeout`.
+    fn wait(&self, timeout: Duration) -> Result<()> {
+        let running = self.running.lock().unwrap();
+        let (running, _) = self
+            .exited
+            .wait_timeout_while(running, timeout, |running| *running > 0)
+            .unwrap();
+        match *running {
+            0 => Ok(()),
+            running => Err(KvsError::StringError(format!(
+                "{} threads of the pool were still running after {:?}",
+                running, timeout
+            ))),
+        }
+    }
+}
+
+struct WorkerGuard(Arc<Workers>);
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.0.exit();
+    }
+}
+
+/// The message of a panic, if it was raised with one.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Box<dyn Any>".to_owned(),
+        },
+    }
 }
@@ -1,11 +1,26 @@
-use std::thread;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use super::ThreadPool;
+use tracing::{debug, error};
+
+use super::{JobOptions, JobStats, PoolStats, ThreadPool, ThreadPoolBuilder, Workers};
 use crate::Result;
 
 /// A naive implementation of a thread pool that spawns a new thread for each job.
 #[derive(Clone)]
-pub struct NaiveThreadPool;
+pub struct NaiveThreadPool {
+    closed: Arc<AtomicBool>,
+    workers: Arc<Workers>,
+    jobs: Arc<JobStats>,
+    options: Arc<ThreadPoolBuilder>,
+    // the threads started so far, numbering their names
+    started: Arc<AtomicUsize>,
+}
 
 /// Implementation of the `ThreadPool` trait for `NaiveThreadPool`.
 ///
@@ -15,27 +30,63 @@ impl ThreadPool for NaiveThreadPool {
     ///
     /// # Arguments
     ///
-    /// * `_threads` - The number of threads parameter is ignored in this implementation.
+    /// * `builder` - The options of the threads. The number of threads is ignored in
+    ///   this implementation.
     ///
     /// # Returns
     ///
     /// Returns a `Result` containing the newly created `NaiveThreadPool`.
-    fn new(_threads: u32) -> Result<Self>
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self>
     where
         Self: Sized,
     {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool {
+            closed: Arc::new(AtomicBool::new(false)),
+            workers: Arc::new(Workers::default()),
+            jobs: Arc::new(JobStats::default()),
+            options: Arc::new(builder),
+            started: Arc::new(AtomicUsize::new(0)),
+        })
     }
 
     /// Spawns a new thread to execute the provided job.
     ///
     /// # Arguments
     ///
+    /// * `options` - When to skip the job. The priority is ignored in this
+    ///   implementation.
     /// * `job` - A closure representing the job to be executed in a new thread.
-    fn spawn<T>(&self, job: T)
+    fn spawn_with_options<T>(&self, options: JobOptions, job: T)
     where
         T: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        if self.closed.load(Ordering::SeqCst) {
+            debug!("Thread pool is shut down, dropping the job");
+            return;
+        }
+        let job = self.jobs.track(options, job);
+        let worker = self.workers.start();
+        let callbacks = Arc::clone(&self.options);
+        let index = self.started.fetch_add(1, Ordering::Relaxed);
+        let res = self.options.spawn(index, move || {
+            let _worker = worker;
+            callbacks.started();
+            job();
+            callbacks.stopped();
+        });
+        if let Err(e) = res {
+            error!("Failed to spawn a thread: {}", e);
+        }
+    }
+
+    /// Stops spawning threads and waits for the running ones to exit.
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        self.workers.wait(timeout)
+    }
+
+    /// Reports a thread per running job, none are ever queued.
+    fn stats(&self) -> PoolStats {
+        self.jobs.snapshot(self.workers.running())
     }
 }
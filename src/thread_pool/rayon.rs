@@ -1,12 +1,24 @@
-use std::sync::Arc;
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
-use super::ThreadPool;
+use tracing::debug;
+
+use super::{JobOptions, JobStats, PoolStats, ThreadPool, ThreadPoolBuilder, Workers};
 
 use crate::{KvsError, Result};
 
 /// A thread pool implementation using the Rayon library.
 #[derive(Clone)]
-pub struct RayonThreadPool(Arc<rayon::ThreadPool>);
+pub struct RayonThreadPool(Arc<Shared>);
+
+struct Shared {
+    // None once shut down
+    pool: RwLock<Option<rayon::ThreadPool>>,
+    workers: Arc<Workers>,
+    jobs: Arc<JobStats>,
+}
 
 /// Implementation of the `ThreadPool` trait for `RayonThreadPool`.
 impl ThreadPool for RayonThreadPool {
@@ -14,7 +26,7 @@ impl ThreadPool for RayonThreadPool {
     ///
     /// # Arguments
     ///
-    /// * `threads` - The number of threads in the pool.
+    /// * `builder` - The number of threads in the pool and their options.
     ///
     /// # Returns
     ///
@@ -23,23 +35,59 @@ impl ThreadPool for RayonThreadPool {
     /// # Errors
     ///
     /// Returns an error if there is an issue creating the Rayon thread pool.
-    fn new(threads: u32) -> Result<Self> {
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(threads as usize)
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self> {
+        let workers = Arc::new(Workers::default());
+        let exited = Arc::clone(&workers);
+        let options = Arc::new(builder);
+        let (named, started, stopped) = (options.clone(), options.clone(), options.clone());
+        let mut pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(options.threads() as usize)
+            .thread_name(move |index| named.thread_name(index))
+            .start_handler(move |_| started.started())
+            .exit_handler(move |_| {
+                stopped.stopped();
+                exited.exit();
+            });
+        if let Some(size) = options.thread_stack_size() {
+            pool = pool.stack_size(size);
+        }
+        let pool = pool
             .build()
             .map_err(|e| KvsError::StringError(format!("{}", e)))?;
-        Ok(RayonThreadPool(Arc::new(pool)))
+        // no thread exits before the pool is dropped
+        workers.add(pool.current_num_threads());
+        Ok(RayonThreadPool(Arc::new(Shared {
+            pool: RwLock::new(Some(pool)),
+            workers,
+            jobs: Arc::new(JobStats::default()),
+        })))
     }
 
     /// Spawns a new task to be executed in the Rayon thread pool.
     ///
     /// # Arguments
     ///
+    /// * `options` - When to skip the task. The priority is ignored in this
+    ///   implementation.
     /// * `job` - A closure representing the task to be executed in the pool.
-    fn spawn<T>(&self, job: T)
+    fn spawn_with_options<T>(&self, options: JobOptions, job: T)
     where
         T: FnOnce() + Send + 'static,
     {
-        self.0.spawn(job)
+        match &*self.0.pool.read().unwrap() {
+            Some(pool) => pool.spawn(self.0.jobs.track(options, job)),
+            None => debug!("Thread pool is shut down, dropping the job"),
+        }
+    }
+
+    /// Drops the Rayon thread pool, whose threads exit once they ran the tasks
+    /// already spawned, and waits for them.
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        drop(self.0.pool.write().unwrap().take());
+        self.0.workers.wait(timeout)
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.0.jobs.snapshot(self.0.workers.running())
     }
 }
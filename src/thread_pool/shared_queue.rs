@@ -1,20 +1,226 @@
 use std::{
+    any::Any,
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock,
     },
     thread,
+    time::Duration,
 };
 
-use log::{debug, error};
+use tracing::{debug, error};
 
-use super::ThreadPool;
-use crate::Result;
+use super::{
+    panic_message, JobOptions, JobStats, PoolStats, Priority, ThreadPool, ThreadPoolBuilder,
+    WorkerGuard, Workers,
+};
+use crate::{KvsError, Result};
+
+// how long the threads above the minimum wait for a job before they exit
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 /// A thread pool implementation using a shared queue for task distribution.
+///
+/// A pool created with `with_bounds` starts with its minimum number of threads,
+/// starts more while jobs are queued and every thread is busy, up to its maximum,
+/// and lets the threads above the minimum exit once they were idle for a while.
+///
+/// Jobs spawned with `Priority::Background` only run once no other job is
+/// queued.
+///
+/// A thread whose job panicked is replaced by a new one. The panics are counted
+/// in `worker_stats` and passed to the hook set with `set_panic_hook`.
 #[derive(Clone)]
 pub struct SharedQueueThreadPool {
-    tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    tx: Sender<Message>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    rx: Mutex<Receiver<Message>>,
+    queue: Mutex<Queue>,
+    state: Mutex<State>,
+    idle_timeout: Duration,
+    workers: Arc<Workers>,
+    job_stats: Arc<JobStats>,
+    options: ThreadPoolBuilder,
+    // the threads started so far, numbering their names
+    started: AtomicUsize,
+    panic_hook: RwLock<Option<PanicHook>>,
+    jobs: AtomicU64,
+    panics: AtomicU64,
+    respawns: AtomicU64,
+}
+
+type PanicHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Default)]
+struct Queue {
+    foreground: VecDeque<Job>,
+    background: VecDeque<Job>,
+}
+
+impl Queue {
+    fn push(&mut self, priority: Priority, job: Job) {
+        match priority {
+            Priority::Foreground => self.foreground.push_back(job),
+            Priority::Background => self.background.push_back(job),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Job> {
+        self.foreground
+            .pop_front()
+            .or_else(|| self.background.pop_front())
+    }
+}
+
+struct State {
+    min: u32,
+    max: u32,
+    // threads running, not counting the ones a stop was queued for
+    live: u32,
+    // threads waiting for a job, counted from when they're started
+    idle: u32,
+    // jobs sent and not yet received by a thread
+    queued: u32,
+    closed: bool,
+}
+
+/// Counters of the jobs a `SharedQueueThreadPool` ran, from `worker_stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// The jobs run to the end or until they panicked.
+    pub jobs: u64,
+    /// The jobs that panicked.
+    pub panics: u64,
+    /// The threads started to replace one whose job panicked.
+    pub respawns: u64,
+}
+
+enum Message {
+    // sent once per job queued, the thread receiving it runs the first job in
+    // order of priority
+    Run,
+    // sent once per thread to retire, behind the jobs already queued
+    Stop,
+}
+
+impl SharedQueueThreadPool {
+    /// Creates a pool running between `min` and `max` threads.
+    ///
+    /// Threads above `min` are started when a job is queued and no thread is idle,
+    /// and exit after waiting `idle_timeout` without a job.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `max` is zero or below `min`, or if a thread can't be
+    /// spawned.
+    pub fn with_bounds(min: u32, max: u32, idle_timeout: Duration) -> Result<Self> {
+        Self::start(min, max, idle_timeout, ThreadPoolBuilder::new(max))
+    }
+
+    fn start(
+        min: u32,
+        max: u32,
+        idle_timeout: Duration,
+        options: ThreadPoolBuilder,
+    ) -> Result<Self> {
+        if max == 0 || min > max {
+            return Err(KvsError::StringError(format!(
+                "Invalid thread pool bounds {}..={}",
+                min, max
+            )));
+        }
+        let (tx, rx) = channel();
+        let shared = Arc::new(Shared {
+            rx: Mutex::new(rx),
+            queue: Mutex::new(Queue::default()),
+            state: Mutex::new(State {
+                min,
+                max,
+                live: 0,
+                idle: 0,
+                queued: 0,
+                closed: false,
+            }),
+            idle_timeout,
+            workers: Arc::new(Workers::default()),
+            job_stats: Arc::new(JobStats::default()),
+            options,
+            started: AtomicUsize::new(0),
+            panic_hook: RwLock::new(None),
+            jobs: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+            respawns: AtomicU64::new(0),
+        });
+        {
+            let mut state = shared.state.lock().unwrap();
+            for _ in 0..min {
+                shared.start_thread(&mut state)?;
+            }
+        }
+        Ok(SharedQueueThreadPool { tx, shared })
+    }
+
+    /// Changes the maximum number of threads to `threads`, lowering the minimum
+    /// if it's above it.
+    ///
+    /// Threads beyond the new maximum exit once the jobs queued before the call
+    /// started. Raising the maximum starts threads for the jobs still queued.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `threads` is zero.
+    pub fn resize(&self, threads: u32) -> Result<()> {
+        if threads == 0 {
+            return Err(KvsError::StringError(
+                "The thread pool needs at least one thread".to_owned(),
+            ));
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        state.max = threads;
+        state.min = state.min.min(threads);
+        if state.closed {
+            return Ok(());
+        }
+        while state.live > state.max {
+            // can't fail, the receiver lives as long as the pool
+            let _ = self.tx.send(Message::Stop);
+            state.live -= 1;
+        }
+        self.shared.grow(&mut state);
+        Ok(())
+    }
+
+    /// The number of threads running, not counting the ones told to exit.
+    pub fn threads(&self) -> u32 {
+        self.shared.state.lock().unwrap().live
+    }
+
+    /// Calls `hook` with the message of every panic of a job, on the thread that
+    /// ran it, before the thread is replaced. Replaces the hook set before.
+    pub fn set_panic_hook<F>(&self, hook: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        *self.shared.panic_hook.write().unwrap() = Some(Arc::new(hook));
+    }
+
+    /// The jobs run, the panics among them and the threads replaced since the
+    /// pool was created.
+    pub fn worker_stats(&self) -> WorkerStats {
+        WorkerStats {
+            jobs: self.shared.jobs.load(Ordering::Relaxed),
+            panics: self.shared.panics.load(Ordering::Relaxed),
+            respawns: self.shared.respawns.load(Ordering::Relaxed),
+        }
+    }
 }
 
 impl ThreadPool for SharedQueueThreadPool {
@@ -22,62 +228,182 @@ impl ThreadPool for SharedQueueThreadPool {
     ///
     /// # Arguments
     ///
-    /// * `threads` - The number of threads in the pool.
+    /// * `builder` - The number of threads in the pool and their options.
     ///
     /// # Returns
     ///
     /// Returns a `Result` containing the newly created `SharedQueueThreadPool`.
-    fn new(threads: u32) -> Result<Self> {
-        let (tx, rx) = channel();
-        let rx = Arc::new(Mutex::new(rx));
-
-        for _ in 0..threads {
-            let rx = Arc::clone(&rx);
-            let rx = JobReceiver(rx);
-            thread::Builder::new().spawn(move || execute(rx))?;
-        }
-        Ok(SharedQueueThreadPool { tx })
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self> {
+        let threads = builder.threads();
+        Self::start(threads, threads.max(1), IDLE_TIMEOUT, builder)
     }
 
     /// Spawns a new task to be executed in the shared queue thread pool.
     ///
     /// # Arguments
     ///
+    /// * `options` - The priority of the task, which is queued behind the ones of
+    ///   the same priority, and when to skip it.
     /// * `job` - A closure representing the task to be executed in the pool.
     ///
-    fn spawn<T>(&self, job: T)
+    fn spawn_with_options<T>(&self, options: JobOptions, job: T)
     where
         T: FnOnce() + Send + 'static,
     {
-        self.tx
-            .send(Box::new(job))
-            .expect("The thread pool has no thread.");
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            debug!("Thread pool is shut down, dropping the job");
+            return;
+        }
+        let priority = options.priority;
+        let job = self.shared.job_stats.track(options, job);
+        self.shared
+            .queue
+            .lock()
+            .unwrap()
+            .push(priority, Box::new(job));
+        // can't fail, the receiver lives as long as the pool
+        let _ = self.tx.send(Message::Run);
+        state.queued += 1;
+        self.shared.grow(&mut state);
+    }
+
+    /// Queues a stop for every thread behind the queued jobs and waits for the
+    /// threads to exit.
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if !state.closed {
+                state.closed = true;
+                for _ in 0..state.live {
+                    // can't fail, the receiver lives as long as the pool
+                    let _ = self.tx.send(Message::Stop);
+                }
+                state.live = 0;
+            }
+        }
+        self.shared.workers.wait(timeout)
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.shared
+            .job_stats
+            .snapshot(self.shared.workers.running())
     }
 }
 
-type ConcurrentReceiver = Arc<Mutex<Receiver<Box<dyn FnOnce() + Send + 'static>>>>;
-struct JobReceiver(ConcurrentReceiver);
+impl Shared {
+    /// Start threads for the queued jobs no idle thread will take, up to the
+    /// maximum.
+    fn grow(self: &Arc<Self>, state: &mut State) {
+        while state.queued > state.idle && state.live < state.max {
+            if let Err(e) = self.start_thread(state) {
+                error!("Failed to spawn a thread: {}", e);
+                return;
+            }
+        }
+    }
+
+    fn start_thread(self: &Arc<Self>, state: &mut State) -> Result<()> {
+        let rx = JobReceiver {
+            shared: Arc::clone(self),
+            _worker: self.workers.start(),
+        };
+        let index = self.started.fetch_add(1, Ordering::Relaxed);
+        self.options.spawn(index, move || execute(rx))?;
+        state.live += 1;
+        state.idle += 1;
+        Ok(())
+    }
+
+    fn panicked(&self, payload: &(dyn Any + Send)) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+        let message = panic_message(payload);
+        error!("A job of the thread pool panicked: {}", message);
+        // cloned so a hook set meanwhile doesn't wait for this one
+        let hook = self.panic_hook.read().unwrap().clone();
+        if let Some(hook) = hook {
+            hook(message.as_str());
+        }
+    }
+}
+
+struct JobReceiver {
+    shared: Arc<Shared>,
+    // dropped after `drop` counted the replacement thread, so shutting down keeps
+    // waiting
+    _worker: WorkerGuard,
+}
 
 impl Drop for JobReceiver {
     fn drop(&mut self) {
         if thread::panicking() {
-            let rx = self.0.clone();
-            let rx = JobReceiver(rx);
-            if let Err(e) = thread::Builder::new().spawn(move || execute(rx)) {
-                error!("Failed to spawn a thread: {}", e);
+            let mut state = self.shared.state.lock().unwrap();
+            match self.shared.start_thread(&mut state) {
+                // the replacement takes over the place of this thread
+                Ok(()) => {
+                    state.live -= 1;
+                    self.shared.respawns.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => error!("Failed to spawn a thread: {}", e),
             }
         }
     }
 }
 
 fn execute(rx: JobReceiver) {
+    rx.shared.options.started();
+    run_jobs(&rx.shared);
+    rx.shared.options.stopped();
+}
+
+/// Run the queued jobs until the thread is told to exit or was idle too long.
+fn run_jobs(shared: &Shared) {
     loop {
-        let job = rx.0.lock().unwrap().recv();
-        match job {
-            Ok(job) => {
-                job();
+        let message = shared.rx.lock().unwrap().recv_timeout(shared.idle_timeout);
+        let mut state = shared.state.lock().unwrap();
+        match message {
+            Ok(Message::Run) => {
+                state.idle -= 1;
+                state.queued -= 1;
+                drop(state);
+                let job = shared
+                    .queue
+                    .lock()
+                    .unwrap()
+                    .pop()
+                    .expect("a job is queued for every message");
+                let res = panic::catch_unwind(AssertUnwindSafe(job));
+                shared.jobs.fetch_add(1, Ordering::Relaxed);
+                if let Err(payload) = res {
+                    shared.panicked(&*payload);
+                    // the job may have left the thread locals broken, the thread
+                    // is replaced
+                    panic::resume_unwind(payload);
+                }
+                shared.state.lock().unwrap().idle += 1;
+            }
+            Ok(Message::Stop) => {
+                state.idle -= 1;
+                debug!("Thread pool is shrunk or shut down, thread exits");
+                return;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                // a job queued since the timeout counted on this thread to take it
+                if state.live > state.min && state.queued < state.idle {
+                    state.live -= 1;
+                    state.idle -= 1;
+                    debug!(
+                        "Thread was idle for {:?}, thread exits",
+                        shared.idle_timeout
+                    );
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                debug!("Thread pool is destroyed, thread exits");
+                return;
             }
-            Err(_) => debug!("Thread pool is destroyed, thread exits"),
         }
     }
 }
@@ -1,7 +1,7 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::process::{self, Command};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -129,6 +129,48 @@ fn client_cli_invalid_subcommand() {
         .failure();
 }
 
+// `kvs-client flushall` without `--yes` should refuse before connecting
+#[test]
+fn client_cli_flushall_unconfirmed() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["flushall", "--addr", "127.0.0.1:1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--yes"));
+}
+
+// `kvs-client --tls` should need a CA bundle, and a client key with a certificate
+#[test]
+fn client_cli_tls_options() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key", "--tls"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--ca-file"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "get",
+            "key",
+            "--tls",
+            "--ca-file",
+            "ca.pem",
+            "--tls-cert",
+            "client.pem",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--tls-key"));
+}
+
 // `kvs-client -V` should print the version
 #[test]
 fn client_cli_version() {
@@ -227,6 +269,14 @@ fn cli_access_server(engine: &str, addr: &str) {
     });
     thread::sleep(Duration::from_secs(1));
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ping", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("PONG\n");
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["set", "key1", "value1", "--addr", addr])
@@ -335,3 +385,664 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs-server --check` should validate and exit without initializing the directory.
+#[test]
+fn server_cli_check() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4006", "--check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("Check passed"));
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
+// `kvs-server --check` should fail on a corrupted log, a database in use and a
+// running server's PID file
+#[test]
+fn server_cli_check_fails() {
+    let check = |dir: &TempDir, args: &[&str]| {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", "127.0.0.1:0", "--check"])
+            .args(args)
+            .current_dir(dir)
+            .assert()
+    };
+
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("1.log"), "{\"Set\":{\"key\":").unwrap();
+    check(&temp_dir, &["--engine", "kvs"])
+        .failure()
+        .stderr(contains("corrupted"));
+
+    let temp_dir = TempDir::new().unwrap();
+    let db = sled::open(temp_dir.path()).unwrap();
+    #[cfg(unix)]
+    check(&temp_dir, &["--engine", "sled"])
+        .failure()
+        .stderr(contains("in use"));
+    drop(db);
+    check(&temp_dir, &["--engine", "sled"]).success();
+
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("kvs.pid"), process::id().to_string()).unwrap();
+    check(&temp_dir, &["--pid-file", "kvs.pid"])
+        .failure()
+        .stderr(contains("already running"));
+}
+
+// `kvs-server --migrate-to` should copy the data into the new engine and switch to it.
+#[test]
+fn server_cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--migrate-to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("Migrated 1 pairs"));
+    let marker = fs::read_to_string(temp_dir.path().join("engine")).unwrap();
+    assert_eq!(marker, "kvs");
+
+    // migrating again into an engine holding data is refused
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--migrate-to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client import` should set the pairs read from stdin, and `export` print them
+#[test]
+fn client_cli_import_export() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["import", "-", "--addr", addr])
+        .write_stdin("SET key1 value one\n\n{\"key\":\"key 2\",\"value\":\"value2\"}\n")
+        .assert()
+        .success()
+        .stderr(contains("Imported 2 pairs"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["export", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(concat!(
+            "{\"key\":\"key 2\",\"value\":\"value2\"}\n",
+            "{\"key\":\"key1\",\"value\":\"value one\"}\n"
+        ));
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["import", "-", "--addr", addr])
+        .write_stdin("SET key3 value3\nGET key3\n")
+        .assert()
+        .failure()
+        .stderr(contains("Line 2"));
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client scan` and `keys` should list up to `--limit` entries in either format
+#[test]
+fn client_cli_scan_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["import", "-", "--addr", addr])
+        .write_stdin("SET a1 x\nSET a2 y\nSET b1 z\n")
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "a", "--limit", "1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("a1\tx\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "a", "--output", "json", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("{\"key\":\"a1\",\"value\":\"x\"}\n{\"key\":\"a2\",\"value\":\"y\"}\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["keys", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("a1\na2\nb1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["keys", "b", "--output", "json", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("\"b1\"\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client set --value-file` and `get --output` should move values through files
+#[test]
+fn client_cli_value_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let value = "line one\n\tline two\n";
+    fs::write(temp_dir.path().join("in.txt"), value).unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "--value-file", "in.txt", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--output", "out.txt", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("out.txt")).unwrap(),
+        value
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value", "--value-file", "in.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client` should take the address and timeout from the environment, with flags
+// placed before or after the subcommand
+#[test]
+fn client_cli_global_options() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .env("KVS_ADDR", addr)
+        .env("KVS_TIMEOUT", "5")
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", addr, "--timeout", "0", "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--timeout", "soon"])
+        .env("KVS_ADDR", addr)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client` should exit with codes telling failures apart, and `--quiet` should
+// only print results
+#[test]
+fn client_cli_exit_codes() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "missing", "--strict", "--addr", addr])
+        .assert()
+        .code(3)
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "missing", "--addr", addr])
+        .assert()
+        .code(3);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "missing", "--quiet", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["-q", "ping", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get"])
+        .assert()
+        .code(2);
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ping", "--addr", addr])
+        .assert()
+        .code(4);
+}
+
+// `kvs-server --pool` and `--threads` should pick the engine's thread pool
+#[test]
+fn server_cli_thread_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--pool", "fibers"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--threads", "0", "--check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let addr = "127.0.0.1:4013";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--pool", "shared-queue", "--threads", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-server --log-level`, `RUST_LOG` and `--log-format` should shape the logs
+#[test]
+fn server_cli_log_level_and_format() {
+    let temp_dir = TempDir::new().unwrap();
+    let check = ["--addr", "127.0.0.1:0", "--check"];
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&check)
+        .args(&["--log-level", "warn"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(is_empty());
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&check)
+        .env("RUST_LOG", "warn")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(is_empty());
+    // the flag wins over the environment
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&check)
+        .args(&["--log-level", "info", "--log-format", "json"])
+        .env("RUST_LOG", "warn")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("\"message\":\"Check passed\""));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&check)
+        .args(&["--log-level", "loud"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-admin` should maintain the data directory of a stopped server
+#[test]
+fn admin_cli_maintenance() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for value in &["value1", "value2", "value3"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", value, "--addr", addr])
+            .assert()
+            .success();
+    }
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let admin = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-admin").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+    admin(&["stats"])
+        .assert()
+        .success()
+        .stdout(contains("keys\t1\n"));
+    admin(&["verify"])
+        .assert()
+        .success()
+        .stdout(contains("No corruption found"));
+    admin(&["compact"]).assert().success();
+    admin(&["stats"])
+        .assert()
+        .success()
+        .stdout(contains("uncompacted_bytes\t0\n"));
+
+    // a backup restores into a directory a server can start in
+    let backup = temp_dir.path().join("backup");
+    let restored = TempDir::new().unwrap();
+    admin(&["backup", backup.to_str().unwrap()])
+        .assert()
+        .success();
+    admin(&["--dir", restored.path().to_str().unwrap(), "restore"])
+        .arg(&backup)
+        .assert()
+        .success()
+        .stdout(contains("Restored 1 keys"));
+    admin(&["stats", "--dir", restored.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("keys\t1\n"));
+
+    admin(&["migrate", "--to", "sled"])
+        .assert()
+        .success()
+        .stdout(contains("Migrated 1 pairs"));
+    admin(&["stats"])
+        .assert()
+        .success()
+        .stdout(contains("engine\tsled\n"));
+    // only the kvs engine keeps log files to check
+    admin(&["verify"]).assert().failure();
+}
+
+// `kvs` should read and write a local database without a server
+#[test]
+fn local_cli_database() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("db");
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).arg("--db").arg(&db).current_dir(&temp_dir);
+        cmd
+    };
+    kvs(&["set", "key1", "value1"]).assert().success();
+    kvs(&["set", "key2", "value2"]).assert().success();
+    kvs(&["set", "other", "value3"]).assert().success();
+    kvs(&["get", "key1"]).assert().success().stdout("value1\n");
+    kvs(&["get", "missing"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    kvs(&["rm", "missing"])
+        .assert()
+        .failure()
+        .stdout("Key not found\n");
+    kvs(&["rm", "other"]).assert().success();
+
+    kvs(&["keys"]).assert().success().stdout("key1\nkey2\n");
+    kvs(&["scan", "key", "--limit", "1"])
+        .assert()
+        .success()
+        .stdout("key1\tvalue1\n");
+    kvs(&["compact"]).assert().success();
+    kvs(&["stats"])
+        .assert()
+        .success()
+        .stdout(contains("keys\t2\n"))
+        .stdout(contains("uncompacted_bytes\t0\n"));
+
+    // a server's database of another engine is left alone
+    fs::write(db.join("engine"), "sled").unwrap();
+    kvs(&["get", "key1"]).assert().failure();
+}
+
+// `kvs dump` should write a dump file that `kvs restore` loads into another database
+#[test]
+fn local_cli_dump_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = |db: &str, args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(&["--db", db]).args(args).current_dir(&temp_dir);
+        cmd
+    };
+    kvs("source", &["set", "key1", "value1"]).assert().success();
+    kvs("source", &["set", "key2", "value2"]).assert().success();
+    kvs("source", &["dump", "--out", "backup.kvsdump"])
+        .assert()
+        .success()
+        .stdout(contains("Dumped 2 pairs"));
+
+    kvs("copy", &["restore", "--in", "backup.kvsdump"])
+        .assert()
+        .success()
+        .stdout(contains("Restored 2 pairs"));
+    kvs("copy", &["scan"])
+        .assert()
+        .success()
+        .stdout("key1\tvalue1\nkey2\tvalue2\n");
+
+    // restoring over existing pairs would mix them in
+    kvs("source", &["restore", "--in", "backup.kvsdump"])
+        .assert()
+        .failure();
+}
+
+// `kvs-server --pid-file` should write its PID, refuse a second server and remove
+// the file when stopped
+#[test]
+fn server_cli_pid_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let pid_file = temp_dir.path().join("kvs-server.pid");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4015", "--pid-file", "kvs-server.pid"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let pid = fs::read_to_string(&pid_file).unwrap();
+    assert_eq!(pid, format!("{}\n", child.id()));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4016", "--pid-file", "kvs-server.pid"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already running"));
+
+    #[cfg(unix)]
+    {
+        Command::new("kill")
+            .arg(child.id().to_string())
+            .assert()
+            .success();
+        assert!(child.wait().unwrap().success());
+        assert!(!pid_file.exists());
+    }
+    #[cfg(not(unix))]
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client top` should print a line of statistics per sample below a header
+#[test]
+fn client_cli_top() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4017";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["top", "--count", "2", "--addr", addr])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("keys"));
+    assert!(lines[0].contains("ops/s"));
+    // the first sample has no rate yet, the second does
+    assert!(lines[1].starts_with("1 "));
+    assert_eq!(lines[1].split_whitespace().nth(2), Some("-"));
+    assert_ne!(lines[2].split_whitespace().nth(2), Some("-"));
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-server` should keep the engine marker in `--data-dir`, name the engine that
+// initialized it and only reinitialize it with `--force-engine` while it holds no keys
+#[test]
+fn server_cli_data_dir_and_force_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4018";
+    let server = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(&["--data-dir", "data", "--addr", addr])
+            .args(args)
+            .current_dir(&temp_dir);
+        cmd
+    };
+    let mut child = server(&["--engine", "sled"]).spawn().unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    let marker = temp_dir.path().join("data").join("engine");
+    assert_eq!(fs::read_to_string(&marker).unwrap(), "sled");
+    assert!(!temp_dir.path().join("engine").exists());
+
+    server(&["--engine", "kvs", "--check"])
+        .assert()
+        .failure()
+        .stderr(contains("initialized with the sled engine"));
+
+    let mut child = server(&["--engine", "kvs", "--force-engine"])
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert_eq!(fs::read_to_string(&marker).unwrap(), "kvs");
+
+    // the kvs engine holds a key now
+    server(&["--engine", "sled", "--force-engine"])
+        .assert()
+        .failure()
+        .stderr(contains("holds keys"));
+}
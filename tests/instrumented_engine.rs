@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use kvs::{InstrumentedEngine, KvsEngine, LatencyHistogram, MemKvsEngine, Result};
+
+// Should count calls, errors and latencies per operation
+#[tokio::test]
+async fn records_operations() -> Result<()> {
+    let engine = InstrumentedEngine::new(MemKvsEngine::new());
+
+    engine
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    engine.clone().get("key1".to_owned()).await?;
+    engine.clone().get("key2".to_owned()).await?;
+    assert!(engine.clone().remove("key2".to_owned()).await.is_err());
+
+    let metrics = engine.metrics();
+    assert_eq!(
+        metrics.keys().copied().collect::<Vec<_>>(),
+        vec!["get", "remove", "set"]
+    );
+    assert_eq!(metrics["get"].count, 2);
+    assert_eq!(metrics["get"].errors, 0);
+    assert_eq!(metrics["get"].latency.count(), 2);
+    assert_eq!(metrics["remove"].count, 1);
+    assert_eq!(metrics["remove"].errors, 1);
+
+    Ok(())
+}
+
+// Should place latencies in doubling buckets and report quantiles from them
+#[test]
+fn latency_quantiles() {
+    let mut histogram = LatencyHistogram::default();
+    assert_eq!(histogram.quantile(0.5), None);
+
+    for _ in 0..9 {
+        histogram.record(Duration::from_micros(3));
+    }
+    histogram.record(Duration::from_millis(1));
+
+    assert_eq!(histogram.count(), 10);
+    assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(4)));
+    assert_eq!(histogram.quantile(1.0), Some(Duration::from_micros(1024)));
+}
@@ -1,6 +1,12 @@
+use bytes::Bytes;
 use futures::future::try_join_all;
+use futures::StreamExt;
 use kvs::thread_pool::RayonThreadPool;
-use kvs::{KvStore, KvsEngine, KvsError, Result};
+use kvs::{
+    KeyEvent, KvStore, KvStoreOptions, KvsEngine, KvsEngineExt, KvsError, Result, ScanOptions,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -263,3 +269,1139 @@ async fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should append to existing values and create missing ones
+#[tokio::test]
+async fn append_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    assert_eq!(
+        store
+            .clone()
+            .append("key1".to_owned(), "foo".to_owned())
+            .await?,
+        3
+    );
+    assert_eq!(
+        store
+            .clone()
+            .append("key1".to_owned(), "bar".to_owned())
+            .await?,
+        6
+    );
+    assert_eq!(
+        store.clone().get("key1".to_owned()).await?,
+        Some("foobar".to_owned())
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("foobar".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should return the previous value while setting or removing
+#[tokio::test]
+async fn getset_and_getdel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    assert_eq!(
+        store
+            .clone()
+            .getset("key1".to_owned(), "value1".to_owned())
+            .await?,
+        None
+    );
+    assert_eq!(
+        store
+            .clone()
+            .getset("key1".to_owned(), "value2".to_owned())
+            .await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.clone().getdel("key1".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.clone().getdel("key1".to_owned()).await?, None);
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+
+    Ok(())
+}
+
+// Should only set keys that are absent or hold the expected value
+#[tokio::test]
+async fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    let cas = |expected: Option<&str>, value: &str| {
+        store.clone().compare_and_swap(
+            "key1".to_owned(),
+            expected.map(str::to_owned),
+            value.to_owned(),
+        )
+    };
+    assert!(cas(None, "value1").await?);
+    assert!(!cas(None, "value2").await?);
+    assert!(!cas(Some("value2"), "value3").await?);
+    assert!(cas(Some("value1"), "value3").await?);
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should rename and copy keys with their expiration, surviving a restart
+#[tokio::test]
+async fn rename_and_copy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store
+        .clone()
+        .set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_secs(60),
+        )
+        .await?;
+    store
+        .clone()
+        .set("key2".to_owned(), "value2".to_owned())
+        .await?;
+
+    assert!(matches!(
+        store
+            .clone()
+            .rename("missing".to_owned(), "key3".to_owned())
+            .await,
+        Err(KvsError::KeyNotFound)
+    ));
+    store
+        .clone()
+        .rename("key1".to_owned(), "key3".to_owned())
+        .await?;
+    assert_eq!(store.clone().get("key1".to_owned()).await?, None);
+    assert!(store.clone().ttl("key3".to_owned()).await?.is_some());
+
+    let copy = |to: &str, overwrite| {
+        store
+            .clone()
+            .copy("key3".to_owned(), to.to_owned(), overwrite)
+    };
+    assert!(!copy("key2", false).await?);
+    assert_eq!(
+        store.clone().get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert!(copy("key2", true).await?);
+    assert!(copy("key4", false).await?);
+    assert!(
+        !store
+            .clone()
+            .copy("missing".to_owned(), "key5".to_owned(), true)
+            .await?
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.clone().get("key1".to_owned()).await?, None);
+    for key in ["key2", "key3", "key4"] {
+        assert_eq!(
+            store.clone().get(key.to_owned()).await?,
+            Some("value1".to_owned())
+        );
+    }
+    assert!(store.ttl("key4".to_owned()).await?.is_some());
+
+    Ok(())
+}
+
+// Should count live keys and test their presence from the index
+#[tokio::test]
+async fn len_and_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    assert!(store.clone().is_empty().await?);
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    store
+        .clone()
+        .set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            Duration::from_millis(50),
+        )
+        .await?;
+    assert_eq!(store.clone().len().await?, 2);
+    assert!(store.clone().exists("key2".to_owned()).await?);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(store.clone().len().await?, 1);
+    assert!(!store.clone().exists("key2".to_owned()).await?);
+    assert!(store.clone().exists("key1".to_owned()).await?);
+    assert!(!store.is_empty().await?);
+
+    Ok(())
+}
+
+// Should report value metadata, keeping the modification time across an expire
+#[tokio::test]
+async fn value_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    assert_eq!(store.clone().meta("key1".to_owned()).await?, None);
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    let meta = store.clone().meta("key1".to_owned()).await?.unwrap();
+    assert_eq!(meta.len, 6);
+    assert_eq!(meta.ttl_ms, None);
+    let modified_ms = meta.modified_ms.expect("modification time not recorded");
+
+    assert!(
+        store
+            .clone()
+            .expire("key1".to_owned(), Duration::from_secs(60))
+            .await?
+    );
+    let meta = store.clone().meta("key1".to_owned()).await?.unwrap();
+    assert_eq!(meta.modified_ms, Some(modified_ms));
+    assert!(meta.ttl_ms.unwrap() <= 60_000);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let meta = store.meta("key1".to_owned()).await?.unwrap();
+    assert_eq!(meta.len, 6);
+    assert_eq!(meta.modified_ms, Some(modified_ms));
+
+    Ok(())
+}
+
+// Should read from the snapshot and apply buffered writes atomically on commit
+#[tokio::test]
+async fn transaction_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+
+    let mut txn = store.clone().transaction().await?;
+    assert_eq!(txn.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    txn.set("key2".to_owned(), "value2".to_owned());
+    txn.remove("key1".to_owned());
+    assert_eq!(txn.get("key1".to_owned()).await?, None);
+    assert_eq!(txn.get("key2".to_owned()).await?, Some("value2".to_owned()));
+    assert_eq!(store.clone().get("key2".to_owned()).await?, None);
+    txn.commit().await?;
+
+    assert_eq!(store.clone().get("key1".to_owned()).await?, None);
+    assert_eq!(
+        store.clone().get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.clone().get("key1".to_owned()).await?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should keep seeing old values and refuse to commit over concurrent changes
+#[tokio::test]
+async fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+
+    let mut txn = store.clone().transaction().await?;
+    store
+        .clone()
+        .set("key1".to_owned(), "value2".to_owned())
+        .await?;
+    assert_eq!(txn.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    txn.set("key2".to_owned(), "value3".to_owned());
+    assert!(matches!(
+        txn.commit().await,
+        Err(KvsError::TransactionConflict)
+    ));
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+
+    Ok(())
+}
+
+// Should keep serving the snapshotted state across writes and compactions
+#[tokio::test]
+async fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    store
+        .clone()
+        .set("key2".to_owned(), "value2".to_owned())
+        .await?;
+
+    let snapshot = store.clone().snapshot().await?;
+    store.clone().remove("key2".to_owned()).await?;
+    // overwrite enough data to trigger at least one compaction
+    let value = "x".repeat(1024);
+    for _ in 0..2048 {
+        store.clone().set("key1".to_owned(), value.clone()).await?;
+    }
+
+    assert_eq!(
+        snapshot.clone().get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        snapshot.scan("key".to_owned()).await?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+
+    Ok(())
+}
+
+// Should deliver changes of watched keys only
+#[tokio::test]
+async fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    let mut events = store.clone().watch("key".to_owned());
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    store
+        .clone()
+        .set("other".to_owned(), "value2".to_owned())
+        .await?;
+    store.clone().remove("key1".to_owned()).await?;
+
+    assert_eq!(
+        events.next().await,
+        Some(KeyEvent::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned()
+        })
+    );
+    assert_eq!(
+        events.next().await,
+        Some(KeyEvent::Remove {
+            key: "key1".to_owned()
+        })
+    );
+
+    Ok(())
+}
+
+// Should store keys and values that are not valid UTF-8
+// and read them back after reopening
+#[tokio::test]
+async fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    let key = Bytes::from_static(&[0xff, 0x00, 0xfe]);
+    let value = Bytes::from_static(&[0x80, 0x81, 0x00]);
+    store.clone().set_bytes(key.clone(), value.clone()).await?;
+    assert_eq!(
+        store.clone().get_bytes(key.clone()).await?,
+        Some(value.clone())
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.clone().get_bytes(key.clone()).await?, Some(value));
+
+    store.clone().remove_bytes(key.clone()).await?;
+    assert_eq!(store.get_bytes(key).await?, None);
+
+    Ok(())
+}
+
+// Should round-trip structs through `set_as` and `get_as`
+#[tokio::test]
+async fn typed_values() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    let user = User {
+        name: "alice".to_owned(),
+        age: 30,
+    };
+    store.clone().set_as("user1".to_owned(), &user).await?;
+    assert_eq!(
+        store.clone().get_as::<User>("user1".to_owned()).await?,
+        Some(user)
+    );
+    assert_eq!(
+        store.clone().get_as::<User>("user2".to_owned()).await?,
+        None
+    );
+
+    store
+        .clone()
+        .set("user2".to_owned(), "not json".to_owned())
+        .await?;
+    assert!(matches!(
+        store.get_as::<User>("user2".to_owned()).await,
+        Err(KvsError::Serde(_))
+    ));
+
+    Ok(())
+}
+
+// Should make written data durable on `flush`
+#[tokio::test]
+async fn flush_persists_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    store.clone().flush().await?;
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should remove every key on `clear`, also after reopening,
+// while snapshots taken before still see the old data
+#[tokio::test]
+async fn clear_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    for i in 0..100 {
+        store
+            .clone()
+            .set(format!("key{}", i), format!("value{}", i))
+            .await?;
+    }
+    let snapshot = store.clone().snapshot().await?;
+
+    store.clone().clear().await?;
+    assert_eq!(store.clone().get("key1".to_owned()).await?, None);
+    assert_eq!(
+        snapshot.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    store
+        .clone()
+        .set("key2".to_owned(), "new".to_owned())
+        .await?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.clone().get("key1".to_owned()).await?, None);
+    assert_eq!(store.get("key2".to_owned()).await?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// Should scan keys by prefix in both directions and find the first and last key
+#[tokio::test]
+async fn scan_and_key_bounds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    assert_eq!(store.clone().first_key().await?, None);
+    assert_eq!(store.clone().last_key().await?, None);
+
+    for key in ["a", "log1", "log2", "log3", "z"] {
+        store
+            .clone()
+            .set(key.to_owned(), key.to_uppercase())
+            .await?;
+    }
+
+    let options = ScanOptions {
+        prefix: "log".to_owned(),
+        ..ScanOptions::default()
+    };
+    let pairs = store.clone().scan(options.clone()).await?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("log1".to_owned(), "LOG1".to_owned()),
+            ("log2".to_owned(), "LOG2".to_owned()),
+            ("log3".to_owned(), "LOG3".to_owned()),
+        ]
+    );
+
+    let latest = ScanOptions {
+        limit: Some(2),
+        reverse: true,
+        ..options
+    };
+    let pairs = store.clone().scan(latest).await?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("log3".to_owned(), "LOG3".to_owned()),
+            ("log2".to_owned(), "LOG2".to_owned()),
+        ]
+    );
+
+    assert_eq!(store.clone().first_key().await?, Some("a".to_owned()));
+    assert_eq!(store.last_key().await?, Some("z".to_owned()));
+
+    Ok(())
+}
+
+// Should write a checkpoint that opens as a store with the data
+// written before it, unaffected by later writes
+#[tokio::test]
+async fn checkpoint_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    for i in 0..1000 {
+        store
+            .clone()
+            .set(format!("key{}", i), format!("value{}", i))
+            .await?;
+    }
+    store.clone().checkpoint(backup_dir.path()).await?;
+    store
+        .clone()
+        .set("key1".to_owned(), "changed".to_owned())
+        .await?;
+    store.clone().remove("key2".to_owned()).await?;
+
+    let backup = KvStore::<RayonThreadPool>::open(backup_dir.path(), 1)?;
+    for i in 0..1000 {
+        assert_eq!(
+            backup.clone().get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    // a directory that already holds a store is refused
+    assert!(store.checkpoint(backup_dir.path()).await.is_err());
+
+    Ok(())
+}
+
+// Should report records whose value was changed on disk
+// and restore only stores that verify cleanly
+#[tokio::test]
+async fn verify_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    store
+        .clone()
+        .set("key2".to_owned(), "value2".to_owned())
+        .await?;
+    drop(store);
+
+    let report = KvStore::<RayonThreadPool>::verify(temp_dir.path())?;
+    assert!(report.is_ok());
+    assert_eq!(report.records, 2);
+    assert_eq!(report.keys, 2);
+
+    let report = KvStore::<RayonThreadPool>::restore(temp_dir.path(), restore_dir.path())?;
+    assert!(report.is_ok());
+    let restored = KvStore::<RayonThreadPool>::open(restore_dir.path(), 1)?;
+    assert_eq!(
+        restored.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    // flip a value without updating its checksum
+    for entry in WalkDir::new(temp_dir.path()) {
+        let path = entry.expect("unable to read directory entry").into_path();
+        if path.is_file() {
+            let log = std::fs::read_to_string(&path)?;
+            std::fs::write(&path, log.replace("value1", "VALUE1"))?;
+        }
+    }
+    let report = KvStore::<RayonThreadPool>::verify(temp_dir.path())?;
+    assert_eq!(report.corrupted.len(), 1);
+    assert_eq!(report.corrupted[0].position, 0);
+
+    let other_dir = TempDir::new().expect("unable to create temporary restore directory");
+    assert!(KvStore::<RayonThreadPool>::restore(temp_dir.path(), other_dir.path()).is_err());
+
+    Ok(())
+}
+
+// Should export every pair and import them into another store
+#[tokio::test]
+async fn export_and_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let other = KvStore::<RayonThreadPool>::open(other_dir.path(), 1)?;
+
+    // more pairs than fit in one export page
+    for i in 0..3000 {
+        store
+            .clone()
+            .set(format!("key{}", i), format!("value{}", i))
+            .await?;
+    }
+
+    let mut dump = Vec::new();
+    assert_eq!(store.export(&mut dump).await?, 3000);
+    assert_eq!(other.clone().import(&dump[..]).await?, 3000);
+    for i in 0..3000 {
+        assert_eq!(
+            other.clone().get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    assert!(other.import(&b"not a dump"[..]).await.is_err());
+
+    Ok(())
+}
+
+// Should reject operations after `close` and keep the data written before it
+#[tokio::test]
+async fn close_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    store.clone().close().await?;
+    store.clone().close().await?;
+    assert!(matches!(
+        store.clone().get("key1".to_owned()).await,
+        Err(KvsError::Closed)
+    ));
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()).await,
+        Err(KvsError::Closed)
+    ));
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should expire keys after their time to live and allow adjusting it
+#[tokio::test]
+async fn ttl_expire_and_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store
+        .clone()
+        .set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_millis(100),
+        )
+        .await?;
+    let ttl = store.clone().ttl("key1".to_owned()).await?;
+    assert!(ttl.map_or(false, |ttl| ttl <= Duration::from_millis(100)));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(store.clone().get("key1".to_owned()).await?, None);
+    assert_eq!(store.clone().ttl("key1".to_owned()).await?, None);
+
+    store
+        .clone()
+        .set("key2".to_owned(), "value2".to_owned())
+        .await?;
+    assert_eq!(store.clone().ttl("key2".to_owned()).await?, None);
+    assert!(!store.clone().persist("key2".to_owned()).await?);
+    assert!(
+        store
+            .clone()
+            .expire("key2".to_owned(), Duration::from_secs(60))
+            .await?
+    );
+    assert!(store.clone().ttl("key2".to_owned()).await?.is_some());
+    assert!(store.clone().persist("key2".to_owned()).await?);
+    assert_eq!(store.clone().ttl("key2".to_owned()).await?, None);
+    assert!(
+        !store
+            .clone()
+            .expire("missing".to_owned(), Duration::from_secs(60))
+            .await?
+    );
+
+    // Open from disk again and check the expiration survived
+    store
+        .clone()
+        .expire("key2".to_owned(), Duration::from_secs(60))
+        .await?;
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert!(store.clone().ttl("key2".to_owned()).await?.is_some());
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+
+    Ok(())
+}
+
+// Should keep the configured number of versions through compaction and reopening
+#[tokio::test]
+async fn version_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        versions: 3,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+
+    for i in 0..5 {
+        store
+            .clone()
+            .set("key1".to_owned(), format!("value{}", i))
+            .await?;
+    }
+    assert_eq!(
+        store.clone().get_versions("key1".to_owned()).await?,
+        vec!["value4", "value3", "value2"]
+    );
+
+    store
+        .clone()
+        .set("key2".to_owned(), "old".to_owned())
+        .await?;
+    store.clone().remove("key2".to_owned()).await?;
+    assert_eq!(
+        store.clone().get_versions("key2".to_owned()).await?,
+        vec!["old"]
+    );
+
+    // write enough other data to trigger compactions
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store
+                .clone()
+                .set(format!("other{}", key_id), format!("{}", iter))
+                .await?;
+        }
+    }
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    assert_eq!(
+        store.clone().get_versions("key1".to_owned()).await?,
+        vec!["value4", "value3", "value2"]
+    );
+    assert_eq!(store.clone().get("key2".to_owned()).await?, None);
+    assert_eq!(store.get_versions("key2".to_owned()).await?, vec!["old"]);
+
+    Ok(())
+}
+
+// Should refuse values over the configured size and leave the store unchanged
+#[tokio::test]
+async fn max_value_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_value_size: Some(8),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+
+    store
+        .clone()
+        .set("key1".to_owned(), "12345678".to_owned())
+        .await?;
+    let res = store
+        .clone()
+        .set("key1".to_owned(), "123456789".to_owned())
+        .await;
+    assert!(matches!(
+        res,
+        Err(KvsError::ValueTooLarge { size: 9, max: 8 })
+    ));
+    let res = store
+        .clone()
+        .append("key1".to_owned(), "9".to_owned())
+        .await;
+    assert!(matches!(res, Err(KvsError::ValueTooLarge { .. })));
+    assert_eq!(
+        store.clone().get("key1".to_owned()).await?,
+        Some("12345678".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should apply a batch of sets and removes at once, surviving a reopen
+#[tokio::test]
+async fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    store
+        .clone()
+        .write_batch(vec![
+            ("key2".to_owned(), Some("value2".to_owned())),
+            ("key1".to_owned(), None),
+            ("missing".to_owned(), None),
+            ("key2".to_owned(), Some("value3".to_owned())),
+        ])
+        .await?;
+    assert_eq!(store.clone().get("key1".to_owned()).await?, None);
+    assert_eq!(
+        store.clone().get("key2".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.clone().get("key1".to_owned()).await?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should report the load of the thread pool in the engine statistics
+#[tokio::test]
+async fn engine_stats_report_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+
+    let stats = store.clone().engine_stats().await?;
+    assert_eq!(stats.get("keys"), Some(&1));
+    assert_eq!(stats.get("pool_threads"), Some(&2));
+    assert_eq!(stats.get("pool_queued_jobs"), Some(&0));
+    // the job of the set may still be finishing
+    assert!(stats["pool_busy_jobs"] + stats["pool_completed_jobs"] >= 1);
+    assert!(stats.contains_key("pool_average_wait_micros"));
+    Ok(())
+}
+
+// Should serve requests with the file IO done on the calling task
+#[tokio::test(flavor = "multi_thread")]
+async fn inline_io() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        inline_io: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+
+    for key_id in 0..100 {
+        store
+            .clone()
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .await?;
+    }
+    assert_eq!(store.clone().get("missing".to_owned()).await?, None);
+    assert_eq!(
+        store
+            .clone()
+            .scan(ScanOptions {
+                limit: Some(2),
+                ..ScanOptions::default()
+            })
+            .await?,
+        vec![
+            ("key0".to_owned(), "value0".to_owned()),
+            ("key1".to_owned(), "value1".to_owned()),
+        ]
+    );
+
+    // more readers than the pool has threads
+    let values = try_join_all((0..100).map(|key_id| {
+        let store = store.clone();
+        tokio::spawn(async move { store.get(format!("key{}", key_id)).await })
+    }))
+    .await
+    .expect("a get panicked");
+    for (key_id, value) in values.into_iter().enumerate() {
+        assert_eq!(value?, Some(format!("value{}", key_id)));
+    }
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key99".to_owned()).await?,
+        Some("value99".to_owned())
+    );
+    Ok(())
+}
+
+// Should keep every write across writer shards, through reopening with another count
+#[tokio::test]
+async fn writer_shards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        writer_shards: 4,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 4, options)?;
+
+    try_join_all((0..1000).map(|key_id| {
+        store
+            .clone()
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+    }))
+    .await?;
+    store
+        .clone()
+        .rename("key0".to_owned(), "renamed".to_owned())
+        .await?;
+    store
+        .clone()
+        .write_batch(vec![
+            ("key1".to_owned(), None),
+            ("key2".to_owned(), Some("batched".to_owned())),
+            ("key3".to_owned(), Some("batched".to_owned())),
+        ])
+        .await?;
+    store.clone().compact().await?;
+
+    let stats = store.clone().engine_stats().await?;
+    assert_eq!(stats.get("keys"), Some(&999));
+    assert_eq!(stats.get("writer_shards"), Some(&4));
+
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.clone().checkpoint(checkpoint_dir.path()).await?;
+    store
+        .clone()
+        .set("key4".to_owned(), "late".to_owned())
+        .await?;
+    drop(store);
+
+    let options = KvStoreOptions {
+        writer_shards: 3,
+        ..KvStoreOptions::default()
+    };
+    for (dir, key4) in [(&temp_dir, "late"), (&checkpoint_dir, "value4")] {
+        let store = KvStore::<RayonThreadPool>::open_with_options(dir.path(), 1, options.clone())?;
+        assert_eq!(store.clone().get("key0".to_owned()).await?, None);
+        assert_eq!(
+            store.clone().get("renamed".to_owned()).await?,
+            Some("value0".to_owned())
+        );
+        assert_eq!(store.clone().get("key1".to_owned()).await?, None);
+        assert_eq!(
+            store.clone().get("key2".to_owned()).await?,
+            Some("batched".to_owned())
+        );
+        assert_eq!(
+            store.clone().get("key4".to_owned()).await?,
+            Some(key4.to_owned())
+        );
+        assert_eq!(
+            store.get("key999".to_owned()).await?,
+            Some("value999".to_owned())
+        );
+    }
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.len().await?, 999);
+    Ok(())
+}
+
+// Should delete the log files of a busy shard even while the other shards get no writes
+#[tokio::test]
+async fn writer_shards_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        writer_shards: 4,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.and_then(|entry| entry.metadata()).map(|m| m.len()))
+            .sum::<walkdir::Result<u64>>()
+            .expect("fail to get directory size")
+    };
+
+    for key_id in 0..100 {
+        store
+            .clone()
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .await?;
+    }
+    // 10 MiB written to a single key
+    let value = "x".repeat(1024);
+    for _ in 0..10 * 1024 {
+        store.clone().set("hot".to_owned(), value.clone()).await?;
+    }
+    // the idle shards catch up in the background
+    for _ in 0..100 {
+        if dir_size() < 5 * 1024 * 1024 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(dir_size() < 5 * 1024 * 1024);
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    assert_eq!(store.clone().get("hot".to_owned()).await?, Some(value));
+    for key_id in 0..100 {
+        assert_eq!(
+            store.clone().get(format!("key{}", key_id)).await?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Should write a batch spanning writer shards as one record, so a crash between the
+// shards' log files keeps all of it or none of it
+#[tokio::test]
+async fn writer_shards_batch_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        writer_shards: 4,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+    for key_id in 0..16 {
+        store
+            .clone()
+            .set(format!("key{}", key_id), "old".to_owned())
+            .await?;
+    }
+
+    let log_sizes = || {
+        std::fs::read_dir(temp_dir.path())
+            .expect("unable to read the data directory")
+            .map(|entry| {
+                let entry = entry.expect("unable to read the data directory");
+                (entry.path(), entry.metadata().map_or(0, |m| m.len()))
+            })
+            .collect::<Vec<_>>()
+    };
+    let before = log_sizes();
+    // 16 keys can't all hash to the same one of 4 shards
+    store
+        .clone()
+        .write_batch(
+            (0..16)
+                .map(|key_id| (format!("key{}", key_id), Some("new".to_owned())))
+                .collect(),
+        )
+        .await?;
+    let grown: Vec<_> = log_sizes()
+        .into_iter()
+        .filter_map(|(path, size)| {
+            let old = before
+                .iter()
+                .find(|(p, _)| *p == path)
+                .map_or(0, |(_, size)| *size);
+            (size > old).then_some((path, old))
+        })
+        .collect();
+    assert_eq!(grown.len(), 1);
+
+    // whatever the shards write next is replayed after the batch
+    store
+        .clone()
+        .set("key0".to_owned(), "newer".to_owned())
+        .await?;
+    drop(store);
+
+    // a crash before the record reached the disk keeps none of the batch
+    let crashed = TempDir::new().expect("unable to create temporary working directory");
+    for (path, _) in log_sizes() {
+        std::fs::copy(&path, crashed.path().join(path.file_name().unwrap()))?;
+    }
+    let (path, old) = &grown[0];
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(crashed.path().join(path.file_name().unwrap()))?
+        .set_len(*old)?;
+    let store = KvStore::<RayonThreadPool>::open_with_options(crashed.path(), 1, options.clone())?;
+    for key_id in 1..16 {
+        assert_eq!(
+            store.clone().get(format!("key{}", key_id)).await?,
+            Some("old".to_owned())
+        );
+    }
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    assert_eq!(
+        store.clone().get("key0".to_owned()).await?,
+        Some("newer".to_owned())
+    );
+    for key_id in 1..16 {
+        assert_eq!(
+            store.clone().get(format!("key{}", key_id)).await?,
+            Some("new".to_owned())
+        );
+    }
+    Ok(())
+}
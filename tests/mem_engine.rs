@@ -0,0 +1,101 @@
+use kvs::{EngineHandle, KvsEngine, KvsError, MemKvsEngine, NoopEngine, Result, ScanOptions};
+
+// Should get, overwrite and remove values without touching the disk
+#[tokio::test]
+async fn get_set_remove() -> Result<()> {
+    let engine = MemKvsEngine::new();
+
+    engine
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    engine
+        .clone()
+        .set("key1".to_owned(), "value2".to_owned())
+        .await?;
+    assert_eq!(
+        engine.clone().get("key1".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    engine.clone().remove("key1".to_owned()).await?;
+    assert_eq!(engine.clone().get("key1".to_owned()).await?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+// Should share data between clones and scan it in key order
+#[tokio::test]
+async fn clones_share_data() -> Result<()> {
+    let engine = MemKvsEngine::new();
+
+    for key in ["b", "a", "c"] {
+        engine.clone().set(key.to_owned(), key.to_owned()).await?;
+    }
+    assert_eq!(
+        engine
+            .clone()
+            .append("a".to_owned(), "!".to_owned())
+            .await?,
+        2
+    );
+
+    let pairs = engine.clone().scan(ScanOptions::default()).await?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "a!".to_owned()),
+            ("b".to_owned(), "b".to_owned()),
+            ("c".to_owned(), "c".to_owned()),
+        ]
+    );
+    assert_eq!(engine.clone().last_key().await?, Some("c".to_owned()));
+
+    engine.clone().clear().await?;
+    assert_eq!(engine.first_key().await?, None);
+
+    Ok(())
+}
+
+// Should accept writes without ever storing them
+#[tokio::test]
+async fn noop_engine_forgets() -> Result<()> {
+    let engine = NoopEngine::new();
+
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    assert_eq!(engine.scan(ScanOptions::default()).await?, vec![]);
+    assert!(matches!(
+        engine.remove("key1".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+// Should drive engines of different types through the same handle type
+#[tokio::test]
+async fn engine_handles() -> Result<()> {
+    let engines = vec![
+        EngineHandle::new(MemKvsEngine::new()),
+        EngineHandle::new(NoopEngine::new()),
+    ];
+
+    for engine in &engines {
+        engine
+            .clone()
+            .set("key1".to_owned(), "value1".to_owned())
+            .await?;
+    }
+    assert_eq!(
+        engines[0].clone().get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engines[1].clone().get("key1".to_owned()).await?, None);
+
+    Ok(())
+}
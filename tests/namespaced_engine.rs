@@ -0,0 +1,71 @@
+use futures::StreamExt;
+use kvs::{KeyEvent, KvsEngine, MemKvsEngine, NamespacedEngine, Result, ScanOptions};
+
+// Should keep the keys of each database apart, with database 0 as the inner keyspace
+#[tokio::test]
+async fn separate_keyspaces() -> Result<()> {
+    let inner = MemKvsEngine::new();
+    let db0 = NamespacedEngine::new(inner.clone(), 0);
+    let db1 = NamespacedEngine::new(inner.clone(), 1);
+
+    db0.clone().set("key1".to_owned(), "db0".to_owned()).await?;
+    db1.clone().set("key1".to_owned(), "db1".to_owned()).await?;
+    db1.clone().set("key2".to_owned(), "db1".to_owned()).await?;
+
+    assert_eq!(
+        db0.clone().get("key1".to_owned()).await?,
+        Some("db0".to_owned())
+    );
+    assert_eq!(
+        db1.clone().get("key1".to_owned()).await?,
+        Some("db1".to_owned())
+    );
+    assert_eq!(db0.clone().get("key2".to_owned()).await?, None);
+    assert_eq!(
+        inner.clone().get("key1".to_owned()).await?,
+        Some("db0".to_owned())
+    );
+
+    // the reserved keys of other databases are neither reachable nor scanned
+    assert!(db0
+        .clone()
+        .set("\0key".to_owned(), "value".to_owned())
+        .await
+        .is_err());
+    let pairs = db0.clone().scan(ScanOptions::default()).await?;
+    assert_eq!(pairs, vec![("key1".to_owned(), "db0".to_owned())]);
+    let options = ScanOptions {
+        limit: Some(1),
+        ..ScanOptions::default()
+    };
+    assert_eq!(db0.clone().scan(options.clone()).await?.len(), 1);
+    let pairs = db1.clone().scan(options).await?;
+    assert_eq!(pairs, vec![("key1".to_owned(), "db1".to_owned())]);
+
+    db1.clone().clear().await?;
+    assert!(db1.scan(ScanOptions::default()).await?.is_empty());
+    assert_eq!(db0.get("key1".to_owned()).await?, Some("db0".to_owned()));
+
+    Ok(())
+}
+
+// Should only deliver changes of the watched database, under their own keys
+#[tokio::test]
+async fn watch_database() -> Result<()> {
+    let inner = MemKvsEngine::new();
+    let db0 = NamespacedEngine::new(inner.clone(), 0);
+    let db1 = NamespacedEngine::new(inner, 1);
+    let mut events = db1.clone().watch(String::new());
+
+    db0.set("key1".to_owned(), "db0".to_owned()).await?;
+    db1.set("key1".to_owned(), "db1".to_owned()).await?;
+    assert_eq!(
+        events.next().await,
+        Some(KeyEvent::Set {
+            key: "key1".to_owned(),
+            value: "db1".to_owned()
+        })
+    );
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use kvs::{
+    KeyEvent, KvsClient, KvsEngine, KvsError, KvsServer, MemKvsEngine, RemoteEngine, Result,
+    ScanOptions, TieredEngine,
+};
+
+// Should serve the engine operations from the server, on its selected database
+#[tokio::test]
+async fn forwards_to_the_server() -> Result<()> {
+    let store = MemKvsEngine::new();
+    let server = KvsServer::new(store.clone())
+        .databases(2)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    client.select(1).await?;
+    let engine = RemoteEngine::new(client);
+
+    engine
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    engine
+        .clone()
+        .set("key2".to_owned(), "value2".to_owned())
+        .await?;
+    assert_eq!(
+        engine.clone().get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        engine
+            .clone()
+            .append("key1".to_owned(), "!".to_owned())
+            .await?,
+        7
+    );
+    assert!(matches!(
+        engine.clone().remove("missing".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    let pairs = engine
+        .clone()
+        .scan(ScanOptions {
+            prefix: "key".to_owned(),
+            limit: Some(1),
+            ..ScanOptions::default()
+        })
+        .await?;
+    assert_eq!(pairs, vec![("key1".to_owned(), "value1!".to_owned())]);
+    assert_eq!(engine.clone().len().await?, 2);
+    // the writes went to database 1, not the engine's own keyspace
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+
+    // concurrent requests open further connections, set up the same way
+    let gets = (0..8).map(|_| engine.clone().get("key2".to_owned()));
+    for value in futures::future::try_join_all(gets).await? {
+        assert_eq!(value, Some("value2".to_owned()));
+    }
+
+    engine.clone().clear().await?;
+    assert!(engine.clone().is_empty().await?);
+    engine.close().await?;
+    server.shutdown().await
+}
+
+// Should sit behind the decorators written for embedded engines
+#[tokio::test]
+async fn tiered_and_watched() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    let engine = TieredEngine::new(RemoteEngine::new(client), 16);
+
+    let mut events = engine.clone().watch("key".to_owned());
+    // the subscription is made in the background
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    engine
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    for _ in 0..2 {
+        assert_eq!(
+            engine.clone().get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+    }
+    assert_eq!(engine.stats().hits, 1);
+    assert_eq!(
+        events.next().await,
+        Some(KeyEvent::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned()
+        })
+    );
+
+    server.shutdown().await
+}
@@ -0,0 +1,68 @@
+#![cfg(feature = "rocksdb")]
+
+use kvs::{
+    thread_pool::SharedQueueThreadPool, KvsEngine, KvsError, Result, RocksKvsEngine, ScanOptions,
+};
+use tempfile::TempDir;
+
+// Should get, overwrite and remove values, and keep them across reopening
+#[tokio::test]
+async fn get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = RocksKvsEngine::<SharedQueueThreadPool>::open(temp_dir.path(), 1)?;
+
+    engine
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    engine
+        .clone()
+        .set("key2".to_owned(), "value2".to_owned())
+        .await?;
+    engine.clone().remove("key2".to_owned()).await?;
+    assert!(matches!(
+        engine.clone().remove("key2".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    engine.close().await?;
+
+    let engine = RocksKvsEngine::<SharedQueueThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        engine.clone().get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.get("key2".to_owned()).await?, None);
+
+    Ok(())
+}
+
+// Should scan in both directions and continue after a key
+#[tokio::test]
+async fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = RocksKvsEngine::<SharedQueueThreadPool>::open(temp_dir.path(), 1)?;
+
+    for key in ["a1", "a2", "a3", "b1"] {
+        engine.clone().set(key.to_owned(), key.to_owned()).await?;
+    }
+
+    let keys =
+        |pairs: Vec<(String, String)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+    let options = ScanOptions {
+        prefix: "a".to_owned(),
+        after: Some("a1".to_owned()),
+        ..ScanOptions::default()
+    };
+    assert_eq!(keys(engine.clone().scan(options).await?), vec!["a2", "a3"]);
+
+    let options = ScanOptions {
+        prefix: "a".to_owned(),
+        reverse: true,
+        limit: Some(2),
+        ..ScanOptions::default()
+    };
+    assert_eq!(keys(engine.clone().scan(options).await?), vec!["a3", "a2"]);
+    assert_eq!(engine.last_key().await?, Some("b1".to_owned()));
+
+    Ok(())
+}
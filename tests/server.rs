@@ -0,0 +1,1806 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{StreamExt, TryStreamExt};
+use kvs::{
+    feature, thread_pool::RayonThreadPool, Acl, AclUser, AuditLog, AuditLogOptions, BatchReply,
+    ClientMetrics, Codec, Compression, KeyChange, KvStore, KvsClient, KvsEngine, KvsError,
+    KvsServer, MemKvsEngine, Request, Response, Result, RetryPolicy, RouterEngine, ShardMap,
+};
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+// Should report the port picked for port 0 and stop listening on shutdown
+#[tokio::test]
+async fn spawn_and_shutdown() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+
+    let mut client = KvsClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    server.shutdown().await?;
+    assert!(KvsClient::connect(addr).await.is_err());
+
+    Ok(())
+}
+
+// Should apply listener options and bind the same port again after a shutdown
+#[tokio::test]
+async fn listener_options() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .listen_backlog(16)
+        .reuse_address(true);
+    let handle = server.clone().spawn("127.0.0.1:0".parse().unwrap()).await?;
+    let addr = handle.local_addr().unwrap();
+    let mut client = KvsClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    handle.shutdown().await?;
+
+    let handle = server.spawn(addr).await?;
+    let mut client = KvsClient::connect(addr).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    handle.shutdown().await?;
+
+    Ok(())
+}
+
+// Should reconnect after the server restarts, restoring the selected database, and
+// only retry requests that are safe to send twice
+#[tokio::test]
+async fn reconnect_after_restart() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .databases(2)
+        .reuse_address(true);
+    let handle = server.clone().spawn("127.0.0.1:0".parse().unwrap()).await?;
+    let addr = handle.local_addr().unwrap();
+    let mut client = KvsClient::builder()
+        .retry_policy(RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+        })
+        .connect(addr)
+        .await?;
+    client.select(1).await?;
+    client.set("key1".to_owned(), "db1".to_owned()).await?;
+    handle.shutdown().await?;
+
+    // a write isn't retried, as it may have been applied already
+    assert!(client
+        .set("key2".to_owned(), "db1".to_owned())
+        .await
+        .is_err());
+
+    let handle = server.spawn(addr).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some("db1".to_owned()));
+    client.set("key2".to_owned(), "db1".to_owned()).await?;
+    assert_eq!(client.len().await?, 2);
+    handle.shutdown().await?;
+
+    // the server is gone for good, so reads give up after the retries
+    assert!(client.get("key1".to_owned()).await.is_err());
+    Ok(())
+}
+
+// Should resolve a host name and connect to whichever of its addresses listens
+#[tokio::test]
+async fn connect_by_host_name() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let port = server.local_addr().unwrap().port();
+
+    // localhost may resolve to ::1 first, where nothing listens
+    let mut client = KvsClient::connect(format!("localhost:{}", port)).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    let mut client = KvsClient::connect(("localhost", port)).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert!(KvsClient::connect("no-port").await.is_err());
+
+    server.shutdown().await
+}
+
+// Should serve clients in the same process without a socket
+#[tokio::test]
+async fn in_memory_transport() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn_in_memory()
+        .await?;
+    assert_eq!(server.local_addr(), None);
+
+    let mut client = KvsClient::connect_in_memory(&server).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    let mut client = KvsClient::builder()
+        .codec(Codec::MessagePack)
+        .connect_in_memory(&server)
+        .await?;
+    assert_eq!(client.codec(), Codec::MessagePack);
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    let tcp_server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    assert!(KvsClient::connect_in_memory(&tcp_server).await.is_err());
+    tcp_server.shutdown().await?;
+
+    server.shutdown().await
+}
+
+// Should connect on the first request instead of when created
+#[tokio::test]
+async fn connect_lazy() -> Result<()> {
+    let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let mut client = KvsClient::builder()
+        .retry_policy(RetryPolicy::never())
+        .connect_lazy(format!("localhost:{}", port));
+    assert!(!client.is_connected());
+    // nothing listens yet
+    assert!(client.ping().await.is_err());
+    assert!(!client.is_connected());
+
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn(([127, 0, 0, 1], port).into())
+        .await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert!(client.is_connected());
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+// Should answer repeated reads from the read cache until the key is written,
+// expires from the cache or, if followed, changes on the server
+#[tokio::test]
+async fn read_cache() -> Result<()> {
+    let store = MemKvsEngine::new();
+    let server = KvsServer::new(store.clone())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+    store
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+
+    let mut client = KvsClient::builder()
+        .read_cache(16, Duration::from_secs(60))
+        .connect(addr)
+        .await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    // a change behind the client's back goes unnoticed
+    store
+        .clone()
+        .set("key1".to_owned(), "value2".to_owned())
+        .await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    // but not one made by the client itself
+    client.append("key1".to_owned(), "!".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value2!".to_owned())
+    );
+
+    let mut client = KvsClient::builder()
+        .read_cache(16, Duration::from_millis(50))
+        .connect(addr)
+        .await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value2!".to_owned())
+    );
+    store
+        .clone()
+        .set("key1".to_owned(), "value3".to_owned())
+        .await?;
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+
+    let mut client = KvsClient::builder()
+        .read_cache(16, Duration::from_secs(60))
+        .invalidate_from_server(true)
+        .connect(addr)
+        .await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+    store
+        .clone()
+        .set("key1".to_owned(), "value4".to_owned())
+        .await?;
+    let mut value = None;
+    for _ in 0..50 {
+        value = client.get("key1".to_owned()).await?;
+        if value.as_deref() == Some("value4") {
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(value, Some("value4".to_owned()));
+
+    server.shutdown().await
+}
+
+// Should report every request with its outcome to the metrics hook
+#[tokio::test]
+async fn client_metrics() -> Result<()> {
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(&'static str, bool)>>);
+
+    impl ClientMetrics for Recorder {
+        fn record(&self, op: &'static str, _latency: Duration, error: Option<&KvsError>) {
+            self.0.lock().unwrap().push((op, error.is_none()));
+        }
+    }
+
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let recorder = Arc::new(Recorder::default());
+    let mut client = KvsClient::builder()
+        .metrics(recorder.clone())
+        .connect(server.local_addr().unwrap())
+        .await?;
+
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.get("key1".to_owned()).await?;
+    assert!(client.remove("missing".to_owned()).await.is_err());
+    client
+        .pipeline(vec![
+            Request::Exists {
+                key: "key1".to_owned(),
+            },
+            Request::Remove {
+                key: "missing".to_owned(),
+            },
+        ])
+        .await?;
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        vec![
+            ("set", true),
+            ("get", true),
+            ("remove", false),
+            ("hello", true),
+            ("exists", true),
+            ("remove", false)
+        ]
+    );
+
+    server.shutdown().await
+}
+
+// Should fail over to the next server of the list when the connection breaks
+#[tokio::test]
+async fn failover() -> Result<()> {
+    let primary = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let standby = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addrs = [primary.local_addr().unwrap(), standby.local_addr().unwrap()];
+
+    let mut client = KvsClient::builder().connect_any(addrs).await?;
+    assert_eq!(client.peer_addr(), Some(addrs[0]));
+    client.set("key1".to_owned(), "primary".to_owned()).await?;
+    primary.shutdown().await?;
+
+    // the read is retried on the standby, which doesn't have the key
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    assert_eq!(client.peer_addr(), Some(addrs[1]));
+
+    // servers that are down are skipped when connecting too
+    let client = KvsClient::builder().connect_any(addrs).await?;
+    assert_eq!(client.peer_addr(), Some(addrs[1]));
+
+    standby.shutdown().await
+}
+
+// Should give up on a request the server never answers
+#[tokio::test]
+async fn request_timeout() -> Result<()> {
+    // accepts connections but never reads from them
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let accept = tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+
+    let mut client = KvsClient::builder()
+        .request_timeout(Some(Duration::from_millis(100)))
+        .connect(addr)
+        .await?;
+    let res = client.get("key1".to_owned()).await;
+    assert!(matches!(res, Err(KvsError::Timeout)));
+
+    // the next request goes out on a new connection, which stalls just the same
+    client.set_request_timeout(Some(Duration::from_millis(50)));
+    let res = client.set("key1".to_owned(), "value1".to_owned()).await;
+    assert!(matches!(res, Err(KvsError::Timeout)));
+    accept.abort();
+    Ok(())
+}
+
+// Should hold further clients back until a connection slot frees up
+#[tokio::test]
+async fn connection_limit() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .max_connections(1)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    let mut first = KvsClient::connect(addr).await?;
+    first.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    // the second connection sits in the backlog, so its request isn't answered yet
+    let mut second = KvsClient::connect(addr).await?;
+    let waiting = time::timeout(Duration::from_millis(200), second.get("key1".to_owned())).await;
+    assert!(waiting.is_err());
+
+    drop(first);
+    assert_eq!(
+        second.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+// Should refuse requests until the connection authenticates with the password
+#[tokio::test]
+async fn require_password() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .require_pass("secret")
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::AUTH));
+    assert!(client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .is_err());
+    assert!(client.authenticate("wrong".to_owned()).await.is_err());
+
+    client.authenticate("secret".to_owned()).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+// Should authenticate with the builder's credentials, again after reconnecting
+#[tokio::test]
+async fn builder_credentials() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .require_pass("secret")
+        .reuse_address(true);
+    let handle = server.clone().spawn("127.0.0.1:0".parse().unwrap()).await?;
+    let addr = handle.local_addr().unwrap();
+
+    let refused = KvsClient::builder()
+        .credentials(None, "wrong".to_owned())
+        .connect(addr)
+        .await;
+    assert!(refused.is_err());
+
+    let mut client = KvsClient::builder()
+        .credentials(None, "secret".to_owned())
+        .connect(addr)
+        .await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    handle.shutdown().await?;
+
+    let handle = server.spawn(addr).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    handle.shutdown().await
+}
+
+// Should limit users authenticated by name to the key prefixes they are granted
+#[tokio::test]
+async fn acl_prefixes() -> Result<()> {
+    let acl = Acl::new(vec![AclUser {
+        name: "team-a".to_owned(),
+        password: "secret-a".to_owned(),
+        read: vec!["a/".to_owned(), "shared/".to_owned()],
+        write: vec!["a/".to_owned()],
+        admin: false,
+    }]);
+    let server = KvsServer::new(MemKvsEngine::new())
+        .acl(acl)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client
+        .authenticate_as("team-a".to_owned(), "wrong".to_owned())
+        .await
+        .is_err());
+    client
+        .authenticate_as("team-a".to_owned(), "secret-a".to_owned())
+        .await?;
+
+    client.set("a/key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(client.get("shared/key".to_owned()).await?, None);
+    assert!(client
+        .set("shared/key".to_owned(), "value".to_owned())
+        .await
+        .is_err());
+    assert!(client.get("b/key".to_owned()).await.is_err());
+
+    server.shutdown().await
+}
+
+// Should remove every key on FlushAll, only for admins
+#[tokio::test]
+async fn flush_all() -> Result<()> {
+    let user = |name: &str, admin| AclUser {
+        name: name.to_owned(),
+        password: "secret".to_owned(),
+        read: vec![String::new()],
+        write: vec![String::new()],
+        admin,
+    };
+    let acl = Acl::new(vec![user("team-a", false), user("admin", true)]);
+    let server = KvsServer::new(MemKvsEngine::new())
+        .acl(acl)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    let mut client = KvsClient::connect(addr).await?;
+    client
+        .authenticate_as("team-a".to_owned(), "secret".to_owned())
+        .await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert!(client.flush_all().await.is_err());
+    assert!(client.get("key1".to_owned()).await?.is_some());
+    client
+        .authenticate_as("admin".to_owned(), "secret".to_owned())
+        .await?;
+    client.flush_all().await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    server.shutdown().await?;
+
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert!(client.flush_all().await.is_err());
+    assert!(client.get("key1".to_owned()).await?.is_some());
+    server.shutdown().await?;
+
+    let server = KvsServer::new(MemKvsEngine::new())
+        .enable_admin(true)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.set("key2".to_owned(), "value2".to_owned()).await?;
+    client.flush_all().await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    assert_eq!(client.get("key2".to_owned()).await?, None);
+
+    server.shutdown().await
+}
+
+// Should answer Redis protocol commands from a RESP listener
+#[tokio::test]
+async fn resp_commands() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn_resp("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut stream = TcpStream::connect(server.local_addr().unwrap()).await?;
+
+    let exchanges: &[(&[u8], &[u8])] = &[
+        (b"PING\r\n", b"+PONG\r\n"),
+        (
+            b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n",
+            b"+OK\r\n",
+        ),
+        (b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", b"$6\r\nvalue1\r\n"),
+        (
+            b"*3\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n",
+            b":1\r\n",
+        ),
+        (
+            b"*3\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n",
+            b":1\r\n",
+        ),
+        (b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", b"$-1\r\n"),
+        (
+            b"*5\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$6\r\nvalue2\r\n$2\r\nEX\r\n$2\r\n60\r\n",
+            b"+OK\r\n",
+        ),
+        // binary values are only stored without a TTL
+        (
+            b"*5\r\n$3\r\nSET\r\n$4\r\nkey3\r\n$2\r\n\xff\xfe\r\n$2\r\nEX\r\n$2\r\n60\r\n",
+            b"-ERR EX and PX need a key and value that are valid UTF-8\r\n",
+        ),
+        (
+            b"*3\r\n$3\r\nSET\r\n$4\r\nkey3\r\n$2\r\n\xff\xfe\r\n",
+            b"+OK\r\n",
+        ),
+        (
+            b"*3\r\n$6\r\nEXISTS\r\n$4\r\nkey2\r\n$4\r\nkey3\r\n",
+            b":2\r\n",
+        ),
+    ];
+    for (command, reply) in exchanges {
+        stream.write_all(command).await?;
+        let mut buf = vec![0; reply.len()];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, reply);
+    }
+
+    server.shutdown().await
+}
+
+// Should refuse a RESP command over the size limit before reading it
+#[tokio::test]
+async fn resp_oversized_command() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .max_request_size(1024)
+        .spawn_resp("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    // only the header is sent, the refusal mustn't wait for the value
+    let mut stream = TcpStream::connect(addr).await?;
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$4096\r\n")
+        .await?;
+    let mut reply = Vec::new();
+    time::timeout(Duration::from_secs(5), stream.read_to_end(&mut reply))
+        .await
+        .unwrap()?;
+    assert_eq!(
+        reply,
+        b"-ERR Protocol error: command exceeds the limit of 1024 bytes\r\n"
+    );
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let value = "x".repeat(512);
+    let set = format!("*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$512\r\n{}\r\n", value);
+    stream.write_all(set.as_bytes()).await?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).await?;
+    assert_eq!(&reply, b"+OK\r\n");
+
+    server.shutdown().await
+}
+
+// Should serve clients over a Unix socket and remove it on shutdown
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn_unix(&path)
+        .await?;
+    assert_eq!(server.local_addr(), None);
+
+    let mut client = KvsClient::connect_unix(&path).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    server.shutdown().await?;
+    assert!(!path.exists());
+    assert!(KvsClient::connect_unix(&path).await.is_err());
+
+    Ok(())
+}
+
+// Should answer every request of a pipelined batch, matched to its request
+#[tokio::test]
+async fn pipelined_requests() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::PIPELINING));
+
+    let sets = (0..500)
+        .map(|i| Request::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+            ttl_ms: None,
+        })
+        .collect();
+    let responses = client.pipeline(sets).await?;
+    assert_eq!(responses.len(), 500);
+    assert!(responses.iter().all(|resp| matches!(resp, Response::Set)));
+
+    let gets = (0..500)
+        .map(|i| Request::Get {
+            key: format!("key{}", i),
+        })
+        .chain(std::iter::once(Request::Ping))
+        .collect();
+    let responses = client.pipeline(gets).await?;
+    for (i, resp) in responses[..500].iter().enumerate() {
+        match resp {
+            Response::Get(value) => assert_eq!(value, &Some(format!("value{}", i))),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+    assert!(matches!(responses[500], Response::Pong));
+
+    // untagged requests keep working on the same connection
+    assert_eq!(
+        client.get("key7".to_owned()).await?,
+        Some("value7".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+// Should push the changes of subscribed keys to the connection
+#[tokio::test]
+async fn subscribe_notifications() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    let mut subscriber = KvsClient::connect(addr).await?;
+    assert!(subscriber.server_info().await?.supports(feature::SUBSCRIBE));
+    let mut notifications = subscriber.subscribe("user/".to_owned()).await?;
+
+    let mut client = KvsClient::connect(addr).await?;
+    client.set("user/1".to_owned(), "alice".to_owned()).await?;
+    client.set("other".to_owned(), "ignored".to_owned()).await?;
+    client.remove("user/1".to_owned()).await?;
+
+    assert_eq!(
+        notifications.next().await.unwrap()?,
+        ("user/1".to_owned(), KeyChange::Set("alice".to_owned()))
+    );
+    assert_eq!(
+        notifications.next().await.unwrap()?,
+        ("user/1".to_owned(), KeyChange::Remove)
+    );
+
+    server.shutdown().await?;
+    assert!(notifications.next().await.is_none());
+
+    Ok(())
+}
+
+// Should page through the keys of a prefix with the returned cursors
+#[tokio::test]
+async fn scan_pages() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::SCAN));
+    for i in 0..5 {
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .await?;
+    }
+    client.set("other".to_owned(), "value".to_owned()).await?;
+
+    let (pairs, cursor) = client.scan_page("key".to_owned(), None, 2).await?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key0".to_owned(), "value0".to_owned()),
+            ("key1".to_owned(), "value1".to_owned()),
+        ]
+    );
+    assert_eq!(cursor, Some("key1".to_owned()));
+
+    let (pairs, cursor) = client.scan_page("key".to_owned(), cursor, 2).await?;
+    assert_eq!(pairs.len(), 2);
+    assert_eq!(pairs[0].0, "key2");
+    let (pairs, cursor) = client.scan_page("key".to_owned(), cursor, 2).await?;
+    assert_eq!(pairs, vec![("key4".to_owned(), "value4".to_owned())]);
+    assert_eq!(cursor, None);
+
+    server.shutdown().await
+}
+
+// Should page through the whole prefix as the scan stream is read
+#[tokio::test]
+async fn scan_stream() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+
+    // more than one page
+    let mut batch = client.batch().set("other".to_owned(), "value".to_owned());
+    for i in 0..2500 {
+        batch = batch.set(format!("key{:04}", i), format!("value{}", i));
+    }
+    batch.send().await?;
+
+    let pairs: Vec<_> = client.scan("key".to_owned()).try_collect().await?;
+    assert_eq!(pairs.len(), 2500);
+    assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(pairs[2499], ("key2499".to_owned(), "value2499".to_owned()));
+
+    // the connection is usable after dropping a stream part way
+    let first = client.scan(String::new()).next().await.transpose()?;
+    assert_eq!(first.map(|(key, _)| key), Some("key0000".to_owned()));
+    assert!(client.exists("other".to_owned()).await?);
+
+    server.shutdown().await
+}
+
+// Should forward each key to the shard owning it and merge scans across shards
+#[tokio::test]
+async fn router_forwards_to_shards() -> Result<()> {
+    let mut shards = Vec::new();
+    for _ in 0..2 {
+        let shard = KvsServer::new(MemKvsEngine::new())
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await?;
+        shards.push(shard);
+    }
+    let addrs: Vec<_> = shards.iter().map(|s| s.local_addr().unwrap()).collect();
+    let map = ShardMap::new(addrs.clone())?;
+    let router = KvsServer::new(RouterEngine::new(map.clone()))
+        .shard_map(map.clone())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(router.local_addr().unwrap()).await?;
+    for i in 0..20 {
+        client
+            .set(format!("key{:02}", i), format!("value{}", i))
+            .await?;
+    }
+    assert_eq!(client.shard_map().await?.shards(), &addrs[..]);
+
+    let mut owned = vec![0; 2];
+    for i in 0..20 {
+        let key = format!("key{:02}", i);
+        let owner = map.shard_for(&key);
+        for (n, &addr) in addrs.iter().enumerate() {
+            let value = KvsClient::connect(addr).await?.get(key.clone()).await?;
+            if addr == owner {
+                assert_eq!(value, Some(format!("value{}", i)));
+                owned[n] += 1;
+            } else {
+                assert_eq!(value, None);
+            }
+        }
+    }
+    assert!(owned.iter().all(|&n| n > 0));
+
+    let (pairs, cursor) = client.scan_page("key".to_owned(), None, 5).await?;
+    let keys: Vec<_> = pairs.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, ["key00", "key01", "key02", "key03", "key04"]);
+    assert_eq!(cursor, Some("key04".to_owned()));
+    assert!(client.remove("missing".to_owned()).await.is_err());
+
+    router.shutdown().await?;
+    for shard in shards {
+        shard.shutdown().await?;
+    }
+    Ok(())
+}
+
+// Should keep the newest requests slower than the threshold
+#[tokio::test]
+async fn slowlog_entries() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .slowlog(Duration::ZERO, 2)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::SLOWLOG));
+    client.slowlog_reset().await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.get("key1".to_owned()).await?;
+    client.remove("key1".to_owned()).await?;
+
+    let entries = client.slowlog(10).await?;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].op, "remove");
+    assert_eq!(entries[1].op, "get");
+    assert_eq!(entries[1].key.as_deref(), Some("key1"));
+    assert_eq!(entries[0].id, entries[1].id + 1);
+    assert!(entries[0].peer.is_some());
+    assert_eq!(client.slowlog(1).await?.len(), 1);
+
+    client.slowlog_reset().await?;
+    assert!(client.slowlog(10).await?.is_empty());
+
+    server.shutdown().await
+}
+
+// Should report connections, ops served, engine statistics and the data dir size
+#[tokio::test]
+async fn server_stats() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(dir.path().join("data"), [0; 100])?;
+    let server = KvsServer::new(MemKvsEngine::new())
+        .data_dir(dir.path())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::STATS));
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.get("key1".to_owned()).await?;
+    client.get("key2".to_owned()).await?;
+
+    let stats = client.stats().await?;
+    assert_eq!(stats.connections, 1);
+    assert_eq!(stats.total_connections, 1);
+    assert_eq!(stats.ops.get("set"), Some(&1));
+    assert_eq!(stats.ops.get("get"), Some(&2));
+    assert_eq!(stats.engine.get("keys"), Some(&1));
+    assert_eq!(stats.data_dir_bytes, Some(100));
+
+    server.shutdown().await
+}
+
+// Should back up a live store into a directory that opens as a store
+#[tokio::test]
+async fn backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let server = KvsServer::new(store)
+        .enable_admin(true)
+        .backup_dir(backup_dir.path())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::BACKUP));
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.backup("daily").await?;
+    client.set("key1".to_owned(), "changed".to_owned()).await?;
+    // a directory that already holds a store is refused
+    assert!(client.backup("daily").await.is_err());
+    // as are paths leaving the backup directory
+    assert!(client.backup(temp_dir.path()).await.is_err());
+    assert!(client.backup("../escaped").await.is_err());
+    server.shutdown().await?;
+
+    let backup = KvStore::<RayonThreadPool>::open(backup_dir.path().join("daily"), 1)?;
+    assert_eq!(
+        backup.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    // servers without a backup directory or admin requests refuse backups
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let server = KvsServer::new(store)
+        .enable_admin(true)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.backup("other").await.is_err());
+    server.shutdown().await?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let server = KvsServer::new(store)
+        .backup_dir(backup_dir.path())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.backup("other").await.is_err());
+    server.shutdown().await?;
+    assert!(!backup_dir.path().join("other").exists());
+
+    // engines without checkpoints refuse backups
+    let server = KvsServer::new(MemKvsEngine::new())
+        .enable_admin(true)
+        .backup_dir(backup_dir.path())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.backup("other").await.is_err());
+    server.shutdown().await
+}
+
+// Should keep the keys of each database apart
+#[tokio::test]
+async fn select_databases() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    let server = KvsServer::new(engine.clone())
+        .databases(2)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    let mut client = KvsClient::connect(addr).await?;
+    assert!(client.server_info().await?.supports(feature::SELECT));
+    client.set("key1".to_owned(), "db0".to_owned()).await?;
+    client.select(1).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    client.set("key1".to_owned(), "db1".to_owned()).await?;
+    client.set("key2".to_owned(), "db1".to_owned()).await?;
+    assert_eq!(client.scan_page(String::new(), None, 10).await?.0.len(), 2);
+    assert!(client.select(2).await.is_err());
+
+    let mut other = KvsClient::connect(addr).await?;
+    assert_eq!(other.get("key1".to_owned()).await?, Some("db0".to_owned()));
+    let (pairs, _) = other.scan_page(String::new(), None, 10).await?;
+    assert_eq!(pairs, vec![("key1".to_owned(), "db0".to_owned())]);
+    // database 0 is the engine's own keyspace
+    assert_eq!(engine.get("key1".to_owned()).await?, Some("db0".to_owned()));
+
+    server.shutdown().await
+}
+
+// Should apply the writes queued between Multi and Exec as one batch
+#[tokio::test]
+async fn multi_exec() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::TRANSACTIONS));
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client
+        .transaction(vec![
+            ("key2".to_owned(), Some("value2".to_owned())),
+            ("key1".to_owned(), None),
+            ("missing".to_owned(), None),
+        ])
+        .await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    assert_eq!(
+        client.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    // a refused request discards the whole transaction
+    let responses = client
+        .pipeline(vec![
+            Request::Multi,
+            Request::Set {
+                key: "key3".to_owned(),
+                value: "value3".to_owned(),
+                ttl_ms: None,
+            },
+            Request::Get {
+                key: "key2".to_owned(),
+            },
+            Request::Exec,
+        ])
+        .await?;
+    assert!(matches!(responses[1], Response::Queued));
+    assert!(matches!(responses[2], Response::Err(_)));
+    assert!(matches!(responses[3], Response::Err(_)));
+    assert_eq!(client.get("key3".to_owned()).await?, None);
+
+    let responses = client
+        .pipeline(vec![
+            Request::Multi,
+            Request::Remove {
+                key: "key2".to_owned(),
+            },
+            Request::Discard,
+            Request::Exec,
+        ])
+        .await?;
+    assert!(matches!(responses[2], Response::Discard));
+    assert!(matches!(responses[3], Response::Err(_)));
+    assert_eq!(
+        client.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+// Should run every request of a batch in order and answer them in one frame
+#[tokio::test]
+async fn batch_requests() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::BATCH));
+    let mut requests: Vec<_> = (0..10)
+        .map(|i| Request::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+            ttl_ms: None,
+        })
+        .collect();
+    requests.push(Request::Get {
+        key: "key3".to_owned(),
+    });
+    requests.push(Request::Remove {
+        key: "missing".to_owned(),
+    });
+    requests.push(Request::Ping);
+    requests.push(Request::Get {
+        key: "key9".to_owned(),
+    });
+    let responses = client.send_batch(requests).await?;
+
+    assert_eq!(responses.len(), 14);
+    assert!(responses[..10]
+        .iter()
+        .all(|res| matches!(res, Response::Set)));
+    assert!(matches!(&responses[10], Response::Get(Some(value)) if value == "value3"));
+    assert!(matches!(responses[11], Response::Err(_)));
+    assert!(matches!(responses[12], Response::Err(_)));
+    assert!(matches!(&responses[13], Response::Get(Some(value)) if value == "value9"));
+
+    server.shutdown().await
+}
+
+// Should send queued operations in as few frames as possible and report each result
+#[tokio::test]
+async fn batch_builder() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+
+    // more than fits one frame
+    let mut batch = client.batch();
+    for i in 0..2500 {
+        batch = batch.set(format!("key{}", i), format!("value{}", i));
+    }
+    let results = batch
+        .get("key2499".to_owned())
+        .remove("key0".to_owned())
+        .remove("missing".to_owned())
+        .get("key0".to_owned())
+        .send()
+        .await?;
+
+    assert_eq!(results.len(), 2504);
+    assert!(results[..2500]
+        .iter()
+        .all(|res| matches!(res, Ok(BatchReply::Set))));
+    assert_eq!(
+        results[2500].as_ref().unwrap(),
+        &BatchReply::Get(Some("value2499".to_owned()))
+    );
+    assert_eq!(results[2501].as_ref().unwrap(), &BatchReply::Remove);
+    assert!(results[2502].is_err());
+    assert_eq!(results[2503].as_ref().unwrap(), &BatchReply::Get(None));
+    assert_eq!(client.len().await?, 2499);
+
+    server.shutdown().await
+}
+
+// Should expire keys set with a TTL and report, extend or remove expirations
+#[tokio::test]
+async fn key_expiration() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+
+    client
+        .set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_millis(100),
+        )
+        .await?;
+    let ttl = client.ttl("key1".to_owned()).await?.unwrap();
+    assert!(ttl <= Duration::from_millis(100));
+    time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+
+    client.set("key2".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(client.ttl("key2".to_owned()).await?, None);
+    assert!(
+        client
+            .expire("key2".to_owned(), Duration::from_secs(60))
+            .await?
+    );
+    assert!(client.ttl("key2".to_owned()).await?.is_some());
+    assert!(client.persist("key2".to_owned()).await?);
+    assert_eq!(client.ttl("key2".to_owned()).await?, None);
+    assert!(
+        !client
+            .expire("missing".to_owned(), Duration::from_secs(60))
+            .await?
+    );
+
+    server.shutdown().await
+}
+
+// Should rename and copy keys on the server
+#[tokio::test]
+async fn rename_and_copy() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::RENAME));
+
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.set("key2".to_owned(), "value2".to_owned()).await?;
+    assert!(client
+        .rename("missing".to_owned(), "key3".to_owned())
+        .await
+        .is_err());
+    client.rename("key1".to_owned(), "key3".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+
+    assert!(
+        !client
+            .copy("key3".to_owned(), "key2".to_owned(), false)
+            .await?
+    );
+    assert_eq!(
+        client.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert!(
+        client
+            .copy("key3".to_owned(), "key2".to_owned(), true)
+            .await?
+    );
+    assert_eq!(
+        client.get("key2".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+// Should count keys and test their presence without reading values
+#[tokio::test]
+async fn len_and_exists() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .databases(2)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::KEY_COUNT));
+
+    assert!(client.is_empty().await?);
+    assert!(!client.exists("key1".to_owned()).await?);
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.set("key2".to_owned(), "value2".to_owned()).await?;
+    assert!(client.exists("key1".to_owned()).await?);
+    assert_eq!(client.len().await?, 2);
+
+    // only the keys of the selected database are counted
+    client.select(1).await?;
+    assert_eq!(client.len().await?, 0);
+    assert!(!client.exists("key1".to_owned()).await?);
+
+    server.shutdown().await
+}
+
+// Should count the keys of a KvStore database without reading their values
+#[tokio::test]
+async fn len_without_values() -> Result<()> {
+    for databases in [1, 2] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        // a value scans can't return, so counting must not read it
+        store
+            .clone()
+            .set_bytes("key1".into(), vec![0xff, 0xfe].into())
+            .await?;
+        store
+            .clone()
+            .set("key2".to_owned(), "value2".to_owned())
+            .await?;
+        let server = KvsServer::new(store)
+            .databases(databases)
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await?;
+        let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+        assert_eq!(client.len().await?, 2);
+
+        if databases > 1 {
+            client.select(1).await?;
+            client.set("key3".to_owned(), "value3".to_owned()).await?;
+            assert_eq!(client.len().await?, 1);
+            client.select(0).await?;
+            assert_eq!(client.len().await?, 2);
+        }
+        server.shutdown().await?;
+    }
+    Ok(())
+}
+
+// Should answer GetMeta without the value
+#[tokio::test]
+async fn get_meta() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(client.server_info().await?.supports(feature::GET_META));
+
+    assert_eq!(client.get_meta("key1".to_owned()).await?, None);
+    client
+        .set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_secs(60),
+        )
+        .await?;
+    let meta = client.get_meta("key1".to_owned()).await?.unwrap();
+    assert_eq!(meta.len, 6);
+    assert!(meta.modified_ms.is_some());
+    assert!(meta.ttl_ms.unwrap() <= 60_000);
+
+    server.shutdown().await
+}
+
+// Should send echo payloads back unchanged and count them as requests
+#[tokio::test]
+async fn echo() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    for codec in [Codec::Json, Codec::MessagePack] {
+        let mut client = KvsClient::builder().codec(codec).connect(addr).await?;
+        assert!(client.server_info().await?.supports(feature::ECHO));
+        assert_eq!(client.echo(Vec::new()).await?, Vec::<u8>::new());
+        let payload: Vec<u8> = (0..=255).collect();
+        assert_eq!(client.echo(payload.clone()).await?, payload);
+    }
+
+    let mut client = KvsClient::connect(addr).await?;
+    assert_eq!(client.stats().await?.ops.get("echo"), Some(&4));
+
+    server.shutdown().await
+}
+
+// Should stream values and scans too large for one frame in chunks
+#[tokio::test]
+async fn streamed_responses() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    let server = KvsServer::new(engine.clone())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    // larger than the 8 MiB frames the client reads
+    let large = "x".repeat(9 * 1024 * 1024);
+    engine
+        .clone()
+        .set("large".to_owned(), large.clone())
+        .await?;
+    for i in 0..100 {
+        let value = "v".repeat(1024);
+        engine.clone().set(format!("key{:03}", i), value).await?;
+    }
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert_eq!(client.get_streamed("large".to_owned()).await?, Some(large));
+    assert_eq!(client.get_streamed("missing".to_owned()).await?, None);
+    let pairs: Vec<_> = client
+        .scan_streamed("key".to_owned())
+        .await?
+        .try_collect()
+        .await?;
+    assert_eq!(pairs.len(), 100);
+    assert_eq!(pairs[0].0, "key000");
+
+    // the rest of a dropped stream is skipped before the next request
+    let mut pairs = client.scan_streamed("key".to_owned()).await?;
+    assert!(pairs.next().await.is_some());
+    drop(pairs);
+    assert_eq!(
+        client.get("key099".to_owned()).await?,
+        Some("v".repeat(1024))
+    );
+
+    server.shutdown().await
+}
+
+// Should only apply conditional sets whose condition holds
+#[tokio::test]
+async fn conditional_set() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut first = KvsClient::connect(server.local_addr().unwrap()).await?;
+    let mut second = KvsClient::connect(server.local_addr().unwrap()).await?;
+    assert!(first
+        .server_info()
+        .await?
+        .supports(feature::CONDITIONAL_SET));
+
+    // only one client takes the lock
+    assert!(first.set_nx("lock".to_owned(), "first".to_owned()).await?);
+    assert!(
+        !second
+            .set_nx("lock".to_owned(), "second".to_owned())
+            .await?
+    );
+
+    assert!(
+        !second
+            .set_if("lock".to_owned(), "second".to_owned(), "free".to_owned())
+            .await?
+    );
+    assert!(
+        first
+            .set_if("lock".to_owned(), "first".to_owned(), "free".to_owned())
+            .await?
+    );
+    assert_eq!(
+        second.get("lock".to_owned()).await?,
+        Some("free".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+// Should report conditional writes as typed outcomes and never lose increments
+#[tokio::test]
+async fn compare_and_swap_and_incr() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+    let mut client = KvsClient::connect(addr).await?;
+
+    assert_eq!(
+        client
+            .set_if_absent("key1".to_owned(), "value1".to_owned())
+            .await?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(
+        client
+            .set_if_absent("key1".to_owned(), "value2".to_owned())
+            .await?,
+        CasOutcome::Conflict
+    );
+    assert_eq!(
+        client
+            .compare_and_swap(
+                "key1".to_owned(),
+                Some("value2".to_owned()),
+                "value3".to_owned()
+            )
+            .await?,
+        CasOutcome::Conflict
+    );
+    assert_eq!(
+        client
+            .compare_and_swap(
+                "key1".to_owned(),
+                Some("value1".to_owned()),
+                "value3".to_owned()
+            )
+            .await?,
+        CasOutcome::Swapped
+    );
+
+    assert!(matches!(
+        client.incr("key1".to_owned(), 1).await,
+        Err(KvsError::NotAnInteger)
+    ));
+    assert_eq!(client.incr("counter".to_owned(), 5).await?, 5);
+    assert_eq!(client.incr("counter".to_owned(), -2).await?, 3);
+
+    // racing clients each get their increment applied
+    let increments = (0..4).map(|_| async move {
+        let mut client = KvsClient::connect(addr).await?;
+        for _ in 0..10 {
+            client.incr("counter".to_owned(), 1).await?;
+        }
+        Ok::<_, KvsError>(())
+    });
+    futures::future::try_join_all(increments).await?;
+    assert_eq!(
+        client.get("counter".to_owned()).await?,
+        Some("43".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+// Should switch to the codec negotiated in the handshake
+#[tokio::test]
+async fn messagepack_codec() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    let mut client = KvsClient::builder()
+        .codec(Codec::MessagePack)
+        .connect(addr)
+        .await?;
+    assert_eq!(client.codec(), Codec::MessagePack);
+    assert_eq!(
+        client.server_info().await?.codec.as_deref(),
+        Some("messagepack")
+    );
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    let responses = client
+        .pipeline(vec![
+            Request::Get {
+                key: "key1".to_owned(),
+            },
+            Request::Ping,
+        ])
+        .await?;
+    assert!(matches!(&responses[0], Response::Get(Some(value)) if value == "value1"));
+    assert!(matches!(responses[1], Response::Pong));
+
+    // other connections keep speaking JSON
+    let mut json = KvsClient::connect(addr).await?;
+    assert_eq!(json.codec(), Codec::Json);
+    assert_eq!(
+        json.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+// Should compress large frames with the negotiated compression
+#[tokio::test]
+async fn compressed_frames() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+    let large = "value".repeat(100_000);
+
+    for compression in [Compression::Lz4, Compression::Zstd] {
+        let mut client = KvsClient::builder()
+            .compression(compression)
+            .connect(addr)
+            .await?;
+        assert_eq!(client.codec(), Codec::Json);
+        assert_eq!(client.compression(), Some(compression));
+        assert_eq!(
+            client.server_info().await?.compression.as_deref(),
+            Some(compression.name())
+        );
+
+        let key = format!("key-{}", compression.name());
+        client.set(key.clone(), large.clone()).await?;
+        assert_eq!(client.get(key.clone()).await?, Some(large.clone()));
+        // small frames go out uncompressed on the same connection
+        client.set(key.clone(), "small".to_owned()).await?;
+        assert_eq!(client.get(key).await?, Some("small".to_owned()));
+    }
+
+    // together with another codec
+    let mut client = KvsClient::builder()
+        .codec(Codec::MessagePack)
+        .compression(Compression::Zstd)
+        .connect(addr)
+        .await?;
+    assert_eq!(client.codec(), Codec::MessagePack);
+    assert_eq!(client.compression(), Some(Compression::Zstd));
+    client.set("key1".to_owned(), large.clone()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some(large));
+
+    // clients that don't ask stay uncompressed
+    let mut plain = KvsClient::connect(addr).await?;
+    assert_eq!(plain.compression(), None);
+    assert!(plain.get("key1".to_owned()).await?.is_some());
+
+    server.shutdown().await
+}
+
+// Should end frames with checksums once negotiated and drop corrupted frames
+#[tokio::test]
+async fn frame_checksums() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    let mut client = KvsClient::builder()
+        .checksums(true)
+        .compression(Compression::Lz4)
+        .connect(addr)
+        .await?;
+    assert!(client.checksums());
+    let large = "value".repeat(1000);
+    client.set("key1".to_owned(), large.clone()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some(large));
+
+    // a frame whose checksum doesn't match closes the connection
+    let mut stream = TcpStream::connect(addr).await?;
+    let hello = br#"{"Hello":{"protocol_version":1,"checksums":true}}"#;
+    stream.write_u32(hello.len() as u32).await?;
+    stream.write_all(hello).await?;
+    let len = stream.read_u32().await?;
+    let mut response = vec![0; len as usize];
+    stream.read_exact(&mut response).await?;
+    let get = br#"{"Get":{"key":"key1"}}"#;
+    stream.write_u32(get.len() as u32 + 4).await?;
+    stream.write_all(get).await?;
+    stream.write_all(&[0; 4]).await?;
+    let mut buf = Vec::new();
+    let read = time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+
+    server.shutdown().await
+}
+
+// Should refuse values over the limit with any engine, over either protocol
+#[tokio::test]
+async fn max_value_size() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .max_value_size(8)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    let err = client
+        .set("key1".to_owned(), "too large".to_owned())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("exceeds the limit of 8 bytes"));
+    assert!(client
+        .append("key1".to_owned(), "too large".to_owned())
+        .await
+        .is_err());
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    server.shutdown().await?;
+
+    let server = KvsServer::new(MemKvsEngine::new())
+        .max_value_size(8)
+        .spawn_resp("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let mut stream = TcpStream::connect(server.local_addr().unwrap()).await?;
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$9\r\ntoo large\r\n")
+        .await?;
+    let reply = b"-ERR Value of 9 bytes exceeds the limit of 8 bytes\r\n";
+    let mut buf = vec![0; reply.len()];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, reply);
+
+    server.shutdown().await
+}
+
+// Should move frames over 8 MiB once both sides raise their limits
+#[tokio::test]
+async fn max_frame_length() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .max_request_size(16 * 1024 * 1024)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+    let large = "x".repeat(10 * 1024 * 1024);
+
+    let mut client = KvsClient::connect(addr).await?;
+    assert!(client.set("key1".to_owned(), large.clone()).await.is_err());
+
+    let mut client = KvsClient::builder()
+        .max_frame_length(16 * 1024 * 1024)
+        .connect(addr)
+        .await?;
+    client.set("key1".to_owned(), large.clone()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some(large));
+
+    server.shutdown().await
+}
+
+// Should answer a request before failing on a bad frame sent right after it
+#[tokio::test]
+async fn bad_frame_after_request() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .max_request_size(1024)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    async fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+        let len = stream.read_u32().await?;
+        let mut frame = vec![0; len as usize];
+        stream.read_exact(&mut frame).await?;
+        Ok(frame)
+    }
+
+    let get = br#"{"Get":{"key":"key1"}}"#;
+    // the bad frame arrives while the request runs, many times to catch either order
+    for _ in 0..20 {
+        // a frame over the size limit
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut frames = (get.len() as u32).to_be_bytes().to_vec();
+        frames.extend_from_slice(get);
+        frames.extend_from_slice(&(64 * 1024u32).to_be_bytes());
+        stream.write_all(&frames).await?;
+        assert_eq!(read_frame(&mut stream).await?, br#"{"Get":null}"#);
+        let error = String::from_utf8(read_frame(&mut stream).await?).unwrap();
+        assert!(error.contains("exceeds the limit"));
+
+        // a frame that isn't a request
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut frames = (get.len() as u32).to_be_bytes().to_vec();
+        frames.extend_from_slice(get);
+        frames.extend_from_slice(&4u32.to_be_bytes());
+        frames.extend_from_slice(b"junk");
+        stream.write_all(&frames).await?;
+        assert_eq!(read_frame(&mut stream).await?, br#"{"Get":null}"#);
+    }
+
+    server.shutdown().await
+}
+
+// Should log successful writes with the peer and user, rotating the log when full
+#[tokio::test]
+async fn audit_log() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = dir.path().join("audit.log");
+    let options = AuditLogOptions {
+        max_size: Some(500),
+        max_files: 1,
+    };
+    let acl = Acl::new(vec![AclUser {
+        name: "team-a".to_owned(),
+        password: "secret-a".to_owned(),
+        read: vec![String::new()],
+        write: vec![String::new()],
+        admin: false,
+    }]);
+    let server = KvsServer::new(MemKvsEngine::new())
+        .acl(acl)
+        .audit_log(AuditLog::open_with_options(&path, options)?)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::connect(server.local_addr().unwrap()).await?;
+    client
+        .authenticate_as("team-a".to_owned(), "secret-a".to_owned())
+        .await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.get("key1".to_owned()).await?;
+    assert!(
+        client
+            .expire("key1".to_owned(), Duration::from_secs(60))
+            .await?
+    );
+    assert!(client.persist("key1".to_owned()).await?);
+    // failed writes changed nothing and aren't logged
+    assert!(!client.persist("key1".to_owned()).await?);
+    assert!(
+        !client
+            .expire("key2".to_owned(), Duration::from_secs(60))
+            .await?
+    );
+    assert!(client.remove("key2".to_owned()).await.is_err());
+    client.remove("key1".to_owned()).await?;
+
+    let log = std::fs::read_to_string(&path)?;
+    let entries: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let ops: Vec<_> = entries.iter().map(|entry| entry["op"].clone()).collect();
+    assert_eq!(ops, ["set", "expire", "persist", "remove"]);
+    assert_eq!(entries[0]["key"], "key1");
+    assert_eq!(entries[0]["user"], "team-a");
+    assert_eq!(entries[1]["key"], "key1");
+    assert!(!log.contains("value1"));
+
+    for i in 0..10 {
+        client.set(format!("key{}", i), "value".to_owned()).await?;
+    }
+    assert!(std::fs::metadata(&path)?.len() <= 500);
+    assert!(dir.path().join("audit.log.1").exists());
+    assert!(!dir.path().join("audit.log.2").exists());
+
+    server.shutdown().await
+}
+
+// Should close connections without requests for longer than the idle timeout
+#[tokio::test]
+async fn idle_timeout() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .idle_timeout(Duration::from_millis(200))
+        .tcp_nodelay(true)
+        .tcp_keepalive(Duration::from_secs(60))
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+
+    let mut client = KvsClient::builder()
+        .tcp_nodelay(true)
+        .tcp_keepalive(Duration::from_secs(60))
+        .connect(server.local_addr().unwrap())
+        .await?;
+    for _ in 0..3 {
+        time::sleep(Duration::from_millis(100)).await;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+    }
+
+    time::sleep(Duration::from_millis(400)).await;
+    assert!(client.get("key1".to_owned()).await.is_err());
+
+    server.shutdown().await
+}
+
+// Should answer a request over the size limit with an error instead of reading it
+#[tokio::test]
+async fn oversized_request() -> Result<()> {
+    let server = KvsServer::new(MemKvsEngine::new())
+        .max_request_size(1024)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    let mut client = KvsClient::connect(addr).await?;
+    let err = client
+        .set("key1".to_owned(), "x".repeat(64 * 1024))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("exceeds the limit"));
+
+    let mut client = KvsClient::connect(addr).await?;
+    client.set("key1".to_owned(), "x".repeat(512)).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?.map(|v| v.len()),
+        Some(512)
+    );
+
+    server.shutdown().await
+}
@@ -0,0 +1,25 @@
+use kvs::{sync::KvStore, KvsError, Result};
+use tempfile::TempDir;
+
+// Should get, set and remove values without an async runtime
+#[test]
+fn blocking_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.close()?;
+
+    // the facade reads the same log as the async engine
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
@@ -1,8 +1,10 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -46,6 +48,171 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+fn shutdown_drains_jobs<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let pool = P::new(4)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+    pool.shutdown(Duration::from_secs(10))?;
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+
+    // jobs spawned afterwards are dropped
+    let late = Arc::clone(&counter);
+    pool.clone().spawn(move || {
+        late.fetch_add(1, Ordering::SeqCst);
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+
+    // a job outlasting the timeout fails the shutdown
+    let pool = P::new(1)?;
+    pool.spawn(|| thread::sleep(Duration::from_millis(500)));
+    assert!(pool.shutdown(Duration::from_millis(10)).is_err());
+    pool.shutdown(Duration::from_secs(10))
+}
+
+fn spawn_with_result_returns<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let handles: Vec<_> = (0..10)
+        .map(|i| pool.spawn_with_result(move || i * 2))
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(futures::executor::block_on(handle)?, i * 2);
+    }
+
+    let panicked: JobHandle<()> = pool.spawn_with_result(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("boom");
+    });
+    match futures::executor::block_on(panicked) {
+        Err(KvsError::JobPanicked(message)) => assert_eq!(message, "boom"),
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    // the pool survives the panic
+    assert_eq!(
+        futures::executor::block_on(pool.spawn_with_result(|| 1))?,
+        1
+    );
+    Ok(())
+}
+
+fn builder_names_threads<P: ThreadPool>() -> Result<()> {
+    let started = Arc::new(AtomicUsize::new(0));
+    let stopped = Arc::new(AtomicUsize::new(0));
+    let (on_start, on_stop) = (Arc::clone(&started), Arc::clone(&stopped));
+    let pool: P = ThreadPoolBuilder::new(2)
+        .name_prefix("test-pool")
+        .stack_size(1 << 20)
+        .on_thread_start(move || {
+            on_start.fetch_add(1, Ordering::SeqCst);
+        })
+        .on_thread_stop(move || {
+            on_stop.fetch_add(1, Ordering::SeqCst);
+        })
+        .build()?;
+
+    let name = futures::executor::block_on(
+        pool.spawn_with_result(|| thread::current().name().map(str::to_owned)),
+    )?;
+    assert!(name.unwrap().starts_with("test-pool-"));
+
+    pool.shutdown(Duration::from_secs(10))?;
+    assert!(started.load(Ordering::SeqCst) > 0);
+    assert_eq!(
+        stopped.load(Ordering::SeqCst),
+        started.load(Ordering::SeqCst)
+    );
+    Ok(())
+}
+
+fn stats_count_jobs<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let barrier = Arc::new(Barrier::new(2));
+    let blocker = Arc::clone(&barrier);
+    let running = pool.spawn_with_result(move || {
+        blocker.wait();
+    });
+    // the job is running once it reached the barrier, until the barrier opens
+    while pool.stats().busy == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let stats = pool.stats();
+    assert_eq!(stats.busy, 1);
+    assert_eq!(stats.completed, 0);
+    barrier.wait();
+    futures::executor::block_on(running)?;
+
+    for _ in 0..10 {
+        pool.spawn(|| {});
+    }
+    pool.shutdown(Duration::from_secs(10))?;
+    let stats = pool.stats();
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.busy, 0);
+    assert_eq!(stats.completed, 11);
+    assert_eq!(stats.threads, 0);
+    Ok(())
+}
+
+fn cancel_and_deadline<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let ran = Arc::new(AtomicUsize::new(0));
+    let counter = |ran: &Arc<AtomicUsize>| {
+        let ran = Arc::clone(ran);
+        move || {
+            ran.fetch_add(1, Ordering::SeqCst);
+        }
+    };
+
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    let options = JobOptions {
+        cancel: Some(cancel),
+        ..JobOptions::default()
+    };
+    let cancelled = pool.spawn_with_result_and_options(options, counter(&ran));
+    assert!(matches!(
+        futures::executor::block_on(cancelled),
+        Err(KvsError::Cancelled)
+    ));
+
+    let options = JobOptions {
+        deadline: Some(Instant::now()),
+        ..JobOptions::default()
+    };
+    let expired = pool.spawn_with_result_and_options(options, counter(&ran));
+    assert!(matches!(
+        futures::executor::block_on(expired),
+        Err(KvsError::Timeout)
+    ));
+
+    // a job that started in time runs to the end
+    let options = JobOptions {
+        deadline: Some(Instant::now() + Duration::from_millis(200)),
+        ..JobOptions::default()
+    };
+    let late = pool.spawn_with_result_and_options(options, || {
+        thread::sleep(Duration::from_millis(400));
+    });
+    futures::executor::block_on(late)?;
+
+    pool.shutdown(Duration::from_secs(10))?;
+    assert_eq!(ran.load(Ordering::SeqCst), 0);
+    let stats = pool.stats();
+    assert_eq!(stats.cancelled, 1);
+    assert_eq!(stats.expired, 1);
+    assert_eq!(stats.overran, 1);
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -68,3 +235,196 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn naive_thread_pool_shutdown() -> Result<()> {
+    shutdown_drains_jobs::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown() -> Result<()> {
+    shutdown_drains_jobs::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_shutdown() -> Result<()> {
+    shutdown_drains_jobs::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_spawn_with_result() -> Result<()> {
+    spawn_with_result_returns::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_result() -> Result<()> {
+    spawn_with_result_returns::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_result() -> Result<()> {
+    spawn_with_result_returns::<RayonThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_resize() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_bounds(1, 4, Duration::from_millis(50))?;
+    assert_eq!(pool.threads(), 1);
+
+    // four jobs waiting for each other only finish if the pool grows
+    let barrier = Arc::new(Barrier::new(5));
+    for _ in 0..4 {
+        let barrier = Arc::clone(&barrier);
+        pool.spawn(move || {
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+    assert_eq!(pool.threads(), 4);
+
+    // the idle threads above the minimum exit
+    wait_for_threads(&pool, 1);
+
+    pool.resize(2)?;
+    let barrier = Arc::new(Barrier::new(3));
+    for _ in 0..2 {
+        let barrier = Arc::clone(&barrier);
+        pool.spawn(move || {
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+    assert!(pool.threads() <= 2);
+
+    assert!(pool.resize(0).is_err());
+    assert!(SharedQueueThreadPool::with_bounds(3, 2, Duration::from_secs(1)).is_err());
+    pool.shutdown(Duration::from_secs(10))
+}
+
+#[test]
+fn shared_queue_thread_pool_idle_exit_keeps_queued_jobs() -> Result<()> {
+    // the only thread keeps timing out while jobs are spawned, each of which it must
+    // still run rather than exit with the job queued
+    let pool = SharedQueueThreadPool::with_bounds(0, 1, Duration::from_millis(1))?;
+    for i in 0..200u64 {
+        thread::sleep(Duration::from_micros(i % 20 * 100));
+        let (tx, rx) = std::sync::mpsc::channel();
+        pool.spawn(move || tx.send(i).unwrap());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(i));
+    }
+    pool.shutdown(Duration::from_secs(10))
+}
+
+fn wait_for_threads(pool: &SharedQueueThreadPool, threads: u32) {
+    for _ in 0..100 {
+        if pool.threads() == threads {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("the pool still runs {} threads", pool.threads());
+}
+
+#[test]
+fn shared_queue_thread_pool_worker_stats() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let panics = Arc::new(Mutex::new(Vec::new()));
+    let hook_panics = Arc::clone(&panics);
+    pool.set_panic_hook(move |message| hook_panics.lock().unwrap().push(message.to_owned()));
+
+    for _ in 0..10 {
+        pool.spawn(|| {});
+    }
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("boom");
+    });
+    pool.shutdown(Duration::from_secs(10))?;
+
+    let stats = pool.worker_stats();
+    assert_eq!(stats.jobs, 11);
+    assert_eq!(stats.panics, 1);
+    assert_eq!(stats.respawns, 1);
+    assert_eq!(*panics.lock().unwrap(), vec!["boom".to_owned()]);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_builder() -> Result<()> {
+    builder_names_threads::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_builder() -> Result<()> {
+    builder_names_threads::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_builder() -> Result<()> {
+    builder_names_threads::<RayonThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_priority() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    let order = Arc::new(Mutex::new(Vec::new()));
+
+    // the only thread is busy until every job is queued
+    let barrier = Arc::new(Barrier::new(2));
+    let blocker = Arc::clone(&barrier);
+    pool.spawn(move || {
+        blocker.wait();
+    });
+    for (name, priority) in [
+        ("background 1", Priority::Background),
+        ("foreground 1", Priority::Foreground),
+        ("background 2", Priority::Background),
+        ("foreground 2", Priority::Foreground),
+    ] {
+        let order = Arc::clone(&order);
+        pool.spawn_at(priority, move || order.lock().unwrap().push(name));
+    }
+    barrier.wait();
+    pool.shutdown(Duration::from_secs(10))?;
+
+    assert_eq!(
+        *order.lock().unwrap(),
+        vec![
+            "foreground 1",
+            "foreground 2",
+            "background 1",
+            "background 2"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_stats() -> Result<()> {
+    stats_count_jobs::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_stats() -> Result<()> {
+    stats_count_jobs::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_stats() -> Result<()> {
+    stats_count_jobs::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_cancel_and_deadline() -> Result<()> {
+    cancel_and_deadline::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_cancel_and_deadline() -> Result<()> {
+    cancel_and_deadline::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_cancel_and_deadline() -> Result<()> {
+    cancel_and_deadline::<RayonThreadPool>()
+}
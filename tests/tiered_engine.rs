@@ -0,0 +1,61 @@
+use kvs::{
+    thread_pool::SharedQueueThreadPool, KvStore, KvsEngine, MemKvsEngine, Result, TieredEngine,
+    TieredStats,
+};
+use tempfile::TempDir;
+
+// Should serve repeated reads from memory and see writes made through it
+#[tokio::test]
+async fn hits_and_write_through() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 1)?;
+    let engine = TieredEngine::new(store, 16);
+
+    engine
+        .clone()
+        .set("key1".to_owned(), "value1".to_owned())
+        .await?;
+    for _ in 0..3 {
+        assert_eq!(
+            engine.clone().get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+    }
+    assert_eq!(engine.stats(), TieredStats { hits: 2, misses: 1 });
+
+    engine
+        .clone()
+        .set("key1".to_owned(), "value2".to_owned())
+        .await?;
+    assert_eq!(
+        engine.clone().get("key1".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    engine.clone().remove("key1".to_owned()).await?;
+    assert_eq!(engine.clone().get("key1".to_owned()).await?, None);
+    drop(engine);
+
+    // every write reached the disk engine
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+
+    Ok(())
+}
+
+// Should evict the least recently used keys once the memory tier is full
+#[tokio::test]
+async fn bounded_memory_tier() -> Result<()> {
+    let engine = TieredEngine::new(MemKvsEngine::new(), 2);
+
+    for key in ["a", "b", "c"] {
+        engine.clone().set(key.to_owned(), key.to_owned()).await?;
+        engine.clone().get(key.to_owned()).await?;
+    }
+    assert_eq!(engine.stats(), TieredStats { hits: 0, misses: 3 });
+
+    engine.clone().get("c".to_owned()).await?;
+    engine.clone().get("a".to_owned()).await?;
+    assert_eq!(engine.stats(), TieredStats { hits: 1, misses: 4 });
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use kvs::rustls::server::AllowAnyAuthenticatedClient;
+use kvs::rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use kvs::{KvsClient, KvsClientBuilder, KvsServer, MemKvsEngine, Result};
+use rcgen::{BasicConstraints, CertificateParams, IsCa};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// A self-signed CA and the certificates it signed for the tests.
+struct Pki {
+    ca: Certificate,
+    server: (Vec<Certificate>, PrivateKey),
+    client: (Vec<Certificate>, PrivateKey),
+}
+
+impl Pki {
+    fn generate() -> Self {
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let signed = |name: &str| {
+            let cert = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+            let der = cert.serialize_der_with_signer(&ca).unwrap();
+            (
+                vec![Certificate(der)],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+        };
+        Pki {
+            server: signed("localhost"),
+            client: signed("client"),
+            ca: Certificate(ca.serialize_der().unwrap()),
+        }
+    }
+
+    fn roots(&self) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(&self.ca).unwrap();
+        roots
+    }
+
+    /// A server config, requiring client certificates signed by the CA if `mutual`.
+    fn server_config(&self, mutual: bool) -> Arc<ServerConfig> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = if mutual {
+            builder
+                .with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(self.roots())))
+        } else {
+            builder.with_no_client_auth()
+        };
+        let (certs, key) = self.server.clone();
+        Arc::new(builder.with_single_cert(certs, key).unwrap())
+    }
+
+    /// A client builder trusting the CA, presenting the client certificate if `mutual`.
+    fn client(&self, mutual: bool) -> KvsClientBuilder {
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(self.roots());
+        let config = if mutual {
+            let (certs, key) = self.client.clone();
+            builder.with_client_auth_cert(certs, key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let name = ServerName::try_from("localhost").unwrap();
+        KvsClient::builder().tls(Arc::new(config), name)
+    }
+}
+
+// Should serve clients over TLS
+#[tokio::test]
+async fn tls() -> Result<()> {
+    let pki = Pki::generate();
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn_tls("127.0.0.1:0".parse().unwrap(), pki.server_config(false))
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    let mut client = pki.client(false).connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    // a plain client doesn't get an answer
+    let mut plain = KvsClient::connect(addr).await?;
+    assert!(plain.get("key1".to_owned()).await.is_err());
+
+    server.shutdown().await
+}
+
+// Should only serve clients presenting a certificate signed by the client CA
+#[tokio::test]
+async fn mutual_tls() -> Result<()> {
+    let pki = Pki::generate();
+    let server = KvsServer::new(MemKvsEngine::new())
+        .spawn_tls("127.0.0.1:0".parse().unwrap(), pki.server_config(true))
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    let mut client = pki.client(true).connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    // the server rejects the handshake, which the client may only notice on its
+    // first request
+    let anonymous = match pki.client(false).connect(addr).await {
+        Ok(mut client) => client.get("key1".to_owned()).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    assert!(anonymous.is_err());
+
+    server.shutdown().await
+}
+
+// Should close connections that don't finish the TLS handshake, releasing their permit
+#[tokio::test]
+async fn tls_handshake_timeout() -> Result<()> {
+    let pki = Pki::generate();
+    let server = KvsServer::new(MemKvsEngine::new())
+        .max_connections(1)
+        .idle_timeout(Duration::from_millis(200))
+        .spawn_tls("127.0.0.1:0".parse().unwrap(), pki.server_config(false))
+        .await?;
+    let addr = server.local_addr().unwrap();
+
+    // connects but never starts the handshake
+    let mut stalled = TcpStream::connect(addr).await?;
+    let mut buf = [0; 1];
+    let closed = time::timeout(Duration::from_secs(5), stalled.read(&mut buf)).await;
+    assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+
+    let mut client = pki.client(false).connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    server.shutdown().await
+}